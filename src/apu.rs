@@ -0,0 +1,1078 @@
+use crate::region::Region;
+
+// -----------------------------
+// APU 2A03
+// pulse, triangle, noise and DMC channels, frame counter ($4000-$4017)
+// -----------------------------
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// $4015 read
+pub const STATUS_PULSE_1: u8 = 0b0000_0001;
+pub const STATUS_PULSE_2: u8 = 0b0000_0010;
+pub const STATUS_TRIANGLE: u8 = 0b0000_0100;
+pub const STATUS_NOISE: u8 = 0b0000_1000;
+pub const STATUS_DMC: u8 = 0b0001_0000;
+pub const STATUS_FRAME_IRQ: u8 = 0b0100_0000;
+pub const STATUS_DMC_IRQ: u8 = 0b1000_0000;
+
+// -----------------------------
+// Building blocks
+// -----------------------------
+
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    // also the length counter halt flag
+    looping: bool,
+    constant: bool,
+    // constant volume, or the divider period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.looping = value & 0b0010_0000 != 0;
+        self.constant = value & 0b0001_0000 != 0;
+        self.volume = value & 0b0000_1111;
+    }
+
+    // quarter frame
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index >> 3) as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // half frame
+    fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+// -----------------------------
+// Pulse
+// -----------------------------
+
+pub struct Pulse {
+    // pulse 1 negates with ones' complement (one less than pulse 2)
+    ones_complement: bool,
+    // the MMC5's copies have no sweep unit, so low periods are not muted
+    has_sweep: bool,
+
+    duty: u8,
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+            has_sweep: true,
+            duty: 0,
+            step: 0,
+            timer_period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // MMC5 expansion audio pulse
+    pub(crate) fn without_sweep() -> Pulse {
+        Pulse {
+            has_sweep: false,
+            ..Pulse::new(false)
+        }
+    }
+
+    pub(crate) fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.envelope.write(value);
+                self.length.halt = self.envelope.looping;
+            }
+            1 => {
+                self.sweep_enabled = value & 0b1000_0000 != 0;
+                self.sweep_period = (value >> 4) & 0b111;
+                self.sweep_negate = value & 0b0000_1000 != 0;
+                self.sweep_shift = value & 0b0000_0111;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value & 0b111) as u16) << 8;
+                self.length.load(value);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // every other CPU cycle
+    pub(crate) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let target = self.timer_period.saturating_sub(change);
+            if self.ones_complement {
+                target.saturating_sub(1)
+            } else {
+                target
+            }
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // the sweep mutes the channel even when it is disabled
+    fn muted(&self) -> bool {
+        self.has_sweep && (self.timer_period < 8 || self.sweep_target() > 0x7FF)
+    }
+
+    // for units clocked outside the APU frame counter (MMC5)
+    pub(crate) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(crate) fn clock_length(&mut self) {
+        self.length.clock();
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.length.active()
+    }
+
+    // half frame
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.muted()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// -----------------------------
+// Triangle
+// -----------------------------
+
+#[derive(Default)]
+pub struct Triangle {
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    length: LengthCounter,
+
+    // the control flag doubles as the length counter halt
+    linear_control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.linear_control = value & 0b1000_0000 != 0;
+                self.linear_reload_value = value & 0b0111_1111;
+                self.length.halt = self.linear_control;
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value & 0b111) as u16) << 8;
+                self.length.load(value);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // quarter frame
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.linear_control {
+            self.linear_reload = false;
+        }
+    }
+
+    // 0-15, silencing only stops the sequencer so the level is held
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+}
+
+// -----------------------------
+// Noise
+// -----------------------------
+
+pub struct Noise {
+    // short mode taps bit 6 instead of bit 1 (93 step sequence)
+    short_mode: bool,
+    shift_register: u16,
+    period_index: usize,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            short_mode: false,
+            shift_register: 1,
+            period_index: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.envelope.write(value);
+                self.length.halt = self.envelope.looping;
+            }
+            1 => {}
+            2 => {
+                self.short_mode = value & 0b1000_0000 != 0;
+                self.period_index = (value & 0b1111) as usize;
+            }
+            _ => {
+                self.length.load(value);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // every other CPU cycle
+    fn clock_timer(&mut self, periods: &[u16; 16]) {
+        if self.timer == 0 {
+            // the table is in CPU cycles, the timer runs at half that
+            self.timer = periods[self.period_index] / 2 - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift_register & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// -----------------------------
+// DMC
+// 1-bit delta samples read from $C000-$FFFF by the memory reader
+// -----------------------------
+
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    rate_index: usize,
+    irq: bool,
+
+    // 7-bit DAC, also loaded directly through $4011
+    output_level: u8,
+    timer: u16,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    // memory reader
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+}
+
+impl Dmc {
+    fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate_index: 0,
+            irq: false,
+            output_level: 0,
+            timer: 0,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0b1000_0000 != 0;
+                self.looping = value & 0b0100_0000 != 0;
+                self.rate_index = (value & 0b1111) as usize;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.output_level = value & 0b0111_1111,
+            2 => self.sample_address = 0xC000 + value as u16 * 64,
+            _ => self.sample_length = value as u16 * 16 + 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    // address the memory reader wants, when the buffer has run dry
+    fn fetch_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        // the address wraps to $8000, not $0000
+        self.current_address = if self.current_address == 0xFFFF {
+            0x8000
+        } else {
+            self.current_address + 1
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // every CPU cycle
+    fn clock_timer(&mut self, periods: &[u16; 16]) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = periods[self.rate_index] - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(value) => {
+                    self.silence = false;
+                    self.shift_register = value;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0-127
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+// -----------------------------
+// APU
+// -----------------------------
+
+pub struct APU {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+
+    pub region: Region,
+
+    // frame counter, in CPU cycles since the sequence started
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // $4017 writes restart the sequence 3 or 4 cycles later
+    frame_reset_delay: u8,
+
+    // CPU cycles since power on, pulse and noise run every other one
+    cycle: u64,
+
+    // nonlinear DAC: pulse1 + pulse2, and 3 * triangle + 2 * noise + dmc
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+}
+
+impl APU {
+    pub fn new(region: Region) -> APU {
+        APU {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            region,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_reset_delay: 0,
+            cycle: 0,
+            pulse_table: std::array::from_fn(|n| {
+                if n == 0 {
+                    0.0
+                } else {
+                    95.52 / (8128.0 / n as f32 + 100.0)
+                }
+            }),
+            tnd_table: std::array::from_fn(|n| {
+                if n == 0 {
+                    0.0
+                } else {
+                    163.67 / (24329.0 / n as f32 + 100.0)
+                }
+            }),
+        }
+    }
+
+    // output level of the 2A03, 0.0 to about 1.0
+    pub fn mix(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let tnd = 3 * self.triangle.output() as usize
+            + 2 * self.noise.output() as usize
+            + self.dmc.output() as usize;
+        self.pulse_table[pulse as usize] + self.tnd_table[tnd]
+    }
+
+    // mix with each channel's DAC input scaled (pulse 1, pulse 2,
+    // triangle, noise, DMC), for muting and per-channel volume
+    pub fn mix_with_gains(&self, gains: &[f32; 5]) -> f32 {
+        if gains.iter().all(|gain| *gain == 1.0) {
+            return self.mix();
+        }
+        let pulse = self.pulse1.output() as f32 * gains[0] + self.pulse2.output() as f32 * gains[1];
+        let tnd = 3.0 * self.triangle.output() as f32 * gains[2]
+            + 2.0 * self.noise.output() as f32 * gains[3]
+            + self.dmc.output() as f32 * gains[4];
+
+        // the same curves as the lookup tables, for fractional inputs
+        let pulse_out = if pulse > 0.0 {
+            95.52 / (8128.0 / pulse + 100.0)
+        } else {
+            0.0
+        };
+        let tnd_out = if tnd > 0.0 {
+            163.67 / (24329.0 / tnd + 100.0)
+        } else {
+            0.0
+        };
+        pulse_out + tnd_out
+    }
+
+    // each channel through the mixer on its own, for recording stems:
+    // pulse 1, pulse 2, triangle, noise, DMC
+    pub fn channel_levels(&self) -> [f32; 5] {
+        [
+            self.pulse_table[self.pulse1.output() as usize],
+            self.pulse_table[self.pulse2.output() as usize],
+            self.tnd_table[3 * self.triangle.output() as usize],
+            self.tnd_table[2 * self.noise.output() as usize],
+            self.tnd_table[self.dmc.output() as usize],
+        ]
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // the DMC memory reader borrows the CPU bus, the CPU fetches the byte
+    // at this address and hands it over with dmc_fill
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill(&mut self, value: u8) {
+        self.dmc.fill(value);
+    }
+
+    // $4015
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length.active() {
+            status |= STATUS_PULSE_1;
+        }
+        if self.pulse2.length.active() {
+            status |= STATUS_PULSE_2;
+        }
+        if self.triangle.length.active() {
+            status |= STATUS_TRIANGLE;
+        }
+        if self.noise.length.active() {
+            status |= STATUS_NOISE;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= STATUS_DMC;
+        }
+        if self.frame_irq {
+            status |= STATUS_FRAME_IRQ;
+        }
+        if self.dmc.irq {
+            status |= STATUS_DMC_IRQ;
+        }
+        // reading acknowledges the frame interrupt
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address - 0x4000, value),
+            0x4004..=0x4007 => self.pulse2.write(address - 0x4004, value),
+            0x4008..=0x400B => self.triangle.write(address - 0x4008, value),
+            0x400C..=0x400F => self.noise.write(address - 0x400C, value),
+            0x4010..=0x4013 => self.dmc.write(address - 0x4010, value),
+            0x4015 => {
+                self.pulse1.length.set_enabled(value & STATUS_PULSE_1 != 0);
+                self.pulse2.length.set_enabled(value & STATUS_PULSE_2 != 0);
+                self.triangle
+                    .length
+                    .set_enabled(value & STATUS_TRIANGLE != 0);
+                self.noise.length.set_enabled(value & STATUS_NOISE != 0);
+                self.dmc.set_enabled(value & STATUS_DMC != 0);
+                self.dmc.irq = false;
+            }
+            0x4017 => {
+                self.five_step = value & 0b1000_0000 != 0;
+                self.irq_inhibit = value & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_reset_delay = if self.cycle.is_multiple_of(2) { 3 } else { 4 };
+            }
+            _ => {}
+        }
+    }
+
+    // one CPU cycle
+    pub fn clock(&mut self) {
+        self.triangle.clock_timer();
+        self.dmc.clock_timer(&self.region.dmc_periods());
+        if self.cycle % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+            self.noise.clock_timer(&self.region.noise_periods());
+        }
+        self.clock_frame_counter();
+        self.cycle += 1;
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if self.frame_reset_delay > 0 {
+            self.frame_reset_delay -= 1;
+            if self.frame_reset_delay == 0 {
+                self.frame_cycle = 0;
+                // 5-step mode clocks everything straight away
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                return;
+            }
+        }
+
+        self.frame_cycle += 1;
+        let steps = self.region.frame_counter_steps();
+        let cycle = self.frame_cycle;
+
+        if cycle == steps[0] || cycle == steps[2] {
+            self.quarter_frame();
+        } else if cycle == steps[1] {
+            self.quarter_frame();
+            self.half_frame();
+        } else if !self.five_step && cycle == steps[3] {
+            self.quarter_frame();
+            self.half_frame();
+            if !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+            self.frame_cycle = 0;
+        } else if self.five_step && cycle == steps[4] {
+            self.quarter_frame();
+            self.half_frame();
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(apu: &mut APU, cycles: u32) {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+
+    #[test]
+    fn test_pulse_duty_and_constant_volume() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4015, STATUS_PULSE_1);
+        // 50% duty, constant volume 9, period 100
+        apu.write_register(0x4000, 0b1001_1001);
+        apu.write_register(0x4002, 100);
+        apu.write_register(0x4003, 0b0000_1000);
+
+        let mut high = 0;
+        for _ in 0..8 {
+            run(&mut apu, 202);
+            if apu.pulse1.output() == 9 {
+                high += 1;
+            }
+        }
+        assert_eq!(high, 4);
+    }
+
+    #[test]
+    fn test_length_counter_and_status() {
+        let mut apu = APU::new(Region::NTSC);
+        // disabled channels ignore length loads
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status() & STATUS_PULSE_1, 0);
+
+        apu.write_register(0x4015, STATUS_PULSE_1 | STATUS_NOISE);
+        // length index 1 = 254, index 3 = 2
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x400F, 0b0001_1000);
+        assert_eq!(apu.read_status(), STATUS_PULSE_1 | STATUS_NOISE);
+
+        // two half frames empty the noise length counter
+        run(&mut apu, 29830);
+        assert_eq!(apu.read_status() & STATUS_NOISE, 0);
+        assert_eq!(apu.read_status() & STATUS_PULSE_1, STATUS_PULSE_1);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_sweep_negate_difference() {
+        let mut apu = APU::new(Region::NTSC);
+        for pulse in [&mut apu.pulse1, &mut apu.pulse2] {
+            pulse.write(1, 0b1000_1001); // enabled, negate, shift 1
+            pulse.write(2, 200);
+        }
+        assert_eq!(apu.pulse1.sweep_target(), 99);
+        assert_eq!(apu.pulse2.sweep_target(), 100);
+    }
+
+    #[test]
+    fn test_sweep_mutes_on_overflow() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4015, STATUS_PULSE_2);
+        apu.write_register(0x4004, 0b0011_1111);
+        apu.write_register(0x4005, 0b0000_0001); // sweep disabled, shift 1
+        apu.write_register(0x4006, 0xFF);
+        apu.write_register(0x4007, 0b0000_1111); // period $7FF
+        assert!(apu.pulse2.muted());
+        apu.write_register(0x4007, 0b0000_1011); // period $3FF
+        assert!(!apu.pulse2.muted());
+    }
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut envelope = Envelope::default();
+        envelope.write(0b0000_0000); // period 0, no loop
+        envelope.start = true;
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+        for _ in 0..20 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.output(), 0);
+
+        envelope.write(0b0010_0000); // loop
+        envelope.clock();
+        assert_eq!(envelope.output(), 15);
+    }
+
+    #[test]
+    fn test_triangle_linear_counter() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4015, STATUS_TRIANGLE);
+        apu.write_register(0x4008, 2); // linear counter 2, control clear
+        apu.write_register(0x400A, 10);
+        apu.write_register(0x400B, 0b0000_1000);
+
+        apu.quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 2);
+        // the reload flag was cleared, so it counts down now
+        apu.quarter_frame();
+        apu.quarter_frame();
+        assert_eq!(apu.triangle.linear_counter, 0);
+
+        // once silenced the sequencer holds its level
+        let level = apu.triangle.output();
+        run(&mut apu, 100);
+        assert_eq!(apu.triangle.output(), level);
+    }
+
+    #[test]
+    fn test_triangle_steps() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4015, STATUS_TRIANGLE);
+        apu.write_register(0x4008, 0x7F);
+        apu.write_register(0x400A, 0);
+        apu.write_register(0x400B, 0b0000_1000);
+        apu.quarter_frame();
+
+        // period 0 steps every cycle
+        assert_eq!(apu.triangle.output(), 15);
+        run(&mut apu, 1);
+        assert_eq!(apu.triangle.output(), 14);
+    }
+
+    #[test]
+    fn test_noise_lfsr_modes() {
+        let periods = Region::NTSC.noise_periods();
+
+        let mut noise = Noise::new();
+        let mut states = std::collections::HashSet::new();
+        for _ in 0..40000 {
+            noise.timer = 0;
+            noise.clock_timer(&periods);
+            states.insert(noise.shift_register);
+        }
+        assert_eq!(states.len(), 32767);
+
+        let mut noise = Noise::new();
+        noise.write(2, 0b1000_0000);
+        let mut states = std::collections::HashSet::new();
+        for _ in 0..1000 {
+            noise.timer = 0;
+            noise.clock_timer(&periods);
+            states.insert(noise.shift_register);
+        }
+        assert_eq!(states.len(), 93);
+    }
+
+    #[test]
+    fn test_frame_irq_four_step() {
+        let mut apu = APU::new(Region::NTSC);
+        run(&mut apu, 29828);
+        assert!(!apu.irq_pending());
+        run(&mut apu, 1);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & STATUS_FRAME_IRQ, STATUS_FRAME_IRQ);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_frame_irq_inhibit_and_five_step() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4017, 0b0100_0000);
+        run(&mut apu, 40000);
+        assert!(!apu.irq_pending());
+
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4017, 0b1000_0000);
+        run(&mut apu, 80000);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_five_step_clocks_immediately() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4015, STATUS_NOISE);
+        apu.write_register(0x400F, 0b0001_1000); // length 2
+        apu.write_register(0x4017, 0b1000_0000);
+        run(&mut apu, 4);
+        assert_eq!(apu.noise.length.counter, 1);
+    }
+
+    #[test]
+    fn test_pal_frame_counter() {
+        let mut apu = APU::new(Region::PAL);
+        run(&mut apu, 29829);
+        assert!(!apu.irq_pending());
+        run(&mut apu, 33253 - 29829);
+        assert!(apu.irq_pending());
+    }
+
+    #[test]
+    fn test_dmc_memory_reader() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4012, 0x01); // $C040
+        apu.write_register(0x4013, 0x01); // 17 bytes
+        assert_eq!(apu.dmc_fetch_address(), None);
+
+        apu.write_register(0x4015, STATUS_DMC);
+        assert_eq!(apu.read_status() & STATUS_DMC, STATUS_DMC);
+        assert_eq!(apu.dmc_fetch_address(), Some(0xC040));
+        apu.dmc_fill(0xFF);
+        // buffer full until the output unit takes it
+        assert_eq!(apu.dmc_fetch_address(), None);
+        assert_eq!(apu.dmc.bytes_remaining, 16);
+    }
+
+    #[test]
+    fn test_dmc_address_wraps() {
+        let mut dmc = Dmc::new();
+        dmc.current_address = 0xFFFF;
+        dmc.bytes_remaining = 2;
+        dmc.fill(0);
+        assert_eq!(dmc.current_address, 0x8000);
+    }
+
+    #[test]
+    fn test_dmc_output_and_direct_load() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4011, 0xC0);
+        assert_eq!(apu.dmc.output(), 0x40);
+
+        apu.write_register(0x4010, 0x0F); // fastest rate, 54 cycles
+        apu.write_register(0x4013, 0x00); // 1 byte
+        apu.write_register(0x4015, STATUS_DMC);
+        apu.dmc_fill(0xFF);
+
+        // the first 8 bits are silence, then every 1 bit adds 2
+        run(&mut apu, 54 * 8 + 54 * 3);
+        assert_eq!(apu.dmc.output(), 0x40 + 2 * 3);
+    }
+
+    #[test]
+    fn test_dmc_irq_and_loop() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4010, 0b1000_0000);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, STATUS_DMC);
+        apu.dmc_fill(0);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & STATUS_DMC_IRQ, STATUS_DMC_IRQ);
+        // reading $4015 leaves the DMC interrupt alone, writing clears it
+        assert!(apu.irq_pending());
+        apu.write_register(0x4015, 0);
+        assert!(!apu.irq_pending());
+
+        apu.write_register(0x4010, 0b0100_0000);
+        apu.write_register(0x4015, STATUS_DMC);
+        apu.dmc_fill(0);
+        assert!(!apu.irq_pending());
+        assert_eq!(apu.dmc.bytes_remaining, 1);
+        assert_eq!(apu.dmc.current_address, 0xC000);
+    }
+
+    #[test]
+    fn test_mixer_is_nonlinear() {
+        let mut apu = APU::new(Region::NTSC);
+        // the triangle holds its level when silenced, park it at 0
+        apu.triangle.step = 15;
+        assert_eq!(apu.mix(), 0.0);
+
+        apu.write_register(0x4011, 0x7F);
+        let full = apu.mix();
+        apu.write_register(0x4011, 0x3F);
+        let half = apu.mix();
+        // lookup tables match the formula, and compress loud levels
+        assert!((full - 159.79 / (100.0 + 1.0 / (127.0 / 22638.0))).abs() < 0.02);
+        assert!(half > full / 2.0);
+        assert!(full < 1.0);
+    }
+
+    #[test]
+    fn test_mix_with_gains() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write_register(0x4011, 0x40);
+        let full = apu.mix();
+        assert_eq!(apu.mix_with_gains(&[1.0; 5]), full);
+
+        // muting the DMC leaves the triangle's held level
+        let without_dmc = apu.mix_with_gains(&[1.0, 1.0, 1.0, 1.0, 0.0]);
+        assert!((without_dmc - apu.tnd_table[3 * 15]).abs() < 1e-6);
+        assert_eq!(apu.mix_with_gains(&[0.0; 5]), 0.0);
+        let half = apu.mix_with_gains(&[1.0, 1.0, 1.0, 1.0, 0.5]);
+        assert!(half > without_dmc && half < full);
+    }
+}
@@ -0,0 +1,387 @@
+use crate::patch::MAX_TARGET_SIZE;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+// -----------------------------
+// ROM files
+// plain dumps, or the ROM inside a .zip / .7z archive
+// -----------------------------
+
+pub const ROM_EXTENSIONS: [&str; 5] = ["nes", "fds", "qd", "unf", "unif"];
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xBC\xAF\x27\x1C";
+
+pub struct RomFile {
+    // file name of the ROM itself, inside the archive if there was one
+    pub name: String,
+    pub data: Vec<u8>,
+    // true when the ROM was unpacked from a .zip / .7z
+    pub from_archive: bool,
+}
+
+impl RomFile {
+    // lower case extension of the ROM, picks the loader
+    pub fn extension(&self) -> Option<String> {
+        Path::new(&self.name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase())
+    }
+
+    // where the .ips / .sav that belong to this ROM live, given the path
+    // it was read from: the dump with its extension swapped, or for an
+    // archive the archive name plus the entry's, so the ROMs of one
+    // archive each get their own ("Games - Zelda.sav"; Zelda.zip holding
+    // Zelda.nes keeps Zelda.sav)
+    pub fn companion_path(&self, path: &Path, extension: &str) -> PathBuf {
+        if !self.from_archive {
+            return path.with_extension(extension);
+        }
+        let stem = |path: &Path| {
+            path.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        let archive_stem = stem(path);
+        let entry_stem = stem(Path::new(&self.name));
+        let name = if archive_stem == entry_stem {
+            format!("{}.{}", archive_stem, extension)
+        } else {
+            format!("{} - {}.{}", archive_stem, entry_stem, extension)
+        };
+        path.with_file_name(name)
+    }
+}
+
+// entry picks a file by name when an archive holds several ROMs,
+// otherwise the first ROM in name order is used; only that one is unpacked
+pub fn read_rom_file(path: &Path, entry: Option<&str>) -> Result<RomFile, String> {
+    let data = fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    if data.starts_with(ZIP_MAGIC) {
+        read_zip(&data, entry, &name)
+    } else if data.starts_with(SEVEN_ZIP_MAGIC) {
+        read_7z(&data, entry, &name)
+    } else {
+        Ok(RomFile {
+            name,
+            data,
+            from_archive: false,
+        })
+    }
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| ROM_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+// pick the ROM to load from the archive listing, before anything is unpacked
+fn select_entry(
+    mut names: Vec<String>,
+    entry: Option<&str>,
+    archive: &str,
+) -> Result<String, String> {
+    if let Some(entry) = entry {
+        return names
+            .into_iter()
+            .find(|name| name == entry || name.ends_with(&format!("/{}", entry)))
+            .ok_or_else(|| format!("{} has no ROM named {}", archive, entry));
+    }
+
+    if names.is_empty() {
+        return Err(format!(
+            "{} does not contain a .nes, .fds or .unf file",
+            archive
+        ));
+    }
+
+    // deterministic choice, whatever order the archive stores them in
+    names.sort_by_key(|name| name.to_lowercase());
+    if names.len() > 1 {
+        println!(
+            "{} holds {} ROMs, loading {}:",
+            archive,
+            names.len(),
+            names[0]
+        );
+        for name in &names {
+            println!("  {}", name);
+        }
+    }
+    Ok(names.swap_remove(0))
+}
+
+fn read_zip(data: &[u8], entry: Option<&str>, archive_name: &str) -> Result<RomFile, String> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(data)).map_err(|e| format!("bad zip archive: {}", e))?;
+
+    let names = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && is_rom_name(name))
+        .map(|name| name.to_string())
+        .collect();
+    let name = select_entry(names, entry, archive_name)?;
+
+    let file = archive
+        .by_name(&name)
+        .map_err(|e| format!("bad zip entry: {}", e))?;
+    let data = read_entry(&name, file.size(), file)?;
+    Ok(RomFile {
+        name,
+        data,
+        from_archive: true,
+    })
+}
+
+fn read_7z(data: &[u8], entry: Option<&str>, archive_name: &str) -> Result<RomFile, String> {
+    let mut source = Cursor::new(data);
+    let archive = sevenz_rust::Archive::read(&mut source, data.len() as u64, &[])
+        .map_err(|e| format!("bad 7z archive: {}", e))?;
+
+    let names = archive
+        .files
+        .iter()
+        .filter(|file| !file.is_directory() && is_rom_name(file.name()))
+        .map(|file| file.name().to_string())
+        .collect();
+    let name = select_entry(names, entry, archive_name)?;
+
+    let index = archive
+        .files
+        .iter()
+        .position(|file| file.name() == name)
+        .ok_or_else(|| format!("{} has no ROM named {}", archive_name, name))?;
+    // empty files have no data block
+    let Some(block) = archive.stream_map.file_folder_index[index] else {
+        return Ok(RomFile {
+            name,
+            data: vec![],
+            from_archive: true,
+        });
+    };
+
+    // only the block holding the ROM is decoded; inside a solid block the
+    // files ahead of it still have to be run through the decoder
+    let mut rom = None;
+    sevenz_rust::BlockDecoder::new(block, &archive, &[], &mut source)
+        .for_each_entries(&mut |file, reader| {
+            if file.name() != name {
+                io::copy(reader, &mut io::sink())?;
+                return Ok(true);
+            }
+            rom = Some(read_entry(&name, file.size(), reader));
+            Ok(false)
+        })
+        .map_err(|e| format!("could not extract {}: {}", name, e))?;
+
+    let data = rom.ok_or_else(|| format!("could not extract {}", name))??;
+    Ok(RomFile {
+        name,
+        data,
+        from_archive: true,
+    })
+}
+
+// the size in the archive header is checked before anything is allocated,
+// and the read stops at the limit in case the header lies
+fn read_entry(name: &str, size: u64, reader: impl Read) -> Result<Vec<u8>, String> {
+    let too_large = || {
+        format!(
+            "{} is larger than {} MiB, too large for a ROM",
+            name,
+            MAX_TARGET_SIZE / (1024 * 1024)
+        )
+    };
+    if size > MAX_TARGET_SIZE as u64 {
+        return Err(too_large());
+    }
+
+    let mut data = Vec::with_capacity(size as usize);
+    reader
+        .take(MAX_TARGET_SIZE as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| format!("could not extract {}: {}", name, e))?;
+    if data.len() > MAX_TARGET_SIZE {
+        return Err(too_large());
+    }
+    Ok(data)
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn zip_file(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    // every entry in one solid block, so reaching a later file means
+    // decoding the ones ahead of it
+    fn seven_zip_file(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = sevenz_rust::SevenZWriter::new(Cursor::new(Vec::new())).unwrap();
+        let files = entries
+            .iter()
+            .map(|(name, _)| {
+                let mut file = sevenz_rust::SevenZArchiveEntry::new();
+                file.name = name.to_string();
+                file.has_stream = true;
+                file
+            })
+            .collect();
+        let readers = entries
+            .iter()
+            .map(|(_, data)| sevenz_rust::SourceReader::new(*data))
+            .collect();
+        writer
+            .push_archive_entries(files, sevenz_rust::SeqReader::new(readers))
+            .unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn temp_file(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("nest-emulator-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_plain_rom() {
+        let path = temp_file("plain.nes", b"NES\x1A");
+        let rom = read_rom_file(&path, None).unwrap();
+        assert_eq!(rom.data, b"NES\x1A");
+        assert_eq!(rom.extension().as_deref(), Some("nes"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_zip_picks_rom_deterministically() {
+        let data = zip_file(&[
+            ("readme.txt", b"hello"),
+            ("Zelda.nes", b"zelda"),
+            ("games/Arkanoid.NES", b"arkanoid"),
+        ]);
+        let path = temp_file("collection.zip", &data);
+
+        let rom = read_rom_file(&path, None).unwrap();
+        assert_eq!(rom.name, "games/Arkanoid.NES");
+        assert_eq!(rom.extension().as_deref(), Some("nes"));
+
+        let rom = read_rom_file(&path, Some("Zelda.nes")).unwrap();
+        assert_eq!(rom.data, b"zelda");
+
+        let rom = read_rom_file(&path, Some("Arkanoid.NES")).unwrap();
+        assert_eq!(rom.data, b"arkanoid");
+
+        assert!(read_rom_file(&path, Some("missing.nes")).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_zip_without_rom() {
+        let path = temp_file("empty.zip", &zip_file(&[("readme.txt", b"hello")]));
+        assert!(read_rom_file(&path, None).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_7z_picks_rom() {
+        let data = seven_zip_file(&[
+            ("readme.txt", b"hello"),
+            ("Zelda.nes", b"zelda"),
+            ("games/Arkanoid.nes", b"arkanoid"),
+        ]);
+        let path = temp_file("collection.7z", &data);
+
+        let rom = read_rom_file(&path, None).unwrap();
+        assert_eq!(rom.name, "games/Arkanoid.nes");
+        assert_eq!(rom.data, b"arkanoid");
+        assert!(rom.from_archive);
+
+        let rom = read_rom_file(&path, Some("Zelda.nes")).unwrap();
+        assert_eq!(rom.data, b"zelda");
+
+        assert!(read_rom_file(&path, Some("readme.txt")).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_companion_path() {
+        let rom = |name: &str, from_archive| RomFile {
+            name: name.to_string(),
+            data: vec![],
+            from_archive,
+        };
+        let dir = Path::new("roms");
+
+        let plain = rom("Zelda.nes", false);
+        assert_eq!(
+            plain.companion_path(&dir.join("Zelda.nes"), "ips"),
+            dir.join("Zelda.ips")
+        );
+
+        let single = rom("Zelda.nes", true);
+        assert_eq!(
+            single.companion_path(&dir.join("Zelda.zip"), "sav"),
+            dir.join("Zelda.sav")
+        );
+
+        let collection = dir.join("Games.7z");
+        assert_eq!(
+            rom("disks/Metroid.fds", true).companion_path(&collection, "sav"),
+            dir.join("Games - Metroid.sav")
+        );
+        assert_eq!(
+            rom("Zelda.nes", true).companion_path(&collection, "sav"),
+            dir.join("Games - Zelda.sav")
+        );
+    }
+
+    #[test]
+    fn test_oversized_entries() {
+        // a central directory that claims a 4 GiB ROM
+        let mut data = zip_file(&[("Huge.nes", b"NES\x1A")]);
+        let directory = data.windows(4).position(|w| w == b"PK\x01\x02").unwrap();
+        data[directory + 24..directory + 28].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        let path = temp_file("huge.zip", &data);
+        let error = read_rom_file(&path, None).err().unwrap();
+        assert_eq!(error, "Huge.nes is larger than 16 MiB, too large for a ROM");
+        fs::remove_file(path).unwrap();
+
+        // a header that lies the other way is cut off at the limit
+        let stream = io::repeat(0);
+        assert!(read_entry("Endless.nes", 16, stream).is_err());
+        assert_eq!(
+            read_entry("Small.nes", 4, &b"NES\x1A"[..]).unwrap(),
+            b"NES\x1A"
+        );
+    }
+
+    #[test]
+    fn test_bad_7z() {
+        let path = temp_file("bad.7z", b"7z\xBC\xAF\x27\x1C broken");
+        assert!(read_rom_file(&path, None).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
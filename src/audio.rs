@@ -0,0 +1,494 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+// -----------------------------
+// Sample buffer
+// band-limited resampling of the mixed APU level (one value per CPU
+// cycle) down to the output rate, then the console's output filters;
+// backends pull finished samples with read_samples
+// -----------------------------
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+// FDS wavetable at full volume (63) is about 2.4 times a full pulse channel
+pub const FDS_MIX_LEVEL: f32 = 0.36 / 63.0;
+
+// band-limited step kernel: taps per step and sub-sample phases
+const KERNEL_TAPS: usize = 16;
+const KERNEL_PHASES: usize = 64;
+// kernel cutoff, as a fraction of the output Nyquist frequency
+const KERNEL_CUTOFF: f64 = 0.9;
+
+// unread samples kept when nothing pulls (headless runs), about a second
+const MAX_BUFFERED_SECONDS: u32 = 1;
+
+// -----------------------------
+// Channel controls
+// mute, solo and volume per voice, applied before mixing
+// -----------------------------
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC,
+    // expansion audio: the FDS wavetable, and the VRC6, 5B, N163 and
+    // MMC5 chips of NSF rips together
+    FDS,
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 7] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::DMC,
+        Channel::FDS,
+        Channel::Expansion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::DMC => "dmc",
+            Channel::FDS => "fds",
+            Channel::Expansion => "expansion",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Channel, String> {
+        let name = name.trim().to_ascii_lowercase();
+        Channel::ALL
+            .iter()
+            .find(|channel| channel.name() == name)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "unknown channel {}, expected pulse1, pulse2, triangle, noise, dmc, fds or expansion",
+                    name
+                )
+            })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChannelMixer {
+    volume: [f32; 7],
+    muted: [bool; 7],
+    // a soloed channel plays alone, mutes and volumes still apply to it
+    solo: Option<Channel>,
+}
+
+impl Default for ChannelMixer {
+    fn default() -> Self {
+        ChannelMixer {
+            volume: [1.0; 7],
+            muted: [false; 7],
+            solo: None,
+        }
+    }
+}
+
+impl ChannelMixer {
+    // 0.0 (silent) to 1.0 (as the console), louder is allowed
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        self.volume[channel as usize] = volume.max(0.0);
+    }
+
+    pub fn volume(&self, channel: Channel) -> f32 {
+        self.volume[channel as usize]
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    pub fn toggle_mute(&mut self, channel: Channel) {
+        self.muted[channel as usize] = !self.muted[channel as usize];
+    }
+
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.solo = channel;
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    // solo the channel, or go back to all channels if it already is
+    pub fn toggle_solo(&mut self, channel: Channel) {
+        self.solo = if self.solo == Some(channel) {
+            None
+        } else {
+            Some(channel)
+        };
+    }
+
+    // what the channel is multiplied by in the mix
+    pub fn gain(&self, channel: Channel) -> f32 {
+        let silenced =
+            self.muted[channel as usize] || self.solo.is_some_and(|solo| solo != channel);
+        if silenced {
+            0.0
+        } else {
+            self.volume[channel as usize]
+        }
+    }
+
+    // gains in Channel::ALL order
+    pub fn gains(&self) -> [f32; 7] {
+        Channel::ALL.map(|channel| self.gain(channel))
+    }
+}
+
+// one-pole filter, as on the console's audio output
+#[derive(Clone, Copy)]
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl Filter {
+    fn high_pass(sample_rate: u32, cutoff: f64) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        Filter {
+            high_pass: true,
+            alpha: (rc / (rc + dt)) as f32,
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    fn low_pass(sample_rate: u32, cutoff: f64) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        Filter {
+            high_pass: false,
+            alpha: (dt / (rc + dt)) as f32,
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.previous_output + input - self.previous_input)
+        } else {
+            self.previous_output + self.alpha * (input - self.previous_output)
+        };
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+pub struct SampleBuffer {
+    sample_rate: u32,
+    clock_rate: f64,
+    // output samples per CPU cycle, and the rate control nudge on it
+    ratio: f64,
+    rate_adjustment: f64,
+
+    // output sample time of the current CPU cycle, since the (re)start
+    position: f64,
+    // level at the last clock, steps are added on changes only
+    level: f32,
+
+    // band-limited impulses per phase, each summing to 1
+    kernel: Vec<[f32; KERNEL_TAPS]>,
+    // pending deltas, index 0 is output sample number `base`
+    deltas: VecDeque<f32>,
+    base: u64,
+    // running sum of the deltas read so far
+    integrator: f32,
+
+    // 90 Hz and 440 Hz high-pass, 14 kHz low-pass
+    filters: [Filter; 3],
+}
+
+impl SampleBuffer {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> SampleBuffer {
+        let mut buffer = SampleBuffer {
+            sample_rate,
+            clock_rate,
+            ratio: 0.0,
+            rate_adjustment: 1.0,
+            position: 0.0,
+            level: 0.0,
+            kernel: build_kernel(),
+            deltas: VecDeque::new(),
+            base: 0,
+            integrator: 0.0,
+            filters: [Filter::high_pass(sample_rate, 90.0); 3],
+        };
+        buffer.restart();
+        buffer
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // 44100, 48000, ... drops anything not yet read
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.restart();
+    }
+
+    // CPU clock of the region, drops anything not yet read
+    pub fn set_clock_rate(&mut self, clock_rate: f64) {
+        self.clock_rate = clock_rate;
+        self.restart();
+    }
+
+    // scale the resampling ratio slightly (e.g. 1.005 makes 0.5% more
+    // samples) to keep an audio queue from draining or overfilling
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.rate_adjustment = adjustment;
+        self.ratio = self.sample_rate as f64 / self.clock_rate * adjustment;
+    }
+
+    fn restart(&mut self) {
+        self.ratio = self.sample_rate as f64 / self.clock_rate * self.rate_adjustment;
+        self.position = 0.0;
+        self.base = 0;
+        self.deltas.clear();
+        self.integrator = self.level;
+        self.filters = [
+            Filter::high_pass(self.sample_rate, 90.0),
+            Filter::high_pass(self.sample_rate, 440.0),
+            Filter::low_pass(self.sample_rate, 14_000.0),
+        ];
+    }
+
+    // one CPU cycle with the mixed output level
+    pub fn clock(&mut self, level: f32) {
+        if level != self.level {
+            self.add_delta(level - self.level);
+            self.level = level;
+        }
+        self.position += self.ratio;
+
+        let limit = (self.sample_rate * MAX_BUFFERED_SECONDS) as usize;
+        let available = self.samples_available();
+        if available > limit {
+            self.skip(available - limit);
+        }
+    }
+
+    fn add_delta(&mut self, delta: f32) {
+        let sample = self.position.floor() as u64;
+        let phase = ((self.position - sample as f64) * KERNEL_PHASES as f64) as usize;
+
+        let start = (sample - self.base) as usize;
+        if self.deltas.len() < start + KERNEL_TAPS {
+            self.deltas.resize(start + KERNEL_TAPS, 0.0);
+        }
+        for (tap, weight) in self.kernel[phase.min(KERNEL_PHASES - 1)].iter().enumerate() {
+            self.deltas[start + tap] += delta * weight;
+        }
+    }
+
+    // finished samples: no later delta can still reach them
+    pub fn samples_available(&self) -> usize {
+        let complete = self.position.floor() as u64;
+        (complete - self.base) as usize
+    }
+
+    // fill out with filtered samples, returns how many were written
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.samples_available());
+        for sample in out.iter_mut().take(count) {
+            self.integrator += self.deltas.pop_front().unwrap_or(0.0);
+            let mut value = self.integrator;
+            for filter in self.filters.iter_mut() {
+                value = filter.process(value);
+            }
+            *sample = value;
+        }
+        self.base += count as u64;
+        count
+    }
+
+    // drop the oldest samples unread
+    fn skip(&mut self, count: usize) {
+        for _ in 0..count {
+            self.integrator += self.deltas.pop_front().unwrap_or(0.0);
+        }
+        self.base += count as u64;
+    }
+}
+
+// windowed sinc impulse for each sub-sample phase; a delta at sample
+// position k + phase lands on samples k..k + KERNEL_TAPS
+fn build_kernel() -> Vec<[f32; KERNEL_TAPS]> {
+    let centre = (KERNEL_TAPS / 2) as f64 - 1.0;
+    (0..KERNEL_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0f64; KERNEL_TAPS];
+            for (tap, weight) in taps.iter_mut().enumerate() {
+                let x = tap as f64 - centre - offset;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x * KERNEL_CUTOFF).sin() / (PI * x * KERNEL_CUTOFF)
+                };
+                // Blackman window over the kernel span
+                let w = (tap as f64 + 1.0 - offset) / KERNEL_TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                *weight = sinc * window.max(0.0);
+            }
+            let sum: f64 = taps.iter().sum();
+            let mut kernel = [0.0f32; KERNEL_TAPS];
+            for (out, weight) in kernel.iter_mut().zip(taps) {
+                *out = (weight / sum) as f32;
+            }
+            kernel
+        })
+        .collect()
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_RATE: f64 = 1_789_773.0;
+
+    fn square_wave(buffer: &mut SampleBuffer, frequency: f64, cycles: u64) {
+        let half_period = (CLOCK_RATE / frequency / 2.0) as u64;
+        for cycle in 0..cycles {
+            let level = if (cycle / half_period).is_multiple_of(2) {
+                0.5
+            } else {
+                0.0
+            };
+            buffer.clock(level);
+        }
+    }
+
+    #[test]
+    fn test_kernel_phases_sum_to_one() {
+        for taps in build_kernel() {
+            let sum: f32 = taps.iter().sum();
+            assert!((sum - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_sample_count_follows_rate() {
+        for rate in [44_100, 48_000] {
+            let mut buffer = SampleBuffer::new(CLOCK_RATE, rate);
+            for _ in 0..CLOCK_RATE as u64 / 10 {
+                buffer.clock(0.0);
+            }
+            let expected = rate as usize / 10;
+            assert!(buffer.samples_available().abs_diff(expected) <= 1);
+
+            let mut out = vec![0.0; 10_000];
+            assert!(buffer.read_samples(&mut out) > 0);
+            assert_eq!(buffer.samples_available(), 0);
+            assert_eq!(buffer.read_samples(&mut out), 0);
+        }
+    }
+
+    #[test]
+    fn test_step_settles_to_level() {
+        // before the filters, the integrated steps reach the level
+        let mut buffer = SampleBuffer::new(CLOCK_RATE, 48_000);
+        for _ in 0..1000 {
+            buffer.clock(0.25);
+        }
+        let mut out = vec![0.0; 32];
+        buffer.read_samples(&mut out);
+        assert!((buffer.integrator - 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+        let mut buffer = SampleBuffer::new(CLOCK_RATE, 48_000);
+        for _ in 0..CLOCK_RATE as u64 / 2 {
+            buffer.clock(0.8);
+        }
+        let mut out = vec![0.0; 48_000];
+        let read = buffer.read_samples(&mut out);
+        assert!(out[read - 1].abs() < 0.01);
+        // the step itself comes through
+        assert!(out[..100].iter().any(|sample| *sample > 0.3));
+    }
+
+    #[test]
+    fn test_square_wave_is_band_limited() {
+        let mut buffer = SampleBuffer::new(CLOCK_RATE, 48_000);
+        square_wave(&mut buffer, 1000.0, CLOCK_RATE as u64 / 10);
+        let mut out = vec![0.0; 4800];
+        let read = buffer.read_samples(&mut out);
+        // bounded, roughly +/- half the step, no wild ringing
+        assert!(out[..read].iter().all(|sample| sample.abs() < 0.5));
+        assert!(out[..read].iter().any(|sample| sample.abs() > 0.15));
+    }
+
+    #[test]
+    fn test_unread_samples_are_dropped() {
+        let mut buffer = SampleBuffer::new(CLOCK_RATE, 48_000);
+        square_wave(&mut buffer, 440.0, CLOCK_RATE as u64 * 3);
+        assert!(buffer.samples_available() <= 48_000);
+        assert!(buffer.deltas.len() <= 48_000 + KERNEL_TAPS);
+    }
+
+    #[test]
+    fn test_rate_adjustment() {
+        let mut buffer = SampleBuffer::new(CLOCK_RATE, 48_000);
+        buffer.set_rate_adjustment(1.005);
+        for _ in 0..CLOCK_RATE as u64 / 2 {
+            buffer.clock(0.0);
+        }
+        assert!(buffer.samples_available().abs_diff(24_120) <= 1);
+    }
+
+    #[test]
+    fn test_channel_mute_and_solo() {
+        let mut mixer = ChannelMixer::default();
+        assert_eq!(mixer.gains(), [1.0; 7]);
+
+        mixer.toggle_mute(Channel::Noise);
+        mixer.set_volume(Channel::Triangle, 0.5);
+        assert_eq!(mixer.gains(), [1.0, 1.0, 0.5, 0.0, 1.0, 1.0, 1.0]);
+
+        mixer.toggle_solo(Channel::Triangle);
+        assert_eq!(mixer.gains(), [0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.0]);
+        // soloing a muted channel keeps it muted
+        mixer.set_solo(Some(Channel::Noise));
+        assert_eq!(mixer.gains(), [0.0; 7]);
+        mixer.toggle_solo(Channel::Noise);
+        assert_eq!(mixer.solo(), None);
+        assert!(mixer.is_muted(Channel::Noise));
+    }
+
+    #[test]
+    fn test_channel_names() {
+        for channel in Channel::ALL {
+            assert_eq!(Channel::from_name(channel.name()), Ok(channel));
+        }
+        assert_eq!(Channel::from_name(" DMC"), Ok(Channel::DMC));
+        assert!(Channel::from_name("vrc6").is_err());
+    }
+}
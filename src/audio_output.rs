@@ -0,0 +1,167 @@
+use crate::audio::SampleBuffer;
+
+// -----------------------------
+// Audio output
+// backends that play the sample buffer, and the dynamic rate control
+// that keeps their queue near a target latency
+// -----------------------------
+
+pub trait AudioBackend {
+    fn sample_rate(&self) -> u32;
+
+    fn queue_samples(&mut self, samples: &[f32]) -> Result<(), String>;
+
+    // samples waiting to be played, None when the backend has no clock
+    fn queued_samples(&self) -> Option<usize>;
+}
+
+// discards everything, for headless runs and machines without a sound card
+pub struct NullAudio {
+    sample_rate: u32,
+    pub samples_received: u64,
+}
+
+impl NullAudio {
+    pub fn new(sample_rate: u32) -> NullAudio {
+        NullAudio {
+            sample_rate,
+            samples_received: 0,
+        }
+    }
+}
+
+impl AudioBackend for NullAudio {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queue_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.samples_received += samples.len() as u64;
+        Ok(())
+    }
+
+    fn queued_samples(&self) -> Option<usize> {
+        None
+    }
+}
+
+// the emulator and the sound card run off different crystals, so the
+// queue slowly drains or fills; nudge the resampling ratio by at most
+// max_adjustment (too little to hear as pitch) towards the target
+pub struct RateControl {
+    pub target_latency: usize,
+    pub max_adjustment: f64,
+}
+
+impl RateControl {
+    // target latency in seconds, e.g. 0.05
+    pub fn new(sample_rate: u32, latency: f64) -> RateControl {
+        RateControl {
+            target_latency: (sample_rate as f64 * latency) as usize,
+            max_adjustment: 0.005,
+        }
+    }
+
+    // ratio scale for a queue holding `queued` samples: above 1.0 when
+    // it runs low (make more samples), below when it runs high
+    pub fn adjustment(&self, queued: usize) -> f64 {
+        let target = self.target_latency.max(1) as f64;
+        let error = ((target - queued as f64) / target).clamp(-1.0, 1.0);
+        1.0 + error * self.max_adjustment
+    }
+}
+
+// move every finished sample to the backend, then retune the buffer
+pub fn pump(
+    buffer: &mut SampleBuffer,
+    backend: &mut dyn AudioBackend,
+    rate_control: &RateControl,
+) -> Result<(), String> {
+    let mut samples = vec![0.0; buffer.samples_available()];
+    let count = buffer.read_samples(&mut samples);
+    backend.queue_samples(&samples[..count])?;
+
+    if let Some(queued) = backend.queued_samples() {
+        buffer.set_rate_adjustment(rate_control.adjustment(queued));
+    }
+    Ok(())
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_RATE: f64 = 1_789_773.0;
+
+    // a sound card whose clock runs slightly off the emulator's
+    struct DriftingAudio {
+        queued: f64,
+        // samples played per pump
+        drain: f64,
+    }
+
+    impl AudioBackend for DriftingAudio {
+        fn sample_rate(&self) -> u32 {
+            48_000
+        }
+
+        fn queue_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+            self.queued = (self.queued + samples.len() as f64 - self.drain).max(0.0);
+            Ok(())
+        }
+
+        fn queued_samples(&self) -> Option<usize> {
+            Some(self.queued as usize)
+        }
+    }
+
+    #[test]
+    fn test_adjustment_direction() {
+        let control = RateControl::new(48_000, 0.05);
+        assert_eq!(control.target_latency, 2400);
+        assert_eq!(control.adjustment(2400), 1.0);
+        assert!(control.adjustment(0) > 1.0);
+        assert!(control.adjustment(4800) < 1.0);
+        assert_eq!(control.adjustment(100_000), 1.0 - control.max_adjustment);
+    }
+
+    #[test]
+    fn test_null_backend() {
+        let mut buffer = SampleBuffer::new(CLOCK_RATE, 48_000);
+        let mut backend = NullAudio::new(48_000);
+        let control = RateControl::new(48_000, 0.05);
+        for _ in 0..CLOCK_RATE as u64 / 10 {
+            buffer.clock(0.0);
+        }
+        pump(&mut buffer, &mut backend, &control).unwrap();
+        assert!(backend.samples_received.abs_diff(4800) <= 1);
+        assert_eq!(buffer.samples_available(), 0);
+    }
+
+    #[test]
+    fn test_queue_settles_near_target() {
+        let mut buffer = SampleBuffer::new(CLOCK_RATE, 48_000);
+        // the sound card plays 0.3% faster than the emulator produces
+        let mut backend = DriftingAudio {
+            queued: 2400.0,
+            drain: 800.0 * 1.003,
+        };
+        let control = RateControl::new(48_000, 0.05);
+
+        // 25 seconds of 800 sample frames
+        let cycles_per_frame = (CLOCK_RATE / 60.0) as u64;
+        for _ in 0..1500 {
+            for _ in 0..cycles_per_frame {
+                buffer.clock(0.0);
+            }
+            pump(&mut buffer, &mut backend, &control).unwrap();
+        }
+        // without rate control the queue would have run dry after 1000
+        // frames; with it, it holds where the nudge matches the drift
+        assert!(backend.queued > 480.0 && backend.queued < 2400.0);
+    }
+}
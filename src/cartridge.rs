@@ -0,0 +1,919 @@
+use crate::gamedb::{GameDatabase, GameInfo};
+use crate::region::Region;
+
+// -----------------------------
+// Cartridge
+// iNES / NES 2.0 and UNIF loading
+// -----------------------------
+
+const NES_TAG: &[u8] = b"NES\x1A";
+const UNIF_TAG: &[u8] = b"UNIF";
+const UNIF_HEADER_SIZE: usize = 32;
+
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+const TRAINER_SIZE: usize = 512;
+
+// UNIF CTRL bits, iNES dumps default to a standard joypad
+pub const CONTROLLER_JOYPAD: u8 = 0b0000_0001;
+pub const CONTROLLER_ZAPPER: u8 = 0b0000_0010;
+pub const CONTROLLER_ROB: u8 = 0b0000_0100;
+pub const CONTROLLER_ARKANOID: u8 = 0b0000_1000;
+pub const CONTROLLER_POWER_PAD: u8 = 0b0001_0000;
+pub const CONTROLLER_FOUR_SCORE: u8 = 0b0010_0000;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    // empty when the board uses CHR RAM
+    pub chr_rom: Vec<u8>,
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Region,
+    pub controllers: u8,
+    // UNIF board name, None for iNES dumps
+    pub board: Option<String>,
+
+    // bank registers, all boards power on with them at 0
+    banks: Banks,
+}
+
+// mappers read_prg and write_register know how to bank
+const SUPPORTED_MAPPERS: [u16; 13] = [0, 1, 2, 3, 7, 34, 58, 66, 133, 143, 145, 148, 149];
+
+const PRG_BANK_16K: usize = 0x4000;
+const PRG_BANK_32K: usize = 0x8000;
+
+struct Banks {
+    // switchable PRG bank, 16 KiB on UxROM / MMC1 / mapper 58, else 32 KiB
+    prg: usize,
+    // 4 KiB CHR banks at PPU $0000 and $1000, handed to the PPU
+    chr: [usize; 2],
+    // mapper 58: one 16 KiB bank mirrored at $8000 and $C000
+    prg_16k_mirrored: bool,
+
+    // MMC1 serial port: five writes, bit 0 first, fill a register
+    mmc1_shift: u8,
+    mmc1_writes: u8,
+    mmc1_control: u8,
+    mmc1_chr: [u8; 2],
+}
+
+impl Banks {
+    fn new() -> Banks {
+        Banks {
+            prg: 0,
+            chr: [0, 1],
+            prg_16k_mirrored: false,
+            mmc1_shift: 0,
+            mmc1_writes: 0,
+            // PRG mode 3: last bank fixed at $C000
+            mmc1_control: 0b0_1100,
+            mmc1_chr: [0, 1],
+        }
+    }
+
+    fn select_chr_8k(&mut self, bank: usize) {
+        self.chr = [bank * 2, bank * 2 + 1];
+    }
+}
+
+impl Cartridge {
+    // pick the loader from the file's magic bytes
+    pub fn load(raw: &[u8]) -> Result<Cartridge, String> {
+        Cartridge::load_with_database(raw, GameDatabase::embedded())
+    }
+
+    // iNES headers are checked against the database, plenty of dumps in
+    // the wild carry a wrong one; UNIF names the board itself
+    pub fn load_with_database(raw: &[u8], database: &GameDatabase) -> Result<Cartridge, String> {
+        if raw.starts_with(NES_TAG) {
+            let mut cartridge = Cartridge::from_ines(raw)?;
+            if let Some(info) = database.lookup(&cartridge.prg_rom, &cartridge.chr_rom) {
+                for change in cartridge.correct_header(info) {
+                    println!("game database ({}): {}", info.name, change);
+                }
+            }
+            Ok(cartridge)
+        } else if raw.starts_with(UNIF_TAG) {
+            Cartridge::from_unif(raw)
+        } else {
+            Err("File is not in iNES or UNIF format".to_string())
+        }
+    }
+
+    // -----------------------------
+    // iNES / NES 2.0
+    // -----------------------------
+
+    pub fn from_ines(raw: &[u8]) -> Result<Cartridge, String> {
+        if raw.len() < 16 || &raw[0..4] != NES_TAG {
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        let nes2 = raw[7] & 0b0000_1100 == 0b0000_1000;
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+        let mut submapper = 0;
+        let mut prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        let mut chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+
+        if nes2 {
+            mapper |= ((raw[8] & 0b0000_1111) as u16) << 8;
+            submapper = raw[8] >> 4;
+            prg_rom_size += ((raw[9] & 0b0000_1111) as usize) << 8 << 14;
+            chr_rom_size += ((raw[9] >> 4) as usize) << 8 << 13;
+        }
+
+        let region = if nes2 {
+            match raw[12] & 0b0000_0011 {
+                1 => Region::PAL,
+                3 => Region::Dendy,
+                // 2 is multi-region, those run on NTSC
+                _ => Region::NTSC,
+            }
+        } else if raw[9] & 0b0000_0001 != 0 {
+            Region::PAL
+        } else {
+            Region::NTSC
+        };
+
+        let four_screen = raw[6] & 0b0000_1000 != 0;
+        let vertical_mirroring = raw[6] & 0b0000_0001 != 0;
+        let mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+
+        let has_trainer = raw[6] & 0b0000_0100 != 0;
+        let trainer_start = 16;
+        let prg_rom_start = trainer_start + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        if prg_rom_size == 0 {
+            return Err("iNES header declares no PRG ROM".to_string());
+        }
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err(format!(
+                "iNES file is truncated: header declares {} bytes of PRG and {} bytes of CHR",
+                prg_rom_size, chr_rom_size
+            ));
+        }
+
+        Ok(Cartridge {
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..chr_rom_start + chr_rom_size].to_vec(),
+            trainer: has_trainer.then(|| raw[trainer_start..prg_rom_start].to_vec()),
+            mapper,
+            submapper,
+            mirroring,
+            battery: raw[6] & 0b0000_0010 != 0,
+            region,
+            controllers: CONTROLLER_JOYPAD,
+            board: None,
+            banks: Banks::new(),
+        })
+    }
+
+    // apply a database entry, returns a description of every field it changed
+    pub fn correct_header(&mut self, info: &GameInfo) -> Vec<String> {
+        let mut changes = vec![];
+
+        if self.mapper != info.mapper {
+            changes.push(format!("mapper {} -> {}", self.mapper, info.mapper));
+            self.mapper = info.mapper;
+        }
+        if self.submapper != info.submapper {
+            changes.push(format!(
+                "submapper {} -> {}",
+                self.submapper, info.submapper
+            ));
+            self.submapper = info.submapper;
+        }
+        if let Some(mirroring) = info.mirroring {
+            if self.mirroring != mirroring {
+                changes.push(format!("mirroring {:?} -> {:?}", self.mirroring, mirroring));
+                self.mirroring = mirroring;
+            }
+        }
+        if self.region != info.region {
+            changes.push(format!("region {:?} -> {:?}", self.region, info.region));
+            self.region = info.region;
+        }
+        if self.battery != info.battery {
+            changes.push(format!("battery {} -> {}", self.battery, info.battery));
+            self.battery = info.battery;
+        }
+
+        changes
+    }
+
+    // -----------------------------
+    // UNIF
+    // 32 byte header then (id, length, data) chunks
+    // -----------------------------
+
+    pub fn from_unif(raw: &[u8]) -> Result<Cartridge, String> {
+        if raw.len() < UNIF_HEADER_SIZE || &raw[0..4] != UNIF_TAG {
+            return Err("File is not in UNIF file format".to_string());
+        }
+
+        let mut board = None;
+        let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut mirroring = None;
+        let mut battery = false;
+        let mut controllers = CONTROLLER_JOYPAD;
+        let mut region = Region::NTSC;
+
+        let mut position = UNIF_HEADER_SIZE;
+        while position < raw.len() {
+            let header = raw
+                .get(position..position + 8)
+                .ok_or("UNIF chunk header is truncated")?;
+            let id = &header[0..4];
+            let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+            position += 8;
+
+            let data = raw.get(position..position + length).ok_or_else(|| {
+                format!("UNIF chunk {} is truncated", String::from_utf8_lossy(id))
+            })?;
+            position += length;
+
+            match id {
+                b"MAPR" => {
+                    let name = data.split(|&b| b == 0).next().unwrap_or_default();
+                    board = Some(String::from_utf8_lossy(name).trim().to_string());
+                }
+                b"MIRR" => {
+                    mirroring = match data.first() {
+                        Some(0) => Some(Mirroring::Horizontal),
+                        Some(1) => Some(Mirroring::Vertical),
+                        Some(2) => Some(Mirroring::SingleScreenLower),
+                        Some(3) => Some(Mirroring::SingleScreenUpper),
+                        Some(4) => Some(Mirroring::FourScreen),
+                        // 5: mirroring is controlled by the mapper
+                        _ => None,
+                    }
+                }
+                b"BATR" => battery = data.first().is_none_or(|&b| b != 0),
+                b"CTRL" => controllers = data.first().copied().unwrap_or(CONTROLLER_JOYPAD),
+                b"TVCI" if data.first() == Some(&1) => region = Region::PAL,
+                _ => {
+                    if let Some(index) = chunk_index(id, b"PRG") {
+                        prg_chunks[index] = Some(data);
+                    } else if let Some(index) = chunk_index(id, b"CHR") {
+                        chr_chunks[index] = Some(data);
+                    }
+                    // NAME, READ, DINF, PCK0, CCK0... are not needed to boot
+                }
+            }
+        }
+
+        let board = board.ok_or("UNIF file has no MAPR chunk")?;
+        let (mapper, submapper) = unif_board_mapper(&board)
+            .ok_or_else(|| format!("UNIF board {} is not supported", board))?;
+
+        let prg_rom: Vec<u8> = prg_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+        let chr_rom: Vec<u8> = chr_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+
+        if prg_rom.is_empty() {
+            return Err("UNIF file has no PRG chunks".to_string());
+        }
+
+        Ok(Cartridge {
+            prg_rom,
+            chr_rom,
+            trainer: None,
+            mapper,
+            submapper,
+            mirroring: mirroring.unwrap_or(Mirroring::Horizontal),
+            battery,
+            region,
+            controllers,
+            board: Some(board),
+            banks: Banks::new(),
+        })
+    }
+
+    // boards without a bank switching model would run their PRG as if
+    // they were NROM and crash; the error names the board so it can be added
+    pub fn check_supported(&self) -> Result<(), String> {
+        if SUPPORTED_MAPPERS.contains(&self.mapper) {
+            return Ok(());
+        }
+        Err(match self.board.as_deref() {
+            Some(board) => format!(
+                "UNIF board {} (mapper {}) is not emulated yet",
+                board, self.mapper
+            ),
+            None => format!("mapper {} is not emulated yet", self.mapper),
+        })
+    }
+
+    // -----------------------------
+    // Bus ($4100-$FFFF)
+    // -----------------------------
+
+    pub fn read_prg(&self, address: u16) -> u8 {
+        let address = address as usize;
+        let offset = match self.mapper {
+            1 => self.mmc1_prg_offset(address),
+            // switchable bank at $8000, last bank fixed at $C000
+            2 if address < 0xC000 => self.banks.prg * PRG_BANK_16K + (address & 0x3FFF),
+            2 => self.prg_rom.len().saturating_sub(PRG_BANK_16K) + (address & 0x3FFF),
+            58 if self.banks.prg_16k_mirrored => self.banks.prg * PRG_BANK_16K + (address & 0x3FFF),
+            58 => (self.banks.prg >> 1) * PRG_BANK_32K + (address & 0x7FFF),
+            7 | 34 | 66 | 133 | 148 => self.banks.prg * PRG_BANK_32K + (address & 0x7FFF),
+            // 16 KiB carts are mirrored into $C000-$FFFF
+            _ if self.prg_rom.len() == PRG_ROM_PAGE_SIZE => address & 0x3FFF,
+            _ => address - 0x8000,
+        };
+        self.prg_rom[offset % self.prg_rom.len()]
+    }
+
+    // registers the board answers below $8000, None when it does not drive the bus
+    pub fn read_register(&self, address: u16) -> Option<u8> {
+        match (self.mapper, address) {
+            // Sachen protection: the inverted low address bits, the top of
+            // the byte is left to open bus ($41)
+            (143, 0x4100..=0x5FFF) if address & 0x4100 == 0x4100 => {
+                Some(!address as u8 & 0b0011_1111 | 0b0100_0000)
+            }
+            _ => None,
+        }
+    }
+
+    // bank and mirroring registers; the CPU hands chr_banks and mirroring
+    // on to the PPU afterwards
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        let nina_001 = self.is_nina_001();
+        let banks = &mut self.banks;
+        match (self.mapper, address) {
+            (1, 0x8000..=0xFFFF) => self.write_mmc1(address, value),
+            (2, 0x8000..=0xFFFF) => banks.prg = value as usize,
+            (3, 0x8000..=0xFFFF) => banks.select_chr_8k(value as usize),
+            (7, 0x8000..=0xFFFF) => {
+                banks.prg = (value & 0b0000_0111) as usize;
+                self.mirroring = if value & 0b0001_0000 != 0 {
+                    Mirroring::SingleScreenUpper
+                } else {
+                    Mirroring::SingleScreenLower
+                };
+            }
+            // NINA-001 has its registers over the PRG RAM
+            (34, 0x7FFD) if nina_001 => banks.prg = (value & 0b0000_0001) as usize,
+            (34, 0x7FFE) if nina_001 => banks.chr[0] = (value & 0b0000_1111) as usize,
+            (34, 0x7FFF) if nina_001 => banks.chr[1] = (value & 0b0000_1111) as usize,
+            (34, 0x8000..=0xFFFF) if !nina_001 => banks.prg = value as usize,
+            // the bank number is on the address lines:
+            // A7 mirroring, A6 16 KiB mode, A3-A5 CHR bank, A0-A2 PRG bank
+            (58, 0x8000..=0xFFFF) => {
+                banks.prg = (address & 0b0111) as usize;
+                banks.select_chr_8k((address >> 3 & 0b0111) as usize);
+                banks.prg_16k_mirrored = address & 0b0100_0000 != 0;
+                self.mirroring = if address & 0b1000_0000 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+            }
+            (66, 0x8000..=0xFFFF) => {
+                banks.prg = (value >> 4 & 0b0011) as usize;
+                banks.select_chr_8k((value & 0b0011) as usize);
+            }
+            // Sachen boards decode $4100 with A8 and A14 only
+            (133, 0x4100..=0x5FFF) if address & 0x4100 == 0x4100 => {
+                banks.prg = (value >> 2 & 0b0001) as usize;
+                banks.select_chr_8k((value & 0b0011) as usize);
+            }
+            (145, 0x4100..=0x5FFF) if address & 0x4100 == 0x4100 => {
+                banks.select_chr_8k((value >> 7) as usize)
+            }
+            (148, 0x8000..=0xFFFF) => {
+                banks.prg = (value >> 3 & 0b0001) as usize;
+                banks.select_chr_8k((value & 0b0111) as usize);
+            }
+            (149, 0x8000..=0xFFFF) => banks.select_chr_8k((value >> 7) as usize),
+            _ => {}
+        }
+    }
+
+    // 4 KiB CHR banks for PPU $0000 and $1000
+    pub fn chr_banks(&self) -> [usize; 2] {
+        self.banks.chr
+    }
+
+    // iNES mapper 34 without a submapper is NINA-001 when it has CHR ROM
+    // to switch, BNROM otherwise
+    fn is_nina_001(&self) -> bool {
+        match self.submapper {
+            1 => true,
+            2 => false,
+            _ => self.chr_rom.len() > CHR_ROM_PAGE_SIZE,
+        }
+    }
+
+    // -----------------------------
+    // MMC1 (SxROM)
+    // -----------------------------
+
+    fn write_mmc1(&mut self, address: u16, value: u8) {
+        let banks = &mut self.banks;
+        // bit 7 clears the shift register and goes back to PRG mode 3
+        if value & 0b1000_0000 != 0 {
+            banks.mmc1_shift = 0;
+            banks.mmc1_writes = 0;
+            banks.mmc1_control |= 0b0_1100;
+            return;
+        }
+
+        banks.mmc1_shift |= (value & 0b0000_0001) << banks.mmc1_writes;
+        banks.mmc1_writes += 1;
+        if banks.mmc1_writes < 5 {
+            return;
+        }
+        let register = banks.mmc1_shift;
+        banks.mmc1_shift = 0;
+        banks.mmc1_writes = 0;
+
+        // the fifth write's address picks the register
+        match address {
+            0x8000..=0x9FFF => banks.mmc1_control = register,
+            0xA000..=0xBFFF => banks.mmc1_chr[0] = register,
+            0xC000..=0xDFFF => banks.mmc1_chr[1] = register,
+            _ => banks.prg = (register & 0b0_1111) as usize,
+        }
+
+        self.mirroring = match banks.mmc1_control & 0b0_0011 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        };
+        // CHR mode 0 switches 8 KiB, ignoring the low bit
+        banks.chr = if banks.mmc1_control & 0b1_0000 != 0 {
+            [banks.mmc1_chr[0] as usize, banks.mmc1_chr[1] as usize]
+        } else {
+            let bank = (banks.mmc1_chr[0] & 0b1_1110) as usize;
+            [bank, bank + 1]
+        };
+    }
+
+    fn mmc1_prg_offset(&self, address: usize) -> usize {
+        let banks = &self.banks;
+        // SUROM: the CHR register's bit 4 picks the 256 KiB half
+        let outer = if self.prg_rom.len() > 16 * PRG_BANK_16K {
+            (banks.mmc1_chr[0] & 0b1_0000) as usize
+        } else {
+            0
+        };
+        let last = (self.prg_rom.len() / PRG_BANK_16K).saturating_sub(1) & 0b1111;
+        let bank = match (banks.mmc1_control >> 2 & 0b11, address) {
+            // 32 KiB, the low bit of the bank is ignored
+            (0 | 1, _) => (banks.prg & 0b1110) + (address >= 0xC000) as usize,
+            // first bank fixed at $8000
+            (2, 0x8000..=0xBFFF) => 0,
+            (2, _) => banks.prg,
+            // last bank fixed at $C000
+            (_, 0x8000..=0xBFFF) => banks.prg,
+            (_, _) => last,
+        };
+        (outer | bank) * PRG_BANK_16K + (address & 0x3FFF)
+    }
+}
+
+// "PRG0".."PRGF" -> 0..15
+fn chunk_index(id: &[u8], prefix: &[u8]) -> Option<usize> {
+    if &id[0..3] != prefix {
+        return None;
+    }
+    (id[3] as char).to_digit(16).map(|digit| digit as usize)
+}
+
+// UNIF board name to (iNES mapper, submapper)
+pub fn unif_board_mapper(board: &str) -> Option<(u16, u8)> {
+    // the licensing prefix does not change the hardware
+    let name = [
+        "NES-", "UNL-", "HVC-", "BTL-", "BMC-", "IREM-", "KONAMI-", "TENGEN-",
+    ]
+    .iter()
+    .find_map(|prefix| board.strip_prefix(prefix))
+    .unwrap_or(board);
+
+    // only boards with a bank switching model in read_prg / write_register
+    let mapper = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => (0, 0),
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM"
+        | "SLROM" | "SL1ROM" | "SNROM" | "SUROM" => (1, 0),
+        "UNROM" | "UOROM" => (2, 0),
+        "CNROM" => (3, 0),
+        "AMROM" | "ANROM" | "AN1ROM" | "AOROM" => (7, 0),
+        "BNROM" => (34, 2),
+        "NINA-001" => (34, 1),
+        "GNROM" | "MHROM" => (66, 0),
+        // multicart
+        "GK-192" => (58, 0),
+        // Sachen pirate boards
+        "SA-72008" => (133, 0),
+        "SA-NROM" => (143, 0),
+        "SA-72007" => (145, 0),
+        "SA-0037" => (148, 0),
+        "SA-0036" => (149, 0),
+        _ => return None,
+    };
+
+    Some(mapper)
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ines(flags_6: u8, flags_7: u8, prg_pages: u8, chr_pages: u8) -> Vec<u8> {
+        let mut raw = vec![
+            0x4E, 0x45, 0x53, 0x1A, prg_pages, chr_pages, flags_6, flags_7,
+        ];
+        raw.resize(16, 0);
+        raw.extend(vec![0xAA; prg_pages as usize * PRG_ROM_PAGE_SIZE]);
+        raw.extend(vec![0xBB; chr_pages as usize * CHR_ROM_PAGE_SIZE]);
+        raw
+    }
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut raw = id.to_vec();
+        raw.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        raw.extend_from_slice(data);
+        raw
+    }
+
+    fn unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut raw = b"UNIF".to_vec();
+        raw.extend_from_slice(&7u32.to_le_bytes());
+        raw.resize(UNIF_HEADER_SIZE, 0);
+        for c in chunks {
+            raw.extend_from_slice(c);
+        }
+        raw
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let cartridge = Cartridge::load(&ines(0b0001_0011, 0b0000_0000, 2, 1)).unwrap();
+        assert_eq!(cartridge.mapper, 1);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(cartridge.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(cartridge.board, None);
+    }
+
+    #[test]
+    fn test_ines_without_prg() {
+        assert!(Cartridge::load(&ines(0, 0, 0, 1)).is_err());
+    }
+
+    #[test]
+    fn test_ines_truncated() {
+        let mut raw = ines(0, 0, 2, 1);
+        raw.truncate(100);
+        assert!(Cartridge::load(&raw).is_err());
+    }
+
+    #[test]
+    fn test_nes2_mapper_and_submapper() {
+        let mut raw = ines(0b0100_0000, 0b0000_1000, 1, 0);
+        raw[8] = 0b0011_0001;
+        let cartridge = Cartridge::load(&raw).unwrap();
+        assert_eq!(cartridge.mapper, 0x104);
+        assert_eq!(cartridge.submapper, 3);
+    }
+
+    #[test]
+    fn test_unif_board() {
+        let raw = unif(&[
+            chunk(b"MAPR", b"NES-SNROM\0"),
+            chunk(b"PRG1", &[2; 4]),
+            chunk(b"PRG0", &[1; 4]),
+            chunk(b"CHR0", &[3; 8]),
+            chunk(b"MIRR", &[1]),
+            chunk(b"BATR", &[1]),
+            chunk(b"CTRL", &[CONTROLLER_JOYPAD | CONTROLLER_ZAPPER]),
+        ]);
+        let cartridge = Cartridge::load(&raw).unwrap();
+        assert_eq!(cartridge.mapper, 1);
+        assert_eq!(cartridge.board.as_deref(), Some("NES-SNROM"));
+        // PRG chunks are joined by number, not file order
+        assert_eq!(cartridge.prg_rom, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+        assert_eq!(cartridge.chr_rom, vec![3; 8]);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.controllers, 0b0000_0011);
+    }
+
+    #[test]
+    fn test_unif_pirate_boards() {
+        assert_eq!(unif_board_mapper("UNL-SA-72008"), Some((133, 0)));
+        assert_eq!(unif_board_mapper("BMC-GK-192"), Some((58, 0)));
+        assert_eq!(unif_board_mapper("NES-BNROM"), Some((34, 2)));
+        assert_eq!(unif_board_mapper("UNL-NOT-A-BOARD"), None);
+        // boards without a bank switching model are not listed
+        assert_eq!(unif_board_mapper("UNL-Sachen-8259A"), None);
+        assert_eq!(unif_board_mapper("NES-TLROM"), None);
+    }
+
+    #[test]
+    fn test_unsupported_boards() {
+        let pirate = unif(&[
+            chunk(b"MAPR", b"UNL-Sachen-8259A\0"),
+            chunk(b"PRG0", &[0; 4]),
+        ]);
+        assert_eq!(
+            Cartridge::load(&pirate).err(),
+            Some("UNIF board UNL-Sachen-8259A is not supported".to_string())
+        );
+
+        // every board in the table runs
+        let nrom = unif(&[chunk(b"MAPR", b"NES-NROM-256\0"), chunk(b"PRG0", &[0; 4])]);
+        assert!(Cartridge::load(&nrom).unwrap().check_supported().is_ok());
+        let multicart = unif(&[chunk(b"MAPR", b"BMC-GK-192\0"), chunk(b"PRG0", &[0; 4])]);
+        assert!(Cartridge::load(&multicart)
+            .unwrap()
+            .check_supported()
+            .is_ok());
+
+        // MMC3
+        assert_eq!(
+            Cartridge::load(&ines(0b0100_0000, 0, 1, 0))
+                .unwrap()
+                .check_supported(),
+            Err("mapper 4 is not emulated yet".to_string())
+        );
+    }
+
+    // PRG / CHR filled with their bank numbers
+    fn banked(mapper: u8, prg_pages: u8, chr_pages: u8) -> Cartridge {
+        let mut raw = ines(mapper << 4, mapper & 0xF0, prg_pages, chr_pages);
+        let prg_start = 16;
+        let chr_start = prg_start + prg_pages as usize * PRG_ROM_PAGE_SIZE;
+        for (i, byte) in raw[prg_start..chr_start].iter_mut().enumerate() {
+            *byte = (i / PRG_BANK_16K) as u8;
+        }
+        for (i, byte) in raw[chr_start..].iter_mut().enumerate() {
+            *byte = (i / 0x1000) as u8;
+        }
+        let cartridge = Cartridge::load(&raw).unwrap();
+        assert!(cartridge.check_supported().is_ok());
+        cartridge
+    }
+
+    #[test]
+    fn test_uxrom() {
+        let mut cartridge = banked(2, 8, 0);
+        assert_eq!(cartridge.read_prg(0x8000), 0);
+        assert_eq!(cartridge.read_prg(0xC000), 7);
+        cartridge.write_register(0x8000, 3);
+        assert_eq!(cartridge.read_prg(0xBFFF), 3);
+        assert_eq!(cartridge.read_prg(0xFFFF), 7);
+    }
+
+    #[test]
+    fn test_cnrom_and_gxrom_chr_banks() {
+        let mut cartridge = banked(3, 2, 4);
+        cartridge.write_register(0x8000, 2);
+        assert_eq!(cartridge.chr_banks(), [4, 5]);
+
+        let mut cartridge = banked(66, 8, 4);
+        cartridge.write_register(0x8000, 0b0011_0001);
+        assert_eq!(cartridge.read_prg(0x8000), 6);
+        assert_eq!(cartridge.chr_banks(), [2, 3]);
+    }
+
+    #[test]
+    fn test_axrom_single_screen() {
+        let mut cartridge = banked(7, 8, 0);
+        cartridge.write_register(0x8000, 0b0001_0010);
+        assert_eq!(cartridge.read_prg(0x8000), 4);
+        assert_eq!(cartridge.mirroring, Mirroring::SingleScreenUpper);
+        cartridge.write_register(0x8000, 0);
+        assert_eq!(cartridge.mirroring, Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_bnrom_and_nina_001() {
+        let mut bnrom = banked(34, 8, 0);
+        bnrom.write_register(0x8000, 2);
+        assert_eq!(bnrom.read_prg(0x8000), 4);
+
+        // CHR ROM to switch makes it a NINA-001
+        let mut nina = banked(34, 4, 4);
+        nina.write_register(0x8000, 1);
+        assert_eq!(nina.read_prg(0x8000), 0);
+        nina.write_register(0x7FFD, 1);
+        nina.write_register(0x7FFE, 3);
+        nina.write_register(0x7FFF, 2);
+        assert_eq!(nina.read_prg(0x8000), 2);
+        assert_eq!(nina.chr_banks(), [3, 2]);
+    }
+
+    #[test]
+    fn test_mmc1() {
+        let mut cartridge = banked(1, 8, 4);
+        // power on in PRG mode 3: last bank at $C000
+        assert_eq!(cartridge.read_prg(0xC000), 7);
+
+        let write = |cartridge: &mut Cartridge, address: u16, value: u8| {
+            for bit in 0..5 {
+                cartridge.write_register(address, value >> bit & 1);
+            }
+        };
+        write(&mut cartridge, 0xE000, 5);
+        assert_eq!(cartridge.read_prg(0x8000), 5);
+        assert_eq!(cartridge.read_prg(0xC000), 7);
+
+        // vertical mirroring, PRG mode 2 (first bank fixed), 4 KiB CHR
+        write(&mut cartridge, 0x8000, 0b1_1010);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert_eq!(cartridge.read_prg(0x8000), 0);
+        assert_eq!(cartridge.read_prg(0xC000), 5);
+        write(&mut cartridge, 0xA000, 3);
+        write(&mut cartridge, 0xC000, 6);
+        assert_eq!(cartridge.chr_banks(), [3, 6]);
+
+        // 32 KiB PRG and 8 KiB CHR ignore the low bits
+        write(&mut cartridge, 0x8000, 0b0_0011);
+        assert_eq!(cartridge.mirroring, Mirroring::Horizontal);
+        assert_eq!(cartridge.read_prg(0x8000), 4);
+        assert_eq!(cartridge.read_prg(0xC000), 5);
+        assert_eq!(cartridge.chr_banks(), [2, 3]);
+
+        // bit 7 resets the shift register mid write
+        cartridge.write_register(0xE000, 1);
+        cartridge.write_register(0xE000, 0x80);
+        write(&mut cartridge, 0xE000, 2);
+        assert_eq!(cartridge.read_prg(0x8000), 2);
+    }
+
+    #[test]
+    fn test_gk_192_multicart() {
+        let mut cartridge = banked(58, 8, 8);
+        // 16 KiB mode: A6, bank 3, CHR bank 2, horizontal: A7
+        cartridge.write_register(0x8000 | 0b1101_0011, 0);
+        assert_eq!(cartridge.read_prg(0x8000), 3);
+        assert_eq!(cartridge.read_prg(0xC000), 3);
+        assert_eq!(cartridge.chr_banks(), [4, 5]);
+        assert_eq!(cartridge.mirroring, Mirroring::Horizontal);
+
+        // 32 KiB mode drops the low bit
+        cartridge.write_register(0x8000 | 0b0000_0011, 0);
+        assert_eq!(cartridge.read_prg(0x8000), 2);
+        assert_eq!(cartridge.read_prg(0xC000), 3);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_sachen_boards() {
+        let mut sa_72008 = banked(133, 4, 4);
+        sa_72008.write_register(0x4100, 0b0000_0110);
+        assert_eq!(sa_72008.read_prg(0x8000), 2);
+        assert_eq!(sa_72008.chr_banks(), [4, 5]);
+        // $4000-$40FF does not reach the board
+        sa_72008.write_register(0x4000, 0);
+        assert_eq!(sa_72008.chr_banks(), [4, 5]);
+
+        let sa_nrom = banked(143, 2, 1);
+        assert_eq!(sa_nrom.read_register(0x4100), Some(0x7F));
+        assert_eq!(sa_nrom.read_register(0x4101), Some(0x7E));
+        assert_eq!(sa_nrom.read_register(0x4000), None);
+
+        let mut sa_72007 = banked(145, 2, 2);
+        sa_72007.write_register(0x4100, 0x80);
+        assert_eq!(sa_72007.chr_banks(), [2, 3]);
+
+        let mut sa_0037 = banked(148, 4, 8);
+        sa_0037.write_register(0x8000, 0b0000_1101);
+        assert_eq!(sa_0037.read_prg(0x8000), 2);
+        assert_eq!(sa_0037.chr_banks(), [10, 11]);
+
+        let mut sa_0036 = banked(149, 2, 2);
+        sa_0036.write_register(0x8000, 0x80);
+        assert_eq!(sa_0036.chr_banks(), [2, 3]);
+    }
+
+    #[test]
+    fn test_unif_errors() {
+        let no_board = unif(&[chunk(b"PRG0", &[0; 4])]);
+        assert!(Cartridge::load(&no_board).is_err());
+
+        let unknown = unif(&[
+            chunk(b"MAPR", b"UNL-NOT-A-BOARD\0"),
+            chunk(b"PRG0", &[0; 4]),
+        ]);
+        assert!(Cartridge::load(&unknown).is_err());
+
+        let mut truncated = unif(&[chunk(b"MAPR", b"NES-NROM-256\0")]);
+        truncated.truncate(truncated.len() - 4);
+        assert!(Cartridge::load(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_correct_header() {
+        let mut cartridge = Cartridge::load(&ines(0b0000_0001, 0, 1, 1)).unwrap();
+        let info = GameInfo {
+            name: "Test".to_string(),
+            mapper: 2,
+            submapper: 0,
+            mirroring: Some(Mirroring::Horizontal),
+            region: Region::PAL,
+            battery: false,
+        };
+
+        let changes = cartridge.correct_header(&info);
+        assert_eq!(
+            changes,
+            vec![
+                "mapper 0 -> 2",
+                "mirroring Vertical -> Horizontal",
+                "region NTSC -> PAL"
+            ]
+        );
+        assert_eq!(cartridge.mapper, 2);
+        assert!(cartridge.correct_header(&info).is_empty());
+    }
+
+    #[test]
+    fn test_bad_header_corrected_on_load() {
+        // a mapper 2 PAL game dumped with a blank mapper 0 NTSC header
+        let mut raw = ines(0b0000_0001, 0, 1, 0);
+        raw[16..].copy_from_slice(&[0x5A; PRG_ROM_PAGE_SIZE]);
+        let text = format!(
+            "{:08X} 2 0 H PAL 1 Bad Header Game (E)",
+            crate::checksum::crc32(&raw[16..])
+        );
+        let database = GameDatabase::parse(&text).unwrap();
+
+        let cartridge = Cartridge::load_with_database(&raw, &database).unwrap();
+        assert_eq!(cartridge.mapper, 2);
+        assert_eq!(cartridge.mirroring, Mirroring::Horizontal);
+        assert_eq!(cartridge.region, Region::PAL);
+        assert!(cartridge.battery);
+
+        // the same bytes with another header are left alone
+        let other = GameDatabase::parse("00000000 2 0 H PAL 1 Other").unwrap();
+        let cartridge = Cartridge::load_with_database(&raw, &other).unwrap();
+        assert_eq!(cartridge.mapper, 0);
+    }
+
+    #[test]
+    fn test_embedded_database_fixes_disk_dude_header() {
+        // "DiskDude!" over bytes 7-15 reads as mapper 64; the contents are
+        // zeros with the last 4 bytes picked to give the CRC32 of
+        // Super Mario Bros. (World)
+        let mut raw = ines(0, 0, 2, 1);
+        raw[7..16].copy_from_slice(b"DiskDude!");
+        raw[16..].fill(0);
+        let end = raw.len();
+        raw[end - 4..].copy_from_slice(&[0xC0, 0xDB, 0x28, 0xBD]);
+        assert_eq!(crate::checksum::crc32(&raw[16..]), 0x3337EC46);
+
+        assert_eq!(Cartridge::from_ines(&raw).unwrap().mapper, 64);
+        let cartridge = Cartridge::load(&raw).unwrap();
+        assert_eq!(cartridge.mapper, 0);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert_eq!(cartridge.region, Region::NTSC);
+    }
+
+    #[test]
+    fn test_read_prg_mirrors_16k() {
+        let mut raw = ines(0, 0, 1, 0);
+        raw[16] = 0x42;
+        let cartridge = Cartridge::load(&raw).unwrap();
+        assert_eq!(cartridge.read_prg(0x8000), 0x42);
+        assert_eq!(cartridge.read_prg(0xC000), 0x42);
+    }
+}
@@ -0,0 +1,413 @@
+// -----------------------------
+// Controller
+// standard joypad: a 4021 shift register behind $4016 (port 1) and
+// $4017 (port 2)
+// -----------------------------
+
+// button bits, in the order the shift register sends them
+pub const BUTTON_A: u8 = 0b0000_0001;
+pub const BUTTON_B: u8 = 0b0000_0010;
+pub const BUTTON_SELECT: u8 = 0b0000_0100;
+pub const BUTTON_START: u8 = 0b0000_1000;
+pub const BUTTON_UP: u8 = 0b0001_0000;
+pub const BUTTON_DOWN: u8 = 0b0010_0000;
+pub const BUTTON_LEFT: u8 = 0b0100_0000;
+pub const BUTTON_RIGHT: u8 = 0b1000_0000;
+
+// frames a turbo button stays pressed, then released
+pub const DEFAULT_TURBO_RATE: u8 = 2;
+
+// config and macro names of the buttons, in shift register order
+pub const BUTTON_NAMES: [(&str, u8); 8] = [
+    ("a", BUTTON_A),
+    ("b", BUTTON_B),
+    ("select", BUTTON_SELECT),
+    ("start", BUTTON_START),
+    ("up", BUTTON_UP),
+    ("down", BUTTON_DOWN),
+    ("left", BUTTON_LEFT),
+    ("right", BUTTON_RIGHT),
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Controller {
+    // buttons held right now, set by the frontend
+    buttons: u8,
+    // buttons whose turbo is held, pressed and released every turbo_rate frames
+    turbo: u8,
+    turbo_rate: u8,
+    // frames since power on, for the turbo phase
+    frame: u64,
+    // macro being played back and the frame it is on, it replaces the
+    // held buttons until it ends
+    playing: Option<(InputMacro, usize)>,
+    // buttons seen each frame while recording a macro
+    recording: Option<Vec<u8>>,
+    // bit 0 of the last $4016 write: while high the register keeps
+    // reloading, so every read returns A
+    strobe: bool,
+    // latched buttons, shifted out one per read
+    shift: u8,
+    // reads since the latch, after 8 the serial line stays high
+    reads: u8,
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Controller {
+            buttons: 0,
+            turbo: 0,
+            turbo_rate: DEFAULT_TURBO_RATE,
+            frame: 0,
+            playing: None,
+            recording: None,
+            strobe: false,
+            shift: 0,
+            reads: 0,
+        }
+    }
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        self.strobe_reload();
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    // turbo buttons held, e.g. BUTTON_A | BUTTON_B
+    pub fn set_turbo(&mut self, buttons: u8) {
+        self.turbo = buttons;
+        self.strobe_reload();
+    }
+
+    // frames pressed then frames released, 1 is the fastest (30 Hz on NTSC)
+    pub fn set_turbo_rate(&mut self, frames: u8) {
+        self.turbo_rate = frames.max(1);
+    }
+
+    // an empty macro has nothing to play and is ignored
+    pub fn play_macro(&mut self, input: &InputMacro) {
+        if input.frames.is_empty() {
+            return;
+        }
+        self.playing = Some((input.clone(), 0));
+        self.strobe_reload();
+    }
+
+    pub fn is_playing_macro(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    pub fn start_macro_recording(&mut self) {
+        self.recording = Some(Vec::new());
+    }
+
+    pub fn is_recording_macro(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // the frames recorded since start_macro_recording, None when no
+    // frame stepped while recording
+    pub fn stop_macro_recording(&mut self) -> Option<InputMacro> {
+        self.recording
+            .take()
+            .filter(|frames| !frames.is_empty())
+            .map(|frames| InputMacro { frames })
+    }
+
+    // what the console sees this frame: the macro while one plays,
+    // otherwise the held buttons plus turbo in its pressed phase
+    pub fn state(&self) -> u8 {
+        if let Some((input, frame)) = self.playing.as_ref() {
+            return input.frames[*frame];
+        }
+        self.buttons | self.turbo_phase_buttons()
+    }
+
+    // called once per video frame, moves turbo and macros on
+    pub fn step_frame(&mut self) {
+        let state = self.state();
+        if let Some(frames) = self.recording.as_mut() {
+            frames.push(state);
+        }
+        self.frame += 1;
+        if let Some((input, frame)) = self.playing.as_mut() {
+            *frame += 1;
+            if *frame >= input.frames.len() {
+                self.playing = None;
+            }
+        }
+        self.strobe_reload();
+    }
+
+    fn turbo_phase_buttons(&self) -> u8 {
+        if (self.frame / self.turbo_rate as u64).is_multiple_of(2) {
+            self.turbo
+        } else {
+            0
+        }
+    }
+
+    // $4016 write, bit 0 is the strobe line shared by both ports
+    pub fn write_strobe(&mut self, value: u8) {
+        self.strobe = value & 1 != 0;
+        self.strobe_reload();
+    }
+
+    // serial data in bit 0: A, B, Select, Start, Up, Down, Left, Right,
+    // then 1 for every read after
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.state() & BUTTON_A;
+        }
+        if self.reads >= 8 {
+            return 1;
+        }
+        let bit = self.shift & 1;
+        self.shift >>= 1;
+        self.reads += 1;
+        bit
+    }
+
+    // while the strobe is high the register follows the buttons
+    fn strobe_reload(&mut self) {
+        if self.strobe {
+            self.shift = self.state();
+            self.reads = 0;
+        }
+    }
+}
+
+// -----------------------------
+// Input macros
+// a button state per frame, written as space separated steps of
+// buttons joined by + with an optional frame count:
+// "right*10 right+a*2 none*5 start"
+// -----------------------------
+
+// longest macro a config line can ask for, about 18 minutes at 60 Hz;
+// repeat counts are expanded into frames
+const MAX_MACRO_FRAMES: usize = 65535;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct InputMacro {
+    pub frames: Vec<u8>,
+}
+
+impl InputMacro {
+    pub fn parse(text: &str) -> Result<InputMacro, String> {
+        let mut frames = Vec::new();
+        for step in text.split_whitespace() {
+            let (buttons, count) = match step.split_once('*') {
+                Some((buttons, count)) => {
+                    let count = count
+                        .parse::<usize>()
+                        .map_err(|_| format!("bad frame count in macro step {}", step))?;
+                    (buttons, count)
+                }
+                None => (step, 1),
+            };
+            let state = parse_buttons(buttons)?;
+            if count > MAX_MACRO_FRAMES - frames.len() {
+                return Err(format!(
+                    "the macro is longer than {} frames at step {}",
+                    MAX_MACRO_FRAMES, step
+                ));
+            }
+            frames.extend(std::iter::repeat_n(state, count));
+        }
+        if frames.is_empty() {
+            return Err("the macro has no frames".to_string());
+        }
+        Ok(InputMacro { frames })
+    }
+}
+
+impl std::fmt::Display for InputMacro {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut steps = Vec::new();
+        let mut frames = self.frames.iter().peekable();
+        while let Some(state) = frames.next() {
+            let mut count = 1;
+            while frames.next_if_eq(&state).is_some() {
+                count += 1;
+            }
+            let names: Vec<&str> = BUTTON_NAMES
+                .iter()
+                .filter(|(_, bit)| state & bit != 0)
+                .map(|(name, _)| *name)
+                .collect();
+            let buttons = if names.is_empty() {
+                "none".to_string()
+            } else {
+                names.join("+")
+            };
+            if count == 1 {
+                steps.push(buttons);
+            } else {
+                steps.push(format!("{}*{}", buttons, count));
+            }
+        }
+        write!(f, "{}", steps.join(" "))
+    }
+}
+
+// "right+a" or "none"
+fn parse_buttons(text: &str) -> Result<u8, String> {
+    if text.eq_ignore_ascii_case("none") {
+        return Ok(0);
+    }
+    let mut state = 0;
+    for name in text.split('+') {
+        let name = name.to_ascii_lowercase();
+        let (_, bit) = BUTTON_NAMES
+            .iter()
+            .find(|(button, _)| *button == name)
+            .ok_or_else(|| format!("unknown button {} in macro", name))?;
+        state |= bit;
+    }
+    Ok(state)
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(controller: &mut Controller) -> Vec<u8> {
+        (0..10).map(|_| controller.read()).collect()
+    }
+
+    #[test]
+    fn test_shift_order() {
+        let mut controller = Controller::new();
+        controller.set_buttons(BUTTON_A | BUTTON_START | BUTTON_LEFT);
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        assert_eq!(
+            read_all(&mut controller),
+            vec![1, 0, 0, 1, 0, 0, 1, 0, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_strobe_high_returns_a() {
+        let mut controller = Controller::new();
+        controller.write_strobe(1);
+        controller.set_buttons(BUTTON_B);
+        assert_eq!(controller.read(), 0);
+        controller.set_buttons(BUTTON_A | BUTTON_B);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+    }
+
+    #[test]
+    fn test_latched_state_ignores_new_presses() {
+        let mut controller = Controller::new();
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        controller.set_buttons(BUTTON_A);
+        assert_eq!(controller.read(), 0);
+    }
+
+    #[test]
+    fn test_turbo() {
+        let mut controller = Controller::new();
+        controller.set_turbo_rate(2);
+        controller.set_buttons(BUTTON_UP);
+        controller.set_turbo(BUTTON_A);
+        let states: Vec<u8> = (0..6)
+            .map(|_| {
+                let state = controller.state();
+                controller.step_frame();
+                state
+            })
+            .collect();
+        let pressed = BUTTON_UP | BUTTON_A;
+        assert_eq!(
+            states,
+            vec![pressed, pressed, BUTTON_UP, BUTTON_UP, pressed, pressed]
+        );
+    }
+
+    #[test]
+    fn test_macro_text() {
+        let input = InputMacro::parse("right*3 Right+A none*2 start").unwrap();
+        assert_eq!(
+            input.frames,
+            vec![
+                BUTTON_RIGHT,
+                BUTTON_RIGHT,
+                BUTTON_RIGHT,
+                BUTTON_RIGHT | BUTTON_A,
+                0,
+                0,
+                BUTTON_START
+            ]
+        );
+        assert_eq!(input.to_string(), "right*3 a+right none*2 start");
+        assert_eq!(InputMacro::parse(&input.to_string()), Ok(input));
+        assert!(InputMacro::parse("jump").is_err());
+        assert!(InputMacro::parse("a*x").is_err());
+        assert!(InputMacro::parse("").is_err());
+    }
+
+    #[test]
+    fn test_macro_length_limit() {
+        let input = InputMacro::parse("a*65535").unwrap();
+        assert_eq!(input.frames.len(), MAX_MACRO_FRAMES);
+        assert!(InputMacro::parse("a*65536").is_err());
+        assert!(InputMacro::parse("a*99999999999999").is_err());
+        // the limit covers the whole macro, not each step
+        assert!(InputMacro::parse("a*40000 b*40000").is_err());
+        assert!(InputMacro::parse("a*65534 b").is_ok());
+    }
+
+    #[test]
+    fn test_record_and_play_macro() {
+        let mut controller = Controller::new();
+        controller.start_macro_recording();
+        for buttons in [BUTTON_B, BUTTON_B | BUTTON_LEFT, 0] {
+            controller.set_buttons(buttons);
+            controller.step_frame();
+        }
+        let input = controller.stop_macro_recording().unwrap();
+        assert_eq!(input.frames, vec![BUTTON_B, BUTTON_B | BUTTON_LEFT, 0]);
+
+        // the macro replaces the held buttons until it ends
+        controller.set_buttons(BUTTON_START);
+        controller.play_macro(&input);
+        let mut states = Vec::new();
+        while controller.is_playing_macro() {
+            states.push(controller.state());
+            controller.step_frame();
+        }
+        assert_eq!(states, input.frames);
+        assert_eq!(controller.state(), BUTTON_START);
+    }
+
+    #[test]
+    fn test_empty_macro() {
+        let mut controller = Controller::new();
+        controller.start_macro_recording();
+        assert_eq!(controller.stop_macro_recording(), None);
+        assert!(!controller.is_recording_macro());
+
+        controller.set_buttons(BUTTON_START);
+        controller.play_macro(&InputMacro { frames: vec![] });
+        assert!(!controller.is_playing_macro());
+        assert_eq!(controller.state(), BUTTON_START);
+        controller.step_frame();
+    }
+}
@@ -1,3 +1,14 @@
+use crate::apu::APU;
+use crate::audio::{ChannelMixer, SampleBuffer, DEFAULT_SAMPLE_RATE, FDS_MIX_LEVEL};
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::controller::Controller;
+use crate::fds::FDS;
+use crate::nsf::NSF;
+use crate::ppu::PPU;
+use crate::recording::{AudioRecorder, WavFormat};
+use crate::region::Region;
+use std::path::Path;
+
 // CPU 6802 Flags
 #[derive(Debug, PartialEq)]
 pub enum Flag {
@@ -23,6 +34,13 @@ enum AddressingMode {
     IndirectY,
 }
 
+// a CPU bus cycle, what a DMC halt lands on
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BusAccess {
+    Read(u16),
+    Write(u16),
+}
+
 pub struct CPU {
     pub register_a: u8,
     pub register_x: u8,
@@ -31,6 +49,56 @@ pub struct CPU {
     pub program_counter: u16,
     pub memory: [u8; 65536],
     pub stack_pointer: u8,
+
+    // total CPU cycles since power on, devices are clocked from it
+    pub cycles: u64,
+
+    // Famicom Disk System RAM adapter, mapped at $4020-$4092 and $6000-$FFFF
+    pub fds: Option<FDS>,
+
+    // game pak PRG ROM, mapped at $8000-$FFFF
+    pub cartridge: Option<Cartridge>,
+
+    // NSF music rip in place of a game pak, banks at $8000-$FFFF
+    // (and RAM at $6000-$DFFF plus the wavetable for FDS rips)
+    pub nsf: Option<NSF>,
+
+    // picture processing unit, registers at $2000-$2007 mirrored to $3FFF
+    pub ppu: PPU,
+
+    // audio processing unit, registers at $4000-$4013, $4015 and $4017
+    pub apu: APU,
+    // mixed 2A03 and expansion audio, resampled for the audio backend
+    pub audio: SampleBuffer,
+    // WAV recording of the mix and the channel stems, when running
+    pub recorder: Option<AudioRecorder>,
+    // master volume, 0.0-1.0, e.g. for fading NSF tracks out
+    pub volume: f32,
+    // per-channel mute, solo and volume
+    pub channels: ChannelMixer,
+
+    // joypads read through $4016 and $4017, strobed by $4016 writes
+    pub controllers: [Controller; 2],
+
+    // page written to $4014, the DMA runs once the write instruction ends
+    dma_page: Option<u8>,
+    // cycles left in a running OAM DMA, the DMC steals fewer cycles then
+    oam_dma_remaining: u16,
+    // last bus cycle of the running instruction, taken by tick
+    bus_access: Option<BusAccess>,
+    // cycles a taken branch adds to the instruction
+    branch_cycles: u8,
+
+    // clock rates and frame timing, from the cartridge header or --region
+    pub region: Region,
+    // PPU dots owed to the PPU, in 1/denominator units (PAL runs 3.2 per cycle)
+    ppu_dot_fraction: u32,
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
@@ -45,6 +113,23 @@ impl CPU {
 
             // 0xFD is the default stack pointer value
             stack_pointer: 0xFD,
+            cycles: 0,
+            fds: None,
+            cartridge: None,
+            nsf: None,
+            ppu: PPU::new(vec![], Mirroring::Horizontal),
+            apu: APU::new(Region::NTSC),
+            audio: SampleBuffer::new(Region::NTSC.cpu_clock_rate(), DEFAULT_SAMPLE_RATE),
+            recorder: None,
+            volume: 1.0,
+            channels: ChannelMixer::default(),
+            controllers: [Controller::new(), Controller::new()],
+            dma_page: None,
+            oam_dma_remaining: 0,
+            bus_access: None,
+            branch_cycles: 0,
+            region: Region::NTSC,
+            ppu_dot_fraction: 0,
         }
     }
 
@@ -56,15 +141,110 @@ impl CPU {
         }
     }
 
-    fn read_memory(&self, address: u16) -> u8 {
-        println!(
-            "addres inside read memory {:?} {:?}",
-            address, self.memory[address as usize]
-        );
+    fn read_memory(&mut self, address: u16) -> u8 {
+        self.bus_access = Some(BusAccess::Read(address));
+        if let 0x2000..=0x3FFF = address {
+            return self.ppu.read_register(address);
+        }
+        if address == 0x4015 {
+            return self.apu.read_status();
+        }
+        if let 0x4016..=0x4017 = address {
+            // only bit 0 is driven, the top bits keep the last value on
+            // the data bus: the $40 high byte of the address just read
+            let open_bus = (address >> 8) as u8 & 0xE0;
+            return open_bus | self.controllers[address as usize - 0x4016].read();
+        }
+        if let Some(fds) = self.fds.as_mut() {
+            match address {
+                0x4030..=0x4092 => return fds.read_register(address),
+                0xE000..=0xFFFF => return fds.read_bios(address),
+                _ => {}
+            }
+        }
+        if let Some(cartridge) = self.cartridge.as_ref() {
+            if address >= 0x8000 {
+                return cartridge.read_prg(address);
+            }
+            if let Some(value) = cartridge.read_register(address) {
+                return value;
+            }
+        }
+        if let Some(nsf) = self.nsf.as_mut() {
+            if let (0x4040..=0x4092, Some(audio)) = (address, nsf.fds_audio.as_ref()) {
+                return audio.read_register(address);
+            }
+            if let Some(value) = nsf.expansion_audio.read_register(address) {
+                return value;
+            }
+            if nsf.maps(address) {
+                return nsf.read_prg(address);
+            }
+        }
         self.memory[address as usize]
     }
 
-    fn write_memory(&mut self, address: u16, value: u8) {
+    pub(crate) fn write_memory(&mut self, address: u16, value: u8) {
+        self.bus_access = Some(BusAccess::Write(address));
+        match address {
+            0x2000..=0x3FFF => return self.ppu.write_register(address, value),
+            0x4014 => {
+                self.dma_page = Some(value);
+                return;
+            }
+            0x4016 => {
+                for controller in self.controllers.iter_mut() {
+                    controller.write_strobe(value);
+                }
+                return;
+            }
+            0x4000..=0x4013 | 0x4015 | 0x4017 => return self.apu.write_register(address, value),
+            _ => {}
+        }
+        if let Some(fds) = self.fds.as_mut() {
+            match address {
+                0x4020..=0x408A => {
+                    fds.write_register(address, value);
+                    // $4025 bit 3 drives the nametable mirroring
+                    self.ppu.mirroring = if fds.horizontal_mirroring() {
+                        Mirroring::Horizontal
+                    } else {
+                        Mirroring::Vertical
+                    };
+                    return;
+                }
+                // the disk BIOS is ROM, $6000-$DFFF is the adapter RAM
+                0xE000..=0xFFFF => return,
+                _ => {}
+            }
+        }
+        if let Some(cartridge) = self.cartridge.as_mut() {
+            if address >= 0x4020 {
+                cartridge.write_register(address, value);
+                self.ppu.chr_banks = cartridge.chr_banks();
+                self.ppu.mirroring = cartridge.mirroring;
+            }
+            // PRG ROM is read only, NINA-001 registers are also PRG RAM
+            if address >= 0x8000 {
+                return;
+            }
+        }
+        if let Some(nsf) = self.nsf.as_mut() {
+            // the sound chips sit on the cartridge bus, writes to their
+            // registers in ROM space still reach the FDS RAM
+            nsf.expansion_audio.write_register(address, value);
+            match address {
+                0x5FF6..=0x5FFF => return nsf.write_bank(address, value),
+                0x4040..=0x408A => {
+                    if let Some(audio) = nsf.fds_audio.as_mut() {
+                        audio.write_register(address, value);
+                    }
+                    return;
+                }
+                _ if nsf.maps(address) => return nsf.write_prg(address, value),
+                _ => {}
+            }
+        }
         self.memory[address as usize] = value;
     }
 
@@ -72,15 +252,15 @@ impl CPU {
     // 0x00FF (low byte) and 0xFF00 (high byte)
     // merge with bitwise OR
     // to get 0xFFFF
-    fn read_memory_16bit(&self, address: u16) -> u16 {
+    fn read_memory_16bit(&mut self, address: u16) -> u16 {
         let low_byte = self.read_memory(address) as u16;
-        let high_byte = self.read_memory(address + 1) as u16;
-        println!(" test {:?} - {:?}", low_byte, high_byte);
-        (high_byte << 8) | (low_byte as u16)
+        let high_byte = self.read_memory(address.wrapping_add(1)) as u16;
+        (high_byte << 8) | low_byte
     }
 
     // split 16 bit data into 2 bytes and write to memory
     // 0xFFFF split into 0x00FF and 0xFF00
+    #[allow(dead_code)]
     fn write_memory_16bit(&mut self, address: u16, value: u16) {
         let low_byte = value as u8;
         let high_byte = (value >> 8) as u8;
@@ -91,7 +271,7 @@ impl CPU {
         self.write_memory(address + 1, high_byte);
     }
 
-    fn stack_push(&mut self, value: u8) {
+    pub(crate) fn stack_push(&mut self, value: u8) {
         // Stack is located at 0x0100 to 0x01FF
         self.write_memory(0x0100 + self.stack_pointer as u16, value);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
@@ -136,7 +316,7 @@ impl CPU {
             AddressingMode::Immediate => self.program_counter,
             AddressingMode::ZeroPage => self.read_memory(self.program_counter) as u16,
             AddressingMode::ZeroPageX => {
-                let base = self.read_memory(self.program_counter as u16);
+                let base = self.read_memory(self.program_counter);
                 let zero_page_address = base.wrapping_add(self.register_x);
                 zero_page_address as u16
             }
@@ -147,7 +327,6 @@ impl CPU {
             }
             AddressingMode::Absolute => {
                 let address = self.read_memory_16bit(self.program_counter);
-                println!("absolute address {}", address);
                 self.program_counter += 1;
                 address
             }
@@ -166,21 +345,26 @@ impl CPU {
             }
 
             AddressingMode::IndirectX => {
-                let address = self.read_memory(self.program_counter) as u16;
-                let pointer = address.wrapping_add(self.register_x as u16);
-                println!("{:?}", pointer);
-                let final_address = self.read_memory_16bit(pointer);
-                final_address
+                let address = self.read_memory(self.program_counter);
+                let pointer = address.wrapping_add(self.register_x);
+                self.read_zero_page_pointer(pointer)
             }
 
             AddressingMode::IndirectY => {
-                let address = self.read_memory(self.program_counter) as u16;
-                let final_address = self.read_memory_16bit(address);
-                final_address
+                let address = self.read_memory(self.program_counter);
+                let base = self.read_zero_page_pointer(address);
+                base.wrapping_add(self.register_y as u16)
             }
         }
     }
 
+    // 16 bit pointer in the zero page, the high byte wraps to $00
+    fn read_zero_page_pointer(&mut self, pointer: u8) -> u16 {
+        let low_byte = self.read_memory(pointer as u16) as u16;
+        let high_byte = self.read_memory(pointer.wrapping_add(1) as u16) as u16;
+        (high_byte << 8) | low_byte
+    }
+
     // -----------------------------
     // LOAD / STORE Operations
     // LDA, LDX, LDY , STA, STX, STY
@@ -327,8 +511,6 @@ impl CPU {
         let value = self.read_memory(address);
         self.register_a |= value;
         self.program_counter += 1;
-
-        println!("register_a {:x} {:?} {:?}", self.register_a, value, address);
         self.set_zero_negative_flag(self.register_a);
     }
 
@@ -353,26 +535,162 @@ impl CPU {
     fn adc(&mut self, mode: AddressingMode) {
         let address = self.select_addressing_mode(mode);
         let value = self.read_memory(address);
+        self.add_to_accumulator(value);
+        self.program_counter += 1;
+    }
 
-        let carry = if self.status & Flag::Carry as u8 > 0 {
-            1
-        } else {
-            0
-        };
+    // SBC (Subtract with Carry), the carry is the inverted borrow so it
+    // is ADC of the complement
+    fn sbc(&mut self, mode: AddressingMode) {
+        let address = self.select_addressing_mode(mode);
+        let value = self.read_memory(address);
+        self.add_to_accumulator(!value);
+        self.program_counter += 1;
+    }
 
-        let result = self.register_a.wrapping_add(value).wrapping_add(carry);
+    // the 2A03 has no decimal mode, the D flag is ignored
+    fn add_to_accumulator(&mut self, value: u8) {
+        let carry = (self.status & Flag::Carry as u8) as u16;
+        let sum = self.register_a as u16 + value as u16 + carry;
+        let result = sum as u8;
 
-        self.set_flag(Flag::Carry, result as u16 >= 0x100);
+        self.set_flag(Flag::Carry, sum >= 0x100);
         self.set_flag(
             Flag::Overflow,
             (self.register_a ^ result) & (value ^ result) & 0x80 > 0,
         );
         self.register_a = result;
         self.set_zero_negative_flag(self.register_a);
+    }
 
+    // CMP (Compare), CPX (Compare X Register), CPY (Compare Y Register)
+    // carry when register >= memory, Z and N from the difference
+    fn compare(&mut self, mode: AddressingMode, register: u8) {
+        let address = self.select_addressing_mode(mode);
+        let value = self.read_memory(address);
+        self.set_flag(Flag::Carry, register >= value);
+        self.set_zero_negative_flag(register.wrapping_sub(value));
         self.program_counter += 1;
     }
 
+    // -----------------------------
+    // Increments & Decrements
+    // INC, INX, INY, DEC, DEX, DEY
+    // -----------------------------
+
+    // INC (Increment Memory)
+    fn inc(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.set_zero_negative_flag(result);
+        result
+    }
+
+    // DEC (Decrement Memory)
+    fn dec(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.set_zero_negative_flag(result);
+        result
+    }
+
+    // INX (Increment X Register)
+    fn inx(&mut self) {
+        self.register_x = self.inc(self.register_x);
+    }
+
+    // INY (Increment Y Register)
+    fn iny(&mut self) {
+        self.register_y = self.inc(self.register_y);
+    }
+
+    // DEX (Decrement X Register)
+    fn dex(&mut self) {
+        self.register_x = self.dec(self.register_x);
+    }
+
+    // DEY (Decrement Y Register)
+    fn dey(&mut self) {
+        self.register_y = self.dec(self.register_y);
+    }
+
+    // -----------------------------
+    // Shifts
+    // ASL, LSR, ROL, ROR
+    // -----------------------------
+
+    // ASL (Arithmetic Shift Left)
+    fn asl(&mut self, value: u8) -> u8 {
+        self.set_flag(Flag::Carry, value & 0b1000_0000 != 0);
+        let result = value << 1;
+        self.set_zero_negative_flag(result);
+        result
+    }
+
+    // LSR (Logical Shift Right)
+    fn lsr(&mut self, value: u8) -> u8 {
+        self.set_flag(Flag::Carry, value & 0b0000_0001 != 0);
+        let result = value >> 1;
+        self.set_zero_negative_flag(result);
+        result
+    }
+
+    // ROL (Rotate Left), through the carry
+    fn rol(&mut self, value: u8) -> u8 {
+        let carry = self.status & Flag::Carry as u8;
+        self.set_flag(Flag::Carry, value & 0b1000_0000 != 0);
+        let result = value << 1 | carry;
+        self.set_zero_negative_flag(result);
+        result
+    }
+
+    // ROR (Rotate Right), through the carry
+    fn ror(&mut self, value: u8) -> u8 {
+        let carry = self.status & Flag::Carry as u8;
+        self.set_flag(Flag::Carry, value & 0b0000_0001 != 0);
+        let result = value >> 1 | carry << 7;
+        self.set_zero_negative_flag(result);
+        result
+    }
+
+    // shift or rotate the accumulator
+    fn accumulator(&mut self, operation: fn(&mut CPU, u8) -> u8) {
+        self.register_a = operation(self, self.register_a);
+    }
+
+    // INC, DEC and the shifts on memory: read, change and write back
+    fn read_modify_write(&mut self, mode: AddressingMode, operation: fn(&mut CPU, u8) -> u8) {
+        let address = self.select_addressing_mode(mode);
+        let value = self.read_memory(address);
+        let result = operation(self, value);
+        self.write_memory(address, result);
+        self.program_counter += 1;
+    }
+
+    // -----------------------------
+    // Branches
+    // BCC, BCS, BEQ, BMI, BNE, BPL, BVC, BVS
+    // -----------------------------
+
+    // relative jump of -128 to 127 bytes from the next instruction, a
+    // taken branch costs a cycle and one more onto another page
+    fn branch(&mut self, condition: bool) {
+        let offset = self.read_memory(self.program_counter) as i8;
+        self.program_counter = self.program_counter.wrapping_add(1);
+        if !condition {
+            return;
+        }
+        let target = self.program_counter.wrapping_add(offset as u16);
+        self.branch_cycles = if target & 0xFF00 == self.program_counter & 0xFF00 {
+            1
+        } else {
+            2
+        };
+        self.program_counter = target;
+    }
+
+    fn flag_set(&self, flag: Flag) -> bool {
+        self.status & flag as u8 != 0
+    }
+
     // -----------------------------
     // System Function
     // BRK, NOP, RTI
@@ -387,186 +705,669 @@ impl CPU {
         self.program_counter += 1;
     }
 
-    fn reset_cpu(&mut self) {
+    // RTI (Return from Interrupt)
+    fn rti(&mut self) {
+        self.status = self.stack_pop();
+        let low_byte = self.stack_pop() as u16;
+        let high_byte = self.stack_pop() as u16;
+        self.program_counter = (high_byte << 8) | low_byte;
+    }
+
+    // -----------------------------
+    // Jumps & Calls
+    // JMP, JSR, RTS
+    // -----------------------------
+
+    // JMP (Jump) absolute
+    fn jmp_absolute(&mut self) {
+        self.program_counter = self.read_memory_16bit(self.program_counter);
+    }
+
+    // JMP (Jump) indirect, the pointer high byte does not cross a page
+    fn jmp_indirect(&mut self) {
+        let pointer = self.read_memory_16bit(self.program_counter);
+        let low_byte = self.read_memory(pointer) as u16;
+        let high_byte = self.read_memory((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF));
+        self.program_counter = (high_byte as u16) << 8 | low_byte;
+    }
+
+    // JSR (Jump to Subroutine), pushes the address of its last byte
+    fn jsr(&mut self) {
+        let target = self.read_memory_16bit(self.program_counter);
+        let return_address = self.program_counter + 1;
+        self.stack_push((return_address >> 8) as u8);
+        self.stack_push(return_address as u8);
+        self.program_counter = target;
+    }
+
+    // RTS (Return from Subroutine)
+    fn rts(&mut self) {
+        let low_byte = self.stack_pop() as u16;
+        let high_byte = self.stack_pop() as u16;
+        self.program_counter = ((high_byte << 8) | low_byte).wrapping_add(1);
+    }
+
+    // -----------------------------
+    // Status Flag Changes
+    // CLC, CLD, CLI, CLV, SEC, SED, SEI
+    // -----------------------------
+
+    fn clc(&mut self) {
+        self.set_flag(Flag::Carry, false);
+    }
+
+    fn cld(&mut self) {
+        self.set_flag(Flag::Decimal, false);
+    }
+
+    fn cli(&mut self) {
+        self.set_flag(Flag::Interrupt, false);
+    }
+
+    fn clv(&mut self) {
+        self.set_flag(Flag::Overflow, false);
+    }
+
+    fn sec(&mut self) {
+        self.set_flag(Flag::Carry, true);
+    }
+
+    fn sed(&mut self) {
+        self.set_flag(Flag::Decimal, true);
+    }
+
+    fn sei(&mut self) {
+        self.set_flag(Flag::Interrupt, true);
+    }
+
+    // -----------------------------
+    // Interrupts
+    // IRQ line from the devices on the bus
+    // -----------------------------
+
+    fn irq_line(&self) -> bool {
+        self.apu.irq_pending() || self.fds.as_ref().is_some_and(|fds| fds.irq_pending())
+    }
+
+    // push program counter and status, then jump through the vector
+    fn interrupt(&mut self, vector: u16) {
+        self.stack_push((self.program_counter >> 8) as u8);
+        self.stack_push(self.program_counter as u8);
+
+        // break flag is only set when pushed by BRK / PHP
+        let status = (self.status & !(Flag::Break as u8)) | Flag::Unused as u8;
+        self.stack_push(status);
+
+        self.set_flag(Flag::Interrupt, true);
+        self.program_counter = self.read_memory_16bit(vector);
+        self.tick(7);
+    }
+
+    // advance the cycle counter and clock every device on the bus
+    // instructions are not split into cycles, their last bus access is
+    // taken to happen on the last cycle
+    fn tick(&mut self, cycles: u16) {
+        self.cycles += cycles as u64;
+        let last_access = self.bus_access.take();
+        for cycle in 0..cycles {
+            self.oam_dma_remaining = self.oam_dma_remaining.saturating_sub(1);
+            self.apu.clock();
+            if let Some(address) = self.apu.dmc_fetch_address() {
+                let halted_on = if cycle == cycles - 1 {
+                    last_access
+                } else {
+                    None
+                };
+                self.dmc_dma(address, halted_on);
+            }
+            let [pulse1, pulse2, triangle, noise, dmc, fds, expansion] = self.channels.gains();
+            let apu_gains = [pulse1, pulse2, triangle, noise, dmc];
+            let mut fds_level = 0.0;
+            if let Some(disk_system) = self.fds.as_mut() {
+                disk_system.clock();
+                fds_level = disk_system.audio.output() as f32 * FDS_MIX_LEVEL * fds;
+            }
+            let mut expansion_level = 0.0;
+            if let Some(nsf) = self.nsf.as_mut() {
+                if let Some(audio) = nsf.fds_audio.as_mut() {
+                    audio.clock();
+                    fds_level = audio.output() as f32 * FDS_MIX_LEVEL * fds;
+                }
+                nsf.expansion_audio.clock();
+                expansion_level = nsf.expansion_audio.output() * expansion;
+            }
+            let level =
+                (self.apu.mix_with_gains(&apu_gains) + fds_level + expansion_level) * self.volume;
+            self.audio.clock(level);
+            if let Some(recorder) = self.recorder.as_mut() {
+                let levels = self.apu.channel_levels();
+                // the fds and expansion stems are only recorded when
+                // present, the recorder takes as many as it has
+                let has_fds = self.fds.is_some()
+                    || self.nsf.as_ref().is_some_and(|nsf| nsf.fds_audio.is_some());
+                let stems = [
+                    levels[0] * pulse1,
+                    levels[1] * pulse2,
+                    levels[2] * triangle,
+                    levels[3] * noise,
+                    levels[4] * dmc,
+                    if has_fds { fds_level } else { expansion_level },
+                    expansion_level,
+                ];
+                recorder.clock(level, &stems.map(|stem| stem * self.volume));
+            }
+            // three PPU dots per CPU cycle (3.2 on PAL)
+            let (numerator, denominator) = self.region.ppu_dots_per_cpu_cycle();
+            self.ppu_dot_fraction += numerator;
+            while self.ppu_dot_fraction >= denominator {
+                self.ppu_dot_fraction -= denominator;
+                self.ppu.clock();
+            }
+        }
+    }
+
+    // let the devices run with the CPU doing nothing (NSF idle time)
+    pub(crate) fn idle(&mut self, cycles: u64) {
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let chunk = remaining.min(u16::MAX as u64);
+            self.tick(chunk as u16);
+            remaining -= chunk;
+        }
+    }
+
+    // the DMC memory reader halts the CPU to fetch a sample byte: 4 cycles
+    // normally, 3 when it lands on a CPU write cycle, 2 inside an OAM DMA,
+    // 1 or 3 on its last two cycles. halted_on is the CPU access it lands
+    // on: a halted read of $4016/$4017 is repeated, which clocks the
+    // joypad again and drops a bit
+    fn dmc_dma(&mut self, address: u16, halted_on: Option<BusAccess>) {
+        let stall = match (self.oam_dma_remaining, halted_on) {
+            (0, Some(BusAccess::Write(_))) => 3,
+            (0, _) => 4,
+            (1, _) => 3,
+            (2, _) => 1,
+            _ => 2,
+        };
+        if let Some(BusAccess::Read(port @ 0x4016..=0x4017)) = halted_on {
+            self.controllers[port as usize - 0x4016].read();
+        }
+        let value = self.read_memory(address);
+        self.apu.dmc_fill(value);
+        self.tick(stall);
+    }
+
+    // copy a page of CPU memory to OAM, starting at OAMADDR
+    // the CPU is halted for 513 cycles, plus one when it starts on an odd cycle
+    fn oam_dma(&mut self, page: u8) {
+        let stall = if self.cycles % 2 == 1 { 514 } else { 513 };
+
+        let base = (page as u16) << 8;
+        for offset in 0..256 {
+            let value = self.read_memory(base + offset);
+            self.ppu.write_oam_data(value);
+        }
+
+        self.oam_dma_remaining = stall;
+        self.tick(stall);
+    }
+
+    // buttons held on port 0 (player 1) or 1 (player 2), BUTTON_* bits
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+        self.controllers[port].set_buttons(buttons);
+    }
+
+    // switch console timing, e.g. to override the cartridge header
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.region = region;
+        self.apu.region = region;
+        self.audio.set_clock_rate(region.cpu_clock_rate());
+        self.ppu_dot_fraction = 0;
+    }
+
+    // record audio to a WAV file; stems go next to it, one per channel
+    // (plus the FDS wavetable for the disk system and FDS music rips, and
+    // the other expansion chips of an NSF)
+    pub fn start_recording(
+        &mut self,
+        path: &Path,
+        format: WavFormat,
+        stems: bool,
+    ) -> Result<(), String> {
+        let mut stem_names = Vec::new();
+        if stems {
+            stem_names.extend(["pulse1", "pulse2", "triangle", "noise", "dmc"]);
+            if self.fds.is_some() || self.nsf.as_ref().is_some_and(|n| n.fds_audio.is_some()) {
+                stem_names.push("fds");
+            }
+            if self
+                .nsf
+                .as_ref()
+                .is_some_and(|n| !n.expansion_audio.is_empty())
+            {
+                stem_names.push("expansion");
+            }
+        }
+        let recorder = AudioRecorder::create(
+            path,
+            format,
+            &stem_names,
+            self.region.cpu_clock_rate(),
+            self.audio.sample_rate(),
+        )?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    // power on / reset: start from the reset vector at $FFFC
+    pub fn reset_cpu(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
-        self.status = 0;
-        self.program_counter = 0;
+        self.status = Flag::Interrupt as u8 | Flag::Unused as u8;
         self.stack_pointer = 0xFD;
+        self.program_counter = self.read_memory_16bit(0xFFFC);
+    }
+
+    // plug the disk system in, the BIOS reset vector takes over
+    pub fn insert_fds(&mut self, fds: FDS) {
+        // the RAM adapter has 8 KiB of CHR RAM
+        self.ppu = PPU::new(vec![], Mirroring::Horizontal);
+        self.ppu.region = self.region;
+        self.fds = Some(fds);
+        self.reset_cpu();
+    }
+
+    // plug a game pak in, the trainer (if any) lives at $7000
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        if let Some(trainer) = cartridge.trainer.as_ref() {
+            self.memory[0x7000..0x7000 + trainer.len()].copy_from_slice(trainer);
+        }
+        self.ppu = PPU::new(cartridge.chr_rom.clone(), cartridge.mirroring);
+        self.ppu.chr_banks = cartridge.chr_banks();
+        self.set_region(cartridge.region);
+        self.cartridge = Some(cartridge);
+        self.reset_cpu();
     }
 
     pub fn interpret(&mut self, program: Vec<u8>) {
         self.load_program_into_memory(program);
         self.program_counter = 0;
+        self.run();
+    }
+
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    // run until the PPU has finished a picture, false if the CPU stopped first
+    pub fn run_frame(&mut self) -> bool {
         loop {
-            let opscode = self.memory[self.program_counter as usize];
-            // get opscode and go to next instruction
-            self.program_counter += 1;
-
-            match opscode {
-                // -----------------------------
-                // LOAD / STORE Operations
-                // LDA, LDX, LDY , STA, STX, STY
-                // -----------------------------
-
-                // LDA (Load Accumulator)
-                0xA9 => self.lda(AddressingMode::Immediate),
-                0xA5 => self.lda(AddressingMode::ZeroPage),
-                0xB5 => self.lda(AddressingMode::ZeroPageX),
-                0xAD => self.lda(AddressingMode::Absolute),
-                0xBD => self.lda(AddressingMode::AbsoluteX),
-                0xB9 => self.lda(AddressingMode::AbsoluteY),
-                0xA1 => self.lda(AddressingMode::IndirectX),
-                0xB1 => self.lda(AddressingMode::IndirectY),
-
-                // LDX (Load X Register)
-                0xA2 => self.ldx(AddressingMode::Immediate),
-                0xA6 => self.ldx(AddressingMode::ZeroPage),
-                0xB6 => self.ldx(AddressingMode::ZeroPageY),
-                0xAE => self.ldx(AddressingMode::Absolute),
-                0xBE => self.ldx(AddressingMode::AbsoluteY),
-
-                // LDY (Load Y Register)
-                0xA0 => self.ldy(AddressingMode::Immediate),
-                0xA4 => self.ldy(AddressingMode::ZeroPage),
-                0xB4 => self.ldy(AddressingMode::ZeroPageX),
-                0xAC => self.ldy(AddressingMode::Absolute),
-                0xBC => self.ldy(AddressingMode::AbsoluteX),
-
-                // STA (Store Accumulator)
-                0x85 => self.sta(AddressingMode::ZeroPage),
-                0x95 => self.sta(AddressingMode::ZeroPageX),
-                0x8D => self.sta(AddressingMode::Absolute),
-                0x9D => self.sta(AddressingMode::AbsoluteX),
-                0x99 => self.sta(AddressingMode::AbsoluteY),
-                0x81 => self.sta(AddressingMode::IndirectX),
-                0x91 => self.sta(AddressingMode::IndirectY),
-
-                // STX (Store X Register)
-                0x86 => self.stx(AddressingMode::ZeroPage),
-                0x96 => self.stx(AddressingMode::ZeroPageY),
-                0x8E => self.stx(AddressingMode::Absolute),
-
-                // STY (Store Y Register)
-                0x84 => self.sty(AddressingMode::ZeroPage),
-                0x94 => self.sty(AddressingMode::ZeroPageX),
-                0x8C => self.sty(AddressingMode::Absolute),
-
-                // -----------------------------
-                // Register Transfer
-                // TAX, TAY, TXA, TYA
-                // -----------------------------
-
-                // TAX (Transfer Accumulator to X)
-                0xAA => self.tax(),
-
-                // TAY (Transfer Accumulator to Y)
-                0xA8 => self.tay(),
-
-                // TXA (Transfer X to Accumulator)
-                0x8A => self.txa(),
-
-                // TYA (Transfer Y to Accumulator)
-                0x98 => self.tya(),
-
-                // -----------------------------
-                // Stack Operations
-                // TSX, TXS, PHA, PHP, PLA, PLP
-                // -----------------------------
-
-                // TSX (Transfer Stack Pointer to X)
-                0xBA => self.tsx(),
-
-                // TXS (Transfer X to Stack Pointer)
-                0x9A => self.txs(),
-
-                // PHA (Push Accumulator)
-                0x48 => self.pha(),
-
-                // PHP (Push Processor Status)
-                0x08 => self.php(),
-
-                // PLA (Pull Accumulator)
-                0x68 => self.pla(),
-
-                // plp (Pull Processor Status)
-                0x28 => self.plp(),
-
-                // -----------------------------
-                // Logical
-                // AND, EOR, ORA, BIT
-                // -----------------------------
-
-                // AND (Logical AND)
-                0x29 => self.and(AddressingMode::Immediate),
-                0x25 => self.and(AddressingMode::ZeroPage),
-                0x35 => self.and(AddressingMode::ZeroPageX),
-                0x2D => self.and(AddressingMode::Absolute),
-                0x3D => self.and(AddressingMode::AbsoluteX),
-                0x39 => self.and(AddressingMode::AbsoluteY),
-                0x21 => self.and(AddressingMode::IndirectX),
-                0x31 => self.and(AddressingMode::IndirectY),
-
-                // EOR (Exclusive OR)
-                0x49 => self.eor(AddressingMode::Immediate),
-                0x45 => self.eor(AddressingMode::ZeroPage),
-                0x55 => self.eor(AddressingMode::ZeroPageX),
-                0x4D => self.eor(AddressingMode::Absolute),
-                0x5D => self.eor(AddressingMode::AbsoluteX),
-                0x59 => self.eor(AddressingMode::AbsoluteY),
-                0x41 => self.eor(AddressingMode::IndirectX),
-                0x51 => self.eor(AddressingMode::IndirectY),
-
-                // ORA (Logical Inclusive OR)
-                0x09 => self.ora(AddressingMode::Immediate),
-                0x05 => self.ora(AddressingMode::ZeroPage),
-                0x15 => self.ora(AddressingMode::ZeroPageX),
-                0x0D => self.ora(AddressingMode::Absolute),
-                0x1D => self.ora(AddressingMode::AbsoluteX),
-                0x19 => self.ora(AddressingMode::AbsoluteY),
-                0x01 => self.ora(AddressingMode::IndirectX),
-                0x11 => self.ora(AddressingMode::IndirectY),
-
-                // BIT (Bit Test)
-                0x24 => self.bit(AddressingMode::ZeroPage),
-                0x2C => self.bit(AddressingMode::Absolute),
-
-                // -----------------------------
-                // Arithmetic
-                // ADC, SBC, CMP, CPX, CPY
-                // -----------------------------
-
-                // ADC (Add with Carry)
-                0x69 => self.adc(AddressingMode::Immediate),
-                0x65 => self.adc(AddressingMode::ZeroPage),
-                0x75 => self.adc(AddressingMode::ZeroPageX),
-                0x6D => self.adc(AddressingMode::Absolute),
-                0x7D => self.adc(AddressingMode::AbsoluteX),
-                0x79 => self.adc(AddressingMode::AbsoluteY),
-                0x61 => self.adc(AddressingMode::IndirectX),
-                0x71 => self.adc(AddressingMode::IndirectY),
-
-                // -----------------------------
-                // System Function
-                // BRK, NOP, RTI
-                // -----------------------------
-
-                // BRK (Break)
-                0x00 => {
-                    self.brk();
-                    break;
+            if !self.step() {
+                return false;
+            }
+            if self.ppu.poll_frame() {
+                // turbo and macros move on once per frame
+                for controller in self.controllers.iter_mut() {
+                    controller.step_frame();
                 }
+                return true;
+            }
+        }
+    }
 
-                // NOP (No Operation)
-                0xEA => self.nop(),
+    // execute a single instruction, returns false when the CPU stops
+    pub fn step(&mut self) -> bool {
+        // NMI is edge triggered and wins over IRQ
+        if self.ppu.poll_nmi() {
+            self.interrupt(0xFFFA);
+        } else if self.irq_line() && self.status & Flag::Interrupt as u8 == 0 {
+            self.interrupt(0xFFFE);
+        }
 
-                _ => {
-                    println!("Unrecognized opscode: {:x}", opscode);
-                    break;
-                }
+        let opscode = self.read_memory(self.program_counter);
+        // get opscode and go to next instruction
+        self.program_counter += 1;
+
+        match opscode {
+            // -----------------------------
+            // LOAD / STORE Operations
+            // LDA, LDX, LDY , STA, STX, STY
+            // -----------------------------
+
+            // LDA (Load Accumulator)
+            0xA9 => self.lda(AddressingMode::Immediate),
+            0xA5 => self.lda(AddressingMode::ZeroPage),
+            0xB5 => self.lda(AddressingMode::ZeroPageX),
+            0xAD => self.lda(AddressingMode::Absolute),
+            0xBD => self.lda(AddressingMode::AbsoluteX),
+            0xB9 => self.lda(AddressingMode::AbsoluteY),
+            0xA1 => self.lda(AddressingMode::IndirectX),
+            0xB1 => self.lda(AddressingMode::IndirectY),
+
+            // LDX (Load X Register)
+            0xA2 => self.ldx(AddressingMode::Immediate),
+            0xA6 => self.ldx(AddressingMode::ZeroPage),
+            0xB6 => self.ldx(AddressingMode::ZeroPageY),
+            0xAE => self.ldx(AddressingMode::Absolute),
+            0xBE => self.ldx(AddressingMode::AbsoluteY),
+
+            // LDY (Load Y Register)
+            0xA0 => self.ldy(AddressingMode::Immediate),
+            0xA4 => self.ldy(AddressingMode::ZeroPage),
+            0xB4 => self.ldy(AddressingMode::ZeroPageX),
+            0xAC => self.ldy(AddressingMode::Absolute),
+            0xBC => self.ldy(AddressingMode::AbsoluteX),
+
+            // STA (Store Accumulator)
+            0x85 => self.sta(AddressingMode::ZeroPage),
+            0x95 => self.sta(AddressingMode::ZeroPageX),
+            0x8D => self.sta(AddressingMode::Absolute),
+            0x9D => self.sta(AddressingMode::AbsoluteX),
+            0x99 => self.sta(AddressingMode::AbsoluteY),
+            0x81 => self.sta(AddressingMode::IndirectX),
+            0x91 => self.sta(AddressingMode::IndirectY),
+
+            // STX (Store X Register)
+            0x86 => self.stx(AddressingMode::ZeroPage),
+            0x96 => self.stx(AddressingMode::ZeroPageY),
+            0x8E => self.stx(AddressingMode::Absolute),
+
+            // STY (Store Y Register)
+            0x84 => self.sty(AddressingMode::ZeroPage),
+            0x94 => self.sty(AddressingMode::ZeroPageX),
+            0x8C => self.sty(AddressingMode::Absolute),
+
+            // -----------------------------
+            // Register Transfer
+            // TAX, TAY, TXA, TYA
+            // -----------------------------
+
+            // TAX (Transfer Accumulator to X)
+            0xAA => self.tax(),
+
+            // TAY (Transfer Accumulator to Y)
+            0xA8 => self.tay(),
+
+            // TXA (Transfer X to Accumulator)
+            0x8A => self.txa(),
+
+            // TYA (Transfer Y to Accumulator)
+            0x98 => self.tya(),
+
+            // -----------------------------
+            // Stack Operations
+            // TSX, TXS, PHA, PHP, PLA, PLP
+            // -----------------------------
+
+            // TSX (Transfer Stack Pointer to X)
+            0xBA => self.tsx(),
+
+            // TXS (Transfer X to Stack Pointer)
+            0x9A => self.txs(),
+
+            // PHA (Push Accumulator)
+            0x48 => self.pha(),
+
+            // PHP (Push Processor Status)
+            0x08 => self.php(),
+
+            // PLA (Pull Accumulator)
+            0x68 => self.pla(),
+
+            // plp (Pull Processor Status)
+            0x28 => self.plp(),
+
+            // -----------------------------
+            // Logical
+            // AND, EOR, ORA, BIT
+            // -----------------------------
+
+            // AND (Logical AND)
+            0x29 => self.and(AddressingMode::Immediate),
+            0x25 => self.and(AddressingMode::ZeroPage),
+            0x35 => self.and(AddressingMode::ZeroPageX),
+            0x2D => self.and(AddressingMode::Absolute),
+            0x3D => self.and(AddressingMode::AbsoluteX),
+            0x39 => self.and(AddressingMode::AbsoluteY),
+            0x21 => self.and(AddressingMode::IndirectX),
+            0x31 => self.and(AddressingMode::IndirectY),
+
+            // EOR (Exclusive OR)
+            0x49 => self.eor(AddressingMode::Immediate),
+            0x45 => self.eor(AddressingMode::ZeroPage),
+            0x55 => self.eor(AddressingMode::ZeroPageX),
+            0x4D => self.eor(AddressingMode::Absolute),
+            0x5D => self.eor(AddressingMode::AbsoluteX),
+            0x59 => self.eor(AddressingMode::AbsoluteY),
+            0x41 => self.eor(AddressingMode::IndirectX),
+            0x51 => self.eor(AddressingMode::IndirectY),
+
+            // ORA (Logical Inclusive OR)
+            0x09 => self.ora(AddressingMode::Immediate),
+            0x05 => self.ora(AddressingMode::ZeroPage),
+            0x15 => self.ora(AddressingMode::ZeroPageX),
+            0x0D => self.ora(AddressingMode::Absolute),
+            0x1D => self.ora(AddressingMode::AbsoluteX),
+            0x19 => self.ora(AddressingMode::AbsoluteY),
+            0x01 => self.ora(AddressingMode::IndirectX),
+            0x11 => self.ora(AddressingMode::IndirectY),
+
+            // BIT (Bit Test)
+            0x24 => self.bit(AddressingMode::ZeroPage),
+            0x2C => self.bit(AddressingMode::Absolute),
+
+            // -----------------------------
+            // Arithmetic
+            // ADC, SBC, CMP, CPX, CPY
+            // -----------------------------
+
+            // ADC (Add with Carry)
+            0x69 => self.adc(AddressingMode::Immediate),
+            0x65 => self.adc(AddressingMode::ZeroPage),
+            0x75 => self.adc(AddressingMode::ZeroPageX),
+            0x6D => self.adc(AddressingMode::Absolute),
+            0x7D => self.adc(AddressingMode::AbsoluteX),
+            0x79 => self.adc(AddressingMode::AbsoluteY),
+            0x61 => self.adc(AddressingMode::IndirectX),
+            0x71 => self.adc(AddressingMode::IndirectY),
+
+            // SBC (Subtract with Carry)
+            0xE9 => self.sbc(AddressingMode::Immediate),
+            0xE5 => self.sbc(AddressingMode::ZeroPage),
+            0xF5 => self.sbc(AddressingMode::ZeroPageX),
+            0xED => self.sbc(AddressingMode::Absolute),
+            0xFD => self.sbc(AddressingMode::AbsoluteX),
+            0xF9 => self.sbc(AddressingMode::AbsoluteY),
+            0xE1 => self.sbc(AddressingMode::IndirectX),
+            0xF1 => self.sbc(AddressingMode::IndirectY),
+
+            // CMP (Compare)
+            0xC9 => self.compare(AddressingMode::Immediate, self.register_a),
+            0xC5 => self.compare(AddressingMode::ZeroPage, self.register_a),
+            0xD5 => self.compare(AddressingMode::ZeroPageX, self.register_a),
+            0xCD => self.compare(AddressingMode::Absolute, self.register_a),
+            0xDD => self.compare(AddressingMode::AbsoluteX, self.register_a),
+            0xD9 => self.compare(AddressingMode::AbsoluteY, self.register_a),
+            0xC1 => self.compare(AddressingMode::IndirectX, self.register_a),
+            0xD1 => self.compare(AddressingMode::IndirectY, self.register_a),
+
+            // CPX (Compare X Register)
+            0xE0 => self.compare(AddressingMode::Immediate, self.register_x),
+            0xE4 => self.compare(AddressingMode::ZeroPage, self.register_x),
+            0xEC => self.compare(AddressingMode::Absolute, self.register_x),
+
+            // CPY (Compare Y Register)
+            0xC0 => self.compare(AddressingMode::Immediate, self.register_y),
+            0xC4 => self.compare(AddressingMode::ZeroPage, self.register_y),
+            0xCC => self.compare(AddressingMode::Absolute, self.register_y),
+
+            // -----------------------------
+            // Increments & Decrements
+            // INC, INX, INY, DEC, DEX, DEY
+            // -----------------------------
+
+            // INC (Increment Memory)
+            0xE6 => self.read_modify_write(AddressingMode::ZeroPage, CPU::inc),
+            0xF6 => self.read_modify_write(AddressingMode::ZeroPageX, CPU::inc),
+            0xEE => self.read_modify_write(AddressingMode::Absolute, CPU::inc),
+            0xFE => self.read_modify_write(AddressingMode::AbsoluteX, CPU::inc),
+
+            // INX (Increment X Register)
+            0xE8 => self.inx(),
+
+            // INY (Increment Y Register)
+            0xC8 => self.iny(),
+
+            // DEC (Decrement Memory)
+            0xC6 => self.read_modify_write(AddressingMode::ZeroPage, CPU::dec),
+            0xD6 => self.read_modify_write(AddressingMode::ZeroPageX, CPU::dec),
+            0xCE => self.read_modify_write(AddressingMode::Absolute, CPU::dec),
+            0xDE => self.read_modify_write(AddressingMode::AbsoluteX, CPU::dec),
+
+            // DEX (Decrement X Register)
+            0xCA => self.dex(),
+
+            // DEY (Decrement Y Register)
+            0x88 => self.dey(),
+
+            // -----------------------------
+            // Shifts
+            // ASL, LSR, ROL, ROR
+            // -----------------------------
+
+            // ASL (Arithmetic Shift Left)
+            0x0A => self.accumulator(CPU::asl),
+            0x06 => self.read_modify_write(AddressingMode::ZeroPage, CPU::asl),
+            0x16 => self.read_modify_write(AddressingMode::ZeroPageX, CPU::asl),
+            0x0E => self.read_modify_write(AddressingMode::Absolute, CPU::asl),
+            0x1E => self.read_modify_write(AddressingMode::AbsoluteX, CPU::asl),
+
+            // LSR (Logical Shift Right)
+            0x4A => self.accumulator(CPU::lsr),
+            0x46 => self.read_modify_write(AddressingMode::ZeroPage, CPU::lsr),
+            0x56 => self.read_modify_write(AddressingMode::ZeroPageX, CPU::lsr),
+            0x4E => self.read_modify_write(AddressingMode::Absolute, CPU::lsr),
+            0x5E => self.read_modify_write(AddressingMode::AbsoluteX, CPU::lsr),
+
+            // ROL (Rotate Left)
+            0x2A => self.accumulator(CPU::rol),
+            0x26 => self.read_modify_write(AddressingMode::ZeroPage, CPU::rol),
+            0x36 => self.read_modify_write(AddressingMode::ZeroPageX, CPU::rol),
+            0x2E => self.read_modify_write(AddressingMode::Absolute, CPU::rol),
+            0x3E => self.read_modify_write(AddressingMode::AbsoluteX, CPU::rol),
+
+            // ROR (Rotate Right)
+            0x6A => self.accumulator(CPU::ror),
+            0x66 => self.read_modify_write(AddressingMode::ZeroPage, CPU::ror),
+            0x76 => self.read_modify_write(AddressingMode::ZeroPageX, CPU::ror),
+            0x6E => self.read_modify_write(AddressingMode::Absolute, CPU::ror),
+            0x7E => self.read_modify_write(AddressingMode::AbsoluteX, CPU::ror),
+
+            // -----------------------------
+            // Branches
+            // BCC, BCS, BEQ, BMI, BNE, BPL, BVC, BVS
+            // -----------------------------
+            0x90 => self.branch(!self.flag_set(Flag::Carry)),
+            0xB0 => self.branch(self.flag_set(Flag::Carry)),
+            0xF0 => self.branch(self.flag_set(Flag::Zero)),
+            0x30 => self.branch(self.flag_set(Flag::Negative)),
+            0xD0 => self.branch(!self.flag_set(Flag::Zero)),
+            0x10 => self.branch(!self.flag_set(Flag::Negative)),
+            0x50 => self.branch(!self.flag_set(Flag::Overflow)),
+            0x70 => self.branch(self.flag_set(Flag::Overflow)),
+
+            // -----------------------------
+            // System Function
+            // BRK, NOP, RTI
+            // -----------------------------
+
+            // BRK (Break)
+            0x00 => {
+                self.brk();
+                return false;
             }
+
+            // NOP (No Operation)
+            0xEA => self.nop(),
+
+            // RTI (Return from Interrupt)
+            0x40 => self.rti(),
+
+            // -----------------------------
+            // Jumps & Calls
+            // JMP, JSR, RTS
+            // -----------------------------
+            0x4C => self.jmp_absolute(),
+            0x6C => self.jmp_indirect(),
+            0x20 => self.jsr(),
+            0x60 => self.rts(),
+
+            // -----------------------------
+            // Status Flag Changes
+            // CLC, CLD, CLI, CLV, SEC, SED, SEI
+            // -----------------------------
+            0x18 => self.clc(),
+            0xD8 => self.cld(),
+            0x58 => self.cli(),
+            0xB8 => self.clv(),
+            0x38 => self.sec(),
+            0xF8 => self.sed(),
+            0x78 => self.sei(),
+
+            _ => {
+                println!("Unrecognized opscode: {:x}", opscode);
+                return false;
+            }
+        }
+
+        let branch_cycles = std::mem::take(&mut self.branch_cycles);
+        self.tick((instruction_cycles(opscode) + branch_cycles).into());
+
+        if let Some(page) = self.dma_page.take() {
+            self.oam_dma(page);
         }
+        true
+    }
+}
+
+// base cycle count of every opscode
+// (page crossing penalties on reads are not counted yet)
+fn instruction_cycles(opscode: u8) -> u8 {
+    match opscode {
+        0xA9 | 0xA2 | 0xA0 | 0x29 | 0x49 | 0x09 | 0x69 | 0xE9 | 0xC9 | 0xE0 | 0xC0 => 2,
+        0xA5 | 0xA6 | 0xA4 | 0x85 | 0x86 | 0x84 | 0x25 | 0x45 | 0x05 | 0x24 | 0x65 => 3,
+        0xE5 | 0xC5 | 0xE4 | 0xC4 => 3,
+        0xB5 | 0xB6 | 0xB4 | 0x95 | 0x96 | 0x94 | 0x35 | 0x55 | 0x15 | 0x75 | 0xF5 | 0xD5 => 4,
+        0xAD | 0xAE | 0xAC | 0x8D | 0x8E | 0x8C | 0x2D | 0x4D | 0x0D | 0x2C | 0x6D => 4,
+        0xED | 0xCD | 0xEC | 0xCC => 4,
+        0xBD | 0xB9 | 0xBE | 0xBC | 0x3D | 0x39 | 0x5D | 0x59 | 0x1D | 0x19 | 0x7D | 0x79 => 4,
+        0xFD | 0xF9 | 0xDD | 0xD9 => 4,
+        0x9D | 0x99 => 5,
+        0xA1 | 0x81 | 0x21 | 0x41 | 0x01 | 0x61 | 0xE1 | 0xC1 => 6,
+        0xB1 | 0x31 | 0x51 | 0x11 | 0x71 | 0xF1 | 0xD1 => 5,
+        0x91 => 6,
+        // read-modify-write
+        0xE6 | 0xC6 | 0x06 | 0x46 | 0x26 | 0x66 => 5,
+        0xF6 | 0xD6 | 0x16 | 0x56 | 0x36 | 0x76 => 6,
+        0xEE | 0xCE | 0x0E | 0x4E | 0x2E | 0x6E => 6,
+        0xFE | 0xDE | 0x1E | 0x5E | 0x3E | 0x7E => 7,
+        0x48 | 0x08 => 3,
+        0x68 | 0x28 => 4,
+        0x40 | 0x20 | 0x60 => 6,
+        0x4C => 3,
+        0x6C => 5,
+        0x00 => 7,
+        // implied, accumulator and untaken branches
+        _ => 2,
     }
 }
 
@@ -674,7 +1475,7 @@ mod test {
         let mut cpu = CPU::new();
         cpu.register_y = 0x05;
         cpu.memory[0x84] = 0x37; // Set up memory so that address 0x84 contains the value 0x37
-        cpu.memory[0x37] = 0x37;
+        cpu.memory[0x37 + cpu.register_y as usize] = 0x37;
         cpu.interpret(vec![0xb1, 0x84, 0x00]); // Execute LDA with indirect Y addressing mode
         assert_eq!(cpu.register_a, 0x37); // Check that register_a contains the value 0x37
     }
@@ -876,7 +1677,8 @@ mod test {
         cpu.register_y = 0x05;
         cpu.memory[0x84] = 0x37; // Set up memory so that address 0x84 contains the value 0x37
         cpu.interpret(vec![0x91, 0x84, 0x00]); // Execute STA with indirect Y addressing mode
-        assert_eq!(cpu.memory[0x84], 0x37); // Check that memory address 0x84 + Y contains the value 0x37
+        assert_eq!(cpu.memory[0x84], 0x37);
+        assert_eq!(cpu.memory[0x37 + cpu.register_y as usize], 0x37); // Check that the pointer + Y contains the value 0x37
     }
 
     // STX (Store X Register)
@@ -1116,7 +1918,7 @@ mod test {
         cpu.register_a = 0b1010_1010;
         cpu.register_y = 0x05;
         cpu.memory[0x84] = 0b1100_1100;
-        cpu.memory[0b1100_1100] = 0b1100_1100;
+        cpu.memory[0b1100_1100 + cpu.register_y as usize] = 0b1100_1100;
         cpu.interpret(vec![0x31, 0x84]);
         assert_eq!(cpu.register_a, 0b1000_1000);
     }
@@ -1195,7 +1997,7 @@ mod test {
         cpu.register_a = 0b1010_1010;
         cpu.register_y = 0x05;
         cpu.memory[0x84] = 0b1100_1100;
-        cpu.memory[0b1100_1100] = 0b1100_1100;
+        cpu.memory[0b1100_1100 + cpu.register_y as usize] = 0b1100_1100;
         cpu.interpret(vec![0x51, 0x84]);
         assert_eq!(cpu.register_a, 0b0110_0110);
     }
@@ -1275,7 +2077,7 @@ mod test {
         cpu.register_a = 0b1010_1010;
         cpu.register_y = 0x05;
         cpu.memory[0x84] = 0b1100_1100;
-        cpu.memory[0b1100_1100] = 0b1100_1100;
+        cpu.memory[0b1100_1100 + cpu.register_y as usize] = 0b1100_1100;
         cpu.interpret(vec![0x11, 0x84]);
         assert_eq!(cpu.register_a, 0b1110_1110);
     }
@@ -1433,8 +2235,597 @@ mod test {
         cpu.register_a = 0x05;
         cpu.register_y = 0x05;
         cpu.memory[0x84] = 0x05;
-        cpu.memory[0x05] = 0x05;
+        cpu.memory[0x05 + cpu.register_y as usize] = 0x05;
         cpu.interpret(vec![0x71, 0x84]);
         assert_eq!(cpu.register_a, 0x0a);
     }
+
+    #[test]
+    fn test_0x69_adc_immediate_with_carry_out() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0xff;
+        cpu.interpret(vec![0x69, 0x01]);
+        assert_eq!(cpu.register_a, 0x00);
+        assert_eq!(cpu.status, 0b0000_0011); // carry and zero flag set
+    }
+
+    // SBC (Subtract with Carry)
+
+    #[test]
+    fn test_0xe9_sbc_immediate() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x0a;
+        cpu.status = 1; // carry set, no borrow
+        cpu.interpret(vec![0xe9, 0x05]);
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.status, 1);
+    }
+
+    #[test]
+    fn test_0xe9_sbc_immediate_with_borrow() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x05;
+        cpu.interpret(vec![0xe9, 0x05]);
+        assert_eq!(cpu.register_a, 0xff);
+        assert_eq!(cpu.status, 128); // borrowed: carry clear, negative set
+    }
+
+    #[test]
+    fn test_0xe9_sbc_immediate_with_overflow_flag_set() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x80;
+        cpu.status = 1;
+        cpu.interpret(vec![0xe9, 0x01]);
+        assert_eq!(cpu.register_a, 0x7f);
+        assert_eq!(cpu.status, 0b0100_0001); // overflow and carry set
+    }
+
+    #[test]
+    fn test_0xe5_sbc_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x0a;
+        cpu.status = 1;
+        cpu.memory[0x84] = 0x03;
+        cpu.interpret(vec![0xe5, 0x84]);
+        assert_eq!(cpu.register_a, 0x07);
+    }
+
+    #[test]
+    fn test_0xfd_sbc_absolute_x() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x0a;
+        cpu.register_x = 0x05;
+        cpu.status = 1;
+        cpu.memory[0x1234 + cpu.register_x as usize] = 0x03;
+        cpu.interpret(vec![0xfd, 0x34, 0x12]);
+        assert_eq!(cpu.register_a, 0x07);
+    }
+
+    #[test]
+    fn test_0xf1_sbc_indirect_y() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x0a;
+        cpu.register_y = 0x05;
+        cpu.status = 1;
+        cpu.memory[0x84] = 0x34;
+        cpu.memory[0x85] = 0x12;
+        cpu.memory[0x1234 + cpu.register_y as usize] = 0x03;
+        cpu.interpret(vec![0xf1, 0x84]);
+        assert_eq!(cpu.register_a, 0x07);
+    }
+
+    // CMP (Compare), CPX, CPY
+
+    #[test]
+    fn test_0xc9_cmp_immediate() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x05;
+        cpu.interpret(vec![0xc9, 0x05]);
+        assert_eq!(cpu.status, 0b0000_0011); // equal: carry and zero
+
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x06;
+        cpu.interpret(vec![0xc9, 0x05]);
+        assert_eq!(cpu.status, 0b0000_0001); // greater: carry
+
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x04;
+        cpu.interpret(vec![0xc9, 0x05]);
+        assert_eq!(cpu.status, 0b1000_0000); // less: negative difference
+        assert_eq!(cpu.register_a, 0x04);
+    }
+
+    #[test]
+    fn test_0xd1_cmp_indirect_y() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0x37;
+        cpu.register_y = 0x05;
+        cpu.memory[0x84] = 0x34;
+        cpu.memory[0x85] = 0x12;
+        cpu.memory[0x1234 + cpu.register_y as usize] = 0x37;
+        cpu.interpret(vec![0xd1, 0x84]);
+        assert_eq!(cpu.status, 0b0000_0011);
+    }
+
+    #[test]
+    fn test_0xe4_cpx_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.register_x = 0x10;
+        cpu.memory[0x84] = 0x20;
+        cpu.interpret(vec![0xe4, 0x84]);
+        assert_eq!(cpu.status, 0b1000_0000);
+    }
+
+    #[test]
+    fn test_0xcc_cpy_absolute() {
+        let mut cpu = CPU::new();
+        cpu.register_y = 0x20;
+        cpu.memory[0x1234] = 0x10;
+        cpu.interpret(vec![0xcc, 0x34, 0x12]);
+        assert_eq!(cpu.status, 0b0000_0001);
+    }
+
+    // -----------------------------
+    // Increments & Decrements
+    // INC, INX, INY, DEC, DEX, DEY
+    // -----------------------------
+
+    #[test]
+    fn test_0xe6_inc_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.memory[0x84] = 0xff;
+        cpu.interpret(vec![0xe6, 0x84]);
+        assert_eq!(cpu.memory[0x84], 0x00);
+        assert_eq!(cpu.status, 0b0000_0010);
+    }
+
+    #[test]
+    fn test_0xde_dec_absolute_x() {
+        let mut cpu = CPU::new();
+        cpu.register_x = 0x05;
+        cpu.memory[0x1234 + cpu.register_x as usize] = 0x00;
+        cpu.interpret(vec![0xde, 0x34, 0x12]);
+        assert_eq!(cpu.memory[0x1234 + cpu.register_x as usize], 0xff);
+        assert_eq!(cpu.status, 0b1000_0000);
+    }
+
+    #[test]
+    fn test_0xe8_inx_0xc8_iny() {
+        let mut cpu = CPU::new();
+        cpu.register_x = 0xff;
+        cpu.register_y = 0x05;
+        cpu.interpret(vec![0xe8, 0xc8]);
+        assert_eq!(cpu.register_x, 0x00);
+        assert_eq!(cpu.register_y, 0x06);
+    }
+
+    #[test]
+    fn test_0xca_dex_0x88_dey() {
+        let mut cpu = CPU::new();
+        cpu.register_x = 0x01;
+        cpu.register_y = 0x00;
+        cpu.interpret(vec![0xca, 0x88]);
+        assert_eq!(cpu.register_x, 0x00);
+        assert_eq!(cpu.register_y, 0xff);
+        assert_eq!(cpu.status, 0b1000_0000);
+    }
+
+    // -----------------------------
+    // Shifts
+    // ASL, LSR, ROL, ROR
+    // -----------------------------
+
+    #[test]
+    fn test_0x0a_asl_accumulator() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0b1100_0001;
+        cpu.interpret(vec![0x0a]);
+        assert_eq!(cpu.register_a, 0b1000_0010);
+        assert_eq!(cpu.status, 0b1000_0001);
+    }
+
+    #[test]
+    fn test_0x1e_asl_absolute_x() {
+        let mut cpu = CPU::new();
+        cpu.register_x = 0x05;
+        cpu.memory[0x1234 + cpu.register_x as usize] = 0b0100_0000;
+        cpu.interpret(vec![0x1e, 0x34, 0x12]);
+        assert_eq!(cpu.memory[0x1234 + cpu.register_x as usize], 0b1000_0000);
+    }
+
+    #[test]
+    fn test_0x46_lsr_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.memory[0x84] = 0b0000_0001;
+        cpu.interpret(vec![0x46, 0x84]);
+        assert_eq!(cpu.memory[0x84], 0);
+        assert_eq!(cpu.status, 0b0000_0011);
+    }
+
+    #[test]
+    fn test_0x2a_rol_accumulator() {
+        let mut cpu = CPU::new();
+        cpu.register_a = 0b1000_0000;
+        cpu.status = 1;
+        cpu.interpret(vec![0x2a]);
+        assert_eq!(cpu.register_a, 0b0000_0001);
+        assert_eq!(cpu.status, 0b0000_0001);
+    }
+
+    #[test]
+    fn test_0x6e_ror_absolute() {
+        let mut cpu = CPU::new();
+        cpu.memory[0x1234] = 0b0000_0010;
+        cpu.status = 1;
+        cpu.interpret(vec![0x6e, 0x34, 0x12]);
+        assert_eq!(cpu.memory[0x1234], 0b1000_0001);
+        assert_eq!(cpu.status, 0b1000_0000);
+    }
+
+    // -----------------------------
+    // Branches
+    // BCC, BCS, BEQ, BMI, BNE, BPL, BVC, BVS
+    // -----------------------------
+
+    #[test]
+    fn test_branch_taken_and_not_taken() {
+        // each branch skips an LDA #$01 when taken
+        for (opscode, status, taken) in [
+            (0x90, 0b0000_0000, true),
+            (0xb0, 0b0000_0000, false),
+            (0xf0, 0b0000_0010, true),
+            (0xd0, 0b0000_0010, false),
+            (0x30, 0b1000_0000, true),
+            (0x10, 0b1000_0000, false),
+            (0x50, 0b0100_0000, false),
+            (0x70, 0b0100_0000, true),
+        ] {
+            let mut cpu = CPU::new();
+            cpu.status = status;
+            cpu.interpret(vec![opscode, 0x02, 0xa9, 0x01]);
+            assert_eq!(cpu.register_a == 0, taken, "opscode {:02x}", opscode);
+        }
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        let mut cpu = CPU::new();
+        // BNE not taken: 2 cycles
+        cpu.status = Flag::Zero as u8;
+        cpu.memory[0x0010..0x0012].copy_from_slice(&[0xd0, 0x10]);
+        cpu.program_counter = 0x0010;
+        cpu.step();
+        assert_eq!((cpu.cycles, cpu.program_counter), (2, 0x0012));
+
+        // taken on the same page: 3
+        cpu.status = 0;
+        cpu.program_counter = 0x0010;
+        cpu.step();
+        assert_eq!((cpu.cycles, cpu.program_counter), (5, 0x0022));
+
+        // taken onto the next page: 4
+        cpu.memory[0x00F0..0x00F2].copy_from_slice(&[0xd0, 0x10]);
+        cpu.program_counter = 0x00F0;
+        cpu.step();
+        assert_eq!((cpu.cycles, cpu.program_counter), (9, 0x0102));
+    }
+
+    #[test]
+    fn test_loop() {
+        // LDX #$05, LDA #$00, loop: CLC, ADC #$03, DEX, BNE loop
+        let mut cpu = CPU::new();
+        cpu.interpret(vec![
+            0xa2, 0x05, 0xa9, 0x00, 0x18, 0x69, 0x03, 0xca, 0xd0, 0xfa,
+        ]);
+        assert_eq!(cpu.register_a, 15);
+        assert_eq!(cpu.register_x, 0);
+    }
+
+    // CARTRIDGE ON THE BUS
+
+    #[test]
+    fn test_mapper_writes_reach_the_ppu() {
+        // AxROM (mapper 7) with 64 KiB of PRG and CHR RAM
+        let mut raw = b"NES\x1A\x04\x00\x70\x00".to_vec();
+        raw.resize(16 + 4 * 16384, 0);
+        let mut cpu = CPU::new();
+        cpu.insert_cartridge(Cartridge::load(&raw).unwrap());
+        // LDA #$11, STA $8000: 32 KiB bank 1, upper nametable
+        cpu.interpret(vec![0xa9, 0x11, 0x8d, 0x00, 0x80]);
+        assert_eq!(cpu.ppu.mirroring, Mirroring::SingleScreenUpper);
+
+        // CNROM with 4 CHR banks holding their number
+        let mut raw = b"NES\x1A\x01\x04\x30\x00".to_vec();
+        raw.resize(16 + 16384, 0);
+        for bank in 0..4 {
+            raw.extend(vec![bank; 8192]);
+        }
+        let mut cpu = CPU::new();
+        cpu.insert_cartridge(Cartridge::load(&raw).unwrap());
+        assert_eq!(cpu.ppu.read_vram(0x1000), 0);
+        // LDA #$02, STA $8000
+        cpu.interpret(vec![0xa9, 0x02, 0x8d, 0x00, 0x80]);
+        assert_eq!(cpu.ppu.read_vram(0x0000), 2);
+        assert_eq!(cpu.ppu.read_vram(0x1FFF), 2);
+    }
+
+    // PPU ON THE BUS
+
+    #[test]
+    fn test_ppu_registers_are_mirrored() {
+        let mut cpu = CPU::new();
+        // LDA #$21, STA $3FFE, LDA #$08, STA $200E, LDA #$55, STA $2FFF
+        cpu.interpret(vec![
+            0xa9, 0x21, 0x8d, 0xfe, 0x3f, 0xa9, 0x08, 0x8d, 0x0e, 0x20, 0xa9, 0x55, 0x8d, 0xff,
+            0x2f,
+        ]);
+        assert_eq!(cpu.ppu.read_vram(0x2108), 0x55);
+        assert_eq!(cpu.memory[0x2fff], 0);
+    }
+
+    #[test]
+    fn test_nmi_on_vblank() {
+        let mut cpu = CPU::new();
+        cpu.memory[0xfffa] = 0x00;
+        cpu.memory[0xfffb] = 0x02;
+        // NMI handler: LDX #$42, BRK
+        cpu.memory[0x0200] = 0xa2;
+        cpu.memory[0x0201] = 0x42;
+
+        cpu.ppu.status |= crate::ppu::STATUS_VBLANK;
+        // LDA #$80, STA $2000 (enable NMI), BRK
+        cpu.interpret(vec![0xa9, 0x80, 0x8d, 0x00, 0x20, 0x00]);
+        assert_eq!(cpu.register_x, 0x42);
+        // return address pushed by the NMI is the BRK after STA
+        assert_eq!(cpu.memory[0x01fc], 0x05);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut cpu = CPU::new();
+        for i in 0..256 {
+            cpu.memory[0x0200 + i] = i as u8;
+        }
+        // LDA #$02, STA $4014
+        cpu.interpret(vec![0xa9, 0x02, 0x8d, 0x14, 0x40]);
+        assert_eq!(cpu.ppu.oam[0x00], 0x00);
+        assert_eq!(cpu.ppu.oam[0x7f], 0x7f);
+        assert_eq!(cpu.ppu.oam[0xff], 0xff);
+        assert_eq!(cpu.memory[0x4014], 0);
+    }
+
+    #[test]
+    fn test_oam_dma_starts_at_oamaddr() {
+        let mut cpu = CPU::new();
+        cpu.memory[0x0300] = 0xAA;
+        cpu.memory[0x03ff] = 0xBB;
+        // LDA #$10, STA $2003, LDA #$03, STA $4014
+        cpu.interpret(vec![
+            0xa9, 0x10, 0x8d, 0x03, 0x20, 0xa9, 0x03, 0x8d, 0x14, 0x40,
+        ]);
+        assert_eq!(cpu.ppu.oam[0x10], 0xAA);
+        assert_eq!(cpu.ppu.oam[0x0f], 0xBB);
+    }
+
+    #[test]
+    fn test_oam_dma_stall() {
+        // LDA #$02 (2), STA $4014 (4): DMA starts on an even cycle
+        let mut cpu = CPU::new();
+        cpu.interpret(vec![0xa9, 0x02, 0x8d, 0x14, 0x40]);
+        assert_eq!(cpu.cycles, 2 + 4 + 513);
+
+        // LDA $00 (3), STA $4014 (4): DMA starts on an odd cycle
+        let mut cpu = CPU::new();
+        cpu.interpret(vec![0xa5, 0x00, 0x8d, 0x14, 0x40]);
+        assert_eq!(cpu.cycles, 3 + 4 + 514);
+    }
+
+    #[test]
+    fn test_pal_ppu_clock_divider() {
+        let mut cpu = CPU::new();
+        cpu.set_region(Region::PAL);
+        // 5 x LDA #$01 = 10 cycles = 32 dots
+        cpu.interpret(vec![
+            0xa9, 0x01, 0xa9, 0x01, 0xa9, 0x01, 0xa9, 0x01, 0xa9, 0x01,
+        ]);
+        assert_eq!(cpu.ppu.dot, 32);
+    }
+
+    #[test]
+    fn test_apu_registers() {
+        let mut cpu = CPU::new();
+        // LDA #$01, STA $4015, LDA #$08, STA $4003, LDA $4015
+        cpu.interpret(vec![
+            0xa9, 0x01, 0x8d, 0x15, 0x40, 0xa9, 0x08, 0x8d, 0x03, 0x40, 0xad, 0x15, 0x40,
+        ]);
+        assert_eq!(cpu.register_a, crate::apu::STATUS_PULSE_1);
+        assert_eq!(cpu.memory[0x4003], 0);
+    }
+
+    #[test]
+    fn test_apu_frame_irq() {
+        let mut cpu = CPU::new();
+        cpu.memory[0xfffe] = 0x00;
+        cpu.memory[0xffff] = 0x02;
+        // IRQ handler: LDX #$42, BRK
+        cpu.memory[0x0200] = 0xa2;
+        cpu.memory[0x0201] = 0x42;
+
+        // let the 4-step sequence run to its interrupt
+        cpu.tick(29830);
+        cpu.interpret(vec![0xea]);
+        assert_eq!(cpu.register_x, 0x42);
+    }
+
+    #[test]
+    fn test_dmc_dma_stall() {
+        let mut cpu = CPU::new();
+        cpu.memory[0xc000] = 0xaa;
+        // LDA #$10, STA $4015 (start the 1 byte sample at $C000)
+        cpu.interpret(vec![0xa9, 0x10, 0x8d, 0x15, 0x40]);
+        assert_eq!(cpu.apu.dmc.output(), 0);
+        assert_eq!(cpu.apu.read_status() & crate::apu::STATUS_DMC, 0);
+        assert_eq!(cpu.cycles, 2 + 4 + 4);
+    }
+
+    #[test]
+    fn test_dmc_dma_during_oam_dma() {
+        let mut cpu = CPU::new();
+        // LDA #$10, STA $4015, LDA #$02, STA $4014
+        cpu.interpret(vec![
+            0xa9, 0x10, 0x8d, 0x15, 0x40, 0xa9, 0x02, 0x8d, 0x14, 0x40,
+        ]);
+        assert_eq!(cpu.cycles, 2 + 4 + 4 + 2 + 4 + 513);
+
+        // stolen cycles depend on where the OAM DMA is
+        for (remaining, stall) in [(100, 2), (2, 1), (1, 3)] {
+            let mut cpu = CPU::new();
+            cpu.apu.write_register(0x4015, crate::apu::STATUS_DMC);
+            cpu.oam_dma_remaining = remaining;
+            cpu.dmc_dma(0xc000, None);
+            assert_eq!(cpu.cycles, stall);
+        }
+    }
+
+    #[test]
+    fn test_dmc_dma_on_write_cycle() {
+        let mut cpu = CPU::new();
+        cpu.write_memory(0x0000, 0x01);
+        cpu.apu.write_register(0x4015, crate::apu::STATUS_DMC);
+        // the fetch lands on the write, the last cycle of the tick
+        cpu.tick(1);
+        assert_eq!(cpu.cycles, 1 + 3);
+    }
+
+    #[test]
+    fn test_dmc_dma_drops_joypad_bit() {
+        let mut cpu = CPU::new();
+        cpu.set_buttons(
+            0,
+            crate::controller::BUTTON_B | crate::controller::BUTTON_START,
+        );
+        cpu.write_memory(0x4016, 1);
+        cpu.write_memory(0x4016, 0);
+
+        // A, then the DMC halts the CPU on that read: B is shifted out
+        // by the repeated read and never seen
+        assert_eq!(cpu.read_memory(0x4016) & 1, 0);
+        cpu.apu.write_register(0x4015, crate::apu::STATUS_DMC);
+        cpu.tick(1);
+        assert_eq!(cpu.cycles, 1 + 4);
+        let bits: Vec<u8> = (0..3).map(|_| cpu.read_memory(0x4016) & 1).collect();
+        assert_eq!(bits, vec![0, 1, 0]);
+
+        // a fetch that misses the read leaves the joypad alone
+        let mut cpu = CPU::new();
+        cpu.set_buttons(0, crate::controller::BUTTON_B);
+        cpu.write_memory(0x4016, 1);
+        cpu.write_memory(0x4016, 0);
+        cpu.read_memory(0x4016);
+        cpu.apu.write_register(0x4015, crate::apu::STATUS_DMC);
+        cpu.tick(2);
+        assert_eq!(cpu.read_memory(0x4016) & 1, 1);
+    }
+
+    #[test]
+    fn test_record_stems() {
+        let path = std::env::temp_dir().join("nest_emulator_cpu_recording_test.wav");
+        let mut cpu = CPU::new();
+        cpu.start_recording(&path, WavFormat::Int16, true).unwrap();
+        // LDA #$3F, STA $4011 (DMC direct load)
+        cpu.interpret(vec![0xa9, 0x3f, 0x8d, 0x11, 0x40]);
+        cpu.tick(20_000);
+        cpu.stop_recording().unwrap();
+
+        let dmc_path = std::env::temp_dir().join("nest_emulator_cpu_recording_test-dmc.wav");
+        let pulse_path = std::env::temp_dir().join("nest_emulator_cpu_recording_test-pulse1.wav");
+        let dmc = std::fs::read(&dmc_path).unwrap();
+        let pulse = std::fs::read(&pulse_path).unwrap();
+        assert_eq!(dmc.len(), pulse.len());
+        assert_ne!(dmc, pulse);
+        // no disk system, no fds stem
+        let fds_path = std::env::temp_dir().join("nest_emulator_cpu_recording_test-fds.wav");
+        assert!(!fds_path.exists());
+
+        for name in ["", "-pulse2", "-triangle", "-noise"] {
+            let stem = format!("nest_emulator_cpu_recording_test{}.wav", name);
+            std::fs::remove_file(std::env::temp_dir().join(stem)).unwrap();
+        }
+        std::fs::remove_file(dmc_path).unwrap();
+        std::fs::remove_file(pulse_path).unwrap();
+    }
+
+    #[test]
+    fn test_0x20_jsr_0x60_rts() {
+        let mut cpu = CPU::new();
+        // subroutine at $0010: LDX #$07, RTS
+        cpu.memory[0x0010] = 0xa2;
+        cpu.memory[0x0011] = 0x07;
+        cpu.memory[0x0012] = 0x60;
+        // JSR $0010, LDY #$09, BRK
+        cpu.interpret(vec![0x20, 0x10, 0x00, 0xa0, 0x09, 0x00]);
+        assert_eq!(cpu.register_x, 0x07);
+        assert_eq!(cpu.register_y, 0x09);
+        assert_eq!(cpu.stack_pointer, 0xfd);
+    }
+
+    #[test]
+    fn test_0x4c_jmp_absolute() {
+        let mut cpu = CPU::new();
+        // JMP $0005, LDX #$01, LDY #$02, BRK
+        cpu.interpret(vec![0x4c, 0x05, 0x00, 0xa2, 0x01, 0xa0, 0x02, 0x00]);
+        assert_eq!(cpu.register_x, 0x00);
+        assert_eq!(cpu.register_y, 0x02);
+    }
+
+    #[test]
+    fn test_0x6c_jmp_indirect_page_wrap() {
+        let mut cpu = CPU::new();
+        // the high byte comes from $0200, not $0300
+        cpu.memory[0x02ff] = 0x08;
+        cpu.memory[0x0200] = 0x00;
+        cpu.memory[0x0300] = 0x40;
+        // JMP ($02FF), BRK x4, LDX #$03, BRK
+        cpu.interpret(vec![
+            0x6c, 0xff, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa2, 0x03, 0x00,
+        ]);
+        assert_eq!(cpu.register_x, 0x03);
+    }
+
+    #[test]
+    fn test_channel_mute() {
+        let mut cpu = CPU::new();
+        cpu.apu.write_register(0x4011, 0x7f);
+        cpu.channels.set_solo(Some(crate::audio::Channel::DMC));
+        cpu.tick(40_000);
+        let mut samples = vec![0.0; 2000];
+        let count = cpu.audio.read_samples(&mut samples);
+        assert!(samples[..count].iter().any(|sample| sample.abs() > 0.1));
+
+        // muting the only voice leaves silence
+        cpu.channels.set_muted(crate::audio::Channel::DMC, true);
+        cpu.tick(40_000);
+        let count = cpu.audio.read_samples(&mut samples);
+        assert!(samples[count - 1].abs() < 0.01);
+    }
+
+    #[test]
+    fn test_controller_read() {
+        let mut cpu = CPU::new();
+        cpu.set_buttons(
+            0,
+            crate::controller::BUTTON_A | crate::controller::BUTTON_RIGHT,
+        );
+        cpu.set_buttons(1, crate::controller::BUTTON_B);
+        cpu.write_memory(0x4016, 1);
+        cpu.write_memory(0x4016, 0);
+
+        let port1: Vec<u8> = (0..9).map(|_| cpu.read_memory(0x4016)).collect();
+        assert_eq!(
+            port1,
+            vec![0x41, 0x40, 0x40, 0x40, 0x40, 0x40, 0x40, 0x41, 0x41]
+        );
+        assert_eq!(cpu.read_memory(0x4017), 0x40);
+        assert_eq!(cpu.read_memory(0x4017), 0x41);
+    }
 }
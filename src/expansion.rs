@@ -0,0 +1,830 @@
+use crate::apu::Pulse;
+
+// -----------------------------
+// Expansion audio
+// sound chips on cartridges that NSF rips drive directly: Konami VRC6,
+// Sunsoft 5B, Namco 163 and the MMC5 (the FDS wavetable is in fds.rs)
+// -----------------------------
+
+// a full volume VRC6 pulse is about as loud as a 2A03 pulse
+const VRC6_MIX_LEVEL: f32 = 0.15 / 15.0;
+// each 5B channel at the top of its volume table
+const SUNSOFT_5B_MIX_LEVEL: f32 = 0.15;
+// one N163 channel at full volume, samples are -8 to 7 times 0-15
+const N163_MIX_LEVEL: f32 = 0.15 / 120.0;
+
+// the MMC5 clocks its envelopes and length counters at a fixed 240 Hz
+const MMC5_FRAME_PERIOD: u16 = 7457;
+
+// -----------------------------
+// Konami VRC6
+// two pulses with 16 duty steps and a sawtooth, $9000-$B002
+// -----------------------------
+
+#[derive(Default)]
+struct VRC6Pulse {
+    volume: u8,
+    duty: u8,
+    // ignore the duty and output the volume
+    digitized: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    // counts down from 15, high while at or below the duty
+    step: u8,
+}
+
+impl VRC6Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.digitized = value & 0b1000_0000 != 0;
+                self.duty = (value >> 4) & 0b111;
+                self.volume = value & 0b1111;
+            }
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0b1111) as u16) << 8;
+                self.enabled = value & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0b1111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-15
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default)]
+struct VRC6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    // 14 steps, the rate is added on every other one
+    step: u8,
+    accumulator: u8,
+}
+
+impl VRC6Saw {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0b0011_1111,
+            1 => self.period = (self.period & 0x0F00) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value & 0b1111) as u16) << 8;
+                self.enabled = value & 0b1000_0000 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step >= 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step.is_multiple_of(2) {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // 0-31, the top five bits of the accumulator
+    fn output(&self) -> u8 {
+        if self.enabled {
+            self.accumulator >> 3
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VRC6 {
+    pulse1: VRC6Pulse,
+    pulse2: VRC6Pulse,
+    saw: VRC6Saw,
+    // $9003: stop every channel, or run them 16 / 256 times faster
+    halted: bool,
+    shift: u8,
+}
+
+impl VRC6 {
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address & 0xF003 {
+            0x9000..=0x9002 => self.pulse1.write(address & 0b11, value),
+            0x9003 => {
+                self.halted = value & 0b001 != 0;
+                self.shift = if value & 0b100 != 0 {
+                    8
+                } else if value & 0b010 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xA000..=0xA002 => self.pulse2.write(address & 0b11, value),
+            0xB000..=0xB002 => self.saw.write(address & 0b11, value),
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.halted {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    pub fn output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        level as f32 * VRC6_MIX_LEVEL
+    }
+}
+
+// -----------------------------
+// Sunsoft 5B
+// a YM2149F: three square waves, noise and an envelope generator behind
+// an address latch at $C000 and a data port at $E000
+// -----------------------------
+
+pub struct Sunsoft5B {
+    latch: u8,
+    registers: [u8; 16],
+
+    // CPU cycles since the last tone / noise / envelope step, they come
+    // every 16 cycles
+    divider: u8,
+    tone_counters: [u16; 3],
+    tones: [bool; 3],
+    noise_counter: u8,
+    noise: u32,
+
+    envelope_counter: u16,
+    // 32 steps up (attack) or down, then the shape decides
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+
+    // 1.5 dB per step, index 0 silent
+    levels: [f32; 32],
+}
+
+impl Default for Sunsoft5B {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sunsoft5B {
+    pub fn new() -> Sunsoft5B {
+        Sunsoft5B {
+            latch: 0,
+            registers: [0; 16],
+            divider: 0,
+            tone_counters: [0; 3],
+            tones: [false; 3],
+            noise_counter: 0,
+            noise: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: true,
+            levels: std::array::from_fn(|n| {
+                if n == 0 {
+                    0.0
+                } else {
+                    10f32.powf((n as f32 - 31.0) * 1.5 / 20.0)
+                }
+            }),
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xC000..=0xDFFF => self.latch = value & 0x0F,
+            0xE000..=0xFFFF => {
+                let register = self.latch as usize;
+                self.registers[register] = value;
+                // writing the shape restarts the envelope
+                if register == 13 {
+                    self.envelope_step = 0;
+                    self.envelope_counter = 0;
+                    self.envelope_attack = value & 0b0100 != 0;
+                    self.envelope_holding = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        let low = self.registers[channel * 2] as u16;
+        let high = (self.registers[channel * 2 + 1] & 0x0F) as u16;
+        ((high << 8) | low).max(1)
+    }
+
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < 16 {
+            return;
+        }
+        self.divider = 0;
+
+        // a tone flips every period steps, so its frequency is
+        // clock / (32 * period)
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tones[channel] = !self.tones[channel];
+            }
+        }
+
+        // 17 bit LFSR, stepped at half the rate of a tone of the same period
+        self.noise_counter += 1;
+        let noise_period = (self.registers[6] & 0x1F).max(1);
+        if self.noise_counter >= noise_period * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise ^ (self.noise >> 3)) & 1;
+            self.noise = (self.noise >> 1) | (feedback << 16);
+        }
+
+        self.clock_envelope();
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        let period = u16::from_le_bytes([self.registers[11], self.registers[12]]).max(1);
+        self.envelope_counter += 1;
+        if self.envelope_counter < period {
+            return;
+        }
+        self.envelope_counter = 0;
+
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        // end of a ramp: shape bits continue, attack, alternate, hold
+        let shape = self.registers[13];
+        let continues = shape & 0b1000 != 0;
+        let alternate = shape & 0b0010 != 0;
+        let hold = shape & 0b0001 != 0;
+        if !continues {
+            // drop to silence and stay there
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+        } else if hold {
+            self.envelope_holding = true;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+                self.envelope_step = 31;
+            }
+        } else {
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    // 0-31 on the volume table
+    fn envelope_level(&self) -> usize {
+        if self.envelope_holding && self.registers[13] & 0b1000 == 0 {
+            0
+        } else if self.envelope_attack {
+            self.envelope_step as usize
+        } else {
+            31 - self.envelope_step as usize
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[7];
+        let noise = self.noise & 1 != 0;
+        let mut level = 0.0;
+        for channel in 0..3 {
+            // the mixer bits disable, so a disabled source reads as high
+            let tone_on = self.tones[channel] || mixer & (1 << channel) != 0;
+            let noise_on = noise || mixer & (1 << (channel + 3)) != 0;
+            if !(tone_on && noise_on) {
+                continue;
+            }
+            let volume = self.registers[8 + channel];
+            let index = if volume & 0b1_0000 != 0 {
+                self.envelope_level()
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                (volume & 0x0F) as usize * 2 + 1
+            };
+            level += self.levels[index];
+        }
+        level * SUNSOFT_5B_MIX_LEVEL
+    }
+}
+
+// -----------------------------
+// Namco 163
+// up to 8 wavetable channels sharing 128 bytes of sound RAM ($4800 data,
+// $F800 address); the chip updates one channel every 15 cycles
+// -----------------------------
+
+pub struct N163 {
+    ram: [u8; 128],
+    address: u8,
+    auto_increment: bool,
+
+    timer: u8,
+    // channel updated next, 7 counting down through the enabled ones
+    channel: usize,
+    outputs: [i16; 8],
+}
+
+impl Default for N163 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl N163 {
+    pub fn new() -> N163 {
+        N163 {
+            ram: [0; 128],
+            address: 0,
+            auto_increment: false,
+            timer: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    fn data_port(&mut self) -> usize {
+        let address = self.address as usize;
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+        address
+    }
+
+    pub fn read_register(&mut self, address: u16) -> Option<u8> {
+        match address {
+            0x4800..=0x4FFF => {
+                let address = self.data_port();
+                Some(self.ram[address])
+            }
+            _ => None,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4800..=0x4FFF => {
+                let address = self.data_port();
+                self.ram[address] = value;
+            }
+            0xF800..=0xFFFF => {
+                self.address = value & 0x7F;
+                self.auto_increment = value & 0b1000_0000 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    // channels 8 - count to 7 play, count is set in $7F
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0b111) as usize + 1
+    }
+
+    pub fn clock(&mut self) {
+        self.timer += 1;
+        if self.timer < 15 {
+            return;
+        }
+        self.timer = 0;
+
+        let first = 8 - self.enabled_channels();
+        if self.channel < first {
+            self.channel = 7;
+        }
+        self.update_channel(self.channel);
+        self.channel = if self.channel == first {
+            7
+        } else {
+            self.channel - 1
+        };
+    }
+
+    // each channel has 8 bytes at $40 + 8 * channel: frequency, phase,
+    // wave length, wave address and volume
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let registers = &self.ram[base..base + 8];
+        let frequency =
+            registers[0] as u32 | (registers[2] as u32) << 8 | ((registers[4] & 0b11) as u32) << 16;
+        let phase = registers[1] as u32 | (registers[3] as u32) << 8 | (registers[5] as u32) << 16;
+        let length = 256 - (registers[4] & 0xFC) as u32;
+        let offset = registers[6] as u32;
+        let volume = (registers[7] & 0x0F) as i16;
+
+        let phase = (phase + frequency) % (length << 16);
+        let sample_index = (((phase >> 16) + offset) & 0xFF) as usize;
+        let sample = (self.ram[sample_index / 2] >> ((sample_index % 2) * 4)) & 0x0F;
+        self.outputs[channel] = (sample as i16 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+
+    // the chip plays the channels in turn, averaged here
+    pub fn output(&self) -> f32 {
+        let count = self.enabled_channels();
+        let sum: i16 = self.outputs[8 - count..].iter().sum();
+        sum as f32 / count as f32 * N163_MIX_LEVEL
+    }
+}
+
+// -----------------------------
+// MMC5
+// two 2A03 pulses without sweep, a raw 8-bit PCM channel and the
+// 8x8 multiplier at $5205/$5206
+// -----------------------------
+
+pub struct MMC5Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    pcm: u8,
+    // $5010 bit 0: PCM is fed by reads instead of $5011 writes
+    pcm_read_mode: bool,
+
+    frame_timer: u16,
+    cycle: u64,
+
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+impl Default for MMC5Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MMC5Audio {
+    pub fn new() -> MMC5Audio {
+        MMC5Audio {
+            pulse1: Pulse::without_sweep(),
+            pulse2: Pulse::without_sweep(),
+            pcm: 0,
+            pcm_read_mode: false,
+            frame_timer: MMC5_FRAME_PERIOD,
+            cycle: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+        }
+    }
+
+    pub fn read_register(&mut self, address: u16) -> Option<u8> {
+        let product = self.multiplicand as u16 * self.multiplier as u16;
+        match address {
+            0x5015 => {
+                let mut status = 0;
+                if self.pulse1.is_active() {
+                    status |= 0b01;
+                }
+                if self.pulse2.is_active() {
+                    status |= 0b10;
+                }
+                Some(status)
+            }
+            0x5205 => Some(product as u8),
+            0x5206 => Some((product >> 8) as u8),
+            _ => None,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5003 => self.pulse1.write(address - 0x5000, value),
+            0x5004..=0x5007 => self.pulse2.write(address - 0x5004, value),
+            0x5010 => self.pcm_read_mode = value & 1 != 0,
+            // a zero write is ignored (it raises the PCM IRQ instead)
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm = value,
+            0x5015 => {
+                self.pulse1.set_enabled(value & 0b01 != 0);
+                self.pulse2.set_enabled(value & 0b10 != 0);
+            }
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.cycle % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.cycle += 1;
+
+        self.frame_timer -= 1;
+        if self.frame_timer == 0 {
+            self.frame_timer = MMC5_FRAME_PERIOD;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.clock_envelope();
+                pulse.clock_length();
+            }
+        }
+    }
+
+    // the pulses go through the same DAC curve as the 2A03's, the PCM
+    // like the DMC at twice its range
+    pub fn output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse > 0.0 {
+            95.52 / (8128.0 / pulse + 100.0)
+        } else {
+            0.0
+        };
+        let pcm = self.pcm as f32 / 2.0;
+        let pcm_out = if pcm > 0.0 {
+            163.67 / (24329.0 / pcm + 100.0)
+        } else {
+            0.0
+        };
+        pulse_out + pcm_out
+    }
+}
+
+// -----------------------------
+// The chips an NSF asks for
+// -----------------------------
+
+#[derive(Default)]
+pub struct ExpansionAudio {
+    pub vrc6: Option<VRC6>,
+    pub sunsoft5b: Option<Sunsoft5B>,
+    pub n163: Option<N163>,
+    pub mmc5: Option<MMC5Audio>,
+}
+
+impl ExpansionAudio {
+    pub fn is_empty(&self) -> bool {
+        self.vrc6.is_none()
+            && self.sunsoft5b.is_none()
+            && self.n163.is_none()
+            && self.mmc5.is_none()
+    }
+
+    pub fn read_register(&mut self, address: u16) -> Option<u8> {
+        if let Some(value) = self
+            .n163
+            .as_mut()
+            .and_then(|n163| n163.read_register(address))
+        {
+            return Some(value);
+        }
+        self.mmc5
+            .as_mut()
+            .and_then(|mmc5| mmc5.read_register(address))
+    }
+
+    // every chip sees every write, as on a cartridge bus
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if let Some(vrc6) = self.vrc6.as_mut() {
+            vrc6.write_register(address, value);
+        }
+        if let Some(sunsoft5b) = self.sunsoft5b.as_mut() {
+            sunsoft5b.write_register(address, value);
+        }
+        if let Some(n163) = self.n163.as_mut() {
+            n163.write_register(address, value);
+        }
+        if let Some(mmc5) = self.mmc5.as_mut() {
+            mmc5.write_register(address, value);
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if let Some(vrc6) = self.vrc6.as_mut() {
+            vrc6.clock();
+        }
+        if let Some(sunsoft5b) = self.sunsoft5b.as_mut() {
+            sunsoft5b.clock();
+        }
+        if let Some(n163) = self.n163.as_mut() {
+            n163.clock();
+        }
+        if let Some(mmc5) = self.mmc5.as_mut() {
+            mmc5.clock();
+        }
+    }
+
+    // the chips summed, on the scale of APU::mix
+    pub fn output(&self) -> f32 {
+        self.vrc6.as_ref().map_or(0.0, |vrc6| vrc6.output())
+            + self
+                .sunsoft5b
+                .as_ref()
+                .map_or(0.0, |sunsoft5b| sunsoft5b.output())
+            + self.n163.as_ref().map_or(0.0, |n163| n163.output())
+            + self.mmc5.as_ref().map_or(0.0, |mmc5| mmc5.output())
+    }
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run<F: FnMut()>(cycles: u32, mut clock: F) {
+        for _ in 0..cycles {
+            clock();
+        }
+    }
+
+    #[test]
+    fn test_vrc6_pulse_duty() {
+        let mut vrc6 = VRC6::default();
+        // volume 15, duty 7 (8 of 16 steps), period 0 (a step per cycle)
+        vrc6.write_register(0x9000, 0b0111_1111);
+        vrc6.write_register(0x9001, 0);
+        vrc6.write_register(0x9002, 0x80);
+        let mut high = 0;
+        for _ in 0..16 {
+            vrc6.clock();
+            if vrc6.pulse1.output() == 15 {
+                high += 1;
+            }
+        }
+        assert_eq!(high, 8);
+
+        // digitized mode ignores the duty
+        vrc6.write_register(0x9000, 0b1000_0101);
+        assert_eq!(vrc6.pulse1.output(), 5);
+        vrc6.write_register(0x9003, 0b001);
+        vrc6.clock();
+        vrc6.write_register(0x9002, 0);
+        assert_eq!(vrc6.output(), 0.0);
+    }
+
+    #[test]
+    fn test_vrc6_saw() {
+        let mut vrc6 = VRC6::default();
+        vrc6.write_register(0xB000, 10);
+        vrc6.write_register(0xB002, 0x80);
+        let mut levels = Vec::new();
+        for _ in 0..14 {
+            vrc6.clock();
+            levels.push(vrc6.saw.accumulator);
+        }
+        // six additions, then back to zero
+        assert_eq!(levels[11], 60);
+        assert_eq!(levels[13], 0);
+        assert_eq!(vrc6.saw.step, 0);
+    }
+
+    #[test]
+    fn test_sunsoft5b_tone_and_volume() {
+        let mut chip = Sunsoft5B::new();
+        // channel A: period 1, tone only, volume 15
+        for (register, value) in [(0, 1), (7, 0b11_1110), (8, 15)] {
+            chip.write_register(0xC000, register);
+            chip.write_register(0xE000, value);
+        }
+        let mut samples = Vec::new();
+        for _ in 0..4 {
+            run(16, || chip.clock());
+            samples.push(chip.output());
+        }
+        assert!(samples[0] > 0.0);
+        assert_eq!(samples[1], 0.0);
+        assert_eq!(samples[0], samples[2]);
+        assert!((samples[0] - SUNSOFT_5B_MIX_LEVEL).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_sunsoft5b_envelope() {
+        let mut chip = Sunsoft5B::new();
+        for (register, value) in [(7, 0b11_1111), (8, 0x10), (11, 1), (13, 0b1101)] {
+            chip.write_register(0xC000, register);
+            chip.write_register(0xE000, value);
+        }
+        // attack, continue, hold: ramps up once then stays at the top
+        assert_eq!(chip.envelope_level(), 0);
+        run(16 * 10, || chip.clock());
+        assert_eq!(chip.envelope_level(), 10);
+        run(16 * 100, || chip.clock());
+        assert_eq!(chip.envelope_level(), 31);
+        assert!(chip.envelope_holding);
+
+        // no continue: one ramp down, then silence
+        chip.write_register(0xC000, 13);
+        chip.write_register(0xE000, 0b0000);
+        assert_eq!(chip.envelope_level(), 31);
+        run(16 * 40, || chip.clock());
+        assert_eq!(chip.envelope_level(), 0);
+        assert_eq!(chip.output(), 0.0);
+    }
+
+    #[test]
+    fn test_n163_ram_port() {
+        let mut chip = N163::new();
+        chip.write_register(0xF800, 0x80 | 0x10);
+        chip.write_register(0x4800, 0x12);
+        chip.write_register(0x4800, 0x34);
+        chip.write_register(0xF800, 0x10);
+        assert_eq!(chip.read_register(0x4800), Some(0x12));
+        assert_eq!(chip.read_register(0x4800), Some(0x12));
+        assert_eq!(chip.read_register(0x5000), None);
+    }
+
+    #[test]
+    fn test_n163_channel() {
+        let mut chip = N163::new();
+        // a 4 sample wave at $00: 15, 0, 15, 0
+        chip.ram[0] = 0x0F;
+        chip.ram[1] = 0x0F;
+        // channel 7: frequency of one sample per update, length 4,
+        // volume 15, one channel enabled
+        chip.ram[0x78] = 0x00;
+        chip.ram[0x7A] = 0x00;
+        chip.ram[0x7C] = 0x01 | (256 - 4) as u8;
+        chip.ram[0x7E] = 0;
+        chip.ram[0x7F] = 0x0F;
+
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            run(15, || chip.clock());
+            levels.push(chip.outputs[7]);
+        }
+        assert_eq!(levels, vec![-120, 105, -120, 105]);
+        assert_eq!(chip.output(), 105.0 * N163_MIX_LEVEL);
+    }
+
+    #[test]
+    fn test_mmc5_pulse_and_multiplier() {
+        let mut mmc5 = MMC5Audio::new();
+        mmc5.write_register(0x5015, 0b01);
+        // constant volume 12, 50% duty, low period still sounds
+        mmc5.write_register(0x5000, 0b1011_1100);
+        mmc5.write_register(0x5002, 2);
+        mmc5.write_register(0x5003, 0b0000_1000);
+        assert_eq!(mmc5.read_register(0x5015), Some(0b01));
+        let mut heard = false;
+        run(64, || {
+            mmc5.clock();
+            heard |= mmc5.pulse1.output() == 12;
+        });
+        assert!(heard);
+
+        mmc5.write_register(0x5011, 0x80);
+        assert_eq!(mmc5.pcm, 0x80);
+
+        mmc5.write_register(0x5205, 200);
+        mmc5.write_register(0x5206, 100);
+        assert_eq!(mmc5.read_register(0x5205), Some((20000u16 & 0xFF) as u8));
+        assert_eq!(mmc5.read_register(0x5206), Some((20000u16 >> 8) as u8));
+    }
+}
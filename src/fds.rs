@@ -0,0 +1,1098 @@
+use crate::patch;
+
+// -----------------------------
+// Famicom Disk System
+// RAM adapter registers, disk drive and wavetable audio
+// -----------------------------
+
+const FDS_HEADER: &[u8] = b"FDS\x1A";
+const FDS_HEADER_SIZE: usize = 16;
+const FDS_SIDE_SIZE: usize = 65500;
+const QD_SIDE_SIZE: usize = 65536;
+const BIOS_SIZE: usize = 0x2000;
+
+// gaps the drive sees between blocks, in bytes
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+
+// the BIOS waits for the motor to spin up, then bytes come every ~150 cycles
+const MOTOR_DELAY: u32 = 50000;
+const BYTE_DELAY: u32 = 150;
+
+// BIOS ROM, rewinding drive, disk side images and the IRQ timer
+pub struct FDS {
+    bios: Vec<u8>,
+
+    // the .fds image as dumped (no header), writes are diffed against it
+    original: Vec<u8>,
+
+    // disk sides as the drive sees them: gaps, start marks, blocks and CRCs
+    sides: Vec<Vec<u8>>,
+    inserted_side: Option<usize>,
+
+    // $4022 / $4020-$4021 timer IRQ
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+
+    // $4023 master I/O enable
+    disk_io_enabled: bool,
+    sound_io_enabled: bool,
+
+    // $4025 control
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal_mirroring: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+
+    // drive head state
+    disk_position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning_disk: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    crc_accumulator: u16,
+    transfer_complete: bool,
+    disk_irq: bool,
+    read_data: u8,
+    write_data: u8,
+
+    // $4026 / $4033 expansion port
+    external_output: u8,
+
+    pub audio: FDSAudio,
+}
+
+impl FDS {
+    // bios is the 8 KiB disksys.rom, image is a .fds (with or without header) or .qd file
+    pub fn new(bios: Vec<u8>, image: Vec<u8>) -> Result<FDS, String> {
+        if bios.len() != BIOS_SIZE {
+            return Err(format!(
+                "FDS BIOS must be {} bytes, got {}",
+                BIOS_SIZE,
+                bios.len()
+            ));
+        }
+
+        let original = parse_disk_image(&image)?;
+        let sides = original.chunks(FDS_SIDE_SIZE).map(build_raw_side).collect();
+
+        Ok(FDS {
+            bios,
+            original,
+            sides,
+            inserted_side: Some(0),
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_io_enabled: false,
+            sound_io_enabled: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            horizontal_mirroring: false,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning_disk: false,
+            gap_ended: false,
+            previous_crc_control: false,
+            crc_accumulator: 0,
+            transfer_complete: false,
+            disk_irq: false,
+            read_data: 0,
+            write_data: 0,
+            external_output: 0,
+            audio: FDSAudio::new(),
+        })
+    }
+
+    // -----------------------------
+    // Disk swapping
+    // -----------------------------
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    pub fn inserted_side(&self) -> Option<usize> {
+        self.inserted_side
+    }
+
+    // side 0 is disk 1 side A, side 1 is disk 1 side B, side 2 is disk 2 side A...
+    pub fn insert_side(&mut self, side: usize) -> Result<(), String> {
+        if side >= self.sides.len() {
+            return Err(format!(
+                "disk side {} does not exist, image has {} sides",
+                side,
+                self.sides.len()
+            ));
+        }
+        self.inserted_side = Some(side);
+        self.end_of_head = true;
+        Ok(())
+    }
+
+    pub fn eject(&mut self) {
+        self.inserted_side = None;
+    }
+
+    // -----------------------------
+    // Saving
+    // writes go to the raw sides, saves are an IPS diff of the .fds image
+    // -----------------------------
+
+    pub fn save_diff(&self) -> Vec<u8> {
+        patch::create_ips(&self.original, &self.disk_image())
+    }
+
+    pub fn load_diff(&mut self, diff: &[u8]) -> Result<(), String> {
+        let image = patch::apply_ips(&self.original, diff)?;
+        if image.len() != self.original.len() {
+            return Err("FDS save does not match the size of the disk image".to_string());
+        }
+        self.sides = image.chunks(FDS_SIDE_SIZE).map(build_raw_side).collect();
+        Ok(())
+    }
+
+    // current disk contents in .fds layout (without header)
+    pub fn disk_image(&self) -> Vec<u8> {
+        self.sides
+            .iter()
+            .flat_map(|side| rebuild_side(side))
+            .collect()
+    }
+
+    // -----------------------------
+    // Bus
+    // -----------------------------
+
+    pub fn horizontal_mirroring(&self) -> bool {
+        self.horizontal_mirroring
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    pub fn read_bios(&self, address: u16) -> u8 {
+        self.bios[(address - 0xE000) as usize]
+    }
+
+    pub fn read_register(&mut self, address: u16) -> u8 {
+        match address {
+            0x4030 => {
+                let mut value = 0;
+                if self.timer_irq {
+                    value |= 0b0000_0001;
+                }
+                if self.transfer_complete {
+                    value |= 0b0000_0010;
+                }
+                if self.read_mode && self.crc_accumulator != 0 {
+                    value |= 0b0001_0000;
+                }
+                if self.end_of_head {
+                    value |= 0b0100_0000;
+                }
+                if self.disk_ready {
+                    value |= 0b1000_0000;
+                }
+
+                // reading the status acknowledges both IRQs
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                value
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                self.read_data
+            }
+            0x4032 => {
+                let mut value = 0b0100_0000;
+                if self.inserted_side.is_none() {
+                    // not inserted, not ready, write protected
+                    value |= 0b0000_0111;
+                } else if !self.scanning_disk {
+                    value |= 0b0000_0010;
+                }
+                value
+            }
+            // bit 7 is the battery good flag
+            0x4033 => 0b1000_0000 | (self.external_output & 0x7F),
+            0x4040..=0x407F | 0x4090 | 0x4092 => self.audio.read_register(address),
+            // open bus
+            _ => (address >> 8) as u8,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        if address == 0x4023 {
+            self.disk_io_enabled = value & 0b0000_0001 != 0;
+            self.sound_io_enabled = value & 0b0000_0010 != 0;
+            if !self.disk_io_enabled {
+                self.timer_enabled = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+            }
+            return;
+        }
+
+        match address {
+            0x4020..=0x4026 if self.disk_io_enabled => self.write_disk_register(address, value),
+            0x4040..=0x408A if self.sound_io_enabled => self.audio.write_register(address, value),
+            _ => {}
+        }
+    }
+
+    fn write_disk_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = value & 0b0000_0001 != 0;
+                self.timer_enabled = value & 0b0000_0010 != 0;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4024 => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = value & 0b0000_0001 != 0;
+                self.reset_transfer = value & 0b0000_0010 != 0;
+                self.read_mode = value & 0b0000_0100 != 0;
+                self.horizontal_mirroring = value & 0b0000_1000 != 0;
+                self.crc_control = value & 0b0001_0000 != 0;
+                self.disk_ready = value & 0b0100_0000 != 0;
+                self.disk_irq_enabled = value & 0b1000_0000 != 0;
+                self.disk_irq = false;
+            }
+            0x4026 => self.external_output = value,
+            _ => {}
+        }
+    }
+
+    // -----------------------------
+    // Clock (once per CPU cycle)
+    // -----------------------------
+
+    pub fn clock(&mut self) {
+        self.clock_timer();
+        self.clock_disk();
+        self.audio.clock();
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled || !self.disk_io_enabled {
+            return;
+        }
+
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_disk(&mut self) {
+        let side = match self.inserted_side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning_disk = false;
+                return;
+            }
+        };
+
+        if self.reset_transfer && !self.scanning_disk {
+            return;
+        }
+
+        // head is rewinding to the start of the disk
+        if self.end_of_head {
+            self.delay = MOTOR_DELAY;
+            self.end_of_head = false;
+            self.disk_position = 0;
+            self.gap_ended = false;
+            return;
+        }
+
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning_disk = true;
+        let mut need_irq = self.disk_irq_enabled;
+
+        if self.read_mode {
+            let data = self.sides[side][self.disk_position];
+            if !self.previous_crc_control {
+                self.update_crc(data);
+            }
+
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc_accumulator = 0;
+            } else if data != 0 && !self.gap_ended {
+                // the start mark ends the gap but is not handed to the CPU
+                self.gap_ended = true;
+                need_irq = false;
+            }
+
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if need_irq {
+                    self.disk_irq = true;
+                }
+            }
+
+            if !self.disk_ready {
+                data = 0;
+            }
+
+            if !self.crc_control {
+                self.update_crc(data);
+            } else {
+                if !self.previous_crc_control {
+                    // flush the CRC before shifting it out low byte first
+                    self.update_crc(0);
+                    self.update_crc(0);
+                }
+                data = self.crc_accumulator as u8;
+                self.crc_accumulator >>= 8;
+            }
+
+            self.sides[side][self.disk_position] = data;
+            self.gap_ended = false;
+        }
+
+        self.previous_crc_control = self.crc_control;
+        self.disk_position += 1;
+
+        if self.disk_position >= self.sides[side].len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+
+    fn update_crc(&mut self, value: u8) {
+        self.crc_accumulator = crc_step(self.crc_accumulator, value);
+    }
+}
+
+// -----------------------------
+// Disk images
+// -----------------------------
+
+// returns the sides back to back, FDS_SIDE_SIZE bytes each
+fn parse_disk_image(image: &[u8]) -> Result<Vec<u8>, String> {
+    if image.starts_with(FDS_HEADER) {
+        let sides = image.get(4).copied().unwrap_or(0) as usize;
+        let data = &image[FDS_HEADER_SIZE.min(image.len())..];
+        if sides == 0 || data.len() < sides * FDS_SIDE_SIZE {
+            return Err(format!(
+                "FDS image is truncated, header declares {} sides",
+                sides
+            ));
+        }
+        return Ok(data[..sides * FDS_SIDE_SIZE].to_vec());
+    }
+
+    if !image.is_empty() && image.len().is_multiple_of(FDS_SIDE_SIZE) {
+        return Ok(image.to_vec());
+    }
+
+    if !image.is_empty() && image.len().is_multiple_of(QD_SIDE_SIZE) {
+        return Ok(image.chunks(QD_SIDE_SIZE).flat_map(strip_qd_side).collect());
+    }
+
+    Err(format!(
+        "unrecognised disk image: {} bytes is neither .fds nor .qd",
+        image.len()
+    ))
+}
+
+// length of the block starting at data[0], file_size comes from the last file header
+fn block_length(data: &[u8], file_size: &mut usize) -> Option<usize> {
+    match data.first()? {
+        1 => Some(56),
+        2 => Some(2),
+        3 => {
+            let header = data.get(..16)?;
+            *file_size = header[13] as usize | (header[14] as usize) << 8;
+            Some(16)
+        }
+        4 => Some(1 + *file_size),
+        _ => None,
+    }
+}
+
+// .qd sides keep the two CRC bytes after each block, .fds sides do not
+fn strip_qd_side(side: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut file_size = 0;
+    let mut position = 0;
+
+    while let Some(length) = block_length(&side[position..], &mut file_size) {
+        let end = (position + length).min(side.len());
+        output.extend_from_slice(&side[position..end]);
+        position = end + 2;
+        if position >= side.len() {
+            break;
+        }
+    }
+
+    output.resize(FDS_SIDE_SIZE, 0);
+    output
+}
+
+// lay a .fds side out the way the drive reads it
+fn build_raw_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP];
+    let mut file_size = 0;
+    let mut position = 0;
+
+    while position < side.len() {
+        let length = match block_length(&side[position..], &mut file_size) {
+            Some(length) => length,
+            None => break,
+        };
+        let end = (position + length).min(side.len());
+        let block = &side[position..end];
+
+        let crc = block_crc(block);
+        raw.push(0x80);
+        raw.extend_from_slice(block);
+        raw.push(crc as u8);
+        raw.push((crc >> 8) as u8);
+        raw.extend(std::iter::repeat_n(0, BLOCK_GAP));
+
+        position = end;
+    }
+
+    // room for the BIOS to append files
+    let minimum = LEAD_IN_GAP + FDS_SIDE_SIZE + FDS_SIDE_SIZE / 8;
+    if raw.len() < minimum {
+        raw.resize(minimum, 0);
+    }
+    raw
+}
+
+// strip gaps, start marks and CRCs back out of a raw side
+fn rebuild_side(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(FDS_SIDE_SIZE);
+    let mut file_size = 0;
+    let mut position = 0;
+
+    while position < raw.len() {
+        if raw[position] != 0x80 {
+            position += 1;
+            continue;
+        }
+        position += 1;
+
+        let length = match block_length(&raw[position..], &mut file_size) {
+            Some(length) => length,
+            None => break,
+        };
+        let end = (position + length).min(raw.len());
+        side.extend_from_slice(&raw[position..end]);
+        position = end + 2;
+    }
+
+    side.resize(FDS_SIDE_SIZE, 0);
+    side
+}
+
+fn crc_step(mut crc: u16, value: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if value & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// CRC over the start mark and block, as written by the drive
+fn block_crc(block: &[u8]) -> u16 {
+    let crc = block
+        .iter()
+        .fold(crc_step(0, 0x80), |crc, &b| crc_step(crc, b));
+    crc_step(crc_step(crc, 0), 0)
+}
+
+// -----------------------------
+// FDS Audio
+// 64 step wavetable with volume envelope and pitch modulation
+// -----------------------------
+
+// mod table entries: pitch counter step, 4 resets the counter
+const MOD_STEPS: [i32; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const MOD_RESET: u8 = 4;
+
+// $4089 master volume 2/2, 2/3, 2/4, 2/5
+const MASTER_VOLUME: [u32; 4] = [36, 24, 17, 14];
+
+pub struct Envelope {
+    pub gain: u8,
+    speed: u8,
+    increase: bool,
+    disabled: bool,
+    timer: u32,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            gain: 0,
+            speed: 0,
+            increase: false,
+            disabled: true,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0b0011_1111;
+        self.increase = value & 0b0100_0000 != 0;
+        self.disabled = value & 0b1000_0000 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    // returns true when the gain changed
+    fn clock(&mut self, master_speed: u8) -> bool {
+        if self.disabled || master_speed == 0 {
+            return false;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer > 0 {
+            return false;
+        }
+
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+        true
+    }
+}
+
+pub struct FDSAudio {
+    wave_table: [u8; 64],
+    wave_write_enabled: bool,
+    wave_position: u8,
+    wave_accumulator: u16,
+    frequency: u16,
+    halt_wave: bool,
+    disable_envelopes: bool,
+    master_volume: u8,
+    master_speed: u8,
+
+    pub volume: Envelope,
+    pub mod_envelope: Envelope,
+
+    mod_table: [u8; 64],
+    mod_position: u8,
+    mod_accumulator: u16,
+    mod_frequency: u16,
+    mod_halted: bool,
+    mod_counter: i8,
+
+    output: u8,
+}
+
+impl Default for FDSAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FDSAudio {
+    pub fn new() -> FDSAudio {
+        FDSAudio {
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_position: 0,
+            wave_accumulator: 0,
+            frequency: 0,
+            halt_wave: true,
+            disable_envelopes: false,
+            master_volume: 0,
+            master_speed: 0xE8,
+            volume: Envelope::new(),
+            mod_envelope: Envelope::new(),
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_counter: 0,
+            output: 0,
+        }
+    }
+
+    // current output level, 0-63
+    pub fn output(&self) -> u8 {
+        self.output
+    }
+
+    fn read_wave(&self, address: u16) -> u8 {
+        self.wave_table[(address & 0x3F) as usize]
+    }
+
+    // $4040-$407F wave table, $4090 / $4092 envelope gains
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0x4040..=0x407F => self.read_wave(address) | 0b0100_0000,
+            0x4090 => self.volume.gain | 0b0100_0000,
+            0x4092 => self.mod_envelope.gain | 0b0100_0000,
+            // open bus
+            _ => (address >> 8) as u8,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave_table[(address & 0x3F) as usize] = value & 0b0011_1111;
+            }
+            0x4080 => self.volume.write(value, self.master_speed),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.halt_wave = value & 0b1000_0000 != 0;
+                self.disable_envelopes = value & 0b0100_0000 != 0;
+                if self.halt_wave {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+                if self.disable_envelopes {
+                    self.volume.reset_timer(self.master_speed);
+                    self.mod_envelope.reset_timer(self.master_speed);
+                }
+            }
+            0x4084 => self.mod_envelope.write(value, self.master_speed),
+            0x4085 => self.mod_counter = sign_extend_7bit(value),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value & 0x0F) as u16) << 8;
+                self.mod_halted = value & 0b1000_0000 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // the table can only be written while the mod unit is halted,
+            // every write fills two consecutive entries (the unit may have
+            // stopped on an odd position, so both wrap)
+            0x4088 if self.mod_halted => {
+                let position = self.mod_position as usize;
+                self.mod_table[position & 0x3F] = value & 0b0000_0111;
+                self.mod_table[(position + 1) & 0x3F] = value & 0b0000_0111;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.wave_write_enabled = value & 0b1000_0000 != 0;
+                self.master_volume = value & 0b0000_0011;
+            }
+            0x408A => self.master_speed = value,
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt_wave && !self.disable_envelopes {
+            self.volume.clock(self.master_speed);
+            self.mod_envelope.clock(self.master_speed);
+        }
+
+        self.clock_modulator();
+
+        if self.halt_wave {
+            self.wave_position = 0;
+        } else if !self.wave_write_enabled {
+            let pitch = self.modulated_pitch();
+            if pitch > 0 {
+                let (accumulator, overflow) = self.wave_accumulator.overflowing_add(pitch);
+                self.wave_accumulator = accumulator;
+                if overflow {
+                    self.wave_position = (self.wave_position + 1) & 0x3F;
+                }
+            }
+        }
+
+        self.update_output();
+    }
+
+    fn clock_modulator(&mut self) {
+        if self.mod_halted || self.mod_frequency == 0 {
+            return;
+        }
+
+        let (accumulator, overflow) = self.mod_accumulator.overflowing_add(self.mod_frequency);
+        self.mod_accumulator = accumulator;
+        if !overflow {
+            return;
+        }
+
+        let step = self.mod_table[self.mod_position as usize];
+        self.mod_counter = if step == MOD_RESET {
+            0
+        } else {
+            sign_extend_7bit((self.mod_counter as i32 + MOD_STEPS[step as usize]) as u8)
+        };
+        self.mod_position = (self.mod_position + 1) & 0x3F;
+    }
+
+    // wave pitch after the mod unit has bent it
+    fn modulated_pitch(&self) -> u16 {
+        let pitch = self.frequency as i32;
+        if self.mod_halted {
+            return pitch as u16;
+        }
+
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+
+        (pitch + temp).clamp(0, 0xFFFF) as u16
+    }
+
+    fn update_output(&mut self) {
+        let gain = self.volume.gain.min(32) as u32;
+        let level = gain * MASTER_VOLUME[self.master_volume as usize];
+        self.output = (self.wave_table[self.wave_position as usize] as u32 * level / 1152) as u8;
+    }
+}
+
+fn sign_extend_7bit(value: u8) -> i8 {
+    ((value << 1) as i8) >> 1
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::region::Region;
+
+    // one side with a disk info block, file count and a single 4 byte file
+    fn test_side() -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        side.extend_from_slice(&[0x02, 0x01]);
+
+        let mut header = vec![0x03, 0x00, 0x00];
+        header.extend_from_slice(b"FILENAME");
+        header.extend_from_slice(&[0x00, 0x60, 0x04, 0x00, 0x00]);
+        side.extend_from_slice(&header);
+        side.extend_from_slice(&[0x04, 0xDE, 0xAD, 0xBE, 0xEF]);
+
+        side.resize(FDS_SIDE_SIZE, 0);
+        side
+    }
+
+    fn test_fds() -> FDS {
+        FDS::new(vec![0; BIOS_SIZE], test_side()).unwrap()
+    }
+
+    #[test]
+    fn test_fds_headered_image() {
+        let mut image = b"FDS\x1A\x02".to_vec();
+        image.resize(FDS_HEADER_SIZE, 0);
+        image.extend(test_side());
+        image.extend(test_side());
+
+        let fds = FDS::new(vec![0; BIOS_SIZE], image).unwrap();
+        assert_eq!(fds.side_count(), 2);
+        assert_eq!(fds.disk_image().len(), 2 * FDS_SIDE_SIZE);
+    }
+
+    #[test]
+    fn test_qd_image() {
+        let fds_side = test_side();
+        let mut qd_side = fds_side[..56].to_vec();
+        qd_side.extend_from_slice(&[0, 0]);
+        qd_side.extend_from_slice(&fds_side[56..58]);
+        qd_side.extend_from_slice(&[0, 0]);
+        qd_side.extend_from_slice(&fds_side[58..74]);
+        qd_side.extend_from_slice(&[0, 0]);
+        qd_side.extend_from_slice(&fds_side[74..79]);
+        qd_side.extend_from_slice(&[0, 0]);
+        qd_side.resize(QD_SIDE_SIZE, 0);
+
+        let fds = FDS::new(vec![0; BIOS_SIZE], qd_side).unwrap();
+        assert_eq!(fds.disk_image(), fds_side);
+    }
+
+    #[test]
+    fn test_fds_rejects_bad_input() {
+        assert!(FDS::new(vec![0; 16], test_side()).is_err());
+        assert!(FDS::new(vec![0; BIOS_SIZE], vec![0; 1000]).is_err());
+        assert!(FDS::new(vec![0; BIOS_SIZE], FDS_HEADER.to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_raw_side_round_trip() {
+        let side = test_side();
+        let raw = build_raw_side(&side);
+        assert!(raw[..LEAD_IN_GAP].iter().all(|&b| b == 0));
+        assert_eq!(raw[LEAD_IN_GAP], 0x80);
+        assert_eq!(rebuild_side(&raw), side);
+    }
+
+    #[test]
+    fn test_block_crc_checks_to_zero() {
+        let block = &test_side()[..56];
+        let crc = block_crc(block);
+
+        let mut accumulator = crc_step(0, 0x80);
+        for &b in block {
+            accumulator = crc_step(accumulator, b);
+        }
+        accumulator = crc_step(accumulator, crc as u8);
+        accumulator = crc_step(accumulator, (crc >> 8) as u8);
+        assert_eq!(accumulator, 0);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = test_fds();
+        fds.write_register(0x4023, 0x01);
+        fds.write_register(0x4020, 0x03);
+        fds.write_register(0x4021, 0x00);
+        fds.write_register(0x4022, 0x02);
+
+        for _ in 0..3 {
+            fds.clock_timer();
+            assert!(!fds.irq_pending());
+        }
+        fds.clock_timer();
+        assert!(fds.irq_pending());
+
+        // one shot: acknowledge and the timer stays quiet
+        assert_eq!(fds.read_register(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq_pending());
+        for _ in 0..10 {
+            fds.clock_timer();
+        }
+        assert!(!fds.irq_pending());
+    }
+
+    #[test]
+    fn test_registers_need_io_enable() {
+        let mut fds = test_fds();
+        fds.write_register(0x4020, 0x10);
+        assert_eq!(fds.timer_reload, 0);
+
+        fds.write_register(0x4023, 0x01);
+        fds.write_register(0x4020, 0x10);
+        assert_eq!(fds.timer_reload, 0x10);
+    }
+
+    #[test]
+    fn test_disk_read_transfer() {
+        let mut fds = test_fds();
+        fds.write_register(0x4023, 0x01);
+        // motor on, read mode, disk ready, transfer IRQs
+        fds.write_register(0x4025, 0b1100_0101);
+
+        let mut bytes = vec![];
+        while bytes.len() < 3 {
+            fds.clock();
+            if fds.irq_pending() {
+                bytes.push(fds.read_register(0x4031));
+            }
+        }
+        // the start mark is swallowed, the disk info block follows
+        assert_eq!(bytes, vec![0x01, b'*', b'N']);
+    }
+
+    #[test]
+    fn test_bios_reads_disk_under_irq() {
+        // reset: LDA #$40, STA $4017 (no APU frame IRQ), LDA #$01, STA $4023,
+        // LDY #$00, LDA #$C5, STA $4025 (motor, read, disk IRQ), CLI,
+        // loop: CPY #$03, BNE loop, BRK
+        // IRQ: LDA $4031, STA $0300,Y, INY, RTI
+        let mut bios = vec![0; BIOS_SIZE];
+        bios[0x0000..0x0017].copy_from_slice(&[
+            0xa9, 0x40, 0x8d, 0x17, 0x40, 0xa9, 0x01, 0x8d, 0x23, 0x40, 0xa0, 0x00, 0xa9, 0xc5,
+            0x8d, 0x25, 0x40, 0x58, 0xc0, 0x03, 0xd0, 0xfc, 0x00,
+        ]);
+        bios[0x0100..0x0108].copy_from_slice(&[0xad, 0x31, 0x40, 0x99, 0x00, 0x03, 0xc8, 0x40]);
+        bios[0x1FFC..0x2000].copy_from_slice(&[0x00, 0xe0, 0x00, 0xe1]);
+
+        let mut cpu = CPU::new();
+        cpu.insert_fds(FDS::new(bios, test_side()).unwrap());
+        let mut steps = 0;
+        while cpu.step() {
+            steps += 1;
+            assert!(steps < 1_000_000, "the BIOS loop never finished");
+        }
+        // the start mark is swallowed, the disk info block follows
+        assert_eq!(cpu.register_y, 3);
+        assert_eq!(cpu.memory[0x0300..0x0303], [0x01, b'*', b'N']);
+    }
+
+    #[test]
+    fn test_region_override_after_insert() {
+        // main applies --region after the disk system is plugged in
+        let mut cpu = CPU::new();
+        cpu.insert_fds(test_fds());
+        cpu.set_region(Region::PAL);
+        assert_eq!(cpu.region, Region::PAL);
+        assert_eq!(cpu.ppu.region, Region::PAL);
+        assert_eq!(cpu.apu.region, Region::PAL);
+        assert!(cpu.fds.is_some());
+    }
+
+    #[test]
+    fn test_drive_status() {
+        let mut fds = test_fds();
+        assert_eq!(fds.read_register(0x4032) & 0x07, 0x02);
+
+        fds.eject();
+        assert_eq!(fds.read_register(0x4032) & 0x07, 0x07);
+
+        assert!(fds.insert_side(1).is_err());
+        assert!(fds.insert_side(0).is_ok());
+        assert_eq!(fds.inserted_side(), Some(0));
+    }
+
+    #[test]
+    fn test_save_diff() {
+        let mut fds = test_fds();
+        assert_eq!(fds.save_diff(), b"PATCHEOF".to_vec());
+
+        // overwrite the file data on the raw side
+        let position = fds.sides[0]
+            .windows(4)
+            .position(|w| w == [0xDE, 0xAD, 0xBE, 0xEF])
+            .unwrap();
+        fds.sides[0][position] = 0x00;
+        let diff = fds.save_diff();
+
+        let mut reloaded = test_fds();
+        reloaded.load_diff(&diff).unwrap();
+        assert_eq!(reloaded.disk_image()[75], 0x00);
+    }
+
+    #[test]
+    fn test_audio_wave_steps() {
+        let mut audio = FDSAudio::new();
+        audio.write_register(0x4089, 0x80);
+        for i in 0..64 {
+            audio.write_register(0x4040 + i, i as u8);
+        }
+        audio.write_register(0x4089, 0x00);
+        audio.write_register(0x4080, 0x80 | 32);
+        audio.write_register(0x4082, 0x00);
+        audio.write_register(0x4083, 0x08);
+
+        // pitch $800 overflows the 16 bit accumulator every 32 clocks
+        for _ in 0..32 {
+            audio.clock();
+        }
+        assert_eq!(audio.wave_position, 1);
+        assert_eq!(audio.output(), 1);
+    }
+
+    #[test]
+    fn test_audio_mod_table() {
+        let mut audio = FDSAudio::new();
+        audio.write_register(0x4087, 0x80);
+        audio.write_register(0x4088, 0x01);
+        audio.write_register(0x4088, 0x04);
+        assert_eq!(audio.mod_table[..4], [1, 1, 4, 4]);
+
+        audio.write_register(0x4085, 0x7F);
+        assert_eq!(audio.mod_counter, -1);
+    }
+
+    #[test]
+    fn test_audio_mod_table_write_at_odd_position() {
+        let mut audio = FDSAudio::new();
+        // run the mod unit until it stops on the last, odd, entry
+        audio.write_register(0x4086, 0xFF);
+        audio.write_register(0x4087, 0x0F);
+        while audio.mod_position != 63 {
+            audio.clock();
+        }
+        audio.write_register(0x4087, 0x8F);
+        audio.write_register(0x4088, 0x03);
+        assert_eq!((audio.mod_table[63], audio.mod_table[0]), (3, 3));
+        assert_eq!(audio.mod_position, 1);
+    }
+}
@@ -0,0 +1,676 @@
+use crate::audio::{Channel, ChannelMixer, DEFAULT_SAMPLE_RATE};
+use crate::audio_output::{self, AudioBackend, NullAudio, RateControl};
+use crate::controller::{Controller, InputMacro};
+use crate::cpu::CPU;
+use crate::input::{InputConfig, InputSource, INPUT_NAMES};
+use crate::nsf::{fade_volume, NsfPlayer};
+use crate::ntsc::NtscFilter;
+use crate::output::OutputSettings;
+use crate::palette::Palette;
+use crate::ppu::{PPU, SCREEN_WIDTH};
+use crate::ppu_debug;
+use crate::screenshot::Screenshot;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::{Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{KeyboardState, Keycode, Mod, Scancode};
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+use sdl2::{AudioSubsystem, GameControllerSubsystem, VideoSubsystem};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// -----------------------------
+// SDL frontend
+// game window plus the optional PPU debug windows, audio through an
+// SDL queue, joypads from the keyboard and game controllers
+// -----------------------------
+
+// audio queued ahead of the speaker, in seconds
+const AUDIO_LATENCY: f64 = 0.05;
+
+pub struct FrontendOptions {
+    // cropping, scaling and scanlines for the game window
+    pub output: OutputSettings,
+    pub debug_viewers: bool,
+    pub palette: Palette,
+    // frames go through the NTSC filter instead of the palette
+    pub ntsc: Option<NtscFilter>,
+    // false plays into the null backend
+    pub audio: bool,
+    // key and gamepad bindings, runtime remaps are saved to input_path
+    pub input: InputConfig,
+    pub input_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy)]
+enum DebugView {
+    Nametables,
+    PatternTables,
+    Palettes,
+    Sprites,
+}
+
+impl DebugView {
+    const ALL: [DebugView; 4] = [
+        DebugView::Nametables,
+        DebugView::PatternTables,
+        DebugView::Palettes,
+        DebugView::Sprites,
+    ];
+
+    fn title(&self) -> &'static str {
+        match self {
+            DebugView::Nametables => "Nametables",
+            DebugView::PatternTables => "Pattern tables",
+            DebugView::Palettes => "Palettes",
+            DebugView::Sprites => "OAM",
+        }
+    }
+
+    // pattern_palette is the PPU palette (0-7) the pattern tables use
+    fn render(&self, ppu: &PPU, palette: &Palette, pattern_palette: u8) -> Screenshot {
+        match self {
+            DebugView::Nametables => ppu_debug::nametables(ppu, palette),
+            DebugView::PatternTables => ppu_debug::pattern_tables(ppu, palette, pattern_palette),
+            DebugView::Palettes => ppu_debug::palette_grid(ppu, palette),
+            DebugView::Sprites => ppu_debug::oam_sprites(ppu, palette),
+        }
+    }
+}
+
+// a window that shows RGB images scaled to its size
+struct Display {
+    canvas: Canvas<Window>,
+}
+
+impl Display {
+    fn new(
+        video: &VideoSubsystem,
+        title: &str,
+        width: u32,
+        height: u32,
+    ) -> Result<Display, String> {
+        let window = video
+            .window(title, width, height)
+            .position_centered()
+            .resizable()
+            .build()
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        Ok(Display { canvas })
+    }
+
+    fn id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    fn set_title(&mut self, title: &str) {
+        // only fails on a title with a NUL in it
+        let _ = self.canvas.window_mut().set_title(title);
+    }
+
+    fn show(&mut self, image: &Screenshot) -> Result<(), String> {
+        let creator = self.canvas.texture_creator();
+        let mut texture = creator
+            .create_texture_static(
+                PixelFormatEnum::RGB24,
+                image.width as u32,
+                image.height as u32,
+            )
+            .map_err(|e| e.to_string())?;
+        texture
+            .update(None, &image.rgb, image.width * 3)
+            .map_err(|e| e.to_string())?;
+
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None)?;
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+// mono f32 SDL audio queue
+struct SdlAudio {
+    queue: AudioQueue<f32>,
+}
+
+impl SdlAudio {
+    fn open(audio: &AudioSubsystem) -> Result<SdlAudio, String> {
+        let desired = AudioSpecDesired {
+            freq: Some(DEFAULT_SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: Some(1024),
+        };
+        let queue = audio.open_queue::<f32, _>(None, &desired)?;
+        queue.resume();
+        Ok(SdlAudio { queue })
+    }
+}
+
+impl AudioBackend for SdlAudio {
+    fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    fn queue_samples(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.queue.queue_audio(samples)
+    }
+
+    fn queued_samples(&self) -> Option<usize> {
+        Some(self.queue.size() as usize / std::mem::size_of::<f32>())
+    }
+}
+
+// SDL audio, or the null backend when disabled or there is no device
+fn open_audio(sdl: &sdl2::Sdl, enabled: bool) -> Box<dyn AudioBackend> {
+    if enabled {
+        match sdl.audio().and_then(|audio| SdlAudio::open(&audio)) {
+            Ok(audio) => return Box::new(audio),
+            Err(e) => eprintln!("no audio: {}", e),
+        }
+    }
+    Box::new(NullAudio::new(DEFAULT_SAMPLE_RATE))
+}
+
+// run the machine in a window until it is closed or the CPU stops
+pub fn run(cpu: &mut CPU, mut options: FrontendOptions) -> Result<(), String> {
+    let sdl = sdl2::init()?;
+    let video = sdl.video()?;
+
+    // sized in NES pixels, the wider NTSC frames are scaled down into it
+    let (width, height) = options.output.output_size(SCREEN_WIDTH);
+    let mut screen = Display::new(&video, "NES", width as u32, height as u32)?;
+
+    let mut viewers = Vec::new();
+    let mut pattern_palette = 0;
+    if options.debug_viewers {
+        println!("in a debug window: P cycles the pattern table palette, O prints the sprites");
+        for view in DebugView::ALL {
+            let image = view.render(&cpu.ppu, &options.palette, pattern_palette);
+            let display = Display::new(
+                &video,
+                view.title(),
+                image.width as u32 * 2,
+                image.height as u32 * 2,
+            )?;
+            viewers.push((view, display));
+        }
+    }
+
+    let mut audio = open_audio(&sdl, options.audio);
+    cpu.audio.set_sample_rate(audio.sample_rate());
+    let rate_control = RateControl::new(audio.sample_rate(), AUDIO_LATENCY);
+
+    check_bindings(&options.input)?;
+    for controller in cpu.controllers.iter_mut() {
+        controller.set_turbo_rate(options.input.turbo_rate);
+    }
+    let mut input = Input::new(sdl.game_controller()?, options.input, options.input_path);
+
+    let mut events = sdl.event_pump()?;
+    let frame_time = Duration::from_secs_f64(1.0 / cpu.region.frame_rate());
+    let mut next_frame = Instant::now();
+
+    'running: loop {
+        for event in events.poll_iter() {
+            match event {
+                Event::Quit { .. } => break 'running,
+                Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } => {
+                    if window_id == screen.id() {
+                        break 'running;
+                    }
+                    viewers.retain(|(_, display)| display.id() != window_id);
+                }
+                Event::KeyDown {
+                    window_id,
+                    keycode: Some(keycode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => {
+                    if viewers.iter().any(|(_, display)| display.id() == window_id) {
+                        debug_key(cpu, &mut viewers, &mut pattern_palette, keycode);
+                    } else {
+                        key_down(cpu, &mut input, keycode, keymod);
+                    }
+                }
+                Event::ControllerButtonDown { button, .. } => input.remap_button(button),
+                Event::ControllerDeviceAdded { which, .. } => input.add_gamepad(which),
+                Event::ControllerDeviceRemoved { which, .. } => input.remove_gamepad(which),
+                _ => {}
+            }
+        }
+
+        let keyboard = events.keyboard_state();
+        for player in 0..2 {
+            cpu.set_buttons(player, input.buttons(player, &keyboard));
+            cpu.controllers[player].set_turbo(input.turbo(player, &keyboard));
+        }
+
+        if !cpu.run_frame() {
+            break;
+        }
+
+        audio_output::pump(&mut cpu.audio, audio.as_mut(), &rate_control)?;
+
+        let frame = match options.ntsc.as_mut() {
+            Some(filter) => Screenshot::capture_ntsc(&cpu.ppu, filter),
+            None => Screenshot::capture(&cpu.ppu, &options.palette),
+        };
+        screen.show(&options.output.apply(&frame))?;
+        for (view, display) in viewers.iter_mut() {
+            display.show(&view.render(&cpu.ppu, &options.palette, pattern_palette))?;
+        }
+
+        // keep to the console frame rate
+        next_frame += frame_time;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+    Ok(())
+}
+
+// keys pressed while a debug window has the focus, so they never clash
+// with joypad bindings
+fn debug_key(
+    cpu: &CPU,
+    viewers: &mut [(DebugView, Display)],
+    pattern_palette: &mut u8,
+    keycode: Keycode,
+) {
+    match keycode {
+        Keycode::P => {
+            *pattern_palette = (*pattern_palette + 1) % 8;
+            for (view, display) in viewers.iter_mut() {
+                if let DebugView::PatternTables = view {
+                    display.set_title(&format!("{} (palette {})", view.title(), pattern_palette));
+                }
+            }
+        }
+        Keycode::O => {
+            println!("OAM (y 239 and up is off screen):");
+            for entry in ppu_debug::oam_entries(&cpu.ppu) {
+                println!("{}", entry);
+            }
+        }
+        _ => {}
+    }
+}
+
+// -----------------------------
+// Joypad input
+// -----------------------------
+
+// every name in the config has to be one SDL knows
+fn check_bindings(config: &InputConfig) -> Result<(), String> {
+    for player in config.players.iter() {
+        for key in player.keyboard.iter().flatten() {
+            if Keycode::from_name(key)
+                .and_then(Scancode::from_keycode)
+                .is_none()
+            {
+                return Err(format!("unknown key {} in the input config", key));
+            }
+        }
+        for button in player.gamepad.iter().flatten() {
+            if Button::from_string(button).is_none() {
+                return Err(format!(
+                    "unknown gamepad button {} in the input config",
+                    button
+                ));
+            }
+        }
+    }
+    for binding in config.macros.iter() {
+        if Keycode::from_name(&binding.key).is_none() {
+            return Err(format!(
+                "unknown macro key {} in the input config",
+                binding.key
+            ));
+        }
+    }
+    Ok(())
+}
+
+// keys go to remapping first, then macros, then the channel toggles
+fn key_down(cpu: &mut CPU, input: &mut Input, keycode: Keycode, keymod: Mod) {
+    if input.remap_key(keycode) || input.macro_key(keycode, &mut cpu.controllers) {
+        return;
+    }
+    channel_key(&mut cpu.channels, keycode, keymod);
+}
+
+// the button being asked for while remapping
+struct Remap {
+    player: usize,
+    // index into INPUT_NAMES
+    button: usize,
+}
+
+struct Input {
+    subsystem: GameControllerSubsystem,
+    // player 1 uses the first game controller plugged in, player 2 the second
+    gamepads: Vec<GameController>,
+    config: InputConfig,
+    config_path: Option<PathBuf>,
+    remap: Option<Remap>,
+    // the last macro recorded with F11, F12 plays it on player 1
+    recorded: Option<InputMacro>,
+}
+
+impl Input {
+    fn new(
+        subsystem: GameControllerSubsystem,
+        config: InputConfig,
+        config_path: Option<PathBuf>,
+    ) -> Input {
+        // SDL sends a device added event for the pads already plugged in
+        Input {
+            subsystem,
+            gamepads: Vec::new(),
+            config,
+            config_path,
+            remap: None,
+            recorded: None,
+        }
+    }
+
+    fn add_gamepad(&mut self, joystick_index: u32) {
+        if self.gamepads.len() >= 2 {
+            return;
+        }
+        match self.subsystem.open(joystick_index) {
+            Ok(gamepad) => {
+                if self
+                    .gamepads
+                    .iter()
+                    .all(|open| open.instance_id() != gamepad.instance_id())
+                {
+                    println!("player {}: {}", self.gamepads.len() + 1, gamepad.name());
+                    self.gamepads.push(gamepad);
+                }
+            }
+            Err(e) => eprintln!("could not open game controller: {}", e),
+        }
+    }
+
+    fn remove_gamepad(&mut self, instance_id: u32) {
+        self.gamepads
+            .retain(|gamepad| gamepad.instance_id() != instance_id);
+    }
+
+    fn buttons(&self, player: usize, keyboard: &KeyboardState) -> u8 {
+        let gamepad = self.gamepads.get(player);
+        self.config.buttons(
+            player,
+            |key| key_pressed(keyboard, key),
+            |button| gamepad_pressed(gamepad, button),
+        )
+    }
+
+    fn turbo(&self, player: usize, keyboard: &KeyboardState) -> u8 {
+        let gamepad = self.gamepads.get(player);
+        self.config.turbo(
+            player,
+            |key| key_pressed(keyboard, key),
+            |button| gamepad_pressed(gamepad, button),
+        )
+    }
+
+    // F11 starts and stops recording player 1, F12 plays the recording
+    // back; the config's macro keys play theirs. true when the key was
+    // used for a macro
+    fn macro_key(&mut self, keycode: Keycode, controllers: &mut [Controller; 2]) -> bool {
+        match keycode {
+            Keycode::F11 => {
+                if !controllers[0].is_recording_macro() {
+                    controllers[0].start_macro_recording();
+                    println!("recording a macro, F11 stops");
+                } else if let Some(input) = controllers[0].stop_macro_recording() {
+                    println!("recorded macro: {}", input);
+                    self.recorded = Some(input);
+                } else {
+                    println!("nothing recorded, the macro was stopped before a frame ran");
+                }
+                true
+            }
+            Keycode::F12 => {
+                if let Some(input) = self.recorded.as_ref() {
+                    controllers[0].play_macro(input);
+                }
+                true
+            }
+            _ => {
+                let name = keycode.name();
+                let binding = self
+                    .config
+                    .macros
+                    .iter()
+                    .find(|binding| binding.key == name);
+                match binding {
+                    Some(binding) => {
+                        controllers[binding.player].play_macro(&binding.input);
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+
+    // F9 / F10 walk through player 1's / player 2's buttons, each taking
+    // the next key or gamepad button pressed; Escape stops early.
+    // true when the key was used for remapping
+    fn remap_key(&mut self, keycode: Keycode) -> bool {
+        if self.remap.is_none() {
+            let player = match keycode {
+                Keycode::F9 => 0,
+                Keycode::F10 => 1,
+                _ => return false,
+            };
+            self.remap = Some(Remap { player, button: 0 });
+            self.prompt();
+            return true;
+        }
+        if keycode == Keycode::Escape {
+            self.remap = None;
+            println!("remapping stopped");
+            self.save();
+        } else {
+            self.bind(InputSource::Keyboard, &keycode.name());
+        }
+        true
+    }
+
+    fn remap_button(&mut self, button: Button) {
+        if self.remap.is_some() {
+            self.bind(InputSource::Gamepad, &button.string());
+        }
+    }
+
+    fn bind(&mut self, source: InputSource, name: &str) {
+        let Some(remap) = self.remap.as_mut() else {
+            return;
+        };
+        self.config.bind(remap.player, remap.button, source, name);
+        remap.button += 1;
+        if remap.button < INPUT_NAMES.len() {
+            self.prompt();
+        } else {
+            self.remap = None;
+            println!("remapping done");
+            self.save();
+        }
+    }
+
+    fn prompt(&self) {
+        if let Some(remap) = self.remap.as_ref() {
+            println!(
+                "player {} {}: press a key or gamepad button (Escape stops)",
+                remap.player + 1,
+                INPUT_NAMES[remap.button]
+            );
+        }
+    }
+
+    fn save(&self) {
+        if let Some(path) = self.config_path.as_ref() {
+            match self.config.save(path) {
+                Ok(()) => println!("saved bindings to {}", path.display()),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    }
+}
+
+fn key_pressed(keyboard: &KeyboardState, key: &str) -> bool {
+    Keycode::from_name(key)
+        .and_then(Scancode::from_keycode)
+        .is_some_and(|scancode| keyboard.is_scancode_pressed(scancode))
+}
+
+fn gamepad_pressed(gamepad: Option<&GameController>, button: &str) -> bool {
+    gamepad
+        .zip(Button::from_string(button))
+        .is_some_and(|(gamepad, button)| gamepad.button(button))
+}
+
+// F1-F6 mute pulse 1, pulse 2, triangle, noise, DMC and the FDS
+// wavetable, F8 the other expansion chips; with shift they solo it
+// instead, F7 puts every channel back on
+fn channel_key(channels: &mut ChannelMixer, keycode: Keycode, keymod: Mod) {
+    let channel = match keycode {
+        Keycode::F1 => Channel::Pulse1,
+        Keycode::F2 => Channel::Pulse2,
+        Keycode::F3 => Channel::Triangle,
+        Keycode::F4 => Channel::Noise,
+        Keycode::F5 => Channel::DMC,
+        Keycode::F6 => Channel::FDS,
+        Keycode::F8 => Channel::Expansion,
+        Keycode::F7 => {
+            *channels = ChannelMixer::default();
+            println!("all channels on");
+            return;
+        }
+        _ => return,
+    };
+
+    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+        channels.toggle_solo(channel);
+        match channels.solo() {
+            Some(solo) => println!("solo {}", solo.name()),
+            None => println!("solo off"),
+        }
+    } else {
+        channels.toggle_mute(channel);
+        let state = if channels.is_muted(channel) {
+            "muted"
+        } else {
+            "on"
+        };
+        println!("{} {}", channel.name(), state);
+    }
+}
+
+// what to do once a song ends or a key is pressed in the NSF player
+enum NsfAction {
+    Play(u8),
+    Quit,
+}
+
+// play an NSF through the speakers, from song `first` to the last one,
+// each for its length then faded out. The window takes the keys: Right
+// and Left skip to the next and previous song, Escape quits, and the
+// channel keys mute and solo voices as in a game
+pub fn play_nsf(player: &mut NsfPlayer, first: u8) -> Result<(), String> {
+    let sdl = sdl2::init()?;
+    let video = sdl.video()?;
+    let title = format!("{} - {}", player.nsf().title, player.nsf().artist);
+    let mut window = Display::new(&video, &title, 512, 64)?;
+    let blank = Screenshot {
+        width: 1,
+        height: 1,
+        rgb: vec![0; 3],
+    };
+    let mut events = sdl.event_pump()?;
+
+    let mut audio = open_audio(&sdl, true);
+    player.cpu.audio.set_sample_rate(audio.sample_rate());
+    let rate_control = RateControl::new(audio.sample_rate(), AUDIO_LATENCY);
+    let frame_time = Duration::from_secs_f64(1.0 / player.play_rate());
+    let song_count = player.nsf().song_count();
+    println!("Right / Left: next / previous song, Escape quits");
+    println!("F1-F6, F8 mute a channel, with Shift solo it, F7 all back on");
+
+    let mut song = first;
+    loop {
+        player.start_song(song)?;
+        println!("{}", player.track());
+
+        let (length, fade) = player.track().play_time_ms();
+        let frames = player.frames_for_ms(length + fade);
+        let fade_start = player.frames_for_ms(length);
+        let mut next_frame = Instant::now();
+        let mut frame = 0;
+        let action = 'song: loop {
+            for event in events.poll_iter() {
+                match event {
+                    Event::Quit { .. } => break 'song NsfAction::Quit,
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        keymod,
+                        repeat: false,
+                        ..
+                    } => match keycode {
+                        Keycode::Escape => break 'song NsfAction::Quit,
+                        Keycode::Right => break 'song NsfAction::Play((song + 1).min(song_count)),
+                        Keycode::Left => {
+                            break 'song NsfAction::Play(song.saturating_sub(1).max(1))
+                        }
+                        _ => channel_key(&mut player.cpu.channels, keycode, keymod),
+                    },
+                    _ => {}
+                }
+            }
+            if frame >= frames {
+                break 'song if song < song_count {
+                    NsfAction::Play(song + 1)
+                } else {
+                    NsfAction::Quit
+                };
+            }
+
+            player.cpu.volume = fade_volume(frame, fade_start, frames);
+            player.play_frame()?;
+            audio_output::pump(&mut player.cpu.audio, audio.as_mut(), &rate_control)?;
+            window.show(&blank)?;
+            frame += 1;
+
+            next_frame += frame_time;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                next_frame = now;
+            }
+        };
+
+        match action {
+            NsfAction::Play(next) => song = next,
+            NsfAction::Quit => break,
+        }
+    }
+    player.cpu.volume = 1.0;
+    Ok(())
+}
@@ -0,0 +1,210 @@
+use crate::cartridge::Mirroring;
+use crate::checksum::{crc32, sha1};
+use crate::region::Region;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// -----------------------------
+// Game database
+// correct mapper / mirroring / region / battery for known dumps
+// -----------------------------
+
+const EMBEDDED_DATABASE: &str = include_str!("gamedb.txt");
+
+#[derive(Debug, PartialEq)]
+pub struct GameInfo {
+    pub name: String,
+    pub mapper: u16,
+    pub submapper: u8,
+    // None when the mapper switches mirroring itself
+    pub mirroring: Option<Mirroring>,
+    pub region: Region,
+    pub battery: bool,
+}
+
+enum GameKey {
+    Crc32(u32),
+    Sha1([u8; 20]),
+}
+
+pub struct GameDatabase {
+    entries: Vec<(GameKey, GameInfo)>,
+}
+
+impl GameDatabase {
+    // the database compiled into the emulator
+    pub fn embedded() -> &'static GameDatabase {
+        static DATABASE: OnceLock<GameDatabase> = OnceLock::new();
+        DATABASE.get_or_init(|| {
+            GameDatabase::parse(EMBEDDED_DATABASE).expect("embedded game database is invalid")
+        })
+    }
+
+    // a database file in the same format, e.g. converted from a NesCartDB
+    // export; its entries are searched before the embedded ones
+    pub fn load_with_embedded(path: &Path) -> Result<GameDatabase, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let mut database =
+            GameDatabase::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        database
+            .entries
+            .extend(GameDatabase::parse(EMBEDDED_DATABASE)?.entries);
+        Ok(database)
+    }
+
+    pub fn parse(text: &str) -> Result<GameDatabase, String> {
+        let mut entries = vec![];
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let entry = parse_line(line)
+                .map_err(|e| format!("game database line {}: {}", number + 1, e))?;
+            entries.push(entry);
+        }
+
+        Ok(GameDatabase { entries })
+    }
+
+    // PRG and CHR are hashed together, as listed in the database
+    pub fn lookup(&self, prg_rom: &[u8], chr_rom: &[u8]) -> Option<&GameInfo> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let mut data = prg_rom.to_vec();
+        data.extend_from_slice(chr_rom);
+        let crc = crc32(&data);
+        let digest = sha1(&data);
+
+        self.entries
+            .iter()
+            .find(|(key, _)| match key {
+                GameKey::Crc32(value) => *value == crc,
+                GameKey::Sha1(value) => *value == digest,
+            })
+            .map(|(_, info)| info)
+    }
+}
+
+fn parse_line(line: &str) -> Result<(GameKey, GameInfo), String> {
+    let mut fields = line.split_whitespace();
+    let mut next = |name: &str| fields.next().ok_or(format!("missing {}", name));
+
+    let key = next("key")?;
+    let key = match key.len() {
+        8 => GameKey::Crc32(u32::from_str_radix(key, 16).map_err(|_| "bad CRC32")?),
+        40 => {
+            let mut digest = [0u8; 20];
+            for (i, byte) in digest.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&key[i * 2..i * 2 + 2], 16).map_err(|_| "bad SHA-1")?;
+            }
+            GameKey::Sha1(digest)
+        }
+        _ => return Err(format!("key {} is neither a CRC32 nor a SHA-1", key)),
+    };
+
+    let mapper = next("mapper")?.parse().map_err(|_| "bad mapper")?;
+    let submapper = next("submapper")?.parse().map_err(|_| "bad submapper")?;
+    let mirroring = match next("mirroring")? {
+        "H" => Some(Mirroring::Horizontal),
+        "V" => Some(Mirroring::Vertical),
+        "4" => Some(Mirroring::FourScreen),
+        "0" => Some(Mirroring::SingleScreenLower),
+        "1" => Some(Mirroring::SingleScreenUpper),
+        "-" => None,
+        other => return Err(format!("unknown mirroring {}", other)),
+    };
+    let region = match next("region")? {
+        "NTSC" => Region::NTSC,
+        "PAL" => Region::PAL,
+        "Dendy" => Region::Dendy,
+        other => return Err(format!("unknown region {}", other)),
+    };
+    let battery = match next("battery")? {
+        "0" => false,
+        "1" => true,
+        other => return Err(format!("battery must be 0 or 1, got {}", other)),
+    };
+    let name = fields.collect::<Vec<_>>().join(" ");
+
+    Ok((
+        key,
+        GameInfo {
+            name,
+            mapper,
+            submapper,
+            mirroring,
+            region,
+            battery,
+        },
+    ))
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_embedded_database_parses() {
+        GameDatabase::embedded();
+    }
+
+    #[test]
+    fn test_lookup_by_crc32_and_sha1() {
+        let prg = vec![1, 2, 3];
+        let chr = vec![4, 5];
+        let data = [1, 2, 3, 4, 5];
+
+        let sha = sha1(&data)
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        let text = format!(
+            "# comment\n{:08X} 1 0 - PAL 1 Some Game (E)\n{} 4 1 V NTSC 0 Other Game\n",
+            crc32(&data),
+            sha
+        );
+        let database = GameDatabase::parse(&text).unwrap();
+
+        let info = database.lookup(&prg, &chr).unwrap();
+        assert_eq!(info.name, "Some Game (E)");
+        assert_eq!(info.mapper, 1);
+        assert_eq!(info.mirroring, None);
+        assert_eq!(info.region, Region::PAL);
+        assert!(info.battery);
+
+        assert!(database.lookup(&prg, &[]).is_none());
+
+        let sha_only = GameDatabase::parse(text.lines().nth(2).unwrap()).unwrap();
+        assert_eq!(sha_only.lookup(&prg, &chr).unwrap().submapper, 1);
+    }
+
+    #[test]
+    fn test_load_file_before_embedded() {
+        let path = std::env::temp_dir().join("nest_emulator_gamedb_test.txt");
+        fs::write(&path, format!("{:08X} 3 0 V NTSC 0 File Game", crc32(&[7]))).unwrap();
+        let database = GameDatabase::load_with_embedded(&path).unwrap();
+        assert_eq!(database.lookup(&[7], &[]).unwrap().name, "File Game");
+        fs::remove_file(&path).unwrap();
+
+        assert!(GameDatabase::load_with_embedded(&path).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(GameDatabase::parse("1234 0 0 H NTSC 0 short key").is_err());
+        assert!(GameDatabase::parse("12345678 0 0 X NTSC 0 bad mirroring").is_err());
+        assert!(GameDatabase::parse("12345678 0 0 H SECAM 0 bad region").is_err());
+        assert!(GameDatabase::parse("12345678 0").is_err());
+    }
+}
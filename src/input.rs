@@ -0,0 +1,450 @@
+use crate::controller::{
+    InputMacro, BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_NAMES, BUTTON_RIGHT,
+    BUTTON_UP, DEFAULT_TURBO_RATE,
+};
+use std::fs;
+use std::path::Path;
+use toml::{Table, Value};
+
+// -----------------------------
+// Input config
+// keyboard and gamepad bindings for both players, kept in a TOML file:
+//
+//   allow_opposing_directions = false
+//   turbo_rate = 2
+//
+//   [player1.keyboard]
+//   a = "X"
+//   b = ["Z", "Left Ctrl"]
+//   turbo_a = "S"
+//
+//   [player1.gamepad]
+//   a = "b"
+//   start = "start"
+//
+//   [[macro]]
+//   key = "F5"
+//   player = 1
+//   input = "right*10 right+a*2 none*5"
+//
+// key names are SDL's (Return, Left, Right Shift, ...), gamepad buttons
+// SDL's game controller names (a, b, x, y, back, start, dpup, ...)
+// -----------------------------
+
+// what can be bound: the joypad buttons in BUTTON_NAMES order, then turbo
+pub const INPUT_NAMES: [&str; 10] = [
+    "a", "b", "select", "start", "up", "down", "left", "right", "turbo_a", "turbo_b",
+];
+
+// turbo_a and turbo_b in INPUT_NAMES
+const TURBO_INPUTS: [(usize, u8); 2] = [(8, BUTTON_A), (9, BUTTON_B)];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum InputSource {
+    Keyboard,
+    Gamepad,
+}
+
+impl InputSource {
+    fn section(&self) -> &'static str {
+        match self {
+            InputSource::Keyboard => "keyboard",
+            InputSource::Gamepad => "gamepad",
+        }
+    }
+}
+
+// inputs bound to each button, indexed like INPUT_NAMES
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct PlayerBindings {
+    pub keyboard: [Vec<String>; 10],
+    pub gamepad: [Vec<String>; 10],
+}
+
+impl PlayerBindings {
+    fn bindings(&self, source: InputSource) -> &[Vec<String>; 10] {
+        match source {
+            InputSource::Keyboard => &self.keyboard,
+            InputSource::Gamepad => &self.gamepad,
+        }
+    }
+
+    fn bindings_mut(&mut self, source: InputSource) -> &mut [Vec<String>; 10] {
+        match source {
+            InputSource::Keyboard => &mut self.keyboard,
+            InputSource::Gamepad => &mut self.gamepad,
+        }
+    }
+
+    // player 1: arrows, Z (B), X (A), right shift, return, turbo on A
+    // and S; player 2 has no keys
+    fn default_keyboard(&mut self) {
+        let keys = [
+            "X",
+            "Z",
+            "Right Shift",
+            "Return",
+            "Up",
+            "Down",
+            "Left",
+            "Right",
+            "S",
+            "A",
+        ];
+        self.keyboard = keys.map(|key| vec![key.to_string()]);
+    }
+
+    // the face buttons where the NES pad has them: B left of A, with
+    // turbo on the two above them
+    fn default_gamepad(&mut self) {
+        let buttons = [
+            "b", "a", "back", "start", "dpup", "dpdown", "dpleft", "dpright", "y", "x",
+        ];
+        self.gamepad = buttons.map(|button| vec![button.to_string()]);
+    }
+}
+
+// a recorded input played on a player's port when the key is pressed
+#[derive(Debug, PartialEq, Clone)]
+pub struct MacroBinding {
+    pub key: String,
+    // 0 for player 1, 1 for player 2
+    pub player: usize,
+    pub input: InputMacro,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct InputConfig {
+    pub players: [PlayerBindings; 2],
+    // left+right or up+down together, impossible on a real pad, glitches
+    // some games (Zelda II walks through walls); off by default
+    pub allow_opposing_directions: bool,
+    // frames a turbo button stays pressed, then released
+    pub turbo_rate: u8,
+    pub macros: Vec<MacroBinding>,
+}
+
+impl Default for InputConfig {
+    fn default() -> Self {
+        let mut player1 = PlayerBindings::default();
+        player1.default_keyboard();
+        player1.default_gamepad();
+        let mut player2 = PlayerBindings::default();
+        player2.default_gamepad();
+        InputConfig {
+            players: [player1, player2],
+            allow_opposing_directions: false,
+            turbo_rate: DEFAULT_TURBO_RATE,
+            macros: Vec::new(),
+        }
+    }
+}
+
+impl InputConfig {
+    pub fn load(path: &Path) -> Result<InputConfig, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        InputConfig::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        fs::write(path, self.to_toml())
+            .map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+
+    // a section left out keeps its defaults, a button left out of a
+    // section is unbound
+    pub fn parse(text: &str) -> Result<InputConfig, String> {
+        let table = text.parse::<Table>().map_err(|e| e.to_string())?;
+        let mut config = InputConfig::default();
+
+        for (key, value) in table.iter() {
+            match (key.as_str(), value) {
+                ("allow_opposing_directions", Value::Boolean(allow)) => {
+                    config.allow_opposing_directions = *allow
+                }
+                ("allow_opposing_directions", _) => {
+                    return Err("allow_opposing_directions must be true or false".to_string())
+                }
+                ("turbo_rate", Value::Integer(rate @ 1..=255)) => config.turbo_rate = *rate as u8,
+                ("turbo_rate", _) => {
+                    return Err("turbo_rate needs a frame count from 1 to 255".to_string())
+                }
+                ("macro", Value::Array(macros)) => {
+                    config.macros = macros.iter().map(parse_macro).collect::<Result<_, _>>()?
+                }
+                ("player1", Value::Table(player)) => parse_player(player, &mut config.players[0])?,
+                ("player2", Value::Table(player)) => parse_player(player, &mut config.players[1])?,
+                _ => return Err(format!("unknown setting {}", key)),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut table = Table::new();
+        table.insert(
+            "allow_opposing_directions".to_string(),
+            Value::Boolean(self.allow_opposing_directions),
+        );
+        table.insert(
+            "turbo_rate".to_string(),
+            Value::Integer(self.turbo_rate as i64),
+        );
+        for (number, player) in self.players.iter().enumerate() {
+            let mut sections = Table::new();
+            for source in [InputSource::Keyboard, InputSource::Gamepad] {
+                let mut section = Table::new();
+                for (name, inputs) in INPUT_NAMES.iter().zip(player.bindings(source)) {
+                    let value = match inputs.as_slice() {
+                        [] => continue,
+                        [input] => Value::String(input.clone()),
+                        _ => Value::Array(inputs.iter().cloned().map(Value::String).collect()),
+                    };
+                    section.insert(name.to_string(), value);
+                }
+                sections.insert(source.section().to_string(), Value::Table(section));
+            }
+            table.insert(format!("player{}", number + 1), Value::Table(sections));
+        }
+        if !self.macros.is_empty() {
+            let macros = self.macros.iter().map(|binding| {
+                let mut entry = Table::new();
+                entry.insert("key".to_string(), Value::String(binding.key.clone()));
+                entry.insert(
+                    "player".to_string(),
+                    Value::Integer(binding.player as i64 + 1),
+                );
+                entry.insert(
+                    "input".to_string(),
+                    Value::String(binding.input.to_string()),
+                );
+                Value::Table(entry)
+            });
+            table.insert("macro".to_string(), Value::Array(macros.collect()));
+        }
+        table.to_string()
+    }
+
+    // make `input` the only binding of a button from that source
+    pub fn bind(&mut self, player: usize, button: usize, source: InputSource, input: &str) {
+        self.players[player].bindings_mut(source)[button] = vec![input.to_string()];
+    }
+
+    // the player's joypad state, given which keys and gamepad buttons
+    // are held down
+    pub fn buttons(
+        &self,
+        player: usize,
+        key_held: impl Fn(&str) -> bool,
+        gamepad_held: impl Fn(&str) -> bool,
+    ) -> u8 {
+        let mut buttons = 0;
+        for (index, (_, bit)) in BUTTON_NAMES.iter().enumerate() {
+            if self.held(player, index, &key_held, &gamepad_held) {
+                buttons |= bit;
+            }
+        }
+        if self.allow_opposing_directions {
+            buttons
+        } else {
+            cancel_opposing_directions(buttons)
+        }
+    }
+
+    // the turbo buttons held, BUTTON_A and / or BUTTON_B
+    pub fn turbo(
+        &self,
+        player: usize,
+        key_held: impl Fn(&str) -> bool,
+        gamepad_held: impl Fn(&str) -> bool,
+    ) -> u8 {
+        let mut buttons = 0;
+        for (index, bit) in TURBO_INPUTS {
+            if self.held(player, index, &key_held, &gamepad_held) {
+                buttons |= bit;
+            }
+        }
+        buttons
+    }
+
+    fn held(
+        &self,
+        player: usize,
+        input: usize,
+        key_held: &impl Fn(&str) -> bool,
+        gamepad_held: &impl Fn(&str) -> bool,
+    ) -> bool {
+        let bindings = &self.players[player];
+        bindings.keyboard[input].iter().any(|key| key_held(key))
+            || bindings.gamepad[input]
+                .iter()
+                .any(|button| gamepad_held(button))
+    }
+}
+
+fn parse_player(table: &Table, player: &mut PlayerBindings) -> Result<(), String> {
+    for (key, value) in table.iter() {
+        let source = match key.as_str() {
+            "keyboard" => InputSource::Keyboard,
+            "gamepad" => InputSource::Gamepad,
+            _ => {
+                return Err(format!(
+                    "unknown input {}, expected keyboard or gamepad",
+                    key
+                ))
+            }
+        };
+        let section = match value {
+            Value::Table(section) => section,
+            _ => return Err(format!("{} must be a table of buttons", key)),
+        };
+
+        let mut bindings: [Vec<String>; 10] = Default::default();
+        for (name, inputs) in section.iter() {
+            let index = INPUT_NAMES
+                .iter()
+                .position(|input| input == name)
+                .ok_or_else(|| format!("unknown button {} in {}", name, key))?;
+            bindings[index] = parse_inputs(inputs)
+                .ok_or_else(|| format!("{} {} needs a name or a list of names", key, name))?;
+        }
+        *player.bindings_mut(source) = bindings;
+    }
+    Ok(())
+}
+
+// { key = "F5", player = 1, input = "right*10 a" }
+fn parse_macro(value: &Value) -> Result<MacroBinding, String> {
+    let entry = value
+        .as_table()
+        .ok_or("each macro needs a key, player and input")?;
+    let key = entry
+        .get("key")
+        .and_then(|key| key.as_str())
+        .ok_or("a macro needs a key")?;
+    let player = match entry.get("player").and_then(|player| player.as_integer()) {
+        Some(player @ 1..=2) => player as usize - 1,
+        _ => return Err(format!("macro {} needs player = 1 or 2", key)),
+    };
+    let input = entry
+        .get("input")
+        .and_then(|input| input.as_str())
+        .ok_or_else(|| format!("macro {} needs an input", key))?;
+    let input = InputMacro::parse(input).map_err(|e| format!("macro {}: {}", key, e))?;
+    Ok(MacroBinding {
+        key: key.to_string(),
+        player,
+        input,
+    })
+}
+
+// "X" or ["X", "Space"]
+fn parse_inputs(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(input) => Some(vec![input.clone()]),
+        Value::Array(inputs) => inputs
+            .iter()
+            .map(|input| input.as_str().map(|input| input.to_string()))
+            .collect(),
+        _ => None,
+    }
+}
+
+// opposing directions held together cancel out, as if neither was
+pub fn cancel_opposing_directions(buttons: u8) -> u8 {
+    let mut buttons = buttons;
+    for pair in [BUTTON_UP | BUTTON_DOWN, BUTTON_LEFT | BUTTON_RIGHT] {
+        if buttons & pair == pair {
+            buttons &= !pair;
+        }
+    }
+    buttons
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::controller::BUTTON_START;
+
+    #[test]
+    fn test_parse_config() {
+        let config = InputConfig::parse(
+            r#"
+            allow_opposing_directions = true
+
+            [player2.keyboard]
+            a = "K"
+            b = ["J", "Space"]
+            "#,
+        )
+        .unwrap();
+        assert!(config.allow_opposing_directions);
+        // player 1 keeps the defaults
+        assert_eq!(config.players[0], InputConfig::default().players[0]);
+        assert_eq!(config.players[1].keyboard[0], vec!["K"]);
+        assert_eq!(config.players[1].keyboard[1], vec!["J", "Space"]);
+        assert!(config.players[1].keyboard[3].is_empty());
+        assert_eq!(config.players[1].gamepad[3], vec!["start"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(InputConfig::parse("[player3.keyboard]").is_err());
+        assert!(InputConfig::parse("[player1.mouse]").is_err());
+        assert!(InputConfig::parse("[player1.keyboard]\nturbo = \"T\"").is_err());
+        assert!(InputConfig::parse("[player1.keyboard]\na = 5").is_err());
+        assert!(InputConfig::parse("allow_opposing_directions = \"yes\"").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut config = InputConfig::default();
+        config.bind(1, 3, InputSource::Keyboard, "Keypad Enter");
+        config.players[0].keyboard[0].push("Space".to_string());
+        assert_eq!(InputConfig::parse(&config.to_toml()).unwrap(), config);
+    }
+
+    #[test]
+    fn test_buttons_and_opposing_directions() {
+        let mut config = InputConfig::default();
+        let keys = ["X", "Left", "Right", "Up"];
+        let held = |key: &str| keys.contains(&key);
+
+        let buttons = config.buttons(0, held, |button| button == "start");
+        assert_eq!(buttons, BUTTON_A | BUTTON_START | BUTTON_UP);
+
+        config.allow_opposing_directions = true;
+        let buttons = config.buttons(0, held, |_| false);
+        assert_eq!(buttons, BUTTON_A | BUTTON_UP | BUTTON_LEFT | BUTTON_RIGHT);
+    }
+
+    #[test]
+    fn test_turbo_and_macros() {
+        let config = InputConfig::parse(
+            r#"
+            turbo_rate = 3
+
+            [[macro]]
+            key = "F5"
+            player = 2
+            input = "right*2 a"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.turbo_rate, 3);
+        assert_eq!(config.macros[0].key, "F5");
+        assert_eq!(config.macros[0].player, 1);
+        assert_eq!(config.macros[0].input.frames.len(), 3);
+        assert_eq!(InputConfig::parse(&config.to_toml()).unwrap(), config);
+
+        let turbo = config.turbo(0, |key| key == "S", |button| button == "x");
+        assert_eq!(turbo, BUTTON_A | BUTTON_B);
+        assert!(InputConfig::parse("turbo_rate = 0").is_err());
+        assert!(InputConfig::parse("[[macro]]\nkey = \"F5\"\nplayer = 3\ninput = \"a\"").is_err());
+    }
+}
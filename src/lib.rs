@@ -0,0 +1,26 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod apu;
+pub mod archive;
+pub mod audio;
+pub mod audio_output;
+pub mod cartridge;
+pub mod checksum;
+pub mod controller;
+pub mod cpu;
+pub mod expansion;
+pub mod fds;
+#[cfg(feature = "sdl")]
+pub mod frontend;
+pub mod gamedb;
+pub mod input;
+pub mod nsf;
+pub mod ntsc;
+pub mod output;
+pub mod palette;
+pub mod patch;
+pub mod ppu;
+pub mod ppu_debug;
+pub mod recording;
+pub mod region;
+pub mod screenshot;
@@ -1,9 +1,394 @@
-mod cpu;
-use cpu::CPU;
+use nest_emulator::archive::{self, RomFile};
+use nest_emulator::audio::{Channel, ChannelMixer};
+use nest_emulator::cartridge::Cartridge;
+use nest_emulator::controller::{InputMacro, BUTTON_A, BUTTON_B};
+use nest_emulator::cpu::CPU;
+use nest_emulator::fds::FDS;
+#[cfg(feature = "sdl")]
+use nest_emulator::frontend::{self, FrontendOptions};
+use nest_emulator::gamedb::GameDatabase;
+#[cfg(feature = "sdl")]
+use nest_emulator::input::InputConfig;
+use nest_emulator::nsf::{NsfPlayer, NSF};
+use nest_emulator::ntsc::{NtscFilter, NtscFilterSettings, NtscPreset};
+use nest_emulator::output::OutputSettings;
+use nest_emulator::palette::{NtscPaletteSettings, Palette};
+use nest_emulator::patch;
+use nest_emulator::recording::WavFormat;
+use nest_emulator::region::Region;
+use nest_emulator::screenshot::Screenshot;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
     let mut instance_cpu = CPU::new();
 
-    instance_cpu.interpret(vec![0xa9]);
-    println!("Hello, world!");
+    let rom_path = match args.get(1) {
+        Some(path) => path,
+        None => {
+            instance_cpu.interpret(vec![0xa9]);
+            println!("Hello, world!");
+            return;
+        }
+    };
+
+    let rom = read_rom(&args, rom_path);
+    let region = load_region(&args);
+
+    match rom.extension().as_deref() {
+        Some("fds") | Some("qd") => {
+            let bios_path = match flag_value(&args, "--fds-bios") {
+                Some(path) => path,
+                None => fail("disk images need the BIOS: --fds-bios <disksys.rom>"),
+            };
+            let bios = read_file(bios_path);
+            // writes to the disk are kept next to the image as an IPS diff,
+            // not .ips so it is never mistaken for a romhack patch
+            let save_path = rom.companion_path(Path::new(rom_path), "sav");
+            let mut fds = FDS::new(bios, rom.data).unwrap_or_else(|e| fail(&e));
+
+            if let Ok(diff) = fs::read(&save_path) {
+                fds.load_diff(&diff).unwrap_or_else(|e| fail(&e));
+            }
+
+            instance_cpu.insert_fds(fds);
+            if let Some(region) = region {
+                instance_cpu.set_region(region);
+            }
+            instance_cpu.channels = load_channels(&args);
+            run_machine(&args, &mut instance_cpu);
+
+            if let Some(fds) = instance_cpu.fds.as_ref() {
+                if let Err(e) = fs::write(&save_path, fds.save_diff()) {
+                    eprintln!("could not save {}: {}", save_path.display(), e);
+                }
+            }
+        }
+        Some("nes") | Some("unf") | Some("unif") => {
+            let cartridge = match flag_value(&args, "--gamedb") {
+                Some(path) => GameDatabase::load_with_embedded(Path::new(path))
+                    .and_then(|database| Cartridge::load_with_database(&rom.data, &database)),
+                None => Cartridge::load(&rom.data),
+            }
+            .unwrap_or_else(|e| fail(&e));
+            cartridge.check_supported().unwrap_or_else(|e| fail(&e));
+            instance_cpu.insert_cartridge(cartridge);
+            instance_cpu.channels = load_channels(&args);
+            if let Some(region) = region {
+                instance_cpu.set_region(region);
+            }
+            run_machine(&args, &mut instance_cpu);
+        }
+        Some("nsf") | Some("nsfe") => {
+            if region.is_some() {
+                fail("--region has no effect on NSF files, the rip sets its own play rate");
+            }
+            play_nsf(&args, &rom.data)
+        }
+        _ => {
+            if let Some(region) = region {
+                instance_cpu.set_region(region);
+            }
+            instance_cpu.interpret(rom.data)
+        }
+    }
+}
+
+// NSF / NSFe music: --list-tracks, --track N (default the starting song),
+// --record-audio renders that track to WAV, otherwise play them all
+fn play_nsf(args: &[String], data: &[u8]) {
+    let nsf = NSF::load(data).unwrap_or_else(|e| fail(&e));
+    println!("{} - {} ({})", nsf.title, nsf.artist, nsf.copyright);
+    for chip in nsf.unsupported_chips() {
+        eprintln!(
+            "{} audio is not emulated, its channels will be silent",
+            chip
+        );
+    }
+
+    if args.iter().any(|arg| arg == "--list-tracks") {
+        for track in nsf.tracks.iter() {
+            println!("{}", track);
+        }
+        return;
+    }
+
+    let mut player = NsfPlayer::new(nsf);
+    player.cpu.channels = load_channels(args);
+    let track = match flag_value(args, "--track") {
+        Some(track) => track
+            .parse::<u8>()
+            .unwrap_or_else(|_| fail("--track needs a song number")),
+        None => player.nsf().starting_song,
+    };
+
+    match flag_value(args, "--record-audio") {
+        Some(path) => {
+            let stems = args.iter().any(|arg| arg == "--record-stems");
+            player
+                .render_to_wav(track, Path::new(path), wav_format(args), stems)
+                .unwrap_or_else(|e| fail(&e));
+            println!("saved song {} to {}", track, path);
+        }
+        None => play_nsf_live(&mut player, track),
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn play_nsf_live(player: &mut NsfPlayer, first: u8) {
+    frontend::play_nsf(player, first).unwrap_or_else(|e| fail(&e));
+}
+
+#[cfg(not(feature = "sdl"))]
+fn play_nsf_live(_player: &mut NsfPlayer, _first: u8) {
+    fail("built without SDL there is no sound output, use --record-audio <file.wav>");
+}
+
+// run until the CPU stops, or headless: up to --screenshot-at-frame N and
+// save that frame to --out, or for --frames N; --record-audio captures
+// the sound of either, --turbo and --macro script player 1's input
+fn run_machine(args: &[String], cpu: &mut CPU) {
+    if let Some(path) = flag_value(args, "--record-audio") {
+        let stems = args.iter().any(|arg| arg == "--record-stems");
+        cpu.start_recording(Path::new(path), wav_format(args), stems)
+            .unwrap_or_else(|e| fail(&e));
+    }
+
+    let script = load_input_script(args, cpu);
+    if flag_value(args, "--screenshot-at-frame").is_some() {
+        save_screenshot_at_frame(args, cpu, &script);
+    } else if let Some(frames) = flag_value(args, "--frames") {
+        let frames = frames
+            .parse::<u64>()
+            .unwrap_or_else(|_| fail("--frames needs a frame count"));
+        run_frames(cpu, frames, &script);
+    } else {
+        run_window(args, cpu);
+    }
+
+    cpu.stop_recording().unwrap_or_else(|e| fail(&e));
+}
+
+// --turbo a,b holds turbo on player 1 for the whole run, at
+// --turbo-rate N frames; --macro "<steps>" plays on player 1 from
+// --macro-at-frame N (default 0). Returns the macro and its start frame
+fn load_input_script(args: &[String], cpu: &mut CPU) -> Option<(u64, InputMacro)> {
+    if let Some(rate) = flag_value(args, "--turbo-rate") {
+        let rate = match rate.parse::<u8>() {
+            Ok(rate) if rate > 0 => rate,
+            _ => fail("--turbo-rate needs a frame count from 1 to 255"),
+        };
+        cpu.controllers[0].set_turbo_rate(rate);
+    }
+    if let Some(buttons) = flag_value(args, "--turbo") {
+        let mut turbo = 0;
+        for name in buttons.split(',') {
+            turbo |= match name.trim().to_ascii_lowercase().as_str() {
+                "a" => BUTTON_A,
+                "b" => BUTTON_B,
+                _ => fail("--turbo takes a, b or a,b"),
+            };
+        }
+        cpu.controllers[0].set_turbo(turbo);
+    }
+
+    let input = InputMacro::parse(flag_value(args, "--macro")?).unwrap_or_else(|e| fail(&e));
+    let start = match flag_value(args, "--macro-at-frame") {
+        Some(frame) => frame
+            .parse::<u64>()
+            .unwrap_or_else(|_| fail("--macro-at-frame needs a frame number")),
+        None => 0,
+    };
+    Some((start, input))
+}
+
+fn run_frames(cpu: &mut CPU, frames: u64, script: &Option<(u64, InputMacro)>) {
+    for frame in 0..frames {
+        if let Some((start, input)) = script.as_ref() {
+            if frame == *start {
+                cpu.controllers[0].play_macro(input);
+            }
+        }
+        if !cpu.run_frame() {
+            fail(&format!("the CPU stopped before frame {}", frames));
+        }
+    }
+}
+
+fn save_screenshot_at_frame(args: &[String], cpu: &mut CPU, script: &Option<(u64, InputMacro)>) {
+    let frame = flag_value(args, "--screenshot-at-frame")
+        .and_then(|frame| frame.parse::<u64>().ok())
+        .unwrap_or_else(|| fail("--screenshot-at-frame needs a frame number"));
+    let out = match flag_value(args, "--out") {
+        Some(out) => Path::new(out),
+        None => fail("--screenshot-at-frame needs --out <file.png|file.ppm>"),
+    };
+
+    run_frames(cpu, frame, script);
+
+    let screenshot = match load_ntsc_filter(args) {
+        Some(mut filter) => Screenshot::capture_ntsc(&cpu.ppu, &mut filter),
+        None => Screenshot::capture(&cpu.ppu, &load_palette(args)),
+    };
+    let screenshot = load_output_settings(args, cpu.region, 1).apply(&screenshot);
+    screenshot.save(out).unwrap_or_else(|e| fail(&e));
+    println!("saved frame {} to {}", frame, out.display());
+}
+
+#[cfg(feature = "sdl")]
+fn run_window(args: &[String], cpu: &mut CPU) {
+    let options = FrontendOptions {
+        output: load_output_settings(args, cpu.region, 3),
+        debug_viewers: args.iter().any(|arg| arg == "--debug-viewers"),
+        palette: load_palette(args),
+        ntsc: load_ntsc_filter(args),
+        audio: !args.iter().any(|arg| arg == "--no-audio"),
+        input: load_input_config(args),
+        input_path: flag_value(args, "--input-config").map(|path| path.into()),
+    };
+    frontend::run(cpu, options).unwrap_or_else(|e| fail(&e));
+}
+
+// --input-config <file.toml>: bindings from the file, the defaults when it
+// does not exist yet; remaps made in the window are saved there
+#[cfg(feature = "sdl")]
+fn load_input_config(args: &[String]) -> InputConfig {
+    match flag_value(args, "--input-config") {
+        Some(path) if Path::new(path).exists() => {
+            InputConfig::load(Path::new(path)).unwrap_or_else(|e| fail(&e))
+        }
+        _ => InputConfig::default(),
+    }
+}
+
+// built without SDL there is no window, just run the CPU
+#[cfg(not(feature = "sdl"))]
+fn run_window(_args: &[String], cpu: &mut CPU) {
+    cpu.run();
+}
+
+// --overscan top,bottom,left,right  --aspect-8-7  --scale N  --scanlines 0.0-1.0
+fn load_output_settings(args: &[String], region: Region, default_scale: usize) -> OutputSettings {
+    let mut settings = OutputSettings::for_region(region);
+    settings.scale = default_scale;
+    settings.aspect_correction = args.iter().any(|arg| arg == "--aspect-8-7");
+
+    if let Some(overscan) = flag_value(args, "--overscan") {
+        settings
+            .parse_overscan(overscan)
+            .unwrap_or_else(|e| fail(&e));
+    }
+    if let Some(scale) = flag_value(args, "--scale") {
+        settings.scale = match scale.parse::<usize>() {
+            Ok(scale) if scale > 0 => scale,
+            _ => fail("--scale needs a whole number of at least 1"),
+        };
+    }
+    if let Some(scanlines) = flag_value(args, "--scanlines") {
+        settings.scanlines = match scanlines.parse::<f32>() {
+            Ok(scanlines) if (0.0..=1.0).contains(&scanlines) => scanlines,
+            _ => fail("--scanlines needs a darkening between 0.0 and 1.0"),
+        };
+    }
+    settings
+}
+
+// --region ntsc|pal|dendy: overrides the header (or the database) of a
+// game pak, and the NTSC timing of the disk system
+fn load_region(args: &[String]) -> Option<Region> {
+    flag_value(args, "--region").map(|name| Region::from_name(name).unwrap_or_else(|e| fail(&e)))
+}
+
+// --ntsc composite|svideo|rgb: frames through the NTSC signal filter
+// instead of the palette, in the window and in screenshots
+fn load_ntsc_filter(args: &[String]) -> Option<NtscFilter> {
+    flag_value(args, "--ntsc").map(|name| {
+        let preset = NtscPreset::from_name(name).unwrap_or_else(|e| fail(&e));
+        NtscFilter::new(NtscFilterSettings::preset(preset))
+    })
+}
+
+// --palette: a .pal file, "ntsc" for the generated palette, default 2C02
+fn load_palette(args: &[String]) -> Palette {
+    match flag_value(args, "--palette").map(|p| p.as_str()) {
+        None => Palette::default(),
+        Some("ntsc") => Palette::generate_ntsc(&NtscPaletteSettings::default()),
+        Some(path) => Palette::from_pal(&read_file(path)).unwrap_or_else(|e| fail(&e)),
+    }
+}
+
+// --mute pulse1,noise  --solo triangle  --channel-volume dmc=0.5,fds=0.8
+fn load_channels(args: &[String]) -> ChannelMixer {
+    let mut channels = ChannelMixer::default();
+    if let Some(names) = flag_value(args, "--mute") {
+        for name in names.split(',') {
+            let channel = Channel::from_name(name).unwrap_or_else(|e| fail(&e));
+            channels.set_muted(channel, true);
+        }
+    }
+    if let Some(name) = flag_value(args, "--solo") {
+        let channel = Channel::from_name(name).unwrap_or_else(|e| fail(&e));
+        channels.set_solo(Some(channel));
+    }
+    if let Some(volumes) = flag_value(args, "--channel-volume") {
+        for setting in volumes.split(',') {
+            let (name, volume) = setting
+                .split_once('=')
+                .unwrap_or_else(|| fail("--channel-volume needs channel=volume pairs"));
+            let channel = Channel::from_name(name).unwrap_or_else(|e| fail(&e));
+            let volume = match volume.trim().parse::<f32>() {
+                Ok(volume) if volume >= 0.0 => volume,
+                _ => fail("--channel-volume needs a volume of 0.0 or more"),
+            };
+            channels.set_volume(channel, volume);
+        }
+    }
+    channels
+}
+
+// --wav-float for 32-bit float WAVs, 16-bit otherwise
+fn wav_format(args: &[String]) -> WavFormat {
+    if args.iter().any(|arg| arg == "--wav-float") {
+        WavFormat::Float32
+    } else {
+        WavFormat::Int16
+    }
+}
+
+fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    let position = args.iter().position(|arg| arg == flag)?;
+    args.get(position + 1)
+}
+
+// ROM contents, unpacked from a .zip / .7z if needed (--archive-entry picks
+// the file), with the --patch file or a .ips/.ups/.bps next to it applied
+fn read_rom(args: &[String], rom_path: &str) -> RomFile {
+    let entry = flag_value(args, "--archive-entry").map(|e| e.as_str());
+    let mut rom = archive::read_rom_file(Path::new(rom_path), entry).unwrap_or_else(|e| fail(&e));
+
+    let patch_path = match flag_value(args, "--patch") {
+        Some(path) => Some(Path::new(path).to_path_buf()),
+        None => patch::find_patch(|extension| rom.companion_path(Path::new(rom_path), extension)),
+    };
+
+    if let Some(path) = patch_path {
+        let patch_data = read_file(&path.to_string_lossy());
+        rom.data = patch::apply_patch(&rom.data, &patch_data)
+            .unwrap_or_else(|e| fail(&format!("could not apply {}: {}", path.display(), e)));
+        println!("applied patch {}", path.display());
+    }
+
+    rom
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| fail(&format!("could not read {}: {}", path, e)))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
@@ -0,0 +1,897 @@
+use crate::cpu::{Flag, CPU};
+use crate::expansion::{ExpansionAudio, MMC5Audio, Sunsoft5B, N163, VRC6};
+use crate::fds::FDSAudio;
+use crate::recording::WavFormat;
+use crate::region::Region;
+use std::fmt;
+use std::path::Path;
+
+// -----------------------------
+// NSF / NSFe
+// music rips: the game's sound driver and data, run by calling INIT once
+// per song and PLAY at a fixed rate, without a PPU
+// -----------------------------
+
+const NSF_MAGIC: &[u8; 5] = b"NESM\x1A";
+const NSFE_MAGIC: &[u8; 4] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+const BANK_SIZE: usize = 0x1000;
+
+// tracks without NSFe times play for this long, then fade out
+pub const DEFAULT_TRACK_LENGTH_MS: u32 = 150_000;
+pub const DEFAULT_FADE_MS: u32 = 5_000;
+
+// PLAY and INIT return here (RTS adds one to the pushed address);
+// nothing is mapped at $5FF0-$5FF5
+const RETURN_ADDRESS: u16 = 0x5FF0;
+
+// INIT gets a generous budget before it is assumed stuck
+const INIT_CYCLE_LIMIT: u64 = 1_789_773;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExpansionChip {
+    VRC6,
+    VRC7,
+    FDS,
+    MMC5,
+    N163,
+    Sunsoft5B,
+}
+
+impl ExpansionChip {
+    // header byte $7B (NSF) / INFO byte 7 (NSFe), bit 0 upwards
+    const ALL: [ExpansionChip; 6] = [
+        ExpansionChip::VRC6,
+        ExpansionChip::VRC7,
+        ExpansionChip::FDS,
+        ExpansionChip::MMC5,
+        ExpansionChip::N163,
+        ExpansionChip::Sunsoft5B,
+    ];
+
+    fn from_flags(flags: u8) -> Vec<ExpansionChip> {
+        ExpansionChip::ALL
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| flags & (1 << bit) != 0)
+            .map(|(_, chip)| *chip)
+            .collect()
+    }
+
+    // every chip but the VRC7's FM synthesis is emulated
+    pub fn is_supported(&self) -> bool {
+        *self != ExpansionChip::VRC7
+    }
+}
+
+impl fmt::Display for ExpansionChip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ExpansionChip::VRC6 => "Konami VRC6",
+            ExpansionChip::VRC7 => "Konami VRC7",
+            ExpansionChip::FDS => "Famicom Disk System",
+            ExpansionChip::MMC5 => "Nintendo MMC5",
+            ExpansionChip::N163 => "Namco 163",
+            ExpansionChip::Sunsoft5B => "Sunsoft 5B",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct NsfTrack {
+    // 1-based, as shown to people
+    pub number: u8,
+    pub title: Option<String>,
+    // from the NSFe time / fade chunks
+    pub length_ms: Option<u32>,
+    pub fade_ms: Option<u32>,
+}
+
+impl NsfTrack {
+    pub fn play_time_ms(&self) -> (u32, u32) {
+        (
+            self.length_ms.unwrap_or(DEFAULT_TRACK_LENGTH_MS),
+            self.fade_ms.unwrap_or(DEFAULT_FADE_MS),
+        )
+    }
+}
+
+impl fmt::Display for NsfTrack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (length, fade) = self.play_time_ms();
+        write!(
+            f,
+            "{:3}  {}  {}:{:02}",
+            self.number,
+            self.title.as_deref().unwrap_or("-"),
+            length / 60_000,
+            length / 1000 % 60
+        )?;
+        if fade > 0 {
+            write!(f, " (+{:.1}s fade)", fade as f32 / 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct NSF {
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub region: Region,
+    // PLAY period in microseconds for the region
+    pub play_period_us: u32,
+    pub expansion_chips: Vec<ExpansionChip>,
+
+    pub starting_song: u8,
+    pub tracks: Vec<NsfTrack>,
+
+    // 4 KiB banks for $6000-$FFFF ($6000-$7FFF only for FDS rips)
+    bank_init: Option<[u8; 8]>,
+    banks: [usize; 10],
+    image: Vec<u8>,
+
+    pub fds_audio: Option<FDSAudio>,
+    // VRC6, 5B, N163 and MMC5, when the rip uses them
+    pub expansion_audio: ExpansionAudio,
+}
+
+impl NSF {
+    // .nsf or .nsfe, told apart by the magic
+    pub fn load(data: &[u8]) -> Result<NSF, String> {
+        if data.starts_with(NSF_MAGIC) {
+            NSF::load_nsf(data)
+        } else if data.starts_with(NSFE_MAGIC) {
+            NSF::load_nsfe(data)
+        } else {
+            Err("not an NSF or NSFe file".to_string())
+        }
+    }
+
+    fn load_nsf(data: &[u8]) -> Result<NSF, String> {
+        if data.len() < NSF_HEADER_SIZE {
+            return Err(format!("NSF header is {} bytes, need 128", data.len()));
+        }
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+
+        let region = match data[0x7A] & 0b11 {
+            // dual region rips prefer NTSC
+            0b01 => Region::PAL,
+            _ => Region::NTSC,
+        };
+        let play_period_us = match region {
+            Region::PAL => word(0x78),
+            _ => word(0x6E),
+        } as u32;
+
+        let mut bank_init = [0; 8];
+        bank_init.copy_from_slice(&data[0x70..0x78]);
+        let song_count = data[0x06];
+
+        let tracks = (1..=song_count)
+            .map(|number| NsfTrack {
+                number,
+                title: None,
+                length_ms: None,
+                fade_ms: None,
+            })
+            .collect();
+
+        NSF::build(
+            NSF {
+                title: header_string(&data[0x0E..0x2E]),
+                artist: header_string(&data[0x2E..0x4E]),
+                copyright: header_string(&data[0x4E..0x6E]),
+                load_address: word(0x08),
+                init_address: word(0x0A),
+                play_address: word(0x0C),
+                region,
+                play_period_us,
+                expansion_chips: ExpansionChip::from_flags(data[0x7B]),
+                starting_song: data[0x07].max(1),
+                tracks,
+                bank_init: bank_init.iter().any(|bank| *bank != 0).then_some(bank_init),
+                banks: [0; 10],
+                image: Vec::new(),
+                fds_audio: None,
+                expansion_audio: ExpansionAudio::default(),
+            },
+            &data[NSF_HEADER_SIZE..],
+        )
+    }
+
+    fn load_nsfe(data: &[u8]) -> Result<NSF, String> {
+        let mut info: Option<&[u8]> = None;
+        let mut program: Option<&[u8]> = None;
+        let mut bank_init = None;
+        let mut rate = None;
+        let mut strings: Vec<String> = Vec::new();
+        let mut titles: Vec<String> = Vec::new();
+        let mut times: Vec<i32> = Vec::new();
+        let mut fades: Vec<i32> = Vec::new();
+
+        let mut position = NSFE_MAGIC.len();
+        loop {
+            if position + 8 > data.len() {
+                return Err("NSFe ends without an NEND chunk".to_string());
+            }
+            let length = u32::from_le_bytes([
+                data[position],
+                data[position + 1],
+                data[position + 2],
+                data[position + 3],
+            ]) as usize;
+            let id = &data[position + 4..position + 8];
+            let body = data
+                .get(position + 8..position + 8 + length)
+                .ok_or_else(|| format!("NSFe chunk {} is cut short", chunk_name(id)))?;
+            position += 8 + length;
+
+            match id {
+                b"INFO" => info = Some(body),
+                b"DATA" => program = Some(body),
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    for (bank, value) in banks.iter_mut().zip(body) {
+                        *bank = *value;
+                    }
+                    bank_init = Some(banks);
+                }
+                b"RATE" if body.len() >= 2 => rate = Some(body),
+                b"auth" => strings = split_strings(body),
+                b"tlbl" => titles = split_strings(body),
+                b"time" => times = split_i32(body),
+                b"fade" => fades = split_i32(body),
+                b"NEND" => break,
+                // an upper case first letter marks a chunk that cannot be skipped
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(format!("unsupported NSFe chunk {}", chunk_name(id)))
+                }
+                _ => {}
+            }
+        }
+
+        let info = info.ok_or("NSFe has no INFO chunk")?;
+        let program = program.ok_or("NSFe has no DATA chunk")?;
+        if info.len() < 8 {
+            return Err("NSFe INFO chunk is too short".to_string());
+        }
+        let word =
+            |bytes: &[u8], offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        let region = match info[6] & 0b11 {
+            0b01 => Region::PAL,
+            _ => Region::NTSC,
+        };
+        // without a RATE chunk PLAY runs at the vertical refresh
+        let play_period_us = match (rate, region) {
+            (Some(rate), Region::PAL) if rate.len() >= 4 => word(rate, 2) as u32,
+            (Some(rate), Region::NTSC) => word(rate, 0) as u32,
+            _ => frame_period_us(region),
+        };
+        let song_count = info.get(8).copied().unwrap_or(1);
+        // 0-based here, build checks it is in range
+        let starting_song = info
+            .get(9)
+            .copied()
+            .unwrap_or(0)
+            .checked_add(1)
+            .unwrap_or(1);
+
+        let millis = |values: &[i32], index: usize| {
+            values
+                .get(index)
+                .and_then(|value| u32::try_from(*value).ok())
+        };
+        let tracks = (0..song_count as usize)
+            .map(|index| NsfTrack {
+                number: index as u8 + 1,
+                title: titles.get(index).filter(|title| !title.is_empty()).cloned(),
+                length_ms: millis(&times, index),
+                fade_ms: millis(&fades, index),
+            })
+            .collect();
+
+        let string = |index: usize| strings.get(index).cloned().unwrap_or_default();
+        NSF::build(
+            NSF {
+                title: string(0),
+                artist: string(1),
+                copyright: string(2),
+                load_address: word(info, 0),
+                init_address: word(info, 2),
+                play_address: word(info, 4),
+                region,
+                play_period_us,
+                expansion_chips: ExpansionChip::from_flags(info[7]),
+                starting_song,
+                tracks,
+                bank_init,
+                banks: [0; 10],
+                image: Vec::new(),
+                fds_audio: None,
+                expansion_audio: ExpansionAudio::default(),
+            },
+            program,
+        )
+    }
+
+    // lay the program out in 4 KiB banks and power on the expansion audio
+    fn build(mut nsf: NSF, program: &[u8]) -> Result<NSF, String> {
+        if nsf.tracks.is_empty() {
+            return Err("NSF has no songs".to_string());
+        }
+        if nsf.starting_song > nsf.song_count() {
+            nsf.starting_song = 1;
+        }
+        // a zero rate would never leave time for PLAY, use the refresh rate
+        if nsf.play_period_us == 0 {
+            nsf.play_period_us = frame_period_us(nsf.region);
+        }
+        let fds = nsf.expansion_chips.contains(&ExpansionChip::FDS);
+        let lowest = if fds { 0x6000 } else { 0x8000 };
+        if nsf.load_address < lowest {
+            return Err(format!(
+                "NSF load address {:04X} is too low",
+                nsf.load_address
+            ));
+        }
+
+        // bankswitched rips pad to the load address within its bank,
+        // flat ones to the load address within $6000/$8000-$FFFF
+        let padding = match nsf.bank_init {
+            Some(_) => nsf.load_address as usize & (BANK_SIZE - 1),
+            None => (nsf.load_address - lowest) as usize,
+        };
+        nsf.image = vec![0; padding];
+        nsf.image.extend_from_slice(program);
+        let bank_count = nsf.image.len().div_ceil(BANK_SIZE).max(10);
+        nsf.image.resize(bank_count * BANK_SIZE, 0);
+
+        if fds {
+            nsf.fds_audio = Some(FDSAudio::new());
+        }
+        nsf.reset_expansion_audio();
+        nsf.reset_banks();
+        Ok(nsf)
+    }
+
+    // power the cartridge chips on, silent
+    fn reset_expansion_audio(&mut self) {
+        let uses = |chip| self.expansion_chips.contains(&chip);
+        self.expansion_audio = ExpansionAudio {
+            vrc6: uses(ExpansionChip::VRC6).then(VRC6::default),
+            sunsoft5b: uses(ExpansionChip::Sunsoft5B).then(Sunsoft5B::new),
+            n163: uses(ExpansionChip::N163).then(N163::new),
+            mmc5: uses(ExpansionChip::MMC5).then(MMC5Audio::new),
+        };
+    }
+
+    pub fn song_count(&self) -> u8 {
+        self.tracks.len() as u8
+    }
+
+    pub fn unsupported_chips(&self) -> Vec<ExpansionChip> {
+        self.expansion_chips
+            .iter()
+            .filter(|chip| !chip.is_supported())
+            .copied()
+            .collect()
+    }
+
+    // bank layout before INIT, also undoes the FDS RAM writes of a song
+    pub fn reset_banks(&mut self) {
+        let fds = self.fds_audio.is_some();
+        match self.bank_init {
+            Some(init) => {
+                for (slot, bank) in init.iter().enumerate() {
+                    self.banks[slot + 2] = *bank as usize;
+                }
+                // FDS rips map $6000-$7FFF with the $E000-$FFFF values
+                self.banks[0] = init[6] as usize;
+                self.banks[1] = init[7] as usize;
+            }
+            None => {
+                for slot in 0..10 {
+                    self.banks[slot] = if fds { slot } else { slot.saturating_sub(2) };
+                }
+            }
+        }
+    }
+
+    // is this address in the rip's ROM (or FDS RAM) rather than CPU memory
+    pub fn maps(&self, address: u16) -> bool {
+        address >= 0x8000 || (self.fds_audio.is_some() && address >= 0x6000)
+    }
+
+    pub fn read_prg(&self, address: u16) -> u8 {
+        let slot = (address as usize - 0x6000) / BANK_SIZE;
+        let offset = self.banks[slot] * BANK_SIZE + (address as usize & (BANK_SIZE - 1));
+        self.image.get(offset).copied().unwrap_or(0)
+    }
+
+    // FDS rips run from RAM at $6000-$DFFF
+    pub fn write_prg(&mut self, address: u16, value: u8) {
+        if self.fds_audio.is_none() || address >= 0xE000 {
+            return;
+        }
+        let slot = (address as usize - 0x6000) / BANK_SIZE;
+        let offset = self.banks[slot] * BANK_SIZE + (address as usize & (BANK_SIZE - 1));
+        if let Some(byte) = self.image.get_mut(offset) {
+            *byte = value;
+        }
+    }
+
+    // $5FF8-$5FFF select the banks at $8000-$FFFF, $5FF6-$5FF7 the FDS RAM
+    pub fn write_bank(&mut self, address: u16, value: u8) {
+        let slot = (address - 0x5FF6) as usize;
+        if slot < 2 && self.fds_audio.is_none() {
+            return;
+        }
+        let bank_count = self.image.len() / BANK_SIZE;
+        self.banks[slot] = value as usize % bank_count;
+    }
+}
+
+// -----------------------------
+// Player
+// -----------------------------
+
+pub struct NsfPlayer {
+    pub cpu: CPU,
+    pub song: u8,
+    // CPU cycles between PLAY calls, and the fraction owed
+    play_period: f64,
+    cycle_debt: f64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: NSF) -> NsfPlayer {
+        let mut cpu = CPU::new();
+        cpu.set_region(nsf.region);
+        let play_period = nsf.play_period_us as f64 * nsf.region.cpu_clock_rate() / 1_000_000.0;
+        let song = nsf.starting_song;
+        cpu.nsf = Some(nsf);
+        NsfPlayer {
+            cpu,
+            song,
+            play_period,
+            cycle_debt: 0.0,
+        }
+    }
+
+    pub fn nsf(&self) -> &NSF {
+        self.cpu.nsf.as_ref().expect("NsfPlayer always has an NSF")
+    }
+
+    pub fn track(&self) -> &NsfTrack {
+        &self.nsf().tracks[self.song as usize - 1]
+    }
+
+    // PLAY calls per second
+    pub fn play_rate(&self) -> f64 {
+        self.cpu.region.cpu_clock_rate() / self.play_period
+    }
+
+    // reset the sound hardware and run INIT for song (1-based)
+    pub fn start_song(&mut self, song: u8) -> Result<(), String> {
+        if song == 0 || song > self.nsf().song_count() {
+            return Err(format!(
+                "no song {}, the NSF has {}",
+                song,
+                self.nsf().song_count()
+            ));
+        }
+        self.song = song;
+        self.cycle_debt = 0.0;
+
+        let cpu = &mut self.cpu;
+        cpu.memory[0x0000..0x0800].fill(0);
+        cpu.memory[0x6000..0x8000].fill(0);
+        if let Some(nsf) = cpu.nsf.as_mut() {
+            nsf.reset_banks();
+            if nsf.fds_audio.is_some() {
+                nsf.fds_audio = Some(FDSAudio::new());
+            }
+            nsf.reset_expansion_audio();
+        }
+
+        // silence the APU, frame interrupts off
+        for address in 0x4000..=0x4013 {
+            cpu.write_memory(address, 0);
+        }
+        cpu.write_memory(0x4015, 0);
+        cpu.write_memory(0x4015, 0x0F);
+        cpu.write_memory(0x4017, 0x40);
+        if cpu.nsf.as_ref().is_some_and(|nsf| nsf.fds_audio.is_some()) {
+            // enable the FDS wave and sound registers
+            cpu.write_memory(0x4089, 0x80);
+            cpu.write_memory(0x408A, 0xE8);
+        }
+
+        cpu.register_a = song - 1;
+        cpu.register_x = match cpu.region {
+            Region::PAL => 1,
+            _ => 0,
+        };
+        cpu.register_y = 0;
+        cpu.stack_pointer = 0xFD;
+
+        let init = self.nsf().init_address;
+        self.call(init, INIT_CYCLE_LIMIT)
+            .map_err(|e| format!("INIT of song {}: {}", song, e))
+    }
+
+    // one PLAY call, then let the sound run until the next one is due
+    pub fn play_frame(&mut self) -> Result<(), String> {
+        let start = self.cpu.cycles;
+        let play = self.nsf().play_address;
+        self.call(play, self.play_period as u64)
+            .map_err(|e| format!("PLAY of song {}: {}", self.song, e))?;
+
+        self.cycle_debt += self.play_period - (self.cpu.cycles - start) as f64;
+        let idle = self.cycle_debt.floor().max(0.0);
+        self.cycle_debt -= idle;
+        self.cpu.idle(idle as u64);
+        Ok(())
+    }
+
+    // JSR-like call that returns to RETURN_ADDRESS, interrupts masked
+    fn call(&mut self, address: u16, cycle_limit: u64) -> Result<(), String> {
+        let cpu = &mut self.cpu;
+        let return_address = RETURN_ADDRESS - 1;
+        cpu.stack_push((return_address >> 8) as u8);
+        cpu.stack_push(return_address as u8);
+        cpu.program_counter = address;
+        cpu.status |= Flag::Interrupt as u8;
+
+        let start = cpu.cycles;
+        while cpu.program_counter != RETURN_ADDRESS {
+            if !cpu.step() {
+                return Err(format!(
+                    "the driver stopped at {:04X} (BRK or an unofficial opscode)",
+                    cpu.program_counter
+                ));
+            }
+            if cpu.cycles - start > cycle_limit {
+                return Err(format!("did not return within {} cycles", cycle_limit));
+            }
+        }
+        Ok(())
+    }
+
+    // render a song for its length plus fade (NSFe times, or the
+    // defaults) to a WAV file; returns the number of PLAY calls
+    pub fn render_to_wav(
+        &mut self,
+        song: u8,
+        path: &Path,
+        format: WavFormat,
+        stems: bool,
+    ) -> Result<u64, String> {
+        self.start_song(song)?;
+        let (length, fade) = self.track().play_time_ms();
+        self.cpu.start_recording(path, format, stems)?;
+
+        let frames = self.frames_for_ms(length + fade);
+        let fade_start = self.frames_for_ms(length);
+        for frame in 0..frames {
+            self.cpu.volume = fade_volume(frame, fade_start, frames);
+            self.play_frame()?;
+        }
+        self.cpu.volume = 1.0;
+        self.cpu.stop_recording()?;
+        Ok(frames)
+    }
+
+    pub fn frames_for_ms(&self, ms: u32) -> u64 {
+        (ms as f64 / 1000.0 * self.play_rate()).round() as u64
+    }
+}
+
+// linear fade from fade_start down to silence at end
+pub fn fade_volume(frame: u64, fade_start: u64, end: u64) -> f32 {
+    if frame < fade_start || end <= fade_start {
+        1.0
+    } else {
+        1.0 - (frame - fade_start) as f32 / (end - fade_start) as f32
+    }
+}
+
+// PLAY period of a rip that runs at the vertical refresh
+fn frame_period_us(region: Region) -> u32 {
+    (1_000_000.0 / region.frame_rate()).round() as u32
+}
+
+// fixed 32 byte, zero padded header strings
+fn header_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn split_strings(body: &[u8]) -> Vec<String> {
+    let body = body.strip_suffix(&[0]).unwrap_or(body);
+    body.split(|b| *b == 0)
+        .map(|s| String::from_utf8_lossy(s).to_string())
+        .collect()
+}
+
+fn split_i32(body: &[u8]) -> Vec<i32> {
+    body.chunks_exact(4)
+        .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn chunk_name(id: &[u8]) -> String {
+    String::from_utf8_lossy(id).to_string()
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // INIT at $8000: STA $0200 (song), CLC, RTS
+    // PLAY at $8010: LDA $0201, CLC, ADC #$01, STA $0201, RTS
+    fn test_program() -> Vec<u8> {
+        let mut program = vec![0; 0x20];
+        program[0x00..0x05].copy_from_slice(&[0x8d, 0x00, 0x02, 0x18, 0x60]);
+        program[0x10..0x1a]
+            .copy_from_slice(&[0xad, 0x01, 0x02, 0x18, 0x69, 0x01, 0x8d, 0x01, 0x02, 0x60]);
+        program
+    }
+
+    fn test_nsf(bank_init: [u8; 8], program: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; NSF_HEADER_SIZE];
+        data[0..5].copy_from_slice(NSF_MAGIC);
+        data[0x05] = 1;
+        data[0x06] = 3;
+        data[0x07] = 2;
+        data[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0x8010u16.to_le_bytes());
+        data[0x0E..0x13].copy_from_slice(b"Title");
+        data[0x2E..0x34].copy_from_slice(b"Artist");
+        data[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        data[0x70..0x78].copy_from_slice(&bank_init);
+        data.extend_from_slice(program);
+        data
+    }
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = (body.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(id);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn test_nsfe() -> Vec<u8> {
+        let mut info = Vec::new();
+        info.extend_from_slice(&0x8000u16.to_le_bytes());
+        info.extend_from_slice(&0x8000u16.to_le_bytes());
+        info.extend_from_slice(&0x8010u16.to_le_bytes());
+        info.extend_from_slice(&[0, 0, 2, 0]);
+
+        let mut times = Vec::new();
+        times.extend_from_slice(&1000i32.to_le_bytes());
+        times.extend_from_slice(&(-1i32).to_le_bytes());
+
+        let mut data = NSFE_MAGIC.to_vec();
+        data.extend(chunk(b"INFO", &info));
+        data.extend(chunk(b"DATA", &test_program()));
+        data.extend(chunk(b"auth", b"Game\0Composer\0Copyright\0Ripper\0"));
+        data.extend(chunk(b"tlbl", b"Overworld\0Castle\0"));
+        data.extend(chunk(b"time", &times));
+        data.extend(chunk(b"fade", &500i32.to_le_bytes()));
+        data.extend(chunk(b"psfx", &[0]));
+        data.extend(chunk(b"NEND", &[]));
+        data
+    }
+
+    #[test]
+    fn test_load_nsf_header() {
+        let nsf = NSF::load(&test_nsf([0; 8], &test_program())).unwrap();
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.song_count(), 3);
+        assert_eq!(nsf.starting_song, 2);
+        assert_eq!(nsf.region, Region::NTSC);
+        assert_eq!(nsf.play_address, 0x8010);
+        assert_eq!(nsf.read_prg(0x8010), 0xad);
+        assert!(nsf.expansion_chips.is_empty());
+    }
+
+    #[test]
+    fn test_expansion_chips() {
+        let mut data = test_nsf([0; 8], &test_program());
+        data[0x7B] = 0b0000_0111;
+        let nsf = NSF::load(&data).unwrap();
+        assert_eq!(
+            nsf.expansion_chips,
+            vec![ExpansionChip::VRC6, ExpansionChip::VRC7, ExpansionChip::FDS]
+        );
+        assert_eq!(nsf.unsupported_chips(), vec![ExpansionChip::VRC7]);
+        assert!(nsf.fds_audio.is_some());
+        assert!(nsf.expansion_audio.vrc6.is_some());
+        assert!(nsf.expansion_audio.n163.is_none());
+        // FDS rips map from $6000
+        assert!(nsf.maps(0x6000));
+        assert_eq!(nsf.read_prg(0x8010), 0xad);
+    }
+
+    #[test]
+    fn test_expansion_audio_on_the_bus() {
+        // INIT: LDA #$8F, STA $9000, LDA #$80, STA $9002, RTS
+        // (VRC6 pulse 1 in digitized mode at volume 15); PLAY: RTS
+        let mut program = vec![0; 0x20];
+        program[0x00..0x0B].copy_from_slice(&[
+            0xa9, 0x8f, 0x8d, 0x00, 0x90, 0xa9, 0x80, 0x8d, 0x02, 0x90, 0x60,
+        ]);
+        program[0x10] = 0x60;
+        let mut data = test_nsf([0; 8], &program);
+        data[0x7B] = 0b0000_0001;
+
+        let mut player = NsfPlayer::new(NSF::load(&data).unwrap());
+        player.start_song(1).unwrap();
+        player.play_frame().unwrap();
+        let level = player.nsf().expansion_audio.output();
+        assert!(level > 0.0);
+
+        // a new song powers the chip back on silent
+        player.cpu.nsf.as_mut().unwrap().init_address = 0x8010;
+        player.start_song(2).unwrap();
+        assert_eq!(player.nsf().expansion_audio.output(), 0.0);
+    }
+
+    #[test]
+    fn test_header_edge_cases() {
+        // no PLAY rate: the NTSC refresh rate
+        let mut data = test_nsf([0; 8], &test_program());
+        data[0x6E..0x70].fill(0);
+        let mut player = NsfPlayer::new(NSF::load(&data).unwrap());
+        assert_eq!(player.nsf().play_period_us, 16639);
+        player.start_song(1).unwrap();
+        player.play_frame().unwrap();
+        assert_eq!(player.frames_for_ms(1000), 60);
+
+        // a starting song past the end, and the 0-based 255 of NSFe
+        data[0x07] = 9;
+        assert_eq!(NSF::load(&data).unwrap().starting_song, 1);
+        let mut data = test_nsfe();
+        data[4 + 8 + 9] = 255;
+        assert_eq!(NSF::load(&data).unwrap().starting_song, 1);
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        // load at $8000, bank 0 = program, bank 1 starts with $42
+        let mut program = test_program();
+        program.resize(BANK_SIZE, 0);
+        program.push(0x42);
+        let mut nsf = NSF::load(&test_nsf([0, 1, 0, 0, 0, 0, 0, 0], &program)).unwrap();
+        assert_eq!(nsf.read_prg(0x8010), 0xad);
+        assert_eq!(nsf.read_prg(0x9000), 0x42);
+
+        nsf.write_bank(0x5FF8, 1);
+        assert_eq!(nsf.read_prg(0x8000), 0x42);
+        nsf.reset_banks();
+        assert_eq!(nsf.read_prg(0x8000), 0x8d);
+    }
+
+    #[test]
+    fn test_load_nsfe() {
+        let nsf = NSF::load(&test_nsfe()).unwrap();
+        assert_eq!(nsf.title, "Game");
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.starting_song, 1);
+        assert_eq!(nsf.tracks.len(), 2);
+        assert_eq!(nsf.tracks[0].title.as_deref(), Some("Overworld"));
+        assert_eq!(nsf.tracks[0].play_time_ms(), (1000, 500));
+        assert_eq!(
+            nsf.tracks[1].play_time_ms(),
+            (DEFAULT_TRACK_LENGTH_MS, DEFAULT_FADE_MS)
+        );
+        assert_eq!(
+            nsf.tracks[0].to_string(),
+            "  1  Overworld  0:01 (+0.5s fade)"
+        );
+    }
+
+    #[test]
+    fn test_nsfe_rejects_unknown_mandatory_chunk() {
+        let mut data = NSFE_MAGIC.to_vec();
+        data.extend(chunk(b"VRC7", &[0]));
+        data.extend(chunk(b"NEND", &[]));
+        assert!(NSF::load(&data).is_err());
+        assert!(NSF::load(b"NSFE").is_err());
+        assert!(NSF::load(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_init_and_play() {
+        let nsf = NSF::load(&test_nsf([0; 8], &test_program())).unwrap();
+        let mut player = NsfPlayer::new(nsf);
+        assert!((player.play_rate() - 60.1).abs() < 0.1);
+
+        player.start_song(3).unwrap();
+        assert_eq!(player.cpu.memory[0x0200], 2);
+
+        let start = player.cpu.cycles;
+        for _ in 0..10 {
+            player.play_frame().unwrap();
+        }
+        assert_eq!(player.cpu.memory[0x0201], 10);
+        // PLAY is paced to the header rate
+        let cycles = (player.cpu.cycles - start) as f64;
+        assert!((cycles - 10.0 * player.play_period).abs() < 1.0);
+
+        // switching songs starts from a clean slate
+        player.start_song(1).unwrap();
+        assert_eq!(player.cpu.memory[0x0200], 0);
+        assert_eq!(player.cpu.memory[0x0201], 0);
+        assert!(player.start_song(4).is_err());
+    }
+
+    #[test]
+    fn test_driver_with_loops() {
+        // INIT: LDX #$10, LDA #$00, loop: CLC, ADC #$02, DEX, BNE loop,
+        // STA $0200, RTS
+        // PLAY: LDY #$03, loop: LDA $0200, SEC, SBC #$01, STA $0200, DEY,
+        // BNE loop, RTS
+        let mut program = vec![0; 0x20];
+        program[0x00..0x0E].copy_from_slice(&[
+            0xa2, 0x10, 0xa9, 0x00, 0x18, 0x69, 0x02, 0xca, 0xd0, 0xfa, 0x8d, 0x00, 0x02, 0x60,
+        ]);
+        program[0x10..0x1F].copy_from_slice(&[
+            0xa0, 0x03, 0xad, 0x00, 0x02, 0x38, 0xe9, 0x01, 0x8d, 0x00, 0x02, 0x88, 0xd0, 0xf4,
+            0x60,
+        ]);
+        let mut player = NsfPlayer::new(NSF::load(&test_nsf([0; 8], &program)).unwrap());
+        player.start_song(1).unwrap();
+        assert_eq!(player.cpu.memory[0x0200], 0x20);
+        player.play_frame().unwrap();
+        player.play_frame().unwrap();
+        assert_eq!(player.cpu.memory[0x0200], 0x1A);
+    }
+
+    #[test]
+    fn test_driver_stops() {
+        // INIT is a BRK
+        let nsf = NSF::load(&test_nsf([0; 8], &[0x00])).unwrap();
+        let mut player = NsfPlayer::new(nsf);
+        assert!(player.start_song(1).is_err());
+    }
+
+    #[test]
+    fn test_fade_volume() {
+        assert_eq!(fade_volume(10, 50, 100), 1.0);
+        assert_eq!(fade_volume(75, 50, 100), 0.5);
+        assert_eq!(fade_volume(20, 20, 20), 1.0);
+    }
+
+    #[test]
+    fn test_render_to_wav() {
+        let path = std::env::temp_dir().join("nest_emulator_nsf_render_test.wav");
+        let nsf = NSF::load(&test_nsfe()).unwrap();
+        let mut player = NsfPlayer::new(nsf);
+        let frames = player
+            .render_to_wav(1, &path, WavFormat::Int16, false)
+            .unwrap();
+        // 1.5 seconds at 60.1 Hz
+        assert_eq!(frames, 90);
+
+        let reader = hound::WavReader::open(&path).unwrap();
+        let seconds = reader.duration() as f64 / reader.spec().sample_rate as f64;
+        assert!((seconds - 1.5).abs() < 0.01);
+        std::fs::remove_file(path).unwrap();
+    }
+}
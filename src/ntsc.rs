@@ -0,0 +1,294 @@
+use crate::palette::{self, NtscPaletteSettings, Palette};
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// -----------------------------
+// NTSC filter
+// rebuilds the composite signal of every dot and decodes it again,
+// which brings back colour fringing, artifact colours and dot crawl
+// -----------------------------
+
+// each dot is 8 samples, a colour clock cycle is 12
+const SAMPLES_PER_DOT: usize = 8;
+const PHASES: usize = 12;
+const LINE_SAMPLES: usize = SCREEN_WIDTH * SAMPLES_PER_DOT;
+
+// a scanline is 341 * 8 samples, so each line starts 4 phases later
+const LINE_PHASE_STEP: usize = 4;
+
+// output pixels per line, one for every 4 samples
+pub const NTSC_WIDTH: usize = 512;
+const OUTPUT_STEP: usize = LINE_SAMPLES / NTSC_WIDTH;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NtscPreset {
+    // luma and chroma share one wire: fringing and dot crawl
+    Composite,
+    // luma and chroma apart: sharp, no crawl, chroma still bleeds
+    SVideo,
+    // no signal artifacts at all, clean palette colours
+    RGB,
+}
+
+impl NtscPreset {
+    pub fn from_name(name: &str) -> Result<NtscPreset, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "composite" => Ok(NtscPreset::Composite),
+            "svideo" | "s-video" => Ok(NtscPreset::SVideo),
+            "rgb" => Ok(NtscPreset::RGB),
+            _ => Err(format!(
+                "unknown NTSC preset {}, expected composite, svideo or rgb",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct NtscFilterSettings {
+    pub preset: NtscPreset,
+    // samples averaged for luma, 12 (a full colour cycle) cancels chroma
+    // on flat areas, fewer is sharper with more crawl
+    pub luma_window: usize,
+    // samples averaged for I / Q, wider blurs colour more
+    pub chroma_window: usize,
+    // average the two alternating field phases to hide dot crawl
+    pub merge_fields: bool,
+    pub colour: NtscPaletteSettings,
+}
+
+impl NtscFilterSettings {
+    pub fn preset(preset: NtscPreset) -> NtscFilterSettings {
+        let (luma_window, chroma_window) = match preset {
+            NtscPreset::Composite => (12, 24),
+            NtscPreset::SVideo => (4, 24),
+            NtscPreset::RGB => (1, 1),
+        };
+        NtscFilterSettings {
+            preset,
+            luma_window,
+            chroma_window,
+            merge_fields: false,
+            colour: NtscPaletteSettings::default(),
+        }
+    }
+}
+
+pub struct NtscFilter {
+    settings: NtscFilterSettings,
+    // clean colours for the RGB preset
+    palette: Palette,
+    // luma part of every entry, for S-Video
+    luma: Vec<f32>,
+    // NTSC_WIDTH x 240 RGB
+    output: Vec<u8>,
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscFilterSettings) -> NtscFilter {
+        let luma = (0..512)
+            .map(|entry| {
+                (0..PHASES)
+                    .map(|phase| palette::signal_level(entry, phase))
+                    .sum::<f32>()
+                    / PHASES as f32
+            })
+            .collect();
+
+        NtscFilter {
+            settings,
+            palette: Palette::generate_ntsc(&settings.colour),
+            luma,
+            output: vec![0; NTSC_WIDTH * SCREEN_HEIGHT * 3],
+        }
+    }
+
+    // filter a PPU frame buffer, the frame number decides the field phase
+    pub fn apply(&mut self, frame_buffer: &[u16], frame: u64) -> &[u8] {
+        // the skipped dot on odd frames moves the picture 8 phases,
+        // so consecutive frames alternate between two phases
+        // merged fields always blend both phases, so the picture stands still
+        let field_phase = if frame % 2 == 1 && !self.settings.merge_fields {
+            4
+        } else {
+            0
+        };
+
+        for y in 0..SCREEN_HEIGHT {
+            let line = &frame_buffer[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+            let phase = field_phase + y * LINE_PHASE_STEP;
+
+            let mut rgb = self.decode_line(line, phase);
+            if self.settings.merge_fields {
+                let other = self.decode_line(line, phase + 4);
+                for (a, b) in rgb.iter_mut().zip(other) {
+                    *a = ((*a as u16 + b as u16) / 2) as u8;
+                }
+            }
+
+            let start = y * NTSC_WIDTH * 3;
+            self.output[start..start + NTSC_WIDTH * 3].copy_from_slice(&rgb);
+        }
+        &self.output
+    }
+
+    fn decode_line(&self, line: &[u16], phase: usize) -> Vec<u8> {
+        if self.settings.preset == NtscPreset::RGB {
+            return line
+                .iter()
+                .flat_map(|&entry| {
+                    let colour = self.palette.rgb(entry);
+                    [colour; NTSC_WIDTH / SCREEN_WIDTH]
+                })
+                .flatten()
+                .collect();
+        }
+
+        // luma and chroma signals, sample by sample
+        let mut luma = Vec::with_capacity(LINE_SAMPLES);
+        let mut chroma_i = Vec::with_capacity(LINE_SAMPLES);
+        let mut chroma_q = Vec::with_capacity(LINE_SAMPLES);
+        for (x, &entry) in line.iter().enumerate() {
+            for sample in 0..SAMPLES_PER_DOT {
+                let phase = (phase + x * SAMPLES_PER_DOT + sample) % PHASES;
+                let signal = palette::signal_level(entry, phase);
+                let angle = palette::chroma_angle(phase, self.settings.colour.hue);
+
+                // S-Video keeps luma on its own wire
+                let (y, c) = match self.settings.preset {
+                    NtscPreset::SVideo => {
+                        let y = self.luma[entry as usize & 0x1FF];
+                        (y, signal - y)
+                    }
+                    _ => (signal, signal),
+                };
+                luma.push(y);
+                chroma_i.push(c * angle.cos());
+                chroma_q.push(c * angle.sin());
+            }
+        }
+
+        let luma = prefix_sums(&luma);
+        let chroma_i = prefix_sums(&chroma_i);
+        let chroma_q = prefix_sums(&chroma_q);
+
+        let mut rgb = Vec::with_capacity(NTSC_WIDTH * 3);
+        for x in 0..NTSC_WIDTH {
+            let centre = x * OUTPUT_STEP + OUTPUT_STEP / 2;
+            let y = window_average(&luma, centre, self.settings.luma_window);
+            let i = window_average(&chroma_i, centre, self.settings.chroma_window);
+            let q = window_average(&chroma_q, centre, self.settings.chroma_window);
+            rgb.extend_from_slice(&palette::yiq_to_rgb(y, i, q, &self.settings.colour));
+        }
+        rgb
+    }
+}
+
+fn prefix_sums(samples: &[f32]) -> Vec<f32> {
+    let mut sums = Vec::with_capacity(samples.len() + 1);
+    let mut total = 0.0;
+    sums.push(total);
+    for sample in samples {
+        total += sample;
+        sums.push(total);
+    }
+    sums
+}
+
+// mean of the samples in a window around centre, clipped to the line
+fn window_average(sums: &[f32], centre: usize, window: usize) -> f32 {
+    let samples = sums.len() - 1;
+    let window = window.max(1);
+    let start = centre
+        .saturating_sub(window / 2)
+        .min(samples - window.min(samples));
+    let end = (start + window).min(samples);
+    (sums[end] - sums[start]) / (end - start) as f32
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flat_frame(entry: u16) -> Vec<u16> {
+        vec![entry; SCREEN_WIDTH * SCREEN_HEIGHT]
+    }
+
+    fn output_pixel(output: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let start = (y * NTSC_WIDTH + x) * 3;
+        [output[start], output[start + 1], output[start + 2]]
+    }
+
+    #[test]
+    fn test_output_size() {
+        let mut filter = NtscFilter::new(NtscFilterSettings::preset(NtscPreset::Composite));
+        assert_eq!(filter.apply(&flat_frame(0x0F), 0).len(), 512 * 240 * 3);
+    }
+
+    #[test]
+    fn test_flat_colour_matches_palette() {
+        let settings = NtscFilterSettings::preset(NtscPreset::Composite);
+        let palette = Palette::generate_ntsc(&settings.colour);
+        let mut filter = NtscFilter::new(settings);
+        let output = filter.apply(&flat_frame(0x16), 0);
+
+        let expected = palette.rgb(0x16);
+        let actual = output_pixel(output, 200, 100);
+        for channel in 0..3 {
+            assert!(actual[channel].abs_diff(expected[channel]) <= 2);
+        }
+    }
+
+    #[test]
+    fn test_rgb_preset_is_clean() {
+        let settings = NtscFilterSettings::preset(NtscPreset::RGB);
+        let palette = Palette::generate_ntsc(&settings.colour);
+        let mut frame = flat_frame(0x0F);
+        frame[10] = 0x30;
+        let mut filter = NtscFilter::new(settings);
+        let output = filter.apply(&frame, 0);
+        assert_eq!(output_pixel(output, 20, 0), palette.rgb(0x30));
+        assert_eq!(output_pixel(output, 21, 0), palette.rgb(0x30));
+        assert_eq!(output_pixel(output, 22, 0), palette.rgb(0x0F));
+    }
+
+    // alternating black / white dots make artifact colours on composite
+    fn stripes() -> Vec<u16> {
+        (0..SCREEN_WIDTH * SCREEN_HEIGHT)
+            .map(|i| if i % 2 == 0 { 0x30 } else { 0x0F })
+            .collect()
+    }
+
+    fn is_grey(colour: [u8; 3]) -> bool {
+        colour[0].abs_diff(colour[1]) <= 4 && colour[1].abs_diff(colour[2]) <= 4
+    }
+
+    #[test]
+    fn test_composite_artifacts_and_dot_crawl() {
+        let mut filter = NtscFilter::new(NtscFilterSettings::preset(NtscPreset::Composite));
+        let even = filter.apply(&stripes(), 0).to_vec();
+        let odd = filter.apply(&stripes(), 1).to_vec();
+        assert!(!is_grey(output_pixel(&even, 200, 50)));
+        // the field phase alternates, so the artifacts crawl
+        assert_ne!(even, odd);
+    }
+
+    #[test]
+    fn test_merge_fields_is_stable() {
+        let mut settings = NtscFilterSettings::preset(NtscPreset::Composite);
+        settings.merge_fields = true;
+        let mut filter = NtscFilter::new(settings);
+        let even = filter.apply(&stripes(), 0).to_vec();
+        let odd = filter.apply(&stripes(), 1).to_vec();
+        assert_eq!(even, odd);
+    }
+
+    #[test]
+    fn test_preset_names() {
+        assert_eq!(NtscPreset::from_name("S-Video"), Ok(NtscPreset::SVideo));
+        assert!(NtscPreset::from_name("vga").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::region::Region;
+use crate::screenshot::Screenshot;
+
+// -----------------------------
+// Output stage
+// overscan cropping, 8:7 pixel aspect, integer scaling and scanlines,
+// applied to frames for the window and for screenshots
+// -----------------------------
+
+// NES pixels are 8:7 (wider than tall) on an NTSC television
+const PIXEL_ASPECT: f64 = 8.0 / 7.0;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OutputSettings {
+    // overscan cut from each edge, in NES pixels
+    pub crop_top: usize,
+    pub crop_bottom: usize,
+    pub crop_left: usize,
+    pub crop_right: usize,
+    // stretch horizontally to 8:7 pixels
+    pub aspect_correction: bool,
+    // whole number scale factor
+    pub scale: usize,
+    // darkening of every other output line, 0.0 (off) to 1.0 (black)
+    pub scanlines: f32,
+}
+
+impl OutputSettings {
+    // NTSC televisions hide about 8 lines top and bottom, PAL shows them all
+    pub fn for_region(region: Region) -> OutputSettings {
+        let crop = match region {
+            Region::NTSC => 8,
+            Region::PAL | Region::Dendy => 0,
+        };
+        OutputSettings {
+            crop_top: crop,
+            crop_bottom: crop,
+            crop_left: 0,
+            crop_right: 0,
+            aspect_correction: false,
+            scale: 1,
+            scanlines: 0.0,
+        }
+    }
+
+    // --overscan top,bottom,left,right
+    pub fn parse_overscan(&mut self, value: &str) -> Result<(), String> {
+        let edges: Vec<usize> = value
+            .split(',')
+            .map(|edge| edge.trim().parse::<usize>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("bad overscan {}, expected top,bottom,left,right", value))?;
+
+        match edges[..] {
+            [top, bottom, left, right]
+                if top + bottom < SCREEN_HEIGHT && left + right < SCREEN_WIDTH =>
+            {
+                self.crop_top = top;
+                self.crop_bottom = bottom;
+                self.crop_left = left;
+                self.crop_right = right;
+                Ok(())
+            }
+            _ => Err(format!(
+                "bad overscan {}, expected top,bottom,left,right",
+                value
+            )),
+        }
+    }
+
+    // size of a processed frame, given the width of the frames coming in
+    // (SCREEN_WIDTH, or more from the NTSC filter); the extra horizontal
+    // resolution is kept, not resampled back to NES pixels
+    pub fn output_size(&self, frame_width: usize) -> (usize, usize) {
+        let width = (SCREEN_WIDTH - self.crop_left - self.crop_right) * frame_width / SCREEN_WIDTH
+            * self.scale.max(1);
+        let height = (SCREEN_HEIGHT - self.crop_top - self.crop_bottom) * self.scale.max(1);
+        if self.aspect_correction {
+            ((width as f64 * PIXEL_ASPECT).round() as usize, height)
+        } else {
+            (width, height)
+        }
+    }
+
+    // frame in, processed frame out; the frame may be wider than 256
+    // (NTSC filter), cropping is always in NES pixels
+    pub fn apply(&self, frame: &Screenshot) -> Screenshot {
+        let scale = self.scale.max(1);
+        let (width, height) = self.output_size(frame.width);
+
+        // source pixels per NES pixel horizontally
+        let source_left = self.crop_left * frame.width / SCREEN_WIDTH;
+        let source_width =
+            (SCREEN_WIDTH - self.crop_left - self.crop_right) * frame.width / SCREEN_WIDTH;
+
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let source_y = self.crop_top + y / scale;
+            let row = &frame.rgb[source_y * frame.width * 3..(source_y + 1) * frame.width * 3];
+
+            // the last line of each scaled row is the dark one
+            let dark =
+                self.scanlines > 0.0 && (y % scale == scale - 1) && (scale > 1 || y % 2 == 1);
+            let brightness = if dark {
+                1.0 - self.scanlines.min(1.0)
+            } else {
+                1.0
+            };
+
+            for x in 0..width {
+                // nearest neighbour, integer maths so output is reproducible
+                let source_x = source_left + x * source_width / width;
+                for channel in 0..3 {
+                    let value = row[source_x * 3 + channel];
+                    rgb.push((value as f32 * brightness).round() as u8);
+                }
+            }
+        }
+
+        Screenshot { width, height, rgb }
+    }
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // every pixel coloured by its position
+    fn test_frame(width: usize) -> Screenshot {
+        let mut rgb = Vec::new();
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..width {
+                rgb.extend_from_slice(&[x as u8, y as u8, 200]);
+            }
+        }
+        Screenshot {
+            width,
+            height: SCREEN_HEIGHT,
+            rgb,
+        }
+    }
+
+    fn pixel(image: &Screenshot, x: usize, y: usize) -> [u8; 3] {
+        let start = (y * image.width + x) * 3;
+        [image.rgb[start], image.rgb[start + 1], image.rgb[start + 2]]
+    }
+
+    #[test]
+    fn test_ntsc_default_crop() {
+        let settings = OutputSettings::for_region(Region::NTSC);
+        let image = settings.apply(&test_frame(256));
+        assert_eq!((image.width, image.height), (256, 224));
+        assert_eq!(pixel(&image, 0, 0), [0, 8, 200]);
+        assert_eq!(pixel(&image, 255, 223), [255, 231, 200]);
+
+        let settings = OutputSettings::for_region(Region::PAL);
+        assert_eq!(settings.output_size(SCREEN_WIDTH), (256, 240));
+    }
+
+    #[test]
+    fn test_crop_all_edges() {
+        let mut settings = OutputSettings::for_region(Region::NTSC);
+        settings.parse_overscan("4, 6, 8, 10").unwrap();
+        let image = settings.apply(&test_frame(256));
+        assert_eq!((image.width, image.height), (238, 230));
+        assert_eq!(pixel(&image, 0, 0), [8, 4, 200]);
+
+        assert!(settings.parse_overscan("4,6,8").is_err());
+        assert!(settings.parse_overscan("200,200,0,0").is_err());
+    }
+
+    #[test]
+    fn test_integer_scale_and_aspect() {
+        let mut settings = OutputSettings::for_region(Region::NTSC);
+        settings.scale = 3;
+        settings.aspect_correction = true;
+        let image = settings.apply(&test_frame(256));
+        // 256 * 3 * 8 / 7 = 877.7
+        assert_eq!((image.width, image.height), (878, 672));
+        assert_eq!(pixel(&image, 877, 671), [255, 231, 200]);
+        assert_eq!(pixel(&image, 0, 2), [0, 8, 200]);
+        assert_eq!(pixel(&image, 0, 3), [0, 9, 200]);
+    }
+
+    #[test]
+    fn test_scanlines() {
+        let mut settings = OutputSettings::for_region(Region::PAL);
+        settings.scale = 2;
+        settings.scanlines = 0.5;
+        let image = settings.apply(&test_frame(256));
+        assert_eq!(pixel(&image, 0, 0), [0, 0, 200]);
+        assert_eq!(pixel(&image, 0, 1), [0, 0, 100]);
+        assert_eq!(pixel(&image, 0, 2), [0, 1, 200]);
+    }
+
+    #[test]
+    fn test_wide_ntsc_filter_frame() {
+        let mut settings = OutputSettings::for_region(Region::NTSC);
+        settings.crop_left = 8;
+        let image = settings.apply(&test_frame(512));
+        // 8 NES pixels are 16 filter pixels, every filter column is kept
+        assert_eq!((image.width, image.height), (496, 224));
+        assert_eq!(pixel(&image, 0, 0), [16, 8, 200]);
+        assert_eq!(pixel(&image, 1, 0), [17, 8, 200]);
+        assert_eq!(pixel(&image, 495, 0), [255, 8, 200]);
+
+        settings.scale = 2;
+        assert_eq!(settings.output_size(512), (992, 448));
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let mut settings = OutputSettings::for_region(Region::NTSC);
+        settings.scale = 2;
+        settings.aspect_correction = true;
+        settings.scanlines = 0.3;
+        let frame = test_frame(256);
+        assert_eq!(settings.apply(&frame).rgb, settings.apply(&frame).rgb);
+    }
+}
@@ -0,0 +1,286 @@
+use std::f32::consts::PI;
+
+// -----------------------------
+// Palette
+// PPU colour index (6 bits) + emphasis bits (3 bits) -> RGB
+// -----------------------------
+
+// 64 colours, then the same 64 for each of the 7 emphasis combinations
+pub const PALETTE_ENTRIES: usize = 512;
+
+// a 2C02 palette close to what a real NTSC console shows
+#[rustfmt::skip]
+const DEFAULT_2C02: [[u8; 3]; 64] = [
+    [0x80, 0x80, 0x80], [0x00, 0x3D, 0xA6], [0x00, 0x12, 0xB0], [0x44, 0x00, 0x96],
+    [0xA1, 0x00, 0x5E], [0xC7, 0x00, 0x28], [0xBA, 0x06, 0x00], [0x8C, 0x17, 0x00],
+    [0x5C, 0x2F, 0x00], [0x10, 0x45, 0x00], [0x05, 0x4A, 0x00], [0x00, 0x47, 0x2E],
+    [0x00, 0x41, 0x66], [0x00, 0x00, 0x00], [0x05, 0x05, 0x05], [0x05, 0x05, 0x05],
+    [0xC7, 0xC7, 0xC7], [0x00, 0x77, 0xFF], [0x21, 0x55, 0xFF], [0x82, 0x37, 0xFA],
+    [0xEB, 0x2F, 0xB5], [0xFF, 0x29, 0x50], [0xFF, 0x22, 0x00], [0xD6, 0x32, 0x00],
+    [0xC4, 0x62, 0x00], [0x35, 0x80, 0x00], [0x05, 0x8F, 0x00], [0x00, 0x8A, 0x55],
+    [0x00, 0x99, 0xCC], [0x21, 0x21, 0x21], [0x09, 0x09, 0x09], [0x09, 0x09, 0x09],
+    [0xFF, 0xFF, 0xFF], [0x0F, 0xD7, 0xFF], [0x69, 0xA2, 0xFF], [0xD4, 0x80, 0xFF],
+    [0xFF, 0x45, 0xF3], [0xFF, 0x61, 0x8B], [0xFF, 0x88, 0x33], [0xFF, 0x9C, 0x12],
+    [0xFA, 0xBC, 0x20], [0x9F, 0xE3, 0x0E], [0x2B, 0xF0, 0x35], [0x0C, 0xF0, 0xA4],
+    [0x05, 0xFB, 0xFF], [0x5E, 0x5E, 0x5E], [0x0D, 0x0D, 0x0D], [0x0D, 0x0D, 0x0D],
+    [0xFF, 0xFF, 0xFF], [0xA6, 0xFC, 0xFF], [0xB3, 0xEC, 0xFF], [0xDA, 0xAB, 0xEB],
+    [0xFF, 0xA8, 0xF9], [0xFF, 0xAB, 0xB3], [0xFF, 0xD2, 0xB0], [0xFF, 0xEF, 0xA6],
+    [0xFF, 0xF7, 0x9C], [0xD7, 0xE8, 0x95], [0xA6, 0xED, 0xAF], [0xA2, 0xF2, 0xDA],
+    [0x99, 0xFF, 0xFC], [0xDD, 0xDD, 0xDD], [0x11, 0x11, 0x11], [0x11, 0x11, 0x11],
+];
+
+// each emphasis bit darkens the channels it does not emphasize
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+#[derive(Clone)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::from_colours(&DEFAULT_2C02)
+    }
+}
+
+impl Palette {
+    // 64 colours with the emphasis variants derived from them
+    fn from_colours(base: &[[u8; 3]]) -> Palette {
+        let mut colours = Vec::with_capacity(PALETTE_ENTRIES);
+        for emphasis in 0..8 {
+            for (index, colour) in base.iter().enumerate() {
+                colours.push(emphasize(*colour, index, emphasis));
+            }
+        }
+        Palette { colours }
+    }
+
+    // .pal files: 64 RGB triplets, or 512 with the emphasis variants included
+    pub fn from_pal(data: &[u8]) -> Result<Palette, String> {
+        let colours: Vec<[u8; 3]> = data.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+
+        match data.len() {
+            192 => Ok(Self::from_colours(&colours)),
+            1536 => Ok(Palette { colours }),
+            length => Err(format!(
+                "palette file is {} bytes, expected 192 (64 colours) or 1536 (512 colours)",
+                length
+            )),
+        }
+    }
+
+    // decode the composite signal the PPU would generate for every colour
+    pub fn generate_ntsc(settings: &NtscPaletteSettings) -> Palette {
+        let colours = (0..PALETTE_ENTRIES as u16)
+            .map(|entry| ntsc_colour(entry, settings))
+            .collect();
+        Palette { colours }
+    }
+
+    // entry as stored in the PPU frame buffer: colour | emphasis << 6
+    pub fn rgb(&self, entry: u16) -> [u8; 3] {
+        self.colours[entry as usize % PALETTE_ENTRIES]
+    }
+
+    // convert a whole frame buffer to packed RGB bytes
+    pub fn to_rgb(&self, frame_buffer: &[u16]) -> Vec<u8> {
+        frame_buffer
+            .iter()
+            .flat_map(|&entry| self.rgb(entry))
+            .collect()
+    }
+}
+
+fn emphasize(colour: [u8; 3], index: usize, emphasis: usize) -> [u8; 3] {
+    // $xE / $xF are forced black and ignore emphasis
+    if emphasis == 0 || index & 0x0E == 0x0E {
+        return colour;
+    }
+
+    let mut result = colour;
+    for (channel, value) in result.iter_mut().enumerate() {
+        // bit 0 emphasizes red, bit 1 green, bit 2 blue
+        let others = emphasis & !(1 << channel);
+        let darkened = others.count_ones() as i32;
+        *value = (*value as f32 * EMPHASIS_ATTENUATION.powi(darkened)) as u8;
+    }
+    result
+}
+
+// -----------------------------
+// NTSC palette generator
+// -----------------------------
+
+#[derive(Clone, Copy)]
+pub struct NtscPaletteSettings {
+    // hue rotation in degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    pub brightness: f32,
+    // display gamma, 2.2 leaves the decoded signal untouched
+    pub gamma: f32,
+}
+
+impl Default for NtscPaletteSettings {
+    fn default() -> Self {
+        NtscPaletteSettings {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+// signal voltages for luma levels 0-3, low and high half of the wave
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+const SIGNAL_EMPHASIS: f32 = 0.746;
+
+fn ntsc_colour(entry: u16, settings: &NtscPaletteSettings) -> [u8; 3] {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let level = signal_level(entry, phase);
+        let angle = chroma_angle(phase, settings.hue);
+        y += level;
+        i += level * angle.cos();
+        q += level * angle.sin();
+    }
+    yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0, settings)
+}
+
+// composite signal of a colour entry at one of the 12 phases of the colour
+// clock, 0.0 is black and 1.0 white
+pub(crate) fn signal_level(entry: u16, phase: usize) -> f32 {
+    let hue = (entry & 0x0F) as usize;
+    let level = ((entry >> 4) & 0b11) as usize;
+    let emphasis = (entry >> 6) & 0b111;
+
+    let (mut low, mut high) = (SIGNAL_LOW[level], SIGNAL_HIGH[level]);
+    match hue {
+        0x00 => low = high,
+        0x0D => high = low,
+        0x0E | 0x0F => (low, high) = (SIGNAL_BLACK, SIGNAL_BLACK),
+        _ => {}
+    }
+
+    // the chroma wave is a square wave over 12 phases of the colour clock
+    let in_colour_phase = |colour: usize| (colour + phase) % 12 < 6;
+
+    let mut signal = if in_colour_phase(hue) { high } else { low };
+
+    // emphasis attenuates the signal during the red / green / blue phases
+    if hue < 0x0E
+        && ((emphasis & 0b001 != 0 && in_colour_phase(0x0C))
+            || (emphasis & 0b010 != 0 && in_colour_phase(0x04))
+            || (emphasis & 0b100 != 0 && in_colour_phase(0x08)))
+    {
+        signal *= SIGNAL_EMPHASIS;
+    }
+
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+// colour burst reference angle of a phase, plus the hue control in degrees
+pub(crate) fn chroma_angle(phase: usize, hue: f32) -> f32 {
+    PI * (phase as f32 + 3.0) / 6.0 + hue.to_radians()
+}
+
+// demodulated Y, I, Q (before any adjustment) to RGB
+pub(crate) fn yiq_to_rgb(y: f32, i: f32, q: f32, settings: &NtscPaletteSettings) -> [u8; 3] {
+    let y = y * settings.contrast + settings.brightness;
+    let i = i * settings.saturation * 2.0;
+    let q = q * settings.saturation * 2.0;
+
+    // FCC matrix
+    let rgb = [
+        y + 0.946_882 * i + 0.623_557 * q,
+        y - 0.274_788 * i - 0.635_691 * q,
+        y - 1.108_545 * i + 1.709_007 * q,
+    ];
+    rgb.map(|channel| {
+        let corrected = channel.clamp(0.0, 1.0).powf(2.2 / settings.gamma);
+        (corrected * 255.0).round() as u8
+    })
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_palette() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x0D), [0, 0, 0]);
+        assert_eq!(palette.rgb(0x30), [0xFF, 0xFF, 0xFF]);
+        // emphasis darkens the other channels
+        let emphasized = palette.rgb(0x30 | 0b001 << 6);
+        assert_eq!(emphasized[0], 0xFF);
+        assert!(emphasized[1] < 0xFF && emphasized[2] < 0xFF);
+        // but not the forced blacks
+        assert_eq!(palette.rgb(0x1E | 0b111 << 6), palette.rgb(0x1E));
+    }
+
+    #[test]
+    fn test_pal_file_64_colours() {
+        let mut data = vec![0; 192];
+        data[3..6].copy_from_slice(&[1, 2, 3]);
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.rgb(1), [1, 2, 3]);
+        assert_eq!(palette.colours.len(), PALETTE_ENTRIES);
+    }
+
+    #[test]
+    fn test_pal_file_512_colours() {
+        let data: Vec<u8> = (0..1536).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.rgb(0x1FF), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(palette.rgb(0x41), [0x41, 0x41, 0x41]);
+    }
+
+    #[test]
+    fn test_pal_file_bad_size() {
+        assert!(Palette::from_pal(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn test_ntsc_generator() {
+        let palette = Palette::generate_ntsc(&NtscPaletteSettings::default());
+        // greys have no chroma
+        let grey = palette.rgb(0x10);
+        assert!(grey[0].abs_diff(grey[1]) <= 1 && grey[1].abs_diff(grey[2]) <= 1);
+        assert_eq!(palette.rgb(0x0F), [0, 0, 0]);
+        assert_eq!(palette.rgb(0x20), [0xFF, 0xFF, 0xFF]);
+        // $16 is a red, $12 is a blue
+        let red = palette.rgb(0x16);
+        assert!(red[0] > red[1] && red[0] > red[2]);
+        let blue = palette.rgb(0x12);
+        assert!(blue[2] > blue[0] && blue[2] > blue[1]);
+    }
+
+    #[test]
+    fn test_ntsc_generator_settings() {
+        let dim = NtscPaletteSettings {
+            brightness: -0.2,
+            ..NtscPaletteSettings::default()
+        };
+        let normal = Palette::generate_ntsc(&NtscPaletteSettings::default());
+        let dim = Palette::generate_ntsc(&dim);
+        assert!(dim.rgb(0x10)[0] < normal.rgb(0x10)[0]);
+
+        let grey = NtscPaletteSettings {
+            saturation: 0.0,
+            ..NtscPaletteSettings::default()
+        };
+        let red = Palette::generate_ntsc(&grey).rgb(0x16);
+        assert_eq!(red[0], red[1]);
+    }
+}
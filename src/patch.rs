@@ -0,0 +1,598 @@
+use crate::checksum::crc32;
+use std::path::PathBuf;
+
+// -----------------------------
+// Soft patching
+// ROM hacks and translations are applied in memory, the dump is never touched
+// -----------------------------
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// game.nes -> game.ips / game.ups / game.bps, first one found wins;
+// companion_path maps an extension to the file next to the ROM
+pub fn find_patch(companion_path: impl Fn(&str) -> PathBuf) -> Option<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|extension| companion_path(extension))
+        .find(|path| path.is_file())
+}
+
+// pick the format from the patch's magic bytes
+pub fn apply_patch(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_HEADER) {
+        apply_ips(source, patch)
+    } else if patch.starts_with(UPS_HEADER) {
+        apply_ups(source, patch)
+    } else if patch.starts_with(BPS_HEADER) {
+        apply_bps(source, patch)
+    } else {
+        Err("patch is not in IPS, UPS or BPS format".to_string())
+    }
+}
+
+// -----------------------------
+// IPS patches
+// "PATCH", records of (offset, size, data), "EOF"
+// -----------------------------
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+
+// an offset of 0x454F46 would be read back as the "EOF" marker
+const IPS_EOF_OFFSET: usize = 0x45_4F46;
+const IPS_MAX_OFFSET: usize = 0xFF_FFFF;
+const IPS_MAX_RECORD: usize = 0xFFFF;
+
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if !patch.starts_with(IPS_HEADER) {
+        return Err("IPS patch is missing the PATCH header".to_string());
+    }
+
+    let mut output = source.to_vec();
+    let mut position = IPS_HEADER.len();
+
+    loop {
+        let record = patch
+            .get(position..position + 3)
+            .ok_or("IPS patch ends before the EOF marker")?;
+        position += 3;
+
+        if record == IPS_FOOTER {
+            break;
+        }
+
+        let offset = (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+        let size = read_u16_be(patch, position)? as usize;
+        position += 2;
+
+        if size == 0 {
+            // RLE record: run length followed by the value to repeat
+            let length = read_u16_be(patch, position)? as usize;
+            let value = *patch
+                .get(position + 2)
+                .ok_or("IPS RLE record is truncated")?;
+            position += 3;
+
+            resize_to_fit(&mut output, offset + length);
+            output[offset..offset + length].fill(value);
+        } else {
+            let data = patch
+                .get(position..position + size)
+                .ok_or("IPS record data is truncated")?;
+            position += size;
+
+            resize_to_fit(&mut output, offset + size);
+            output[offset..offset + size].copy_from_slice(data);
+        }
+    }
+
+    // optional truncation extension after the EOF marker
+    if let Some(size) = patch.get(position..position + 3) {
+        let size = (size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize;
+        output.truncate(size);
+    }
+
+    Ok(output)
+}
+
+pub fn create_ips(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut patch = IPS_HEADER.to_vec();
+    let length = modified.len().min(IPS_MAX_OFFSET);
+    let differs = |i: usize| original.get(i) != Some(&modified[i]);
+
+    let mut i = 0;
+    while i < length {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+
+        // step back one byte rather than write a record at the EOF offset
+        let start = if i == IPS_EOF_OFFSET { i - 1 } else { i };
+        let mut end = i;
+        while end < length && end - start < IPS_MAX_RECORD && differs(end) {
+            end += 1;
+        }
+
+        patch.push((start >> 16) as u8);
+        patch.push((start >> 8) as u8);
+        patch.push(start as u8);
+        patch.push(((end - start) >> 8) as u8);
+        patch.push((end - start) as u8);
+        patch.extend_from_slice(&modified[start..end]);
+
+        i = end;
+    }
+
+    patch.extend_from_slice(IPS_FOOTER);
+
+    if modified.len() < original.len() {
+        let size = modified.len();
+        patch.push((size >> 16) as u8);
+        patch.push((size >> 8) as u8);
+        patch.push(size as u8);
+    }
+
+    patch
+}
+
+// -----------------------------
+// UPS patches
+// "UPS1", sizes, XOR runs, then source / target / patch CRC32
+// -----------------------------
+
+const UPS_HEADER: &[u8] = b"UPS1";
+const BPS_HEADER: &[u8] = b"BPS1";
+const FOOTER_SIZE: usize = 12;
+
+// sizes are read from the patch before its actions are checked, so a
+// crafted header must not be able to ask for gigabytes; the largest
+// cartridges are a few MiB
+pub(crate) const MAX_TARGET_SIZE: usize = 16 * 1024 * 1024;
+
+pub fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let footer = check_footer("UPS", source, patch)?;
+
+    let mut reader = PatchReader::new(patch, UPS_HEADER.len());
+    let source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    if source_size != source.len() {
+        return Err(format!(
+            "UPS patch expects a {} byte ROM, this one is {} bytes",
+            source_size,
+            source.len()
+        ));
+    }
+    check_target_size("UPS", target_size)?;
+
+    let mut output = source.to_vec();
+    output.resize(target_size, 0);
+
+    let mut position: usize = 0;
+    while reader.position < footer {
+        position = position
+            .checked_add(reader.read_number()?)
+            .ok_or("UPS patch skips past the end of the target")?;
+        loop {
+            let value = reader.read_byte()?;
+            if value == 0 {
+                position += 1;
+                break;
+            }
+            let byte = output
+                .get_mut(position)
+                .ok_or("UPS patch writes past the end of the target")?;
+            *byte ^= value;
+            position += 1;
+        }
+    }
+
+    check_target_crc("UPS", &output, patch)?;
+    Ok(output)
+}
+
+// -----------------------------
+// BPS patches
+// "BPS1", sizes, metadata, copy actions, then source / target / patch CRC32
+// -----------------------------
+
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let footer = check_footer("BPS", source, patch)?;
+
+    let mut reader = PatchReader::new(patch, BPS_HEADER.len());
+    let source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    let metadata_size = reader.read_number()?;
+    reader.position = reader
+        .position
+        .checked_add(metadata_size)
+        .filter(|position| *position <= footer)
+        .ok_or("BPS metadata runs past the end of the patch")?;
+
+    if source_size != source.len() {
+        return Err(format!(
+            "BPS patch expects a {} byte ROM, this one is {} bytes",
+            source_size,
+            source.len()
+        ));
+    }
+    check_target_size("BPS", target_size)?;
+
+    let mut output: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_relative: i64 = 0;
+    let mut target_relative: i64 = 0;
+
+    while reader.position < footer {
+        let data = reader.read_number()?;
+        let command = data & 0b11;
+        let length = (data >> 2) + 1;
+        if length > target_size - output.len() {
+            return Err(format!(
+                "BPS patch writes past the {} byte target",
+                target_size
+            ));
+        }
+
+        match command {
+            // SourceRead: same offset in the source
+            0 => {
+                let start = output.len();
+                let bytes = source
+                    .get(start..start + length)
+                    .ok_or("BPS SourceRead is past the end of the source")?;
+                output.extend_from_slice(bytes);
+            }
+            // TargetRead: literal bytes from the patch
+            1 => {
+                for _ in 0..length {
+                    output.push(reader.read_byte()?);
+                }
+            }
+            // SourceCopy: relative seek in the source
+            2 => {
+                source_relative = source_relative
+                    .checked_add(reader.read_signed_number()?)
+                    .ok_or("BPS SourceCopy seeks out of range")?;
+                let start = usize::try_from(source_relative)
+                    .map_err(|_| "BPS SourceCopy seeks before the source")?;
+                let bytes = start
+                    .checked_add(length)
+                    .and_then(|end| source.get(start..end))
+                    .ok_or("BPS SourceCopy is past the end of the source")?;
+                output.extend_from_slice(bytes);
+                source_relative += length as i64;
+            }
+            // TargetCopy: relative seek in the output, may overlap itself
+            _ => {
+                target_relative = target_relative
+                    .checked_add(reader.read_signed_number()?)
+                    .ok_or("BPS TargetCopy seeks out of range")?;
+                for _ in 0..length {
+                    let byte = usize::try_from(target_relative)
+                        .ok()
+                        .and_then(|i| output.get(i).copied())
+                        .ok_or("BPS TargetCopy reads outside the target")?;
+                    output.push(byte);
+                    target_relative += 1;
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(format!(
+            "BPS patch produced {} bytes, header says {}",
+            output.len(),
+            target_size
+        ));
+    }
+
+    check_target_crc("BPS", &output, patch)?;
+    Ok(output)
+}
+
+fn check_target_size(format: &str, target_size: usize) -> Result<(), String> {
+    if target_size > MAX_TARGET_SIZE {
+        return Err(format!(
+            "{} patch target of {} bytes is too large",
+            format, target_size
+        ));
+    }
+    Ok(())
+}
+
+// validate the patch and source CRC32, returns where the actions end
+fn check_footer(format: &str, source: &[u8], patch: &[u8]) -> Result<usize, String> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        return Err(format!("{} patch is truncated", format));
+    }
+    let footer = patch.len() - FOOTER_SIZE;
+
+    let patch_crc = read_u32_le(patch, footer + 8);
+    if crc32(&patch[..footer + 8]) != patch_crc {
+        return Err(format!(
+            "{} patch is corrupt (patch CRC32 mismatch)",
+            format
+        ));
+    }
+
+    let expected = read_u32_le(patch, footer);
+    let actual = crc32(source);
+    if expected != actual {
+        return Err(format!(
+            "{} patch is for a different ROM: expected CRC32 {:08X}, got {:08X}",
+            format, expected, actual
+        ));
+    }
+
+    Ok(footer)
+}
+
+fn check_target_crc(format: &str, output: &[u8], patch: &[u8]) -> Result<(), String> {
+    let expected = read_u32_le(patch, patch.len() - FOOTER_SIZE + 4);
+    let actual = crc32(output);
+    if expected != actual {
+        return Err(format!(
+            "{} patched ROM has the wrong CRC32: expected {:08X}, got {:08X}",
+            format, expected, actual
+        ));
+    }
+    Ok(())
+}
+
+fn read_u32_le(data: &[u8], position: usize) -> u32 {
+    u32::from_le_bytes([
+        data[position],
+        data[position + 1],
+        data[position + 2],
+        data[position + 3],
+    ])
+}
+
+// UPS / BPS variable length numbers
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], position: usize) -> PatchReader<'a> {
+        PatchReader { data, position }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, String> {
+        let value = *self
+            .data
+            .get(self.position)
+            .ok_or("patch ends in the middle of a record")?;
+        self.position += 1;
+        Ok(value)
+    }
+
+    // 7 bits per byte, high bit ends the number, each continuation adds one
+    fn read_number(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.read_byte()?;
+            value = value
+                .checked_add((byte & 0x7F) as usize * shift)
+                .ok_or("patch number overflows")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or("patch number overflows")?;
+            value = value.checked_add(shift).ok_or("patch number overflows")?;
+        }
+    }
+
+    // low bit is the sign
+    fn read_signed_number(&mut self) -> Result<i64, String> {
+        let value = self.read_number()?;
+        let magnitude = (value >> 1) as i64;
+        Ok(if value & 1 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+}
+
+fn read_u16_be(data: &[u8], position: usize) -> Result<u16, String> {
+    match data.get(position..position + 2) {
+        Some(bytes) => Ok((bytes[0] as u16) << 8 | bytes[1] as u16),
+        None => Err("IPS record header is truncated".to_string()),
+    }
+}
+
+fn resize_to_fit(output: &mut Vec<u8>, length: usize) {
+    if output.len() < length {
+        output.resize(length, 0);
+    }
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ips_round_trip() {
+        let original = vec![0u8; 64];
+        let mut modified = original.clone();
+        modified[3] = 0x11;
+        modified[4] = 0x22;
+        modified[40] = 0x33;
+
+        let patch = create_ips(&original, &modified);
+        assert_eq!(apply_ips(&original, &patch).unwrap(), modified);
+    }
+
+    #[test]
+    fn test_ips_unchanged_is_empty_patch() {
+        let data = vec![1, 2, 3];
+        assert_eq!(create_ips(&data, &data), b"PATCHEOF".to_vec());
+    }
+
+    #[test]
+    fn test_ips_rle_record() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0xAA]);
+        patch.extend_from_slice(b"EOF");
+
+        let output = apply_ips(&[0; 8], &patch).unwrap();
+        assert_eq!(output, vec![0, 0, 0xAA, 0xAA, 0xAA, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_ips_grows_and_truncates() {
+        let original = vec![1, 2, 3, 4];
+        let longer = vec![1, 2, 3, 4, 5, 6];
+        let shorter = vec![1, 2];
+
+        let patch = create_ips(&original, &longer);
+        assert_eq!(apply_ips(&original, &patch).unwrap(), longer);
+
+        let patch = create_ips(&original, &shorter);
+        assert_eq!(apply_ips(&original, &patch).unwrap(), shorter);
+    }
+
+    fn encode_number(mut value: usize, output: &mut Vec<u8>) {
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                output.push(0x80 | x);
+                break;
+            }
+            output.push(x);
+            value -= 1;
+        }
+    }
+
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = vec![1, 2, 3, 4, 5];
+        let target = vec![1, 9, 3, 4, 5, 6];
+
+        let mut patch = b"UPS1".to_vec();
+        encode_number(source.len(), &mut patch);
+        encode_number(target.len(), &mut patch);
+        // skip 1, xor 2^9, terminator
+        encode_number(1, &mut patch);
+        patch.extend_from_slice(&[2 ^ 9, 0x00]);
+        // skip to offset 5 and xor 0^6
+        encode_number(2, &mut patch);
+        patch.extend_from_slice(&[6, 0x00]);
+        let patch = finish(patch, &source, &target);
+
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"HELLO WORLD".to_vec();
+        let target = b"HELLO HELLO!!!".to_vec();
+
+        let mut patch = b"BPS1".to_vec();
+        encode_number(source.len(), &mut patch);
+        encode_number(target.len(), &mut patch);
+        encode_number(0, &mut patch);
+        // SourceRead "HELLO "
+        encode_number((6 - 1) << 2, &mut patch);
+        // SourceCopy "HELLO" from offset 0
+        encode_number(((5 - 1) << 2) | 2, &mut patch);
+        encode_number(0, &mut patch);
+        // TargetRead "!"
+        encode_number(1, &mut patch);
+        patch.push(b'!');
+        // TargetCopy "!!" from the byte just written
+        encode_number(((2 - 1) << 2) | 3, &mut patch);
+        encode_number(11 << 1, &mut patch);
+        let patch = finish(patch, &source, &target);
+
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_checksum_errors() {
+        let source = vec![1, 2, 3];
+        let mut patch = b"UPS1".to_vec();
+        encode_number(3, &mut patch);
+        encode_number(3, &mut patch);
+        let patch = finish(patch, &source, &source);
+
+        // wrong ROM
+        let error = apply_patch(&[4, 5, 6], &patch).unwrap_err();
+        assert!(error.contains("different ROM"));
+
+        // corrupted patch
+        let mut corrupt = patch.clone();
+        corrupt[5] ^= 0xFF;
+        let error = apply_patch(&source, &corrupt).unwrap_err();
+        assert!(error.contains("corrupt"));
+
+        assert!(apply_patch(&source, b"NOT A PATCH").is_err());
+    }
+
+    #[test]
+    fn test_crafted_sizes() {
+        let source = vec![1, 2, 3];
+
+        // a target far larger than any cartridge
+        for header in [b"UPS1", b"BPS1"] {
+            let mut patch = header.to_vec();
+            encode_number(3, &mut patch);
+            encode_number(1 << 40, &mut patch);
+            encode_number(0, &mut patch);
+            let patch = finish(patch, &source, &source);
+            let error = apply_patch(&source, &patch).unwrap_err();
+            assert!(error.contains("too large"), "{}", error);
+        }
+
+        // metadata longer than the patch
+        let mut patch = b"BPS1".to_vec();
+        encode_number(3, &mut patch);
+        encode_number(3, &mut patch);
+        encode_number(usize::MAX >> 8, &mut patch);
+        let patch = finish(patch, &source, &source);
+        assert!(apply_patch(&source, &patch).is_err());
+
+        // a number that does not fit in usize
+        let mut patch = b"UPS1".to_vec();
+        patch.extend_from_slice(&[0x7F; 12]);
+        patch.push(0x80);
+        let patch = finish(patch, &source, &source);
+        let error = apply_patch(&source, &patch).unwrap_err();
+        assert!(error.contains("overflows"), "{}", error);
+
+        // a TargetCopy that keeps copying itself past the target size
+        let mut patch = b"BPS1".to_vec();
+        encode_number(3, &mut patch);
+        encode_number(4, &mut patch);
+        encode_number(0, &mut patch);
+        encode_number(1, &mut patch);
+        patch.push(0);
+        encode_number(((1 << 30) << 2) | 3, &mut patch);
+        encode_number(0, &mut patch);
+        let patch = finish(patch, &source, &[0; 4]);
+        assert!(apply_patch(&source, &patch).is_err());
+    }
+
+    #[test]
+    fn test_ips_bad_header() {
+        assert!(apply_ips(&[0; 4], b"NOPE").is_err());
+        assert!(apply_ips(&[0; 4], b"PATCH\x00\x00").is_err());
+    }
+}
@@ -0,0 +1,1262 @@
+use crate::cartridge::Mirroring;
+use crate::region::Region;
+
+// -----------------------------
+// PPU 2C02
+// registers $2000-$2007, VRAM, palette RAM and OAM
+// -----------------------------
+
+const CHR_RAM_SIZE: usize = 8192;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+
+// PPUCTRL ($2000)
+pub const CTRL_VRAM_INCREMENT: u8 = 0b0000_0100;
+pub const CTRL_SPRITE_TABLE: u8 = 0b0000_1000;
+pub const CTRL_BACKGROUND_TABLE: u8 = 0b0001_0000;
+pub const CTRL_SPRITE_SIZE: u8 = 0b0010_0000;
+pub const CTRL_GENERATE_NMI: u8 = 0b1000_0000;
+
+// PPUMASK ($2001)
+pub const MASK_GREYSCALE: u8 = 0b0000_0001;
+pub const MASK_SHOW_BACKGROUND_LEFT: u8 = 0b0000_0010;
+pub const MASK_SHOW_SPRITES_LEFT: u8 = 0b0000_0100;
+pub const MASK_SHOW_BACKGROUND: u8 = 0b0000_1000;
+pub const MASK_SHOW_SPRITES: u8 = 0b0001_0000;
+pub const MASK_EMPHASIZE_RED: u8 = 0b0010_0000;
+pub const MASK_EMPHASIZE_GREEN: u8 = 0b0100_0000;
+pub const MASK_EMPHASIZE_BLUE: u8 = 0b1000_0000;
+
+// PPUSTATUS ($2002)
+pub const STATUS_SPRITE_OVERFLOW: u8 = 0b0010_0000;
+pub const STATUS_SPRITE_ZERO_HIT: u8 = 0b0100_0000;
+pub const STATUS_VBLANK: u8 = 0b1000_0000;
+
+pub struct PPU {
+    // pattern tables: CHR ROM from the cartridge or 8 KiB of CHR RAM
+    pub chr: Vec<u8>,
+    chr_is_ram: bool,
+    // 4 KiB banks of chr seen at $0000 and $1000, set by the mapper
+    pub chr_banks: [usize; 2],
+
+    // 2 KiB of nametables inside the console, plus the extra 2 KiB
+    // four screen boards carry on the cartridge
+    pub vram: [u8; 2048],
+    four_screen_vram: [u8; 2048],
+    pub mirroring: Mirroring,
+
+    pub palette: [u8; 32],
+    pub oam: [u8; 256],
+
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    oam_address: u8,
+
+    // loopy registers: current / temporary VRAM address, fine X scroll
+    // and the write toggle shared by PPUSCROLL and PPUADDR
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    w: bool,
+
+    read_buffer: u8,
+    // last value written to or read from a register (open bus)
+    io_latch: u8,
+
+    // background fetch latches and the shift registers they feed
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_low: u8,
+    next_pattern_high: u8,
+    pattern_shift_low: u16,
+    pattern_shift_high: u16,
+    attribute_shift_low: u16,
+    attribute_shift_high: u16,
+
+    // 256x240 system palette indices, one per pixel: colour in bits 0-5,
+    // PPUMASK emphasis bits (red, green, blue) in bits 6-8
+    pub frame_buffer: Vec<u16>,
+
+    // 2C02 (NTSC / Dendy) or 2C07 (PAL) frame timing
+    pub region: Region,
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame: u64,
+
+    nmi_pending: bool,
+    // set when the last visible line is done and frame_buffer is complete
+    frame_ready: bool,
+
+    // sprites found by evaluation for the line being drawn
+    line_sprites: Vec<LineSprite>,
+    // draw every sprite on a line instead of the first 8 (less flicker,
+    // not what the hardware does)
+    pub disable_sprite_limit: bool,
+}
+
+// a sprite picked for the current scanline, pattern already fetched
+#[derive(Clone, Copy)]
+struct LineSprite {
+    x: u8,
+    pattern_low: u8,
+    pattern_high: u8,
+    attributes: u8,
+    sprite_zero: bool,
+}
+
+// OAM attribute byte
+pub const SPRITE_PALETTE: u8 = 0b0000_0011;
+pub const SPRITE_BEHIND_BACKGROUND: u8 = 0b0010_0000;
+pub const SPRITE_FLIP_HORIZONTAL: u8 = 0b0100_0000;
+pub const SPRITE_FLIP_VERTICAL: u8 = 0b1000_0000;
+
+const SPRITES_PER_LINE: usize = 8;
+
+impl PPU {
+    // an empty chr means the board uses CHR RAM
+    pub fn new(chr: Vec<u8>, mirroring: Mirroring) -> PPU {
+        let chr_is_ram = chr.is_empty();
+        PPU {
+            chr: if chr_is_ram {
+                vec![0; CHR_RAM_SIZE]
+            } else {
+                chr
+            },
+            chr_is_ram,
+            chr_banks: [0, 1],
+            vram: [0; 2048],
+            four_screen_vram: [0; 2048],
+            mirroring,
+            palette: [0; 32],
+            oam: [0; 256],
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_address: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            next_tile: 0,
+            next_attribute: 0,
+            next_pattern_low: 0,
+            next_pattern_high: 0,
+            pattern_shift_low: 0,
+            pattern_shift_high: 0,
+            attribute_shift_low: 0,
+            attribute_shift_high: 0,
+            frame_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            region: Region::NTSC,
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            nmi_pending: false,
+            frame_ready: false,
+            line_sprites: Vec::new(),
+            disable_sprite_limit: false,
+        }
+    }
+
+    // -----------------------------
+    // CPU bus ($2000-$2007, mirrored to $3FFF)
+    // -----------------------------
+
+    pub fn read_register(&mut self, address: u16) -> u8 {
+        match address & 0x0007 {
+            // PPUSTATUS: reading clears vblank and the write toggle
+            2 => {
+                let value = (self.status & 0b1110_0000) | (self.io_latch & 0b0001_1111);
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                self.io_latch = value;
+            }
+            // OAMDATA, attribute bytes have no bits 2-4
+            4 => {
+                let mut value = self.oam[self.oam_address as usize];
+                if self.oam_address & 0b11 == 2 {
+                    value &= 0b1110_0011;
+                }
+                self.io_latch = value;
+            }
+            // PPUDATA
+            7 => {
+                let address = self.v & 0x3FFF;
+                let value = if address >= 0x3F00 {
+                    // palette reads are not buffered, the buffer gets the
+                    // nametable byte "under" the palette instead
+                    self.read_buffer = self.read_vram(address - 0x1000);
+                    (self.read_vram(address) & 0b0011_1111) | (self.io_latch & 0b1100_0000)
+                } else {
+                    let buffered = self.read_buffer;
+                    self.read_buffer = self.read_vram(address);
+                    buffered
+                };
+                self.increment_vram_address();
+                self.io_latch = value;
+            }
+            // PPUCTRL, PPUMASK, OAMADDR, PPUSCROLL, PPUADDR are write only
+            _ => {}
+        }
+        self.io_latch
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        self.io_latch = value;
+
+        match address & 0x0007 {
+            // PPUCTRL
+            0 => {
+                let was_enabled = self.ctrl & CTRL_GENERATE_NMI != 0;
+                self.ctrl = value;
+                // nametable select goes to t bits 10-11
+                self.t = (self.t & !0x0C00) | ((value & 0b11) as u16) << 10;
+
+                // enabling NMI during vblank fires one straight away
+                if !was_enabled
+                    && value & CTRL_GENERATE_NMI != 0
+                    && self.status & STATUS_VBLANK != 0
+                {
+                    self.nmi_pending = true;
+                }
+            }
+            // PPUMASK
+            1 => self.mask = value,
+            // OAMADDR
+            3 => self.oam_address = value,
+            // OAMDATA
+            4 => self.write_oam_data(value),
+            // PPUSCROLL
+            5 => {
+                if !self.w {
+                    self.t = (self.t & !0x001F) | (value >> 3) as u16;
+                    self.fine_x = value & 0b111;
+                } else {
+                    self.t = (self.t & !0x73E0)
+                        | ((value & 0b111) as u16) << 12
+                        | ((value >> 3) as u16) << 5;
+                }
+                self.w = !self.w;
+            }
+            // PPUADDR
+            6 => {
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | ((value & 0b0011_1111) as u16) << 8;
+                } else {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            // PPUDATA
+            7 => {
+                self.write_vram(self.v & 0x3FFF, value);
+                self.increment_vram_address();
+            }
+            // PPUSTATUS is read only
+            _ => {}
+        }
+    }
+
+    pub fn write_oam_data(&mut self, value: u8) {
+        self.oam[self.oam_address as usize] = value;
+        self.oam_address = self.oam_address.wrapping_add(1);
+    }
+
+    fn increment_vram_address(&mut self) {
+        let step = if self.ctrl & CTRL_VRAM_INCREMENT != 0 {
+            32
+        } else {
+            1
+        };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+
+    // -----------------------------
+    // PPU bus ($0000-$3FFF)
+    // -----------------------------
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        match address & 0x3FFF {
+            0x0000..=0x1FFF => self.chr[self.chr_index(address)],
+            0x2000..=0x3EFF => {
+                let (four_screen, index) = self.nametable_index(address);
+                if four_screen {
+                    self.four_screen_vram[index]
+                } else {
+                    self.vram[index]
+                }
+            }
+            _ => self.palette[palette_index(address)],
+        }
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        match address & 0x3FFF {
+            0x0000..=0x1FFF => {
+                if self.chr_is_ram {
+                    let index = self.chr_index(address);
+                    self.chr[index] = value;
+                }
+            }
+            0x2000..=0x3EFF => {
+                let (four_screen, index) = self.nametable_index(address);
+                if four_screen {
+                    self.four_screen_vram[index] = value;
+                } else {
+                    self.vram[index] = value;
+                }
+            }
+            _ => self.palette[palette_index(address)] = value & 0b0011_1111,
+        }
+    }
+
+    fn chr_index(&self, address: u16) -> usize {
+        let bank = self.chr_banks[(address >> 12) as usize & 1];
+        (bank * 0x1000 + (address & 0x0FFF) as usize) % self.chr.len()
+    }
+
+    // fold the four logical nametables onto the physical 1 KiB pages
+    fn nametable_index(&self, address: u16) -> (bool, usize) {
+        let address = (address - 0x2000) as usize % 0x1000;
+        let table = address / 0x400;
+        let offset = address % 0x400;
+
+        let page = match (self.mirroring, table) {
+            (Mirroring::Horizontal, 0 | 1) => 0,
+            (Mirroring::Horizontal, _) => 1,
+            (Mirroring::Vertical, 0 | 2) => 0,
+            (Mirroring::Vertical, _) => 1,
+            (Mirroring::SingleScreenLower, _) => 0,
+            (Mirroring::SingleScreenUpper, _) => 1,
+            (Mirroring::FourScreen, 0 | 1) => table,
+            (Mirroring::FourScreen, _) => return (true, (table - 2) * 0x400 + offset),
+        };
+        (false, page * 0x400 + offset)
+    }
+
+    // -----------------------------
+    // Timing
+    // -----------------------------
+
+    // NMI line, cleared once the CPU has seen it
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
+    }
+
+    // true once per frame, when the picture is complete
+    pub fn poll_frame(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    // one PPU dot, three of them per CPU cycle
+    pub fn clock(&mut self) {
+        let visible_line = (self.scanline as usize) < SCREEN_HEIGHT;
+        let pre_render_line = self.scanline == self.pre_render_scanline();
+
+        if self.rendering_enabled() && (visible_line || pre_render_line) {
+            self.background_fetch();
+
+            if self.dot == 257 {
+                self.evaluate_sprites();
+            }
+            // OAMADDR is cleared during the sprite tile loading interval
+            if (257..=320).contains(&self.dot) {
+                self.oam_address = 0;
+            }
+        }
+
+        if visible_line && (1..=256).contains(&self.dot) {
+            self.render_pixel();
+        }
+
+        if self.scanline as usize == SCREEN_HEIGHT && self.dot == 0 {
+            self.frame_ready = true;
+        }
+
+        if self.scanline == self.region.vblank_scanline() && self.dot == 1 {
+            self.status |= STATUS_VBLANK;
+            if self.ctrl & CTRL_GENERATE_NMI != 0 {
+                self.nmi_pending = true;
+            }
+        }
+
+        if pre_render_line && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+        }
+
+        self.dot += 1;
+
+        // NTSC odd frames skip the last dot of the pre-render line while rendering
+        if self.region == Region::NTSC
+            && pre_render_line
+            && self.dot == DOTS_PER_SCANLINE - 1
+            && self.frame % 2 == 1
+            && self.rendering_enabled()
+        {
+            self.dot += 1;
+        }
+
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == self.region.scanlines_per_frame() {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+    }
+
+    // scroll origin from t and fine X, in pixels across the 512x480 nametable space
+    pub fn scroll_position(&self) -> (usize, usize) {
+        let x = ((self.t >> 10) & 1) * 256 + (self.t & 0x1F) * 8 + self.fine_x as u16;
+        let y = ((self.t >> 11) & 1) * 240 + ((self.t >> 5) & 0x1F) * 8 + ((self.t >> 12) & 0b111);
+        (x as usize, y as usize)
+    }
+
+    // the last line of the frame
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.region.scanlines_per_frame() - 1
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES) != 0
+    }
+
+    // -----------------------------
+    // Background
+    // tile fetches every 8 dots, loopy v/t scrolling
+    // -----------------------------
+
+    fn background_fetch(&mut self) {
+        let dot = self.dot;
+
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_background();
+
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.next_tile = self.read_vram(0x2000 | (self.v & 0x0FFF));
+                }
+                2 => {
+                    let address = 0x23C0
+                        | (self.v & 0x0C00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    // pick the 2x2 tile quadrant inside the attribute byte
+                    let shift = ((self.v >> 4) & 0b100) | (self.v & 0b10);
+                    self.next_attribute = (self.read_vram(address) >> shift) & 0b11;
+                }
+                4 => self.next_pattern_low = self.read_vram(self.background_pattern_address()),
+                6 => self.next_pattern_high = self.read_vram(self.background_pattern_address() + 8),
+                7 => self.increment_coarse_x(),
+                _ => {}
+            }
+        }
+
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.load_background_shifters();
+                // horizontal copy: coarse X and nametable X from t
+                self.v = (self.v & !0x041F) | (self.t & 0x041F);
+            }
+            // vertical copy: fine Y, coarse Y and nametable Y from t
+            280..=304 if self.scanline == self.pre_render_scanline() => {
+                self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+            }
+            _ => {}
+        }
+    }
+
+    fn background_pattern_address(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        let fine_y = (self.v >> 12) & 0b111;
+        table + self.next_tile as u16 * 16 + fine_y
+    }
+
+    fn shift_background(&mut self) {
+        self.pattern_shift_low <<= 1;
+        self.pattern_shift_high <<= 1;
+        self.attribute_shift_low <<= 1;
+        self.attribute_shift_high <<= 1;
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.pattern_shift_low = (self.pattern_shift_low & 0xFF00) | self.next_pattern_low as u16;
+        self.pattern_shift_high =
+            (self.pattern_shift_high & 0xFF00) | self.next_pattern_high as u16;
+
+        let attribute_low = if self.next_attribute & 0b01 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        let attribute_high = if self.next_attribute & 0b10 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        self.attribute_shift_low = (self.attribute_shift_low & 0xFF00) | attribute_low;
+        self.attribute_shift_high = (self.attribute_shift_high & 0xFF00) | attribute_high;
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            // wrap into the horizontally adjacent nametable
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            // wrap into the vertically adjacent nametable
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // rows 30 and 31 are attribute data, wrap without switching
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // background pixel (0-3) and palette (0-3) under the current dot
+    fn background_pixel(&self, x: usize) -> (u8, u8) {
+        if self.mask & MASK_SHOW_BACKGROUND == 0
+            || (x < 8 && self.mask & MASK_SHOW_BACKGROUND_LEFT == 0)
+        {
+            return (0, 0);
+        }
+
+        let bit = 0x8000 >> self.fine_x;
+        let pixel = ((self.pattern_shift_high & bit != 0) as u8) << 1
+            | (self.pattern_shift_low & bit != 0) as u8;
+        let palette = ((self.attribute_shift_high & bit != 0) as u8) << 1
+            | (self.attribute_shift_low & bit != 0) as u8;
+        (pixel, palette)
+    }
+
+    // -----------------------------
+    // Sprites
+    // evaluated at the end of each line for the next one
+    // -----------------------------
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_SIZE != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn evaluate_sprites(&mut self) {
+        self.line_sprites.clear();
+        // nothing is drawn on the line after the pre-render line
+        if self.scanline == self.pre_render_scanline() {
+            return;
+        }
+
+        let line = self.scanline;
+        let height = self.sprite_height();
+        let in_range = |y: u8| line.wrapping_sub(y as u16) < height;
+
+        let mut found = Vec::new();
+        let mut n = 0;
+        while n < 64 && found.len() < SPRITES_PER_LINE {
+            if in_range(self.oam[n * 4]) {
+                found.push(n);
+            }
+            n += 1;
+        }
+
+        // overflow check: the hardware bumps the byte offset along with the
+        // sprite index, so it compares tile / attribute / X bytes as Y
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+
+        if self.disable_sprite_limit {
+            found = (0..64).filter(|&n| in_range(self.oam[n * 4])).collect();
+        }
+
+        for n in found {
+            let sprite = self.fetch_line_sprite(n, line);
+            self.line_sprites.push(sprite);
+        }
+    }
+
+    fn fetch_line_sprite(&self, n: usize, line: u16) -> LineSprite {
+        let y = self.oam[n * 4];
+        let tile = self.oam[n * 4 + 1];
+        let attributes = self.oam[n * 4 + 2];
+        let x = self.oam[n * 4 + 3];
+
+        let height = self.sprite_height();
+        let mut row = line.wrapping_sub(y as u16);
+        if attributes & SPRITE_FLIP_VERTICAL != 0 {
+            row = height - 1 - row;
+        }
+
+        let address = if height == 16 {
+            // 8x16 sprites take the pattern table from bit 0 of the tile
+            let table = (tile as u16 & 1) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + row / 8;
+            table + tile * 16 + row % 8
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                0x1000
+            } else {
+                0
+            };
+            table + tile as u16 * 16 + row
+        };
+
+        let mut pattern_low = self.read_vram(address);
+        let mut pattern_high = self.read_vram(address + 8);
+        if attributes & SPRITE_FLIP_HORIZONTAL != 0 {
+            pattern_low = pattern_low.reverse_bits();
+            pattern_high = pattern_high.reverse_bits();
+        }
+
+        LineSprite {
+            x,
+            pattern_low,
+            pattern_high,
+            attributes,
+            sprite_zero: n == 0,
+        }
+    }
+
+    // first opaque sprite pixel at x: (pixel, palette, behind background, sprite 0)
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, u8, bool, bool)> {
+        if self.mask & MASK_SHOW_SPRITES == 0 || (x < 8 && self.mask & MASK_SHOW_SPRITES_LEFT == 0)
+        {
+            return None;
+        }
+
+        self.line_sprites.iter().find_map(|sprite| {
+            let offset = x.wrapping_sub(sprite.x as usize);
+            if offset >= 8 {
+                return None;
+            }
+            let bit = 0x80 >> offset;
+            let pixel = ((sprite.pattern_high & bit != 0) as u8) << 1
+                | (sprite.pattern_low & bit != 0) as u8;
+            if pixel == 0 {
+                return None;
+            }
+            Some((
+                pixel,
+                sprite.attributes & SPRITE_PALETTE,
+                sprite.attributes & SPRITE_BEHIND_BACKGROUND != 0,
+                sprite.sprite_zero,
+            ))
+        })
+    }
+
+    // -----------------------------
+    // Pixel output
+    // background and sprite priority, sprite 0 hit
+    // -----------------------------
+
+    fn render_pixel(&mut self) {
+        let x = self.dot as usize - 1;
+        let y = self.scanline as usize;
+
+        let colour = if self.rendering_enabled() {
+            let (background, background_palette) = self.background_pixel(x);
+            let sprite = self.sprite_pixel(x);
+
+            let index = match sprite {
+                None if background == 0 => 0,
+                None => background_palette * 4 + background,
+                Some((pixel, palette, behind, sprite_zero)) => {
+                    if sprite_zero && background != 0 && x != 255 {
+                        self.status |= STATUS_SPRITE_ZERO_HIT;
+                    }
+                    if behind && background != 0 {
+                        background_palette * 4 + background
+                    } else {
+                        0x10 + palette * 4 + pixel
+                    }
+                }
+            };
+            self.palette[palette_index(index as u16)]
+        } else if self.v & 0x3F00 == 0x3F00 {
+            // with rendering off and v pointing into the palette, that
+            // colour is shown instead of the backdrop
+            self.palette[palette_index(self.v)]
+        } else {
+            self.palette[0]
+        };
+
+        let colour = if self.mask & MASK_GREYSCALE != 0 {
+            colour & 0x30
+        } else {
+            colour
+        };
+        let mut emphasis = (self.mask >> 5) as u16;
+        // the 2C07 has the red and green emphasis bits swapped
+        if self.region == Region::PAL {
+            emphasis = (emphasis & 0b100) | (emphasis & 0b001) << 1 | (emphasis & 0b010) >> 1;
+        }
+        self.frame_buffer[y * SCREEN_WIDTH + x] = colour as u16 | emphasis << 6;
+    }
+}
+
+// $3F10/$3F14/$3F18/$3F1C mirror the backdrop entries at $3F00/$3F04/$3F08/$3F0C
+fn palette_index(address: u16) -> usize {
+    let index = (address & 0x1F) as usize;
+    if index & 0x13 == 0x10 {
+        index & !0x10
+    } else {
+        index
+    }
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // NTSC timing
+    const SCANLINES_PER_FRAME: u16 = 262;
+    const VBLANK_SCANLINE: u16 = 241;
+    const PRE_RENDER_SCANLINE: u16 = 261;
+
+    fn set_address(ppu: &mut PPU, address: u16) {
+        ppu.write_register(0x2006, (address >> 8) as u8);
+        ppu.write_register(0x2006, address as u8);
+    }
+
+    #[test]
+    fn test_ppudata_buffered_read() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        set_address(&mut ppu, 0x2305);
+        ppu.write_register(0x2007, 0x66);
+        ppu.write_register(0x2007, 0x77);
+
+        set_address(&mut ppu, 0x2305);
+        ppu.read_register(0x2007); // dummy read fills the buffer
+        assert_eq!(ppu.read_register(0x2007), 0x66);
+        assert_eq!(ppu.read_register(0x2007), 0x77);
+    }
+
+    #[test]
+    fn test_ppudata_increment_32() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_register(0x2000, CTRL_VRAM_INCREMENT);
+        set_address(&mut ppu, 0x2000);
+        ppu.write_register(0x2007, 0x11);
+        ppu.write_register(0x2007, 0x22);
+        assert_eq!(ppu.read_vram(0x2000), 0x11);
+        assert_eq!(ppu.read_vram(0x2020), 0x22);
+    }
+
+    #[test]
+    fn test_palette_read_is_not_buffered() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_vram(0x2F00, 0x55);
+        ppu.palette[0] = 0x0F;
+
+        set_address(&mut ppu, 0x3F00);
+        assert_eq!(ppu.read_register(0x2007) & 0x3F, 0x0F);
+        // the buffer now holds the nametable byte below the palette
+        set_address(&mut ppu, 0x2000);
+        assert_eq!(ppu.read_register(0x2007), 0x55);
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_vram(0x3F10, 0x21);
+        assert_eq!(ppu.read_vram(0x3F00), 0x21);
+        ppu.write_vram(0x3F04, 0x22);
+        assert_eq!(ppu.read_vram(0x3F14), 0x22);
+        // $3F11 is a real sprite palette entry
+        ppu.write_vram(0x3F11, 0x23);
+        assert_eq!(ppu.read_vram(0x3F01), 0x00);
+        assert_eq!(ppu.read_vram(0x3FF1), 0x23);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_vram(0x2005, 1);
+        ppu.write_vram(0x2805, 2);
+        assert_eq!(ppu.read_vram(0x2405), 1);
+        assert_eq!(ppu.read_vram(0x2C05), 2);
+
+        let mut ppu = PPU::new(vec![], Mirroring::Vertical);
+        ppu.write_vram(0x2005, 1);
+        ppu.write_vram(0x2405, 2);
+        assert_eq!(ppu.read_vram(0x2805), 1);
+        assert_eq!(ppu.read_vram(0x2C05), 2);
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(ppu.read_vram(0x3005), 1);
+
+        let mut ppu = PPU::new(vec![], Mirroring::FourScreen);
+        for (i, base) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            ppu.write_vram(*base, i as u8 + 1);
+        }
+        for (i, base) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            assert_eq!(ppu.read_vram(*base), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_chr_rom_is_read_only() {
+        let mut ppu = PPU::new(vec![0xAA; 8192], Mirroring::Horizontal);
+        ppu.write_vram(0x0010, 0x55);
+        assert_eq!(ppu.read_vram(0x0010), 0xAA);
+
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_vram(0x0010, 0x55);
+        assert_eq!(ppu.read_vram(0x0010), 0x55);
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+
+        while !(ppu.scanline == VBLANK_SCANLINE && ppu.dot == 2) {
+            ppu.clock();
+        }
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        // reading PPUSTATUS clears vblank
+        assert_eq!(ppu.read_register(0x2002) & STATUS_VBLANK, STATUS_VBLANK);
+        assert_eq!(ppu.read_register(0x2002) & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn test_enable_nmi_during_vblank() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.status |= STATUS_VBLANK;
+        ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+        assert!(ppu.poll_nmi());
+    }
+
+    #[test]
+    fn test_vblank_cleared_on_pre_render_line() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.status = STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT;
+        ppu.scanline = PRE_RENDER_SCANLINE;
+        ppu.dot = 1;
+        ppu.clock();
+        assert_eq!(ppu.status, 0);
+    }
+
+    #[test]
+    fn test_frame_counter() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        for _ in 0..(SCANLINES_PER_FRAME as u32 * DOTS_PER_SCANLINE as u32) {
+            ppu.clock();
+        }
+        assert_eq!(ppu.frame, 1);
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+    }
+
+    #[test]
+    fn test_status_read_resets_write_toggle() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_register(0x2006, 0x21);
+        ppu.read_register(0x2002);
+        set_address(&mut ppu, 0x2208);
+        assert_eq!(ppu.v, 0x2208);
+    }
+
+    #[test]
+    fn test_scroll_writes_go_to_t() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_register(0x2000, 0b0000_0011);
+        ppu.write_register(0x2005, 0b0111_1101);
+        ppu.write_register(0x2005, 0b0101_1110);
+        assert_eq!(ppu.fine_x, 0b101);
+        assert_eq!(ppu.t, 0b0110_1101_0110_1111);
+    }
+
+    #[test]
+    fn test_oam_data() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_register(0x2003, 0x10);
+        ppu.write_register(0x2004, 0x66);
+        ppu.write_register(0x2004, 0xFF);
+        assert_eq!(ppu.oam[0x10], 0x66);
+
+        ppu.write_register(0x2003, 0x11);
+        assert_eq!(ppu.read_register(0x2004), 0xFF);
+        // byte 2 of each sprite is the attribute byte
+        ppu.write_register(0x2003, 0x12);
+        ppu.write_register(0x2004, 0xFF);
+        ppu.write_register(0x2003, 0x12);
+        assert_eq!(ppu.read_register(0x2004), 0xE3);
+    }
+
+    #[test]
+    fn test_write_only_registers_read_open_bus() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.write_register(0x2001, 0x5A);
+        assert_eq!(ppu.read_register(0x2000), 0x5A);
+        assert_eq!(ppu.read_register(0x2002) & 0x1F, 0x1A);
+    }
+
+    // a tile of solid colour 1 at 0, solid colour 3 at 1 (CHR RAM)
+    fn solid_tiles_ppu() -> PPU {
+        let mut ppu = PPU::new(vec![], Mirroring::Vertical);
+        for i in 0..8 {
+            ppu.chr[16 + i] = 0xFF;
+            ppu.chr[16 + 8 + i] = 0xFF;
+        }
+        for i in 0..8 {
+            ppu.chr[i] = 0xFF;
+        }
+        ppu.palette[0] = 0x0F;
+        ppu.palette[1] = 0x01;
+        ppu.palette[3] = 0x03;
+        ppu.palette[5] = 0x11;
+        ppu.mask = MASK_SHOW_BACKGROUND | MASK_SHOW_BACKGROUND_LEFT;
+        ppu
+    }
+
+    fn run_to(ppu: &mut PPU, scanline: u16, dot: u16) {
+        while !(ppu.scanline == scanline && ppu.dot == dot) {
+            ppu.clock();
+        }
+    }
+
+    // from the pre-render line to the end of the visible picture
+    fn render_frame(ppu: &mut PPU) {
+        run_to(ppu, PRE_RENDER_SCANLINE, 0);
+        run_to(ppu, SCREEN_HEIGHT as u16, 0);
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u8 {
+        ppu.frame_buffer[y * SCREEN_WIDTH + x] as u8
+    }
+
+    #[test]
+    fn test_background_renders_tiles() {
+        let mut ppu = solid_tiles_ppu();
+        // tile 1 in the second column, attribute palette 1 for the top-left block
+        ppu.write_vram(0x2001, 1);
+        ppu.write_vram(0x23C0, 0b01);
+        ppu.palette[7] = 0x17;
+        // tile 0 everywhere else uses colour 1
+        render_frame(&mut ppu);
+
+        assert_eq!(pixel(&ppu, 0, 0), 0x11); // palette 1, colour 1
+        assert_eq!(pixel(&ppu, 8, 0), 0x17); // palette 1, colour 3
+        assert_eq!(pixel(&ppu, 40, 0), 0x01); // palette 0, colour 1
+    }
+
+    #[test]
+    fn test_background_fine_scroll() {
+        let mut ppu = solid_tiles_ppu();
+        ppu.write_vram(0x2000, 1);
+        ppu.write_vram(0x2001, 2); // tile 2 is empty
+        ppu.palette[3] = 0x03;
+
+        ppu.write_register(0x2005, 3);
+        ppu.write_register(0x2005, 0);
+        render_frame(&mut ppu);
+
+        // three pixels scrolled out of tile 1, five left
+        for x in 0..5 {
+            assert_eq!(pixel(&ppu, x, 0), 0x03);
+        }
+        assert_eq!(pixel(&ppu, 5, 0), 0x0F);
+    }
+
+    #[test]
+    fn test_background_left_column_mask() {
+        let mut ppu = solid_tiles_ppu();
+        ppu.mask = MASK_SHOW_BACKGROUND;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 7, 10), 0x0F);
+        assert_eq!(pixel(&ppu, 8, 10), 0x01);
+    }
+
+    #[test]
+    fn test_mid_frame_scroll_split() {
+        let mut ppu = solid_tiles_ppu();
+        // left nametable is tile 0, right nametable ($2400) is tile 1
+        for i in 0..960 {
+            ppu.write_vram(0x2400 + i, 1);
+        }
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 0);
+
+        // status bar on top, then scroll to the right nametable in hblank
+        run_to(&mut ppu, 32, 0);
+        ppu.write_register(0x2000, 0b01);
+        ppu.write_register(0x2005, 0);
+        ppu.write_register(0x2005, 0);
+        run_to(&mut ppu, SCREEN_HEIGHT as u16, 0);
+
+        // the new horizontal scroll is copied into v at dot 257
+        assert_eq!(pixel(&ppu, 100, 32), 0x01);
+        assert_eq!(pixel(&ppu, 100, 33), 0x03);
+        assert_eq!(pixel(&ppu, 100, 239), 0x03);
+    }
+
+    #[test]
+    fn test_odd_frame_skips_a_dot() {
+        let mut ppu = solid_tiles_ppu();
+        run_to(&mut ppu, 0, 0);
+        let mut dots = 0;
+        let frame = ppu.frame;
+        while ppu.frame == frame {
+            ppu.clock();
+            dots += 1;
+        }
+        let expected = SCANLINES_PER_FRAME as u32 * DOTS_PER_SCANLINE as u32;
+        assert_eq!(dots + (frame % 2) as u32, expected);
+    }
+
+    #[test]
+    fn test_backdrop_when_rendering_disabled() {
+        let mut ppu = solid_tiles_ppu();
+        ppu.mask = 0;
+        render_frame(&mut ppu);
+        assert!(ppu.frame_buffer.iter().all(|&c| c == 0x0F));
+    }
+
+    #[test]
+    fn test_emphasis_bits_in_frame_buffer() {
+        let mut ppu = solid_tiles_ppu();
+        ppu.mask |= MASK_EMPHASIZE_RED | MASK_EMPHASIZE_BLUE;
+        render_frame(&mut ppu);
+        assert_eq!(ppu.frame_buffer[0], 0x01 | 0b101 << 6);
+    }
+
+    fn set_sprite(ppu: &mut PPU, n: usize, y: u8, tile: u8, attributes: u8, x: u8) {
+        ppu.oam[n * 4..n * 4 + 4].copy_from_slice(&[y, tile, attributes, x]);
+    }
+
+    fn sprite_ppu() -> PPU {
+        let mut ppu = solid_tiles_ppu();
+        // tile 2: a single opaque pixel at the top left, colour 2
+        ppu.chr[2 * 16 + 8] = 0x80;
+        ppu.palette[0x12] = 0x22;
+        ppu.palette[0x17] = 0x27;
+        // all sprites off screen, background tile 3 is empty
+        ppu.oam = [0xFF; 256];
+        for i in 0..960 {
+            ppu.write_vram(0x2000 + i, 3);
+        }
+        ppu.mask = MASK_SHOW_BACKGROUND
+            | MASK_SHOW_BACKGROUND_LEFT
+            | MASK_SHOW_SPRITES
+            | MASK_SHOW_SPRITES_LEFT;
+        ppu
+    }
+
+    #[test]
+    fn test_sprite_is_drawn_one_line_below_y() {
+        let mut ppu = sprite_ppu();
+        set_sprite(&mut ppu, 0, 20, 1, 0b01, 40);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 40, 20), 0x0F);
+        assert_eq!(pixel(&ppu, 40, 21), 0x27);
+        assert_eq!(pixel(&ppu, 47, 28), 0x27);
+        assert_eq!(pixel(&ppu, 48, 21), 0x0F);
+        assert_eq!(pixel(&ppu, 40, 29), 0x0F);
+    }
+
+    #[test]
+    fn test_sprite_flip() {
+        let mut ppu = sprite_ppu();
+        set_sprite(
+            &mut ppu,
+            0,
+            10,
+            2,
+            SPRITE_FLIP_HORIZONTAL | SPRITE_FLIP_VERTICAL,
+            100,
+        );
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 100, 11), 0x0F);
+        assert_eq!(pixel(&ppu, 107, 18), 0x22);
+    }
+
+    #[test]
+    fn test_sprite_8x16() {
+        let mut ppu = sprite_ppu();
+        ppu.ctrl |= CTRL_SPRITE_SIZE;
+        // tile 4 on top (one pixel, colour 2), tile 5 (solid colour 3) below
+        ppu.chr[4 * 16 + 8] = 0x80;
+        for i in 0..16 {
+            ppu.chr[5 * 16 + i] = 0xFF;
+        }
+        set_sprite(&mut ppu, 0, 50, 4, 0, 60);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 60, 51), 0x22);
+        assert_eq!(pixel(&ppu, 61, 51), 0x0F);
+        assert_eq!(pixel(&ppu, 61, 59), ppu.palette[0x13]);
+        assert_eq!(pixel(&ppu, 61, 67), 0x0F);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = sprite_ppu();
+        // background tile 0 (colour 1) in the second tile column
+        for row in 0..30 {
+            ppu.write_vram(0x2000 + row * 32 + 1, 0);
+        }
+        set_sprite(&mut ppu, 0, 0, 1, SPRITE_BEHIND_BACKGROUND, 4);
+        // lower OAM index wins even when it is behind the background
+        set_sprite(&mut ppu, 1, 0, 1, 0b01, 4);
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 4, 1), ppu.palette[0x13]);
+        assert_eq!(pixel(&ppu, 8, 1), 0x01);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_timing() {
+        let mut ppu = sprite_ppu();
+        for row in 0..30 {
+            ppu.write_vram(0x2000 + row * 32 + 12, 0);
+        }
+        set_sprite(&mut ppu, 0, 99, 1, 0, 96);
+
+        // first overlap is x = 96 on line 100, drawn at dot 97
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 0);
+        run_to(&mut ppu, 100, 97);
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+        ppu.clock();
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, STATUS_SPRITE_ZERO_HIT);
+
+        // cleared on the pre-render line
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 2);
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+    }
+
+    #[test]
+    fn test_no_sprite_zero_hit_at_x_255() {
+        let mut ppu = sprite_ppu();
+        for row in 0..30 {
+            ppu.write_vram(0x2000 + row * 32 + 31, 0);
+        }
+        set_sprite(&mut ppu, 0, 99, 1, 0, 255);
+        render_frame(&mut ppu);
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+    }
+
+    #[test]
+    fn test_sprite_limit_and_overflow() {
+        let mut ppu = sprite_ppu();
+        for n in 0..9 {
+            set_sprite(&mut ppu, n, 30, 1, 0, n as u8 * 10);
+        }
+        render_frame(&mut ppu);
+        assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, STATUS_SPRITE_OVERFLOW);
+        assert_eq!(pixel(&ppu, 70, 31), ppu.palette[0x13]);
+        assert_eq!(pixel(&ppu, 80, 31), 0x0F);
+
+        ppu.disable_sprite_limit = true;
+        render_frame(&mut ppu);
+        assert_eq!(pixel(&ppu, 80, 31), ppu.palette[0x13]);
+    }
+
+    #[test]
+    fn test_sprite_overflow_bug() {
+        let mut ppu = sprite_ppu();
+        for n in 0..8 {
+            set_sprite(&mut ppu, n, 30, 1, 0, 0);
+        }
+        // sprite 8 is on the line but its Y is not the byte checked, the
+        // tile byte of sprite 9 is read as Y instead and is out of range
+        set_sprite(&mut ppu, 8, 0xF0, 0xFF, 0xFF, 0xFF);
+        set_sprite(&mut ppu, 9, 0xFF, 0xFF, 0xFF, 0xFF);
+        set_sprite(&mut ppu, 10, 0xFF, 0xFF, 30, 0xFF);
+        render_frame(&mut ppu);
+        // attribute byte of sprite 10 happens to be in range
+        assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, STATUS_SPRITE_OVERFLOW);
+
+        let mut ppu = sprite_ppu();
+        for n in 0..8 {
+            set_sprite(&mut ppu, n, 30, 1, 0, 0);
+        }
+        set_sprite(&mut ppu, 9, 30, 0xFF, 0xFF, 0xFF);
+        render_frame(&mut ppu);
+        // sprite 9 is on the line, but its X byte is compared instead
+        assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn test_pal_frame_timing() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.region = Region::PAL;
+        ppu.write_register(0x2000, CTRL_GENERATE_NMI);
+
+        let mut dots = 0;
+        while ppu.frame == 0 {
+            ppu.clock();
+            dots += 1;
+            if ppu.scanline == 241 && ppu.dot == 2 {
+                assert!(ppu.poll_nmi());
+            }
+            if ppu.scanline == 311 && ppu.dot == 0 {
+                // vblank lasts until the pre-render line
+                assert_eq!(ppu.status & STATUS_VBLANK, STATUS_VBLANK);
+            }
+        }
+        assert_eq!(dots, 312 * 341);
+    }
+
+    #[test]
+    fn test_dendy_vblank_scanline() {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.region = Region::Dendy;
+        while !(ppu.scanline == 291 && ppu.dot == 0) {
+            ppu.clock();
+        }
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        ppu.clock();
+        ppu.clock();
+        assert_eq!(ppu.status & STATUS_VBLANK, STATUS_VBLANK);
+    }
+
+    #[test]
+    fn test_pal_emphasis_swap() {
+        let mut ppu = solid_tiles_ppu();
+        ppu.region = Region::PAL;
+        ppu.mask |= MASK_EMPHASIZE_RED;
+        run_to(&mut ppu, 311, 0);
+        run_to(&mut ppu, SCREEN_HEIGHT as u16, 0);
+        assert_eq!(ppu.frame_buffer[0] >> 6, 0b010);
+    }
+}
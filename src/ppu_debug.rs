@@ -0,0 +1,348 @@
+use crate::palette::Palette;
+use crate::ppu::{
+    CTRL_BACKGROUND_TABLE, CTRL_SPRITE_SIZE, CTRL_SPRITE_TABLE, PPU, SCREEN_HEIGHT, SCREEN_WIDTH,
+    SPRITE_BEHIND_BACKGROUND, SPRITE_FLIP_HORIZONTAL, SPRITE_FLIP_VERTICAL, SPRITE_PALETTE,
+};
+use crate::screenshot::Screenshot;
+use std::fmt;
+
+// -----------------------------
+// PPU debug viewers
+// nametables, pattern tables, palettes and OAM as RGB images
+// -----------------------------
+
+// colour of the scroll window outline in the nametable view
+const SCROLL_WINDOW_COLOUR: [u8; 3] = [0xFF, 0x00, 0xFF];
+
+// size of one colour in the palette grid
+const SWATCH_SIZE: usize = 16;
+
+fn blank_image(width: usize, height: usize) -> Screenshot {
+    Screenshot {
+        width,
+        height,
+        rgb: vec![0; width * height * 3],
+    }
+}
+
+fn set_pixel(image: &mut Screenshot, x: usize, y: usize, colour: [u8; 3]) {
+    let start = (y * image.width + x) * 3;
+    image.rgb[start..start + 3].copy_from_slice(&colour);
+}
+
+// pixel values (0-3) of one row of a tile
+fn tile_row(ppu: &PPU, table: u16, tile: u16, row: u16) -> [u8; 8] {
+    let address = table + tile * 16 + row;
+    let low = ppu.read_vram(address);
+    let high = ppu.read_vram(address + 8);
+
+    let mut pixels = [0; 8];
+    for (x, pixel) in pixels.iter_mut().enumerate() {
+        let bit = 7 - x;
+        *pixel = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+    }
+    pixels
+}
+
+// colour of a pixel value in one of the 8 PPU palettes (4-7 are sprites)
+fn palette_colour(ppu: &PPU, palette: &Palette, number: u8, pixel: u8) -> [u8; 3] {
+    let index = if pixel == 0 { 0 } else { number * 4 + pixel };
+    palette.rgb(ppu.read_vram(0x3F00 + index as u16) as u16)
+}
+
+// -----------------------------
+// Nametables
+// -----------------------------
+
+// all four nametables (512x480) with the scroll window outlined
+pub fn nametables(ppu: &PPU, palette: &Palette) -> Screenshot {
+    let mut image = blank_image(SCREEN_WIDTH * 2, SCREEN_HEIGHT * 2);
+    let table = if ppu.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+        0x1000
+    } else {
+        0
+    };
+
+    for nametable in 0..4u16 {
+        let base = 0x2000 + nametable * 0x400;
+        let origin_x = (nametable as usize % 2) * SCREEN_WIDTH;
+        let origin_y = (nametable as usize / 2) * SCREEN_HEIGHT;
+
+        for row in 0..30u16 {
+            for column in 0..32u16 {
+                let tile = ppu.read_vram(base + row * 32 + column) as u16;
+                let attribute = ppu.read_vram(base + 0x3C0 + (row / 4) * 8 + column / 4);
+                let shift = ((row % 4) / 2) * 4 + ((column % 4) / 2) * 2;
+                let number = (attribute >> shift) & 0b11;
+
+                for y in 0..8 {
+                    let pixels = tile_row(ppu, table, tile, y);
+                    for (x, &pixel) in pixels.iter().enumerate() {
+                        let colour = palette_colour(ppu, palette, number, pixel);
+                        let px = origin_x + column as usize * 8 + x;
+                        let py = origin_y + row as usize * 8 + y as usize;
+                        set_pixel(&mut image, px, py, colour);
+                    }
+                }
+            }
+        }
+    }
+
+    draw_scroll_window(&mut image, ppu.scroll_position());
+    image
+}
+
+// outline of the visible 256x240 area, wrapping around the edges
+fn draw_scroll_window(image: &mut Screenshot, (scroll_x, scroll_y): (usize, usize)) {
+    let (width, height) = (image.width, image.height);
+    for offset in 0..SCREEN_WIDTH {
+        let x = (scroll_x + offset) % width;
+        set_pixel(image, x, scroll_y % height, SCROLL_WINDOW_COLOUR);
+        set_pixel(
+            image,
+            x,
+            (scroll_y + SCREEN_HEIGHT - 1) % height,
+            SCROLL_WINDOW_COLOUR,
+        );
+    }
+    for offset in 0..SCREEN_HEIGHT {
+        let y = (scroll_y + offset) % height;
+        set_pixel(image, scroll_x % width, y, SCROLL_WINDOW_COLOUR);
+        set_pixel(
+            image,
+            (scroll_x + SCREEN_WIDTH - 1) % width,
+            y,
+            SCROLL_WINDOW_COLOUR,
+        );
+    }
+}
+
+// -----------------------------
+// Pattern tables and palettes
+// -----------------------------
+
+// both pattern tables side by side (256x128), drawn with PPU palette 0-7
+pub fn pattern_tables(ppu: &PPU, palette: &Palette, number: u8) -> Screenshot {
+    let mut image = blank_image(256, 128);
+
+    for table in 0..2u16 {
+        for tile in 0..256u16 {
+            let origin_x = table as usize * 128 + (tile as usize % 16) * 8;
+            let origin_y = (tile as usize / 16) * 8;
+            for y in 0..8 {
+                let pixels = tile_row(ppu, table * 0x1000, tile, y);
+                for (x, &pixel) in pixels.iter().enumerate() {
+                    let colour = palette_colour(ppu, palette, number & 0b111, pixel);
+                    set_pixel(&mut image, origin_x + x, origin_y + y as usize, colour);
+                }
+            }
+        }
+    }
+    image
+}
+
+// palette RAM as a 16x2 grid, background on top, sprites below
+pub fn palette_grid(ppu: &PPU, palette: &Palette) -> Screenshot {
+    let mut image = blank_image(16 * SWATCH_SIZE, 2 * SWATCH_SIZE);
+
+    for index in 0..32u16 {
+        let colour = palette.rgb(ppu.read_vram(0x3F00 + index) as u16);
+        let origin_x = (index as usize % 16) * SWATCH_SIZE;
+        let origin_y = (index as usize / 16) * SWATCH_SIZE;
+        for y in 0..SWATCH_SIZE {
+            for x in 0..SWATCH_SIZE {
+                set_pixel(&mut image, origin_x + x, origin_y + y, colour);
+            }
+        }
+    }
+    image
+}
+
+// -----------------------------
+// OAM
+// -----------------------------
+
+pub struct OamEntry {
+    pub index: usize,
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub palette: u8,
+    pub behind_background: bool,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+}
+
+impl fmt::Display for OamEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{:02} x={:3} y={:3} tile=${:02X} palette={} {}{}{}",
+            self.index,
+            self.x,
+            self.y,
+            self.tile,
+            self.palette,
+            if self.behind_background {
+                "back"
+            } else {
+                "front"
+            },
+            if self.flip_horizontal { " H" } else { "" },
+            if self.flip_vertical { " V" } else { "" },
+        )
+    }
+}
+
+pub fn oam_entries(ppu: &PPU) -> Vec<OamEntry> {
+    ppu.oam
+        .chunks_exact(4)
+        .enumerate()
+        .map(|(index, sprite)| OamEntry {
+            index,
+            y: sprite[0],
+            tile: sprite[1],
+            x: sprite[3],
+            palette: sprite[2] & SPRITE_PALETTE,
+            behind_background: sprite[2] & SPRITE_BEHIND_BACKGROUND != 0,
+            flip_horizontal: sprite[2] & SPRITE_FLIP_HORIZONTAL != 0,
+            flip_vertical: sprite[2] & SPRITE_FLIP_VERTICAL != 0,
+        })
+        .collect()
+}
+
+// the 64 sprites in an 8x8 grid of 8x16 cells (64x128), transparent
+// pixels use the backdrop colour
+pub fn oam_sprites(ppu: &PPU, palette: &Palette) -> Screenshot {
+    let mut image = blank_image(64, 128);
+    let tall = ppu.ctrl & CTRL_SPRITE_SIZE != 0;
+    let height: u16 = if tall { 16 } else { 8 };
+
+    for entry in oam_entries(ppu) {
+        let origin_x = (entry.index % 8) * 8;
+        let origin_y = (entry.index / 8) * 16;
+
+        for row in 0..height {
+            let source_row = if entry.flip_vertical {
+                height - 1 - row
+            } else {
+                row
+            };
+            let (table, tile) = if tall {
+                let table = (entry.tile as u16 & 1) * 0x1000;
+                (table, (entry.tile & 0xFE) as u16 + source_row / 8)
+            } else if ppu.ctrl & CTRL_SPRITE_TABLE != 0 {
+                (0x1000, entry.tile as u16)
+            } else {
+                (0, entry.tile as u16)
+            };
+
+            let mut pixels = tile_row(ppu, table, tile, source_row % 8);
+            if entry.flip_horizontal {
+                pixels.reverse();
+            }
+            for (x, &pixel) in pixels.iter().enumerate() {
+                let colour = palette_colour(ppu, palette, 4 + entry.palette, pixel);
+                set_pixel(&mut image, origin_x + x, origin_y + row as usize, colour);
+            }
+        }
+    }
+    image
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    fn pixel(image: &Screenshot, x: usize, y: usize) -> [u8; 3] {
+        let start = (y * image.width + x) * 3;
+        [image.rgb[start], image.rgb[start + 1], image.rgb[start + 2]]
+    }
+
+    fn test_ppu() -> PPU {
+        let mut ppu = PPU::new(vec![], Mirroring::Vertical);
+        // tile 1: left column colour 1, everything else colour 0
+        for row in 0..8 {
+            ppu.chr[16 + row] = 0x80;
+        }
+        ppu.write_vram(0x3F00, 0x0F);
+        ppu.write_vram(0x3F01, 0x16);
+        ppu.write_vram(0x3F05, 0x2A);
+        ppu.write_vram(0x3F11, 0x30);
+        ppu
+    }
+
+    #[test]
+    fn test_nametables() {
+        let palette = Palette::default();
+        let mut ppu = test_ppu();
+        ppu.write_vram(0x2400 + 32 + 1, 1); // right table, row 1, column 1
+        ppu.write_vram(0x27C0, 0b01); // palette 1 for that block
+
+        let image = nametables(&ppu, &palette);
+        assert_eq!((image.width, image.height), (512, 480));
+        assert_eq!(pixel(&image, 256 + 8, 8), palette.rgb(0x2A));
+        assert_eq!(pixel(&image, 256 + 9, 8), palette.rgb(0x0F));
+        // vertical mirroring: the bottom right table shows the same tile
+        assert_eq!(pixel(&image, 256 + 8, 240 + 8), palette.rgb(0x2A));
+    }
+
+    #[test]
+    fn test_scroll_window_outline() {
+        let palette = Palette::default();
+        let mut ppu = test_ppu();
+        ppu.write_register(0x2005, 100);
+        ppu.write_register(0x2005, 16);
+
+        let image = nametables(&ppu, &palette);
+        assert_eq!(pixel(&image, 100, 16), SCROLL_WINDOW_COLOUR);
+        assert_eq!(pixel(&image, 355, 255), SCROLL_WINDOW_COLOUR);
+        assert_ne!(pixel(&image, 101, 17), SCROLL_WINDOW_COLOUR);
+    }
+
+    #[test]
+    fn test_pattern_tables() {
+        let palette = Palette::default();
+        let ppu = test_ppu();
+        let image = pattern_tables(&ppu, &palette, 0);
+        assert_eq!((image.width, image.height), (256, 128));
+        assert_eq!(pixel(&image, 8, 3), palette.rgb(0x16));
+        assert_eq!(pixel(&image, 9, 3), palette.rgb(0x0F));
+        // palette 1 colour 1
+        let image = pattern_tables(&ppu, &palette, 1);
+        assert_eq!(pixel(&image, 8, 3), palette.rgb(0x2A));
+    }
+
+    #[test]
+    fn test_palette_grid() {
+        let palette = Palette::default();
+        let ppu = test_ppu();
+        let image = palette_grid(&ppu, &palette);
+        assert_eq!(pixel(&image, 16 + 5, 5), palette.rgb(0x16));
+        assert_eq!(pixel(&image, 16 + 5, 16 + 5), palette.rgb(0x30));
+    }
+
+    #[test]
+    fn test_oam() {
+        let palette = Palette::default();
+        let mut ppu = test_ppu();
+        ppu.oam[4..8].copy_from_slice(&[40, 1, SPRITE_FLIP_HORIZONTAL, 120]);
+
+        let entries = oam_entries(&ppu);
+        assert_eq!(entries.len(), 64);
+        assert_eq!(
+            entries[1].to_string(),
+            "#01 x=120 y= 40 tile=$01 palette=0 front H"
+        );
+
+        // sprite 1 sits in the second cell, flipped so colour 1 is on the right
+        let image = oam_sprites(&ppu, &palette);
+        assert_eq!(pixel(&image, 8 + 7, 2), palette.rgb(0x30));
+        assert_eq!(pixel(&image, 8, 2), palette.rgb(0x0F));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::audio::SampleBuffer;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+// -----------------------------
+// Audio recording
+// the mixed output, and optionally each channel on its own (stems),
+// written to WAV files while the machine runs
+// -----------------------------
+
+// samples buffered before they are written out
+const WRITE_CHUNK: usize = 4096;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WavFormat {
+    Int16,
+    Float32,
+}
+
+struct Track {
+    path: PathBuf,
+    buffer: SampleBuffer,
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl Track {
+    fn create(
+        path: PathBuf,
+        format: WavFormat,
+        clock_rate: f64,
+        sample_rate: u32,
+    ) -> Result<Track, String> {
+        let spec = match format {
+            WavFormat::Int16 => WavSpec {
+                channels: 1,
+                sample_rate,
+                bits_per_sample: 16,
+                sample_format: SampleFormat::Int,
+            },
+            WavFormat::Float32 => WavSpec {
+                channels: 1,
+                sample_rate,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            },
+        };
+        let writer = WavWriter::create(&path, spec)
+            .map_err(|e| format!("could not create {}: {}", path.display(), e))?;
+        Ok(Track {
+            path,
+            buffer: SampleBuffer::new(clock_rate, sample_rate),
+            writer,
+        })
+    }
+
+    fn write_available(&mut self) -> Result<(), String> {
+        let mut samples = vec![0.0; self.buffer.samples_available()];
+        let count = self.buffer.read_samples(&mut samples);
+        let float = self.writer.spec().sample_format == SampleFormat::Float;
+
+        for sample in &samples[..count] {
+            let result = if float {
+                self.writer.write_sample(*sample)
+            } else {
+                let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                self.writer.write_sample(value)
+            };
+            result.map_err(|e| format!("could not write {}: {}", self.path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct AudioRecorder {
+    mix: Track,
+    stems: Vec<Track>,
+    // first write error, reported by finish
+    error: Option<String>,
+}
+
+impl AudioRecorder {
+    // the mix goes to path, each stem next to it as <name>-<stem>.wav
+    pub fn create(
+        path: &Path,
+        format: WavFormat,
+        stem_names: &[&str],
+        clock_rate: f64,
+        sample_rate: u32,
+    ) -> Result<AudioRecorder, String> {
+        let mix = Track::create(path.to_path_buf(), format, clock_rate, sample_rate)?;
+        let stems = stem_names
+            .iter()
+            .map(|name| Track::create(stem_path(path, name), format, clock_rate, sample_rate))
+            .collect::<Result<_, _>>()?;
+        Ok(AudioRecorder {
+            mix,
+            stems,
+            error: None,
+        })
+    }
+
+    // one CPU cycle: the mixed level, and each stem's level on its own
+    pub fn clock(&mut self, mix: f32, stem_levels: &[f32]) {
+        self.mix.buffer.clock(mix);
+        for (track, level) in self.stems.iter_mut().zip(stem_levels) {
+            track.buffer.clock(*level);
+        }
+
+        if self.mix.buffer.samples_available() >= WRITE_CHUNK {
+            if let Err(e) = self.write_available() {
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+
+    fn write_available(&mut self) -> Result<(), String> {
+        self.mix.write_available()?;
+        for track in self.stems.iter_mut() {
+            track.write_available()?;
+        }
+        Ok(())
+    }
+
+    // write what is left and close the files
+    pub fn finish(mut self) -> Result<(), String> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.write_available()?;
+        for track in std::iter::once(self.mix).chain(self.stems) {
+            track
+                .writer
+                .finalize()
+                .map_err(|e| format!("could not write {}: {}", track.path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+// song.wav -> song-pulse1.wav
+fn stem_path(path: &Path, stem: &str) -> PathBuf {
+    let name = path
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!("{}-{}.wav", name, stem))
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_RATE: f64 = 1_789_773.0;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(name)
+    }
+
+    #[test]
+    fn test_stem_path() {
+        assert_eq!(
+            stem_path(Path::new("out/song.wav"), "dmc"),
+            Path::new("out/song-dmc.wav")
+        );
+    }
+
+    #[test]
+    fn test_record_mix_and_stems() {
+        let path = temp_path("nest_emulator_recording_test.wav");
+        let mut recorder = AudioRecorder::create(
+            &path,
+            WavFormat::Int16,
+            &["pulse1", "noise"],
+            CLOCK_RATE,
+            44_100,
+        )
+        .unwrap();
+        // a tenth of a second of a 1 kHz square on pulse 1, noise silent
+        for cycle in 0..CLOCK_RATE as u64 / 10 {
+            let level = if (cycle / 895) % 2 == 0 { 0.2 } else { 0.0 };
+            recorder.clock(level, &[level, 0.0]);
+        }
+        recorder.finish().unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        assert_eq!((spec.channels, spec.sample_rate), (1, 44_100));
+        assert_eq!(spec.bits_per_sample, 16);
+        let mix: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert!(mix.len().abs_diff(4410) <= 1);
+        assert!(mix.iter().any(|sample| sample.abs() > 2000));
+
+        let stem_path = temp_path("nest_emulator_recording_test-pulse1.wav");
+        let mut reader = hound::WavReader::open(&stem_path).unwrap();
+        let stem: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(stem, mix);
+
+        let noise_path = temp_path("nest_emulator_recording_test-noise.wav");
+        let mut reader = hound::WavReader::open(&noise_path).unwrap();
+        assert!(reader.samples::<i16>().all(|s| s.unwrap() == 0));
+
+        for path in [path, stem_path, noise_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_record_float() {
+        let path = temp_path("nest_emulator_recording_float_test.wav");
+        let mut recorder =
+            AudioRecorder::create(&path, WavFormat::Float32, &[], CLOCK_RATE, 48_000).unwrap();
+        for _ in 0..CLOCK_RATE as u64 / 100 {
+            recorder.clock(0.5, &[]);
+        }
+        recorder.finish().unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().sample_format, SampleFormat::Float);
+        let samples: Vec<f32> = reader.samples::<f32>().map(|s| s.unwrap()).collect();
+        assert!(samples.len().abs_diff(480) <= 1);
+        assert!(samples.iter().any(|sample| *sample > 0.3));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_bad_path() {
+        let path = Path::new("/nonexistent/dir/song.wav");
+        let result = AudioRecorder::create(path, WavFormat::Int16, &[], CLOCK_RATE, 48_000);
+        assert!(result.is_err());
+    }
+}
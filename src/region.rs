@@ -0,0 +1,137 @@
+// -----------------------------
+// Region
+// console timing: NTSC (2A03 / 2C02), PAL (2A07 / 2C07) and Dendy clones
+// -----------------------------
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    NTSC,
+    PAL,
+    Dendy,
+}
+
+impl Region {
+    // --region on the command line
+    pub fn from_name(name: &str) -> Result<Region, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::NTSC),
+            "pal" => Ok(Region::PAL),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!(
+                "unknown region {}, expected ntsc, pal or dendy",
+                name
+            )),
+        }
+    }
+
+    // master clock divided by 12 (NTSC), 16 (PAL) or 15 (Dendy)
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 21_477_272.0 / 12.0,
+            Region::PAL => 26_601_712.0 / 16.0,
+            Region::Dendy => 26_601_712.0 / 15.0,
+        }
+    }
+
+    // PPU dots per CPU cycle as a fraction (numerator, denominator),
+    // PAL runs 3.2 dots per cycle
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (u32, u32) {
+        match self {
+            Region::PAL => (16, 5),
+            Region::NTSC | Region::Dendy => (3, 1),
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::Dendy => 312,
+        }
+    }
+
+    // first vblank scanline, the NMI fires on dot 1 of it
+    // Dendy keeps the 20 line vblank and pads the post-render time instead
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    pub fn vblank_scanlines(&self) -> u16 {
+        self.scanlines_per_frame() - 1 - self.vblank_scanline()
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        let dots = self.scanlines_per_frame() as f64 * 341.0;
+        let (numerator, denominator) = self.ppu_dots_per_cpu_cycle();
+        self.cpu_clock_rate() * numerator as f64 / denominator as f64 / dots
+    }
+
+    // -----------------------------
+    // APU
+    // -----------------------------
+
+    // CPU cycles to each step of the frame counter, the 4th step ends the
+    // 4-step sequence, the 5th the 5-step one
+    pub fn frame_counter_steps(&self) -> [u32; 5] {
+        match self {
+            Region::PAL => [8313, 16627, 24939, 33253, 41565],
+            Region::NTSC | Region::Dendy => [7457, 14913, 22371, 29829, 37281],
+        }
+    }
+
+    // noise channel timer periods, in CPU cycles
+    pub fn noise_periods(&self) -> [u16; 16] {
+        match self {
+            Region::PAL => [
+                4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+            ],
+            Region::NTSC | Region::Dendy => [
+                4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+            ],
+        }
+    }
+
+    // DMC output rates, in CPU cycles
+    pub fn dmc_periods(&self) -> [u16; 16] {
+        match self {
+            Region::PAL => [
+                398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+            ],
+            Region::NTSC | Region::Dendy => [
+                428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+            ],
+        }
+    }
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_rates() {
+        assert!((Region::NTSC.frame_rate() - 60.0988).abs() < 0.001);
+        assert!((Region::PAL.frame_rate() - 50.007).abs() < 0.001);
+        assert!((Region::Dendy.frame_rate() - 50.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_vblank_length() {
+        assert_eq!(Region::NTSC.vblank_scanlines(), 20);
+        assert_eq!(Region::PAL.vblank_scanlines(), 70);
+        assert_eq!(Region::Dendy.vblank_scanlines(), 20);
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Region::from_name("PAL"), Ok(Region::PAL));
+        assert_eq!(Region::from_name("dendy"), Ok(Region::Dendy));
+        assert!(Region::from_name("secam").is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use crate::ntsc::{NtscFilter, NTSC_WIDTH};
+use crate::palette::Palette;
+use crate::ppu::{PPU, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::fs;
+use std::path::Path;
+
+// -----------------------------
+// Screenshot
+// the current PPU frame as RGB, saved as PNG or PPM
+// -----------------------------
+
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    // packed RGB, 3 bytes per pixel
+    pub rgb: Vec<u8>,
+}
+
+impl Screenshot {
+    // grab the frame buffer through a palette
+    pub fn capture(ppu: &PPU, palette: &Palette) -> Screenshot {
+        Screenshot {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            rgb: palette.to_rgb(&ppu.frame_buffer),
+        }
+    }
+
+    // grab the frame buffer through the NTSC filter (wider picture)
+    pub fn capture_ntsc(ppu: &PPU, filter: &mut NtscFilter) -> Screenshot {
+        Screenshot {
+            width: NTSC_WIDTH,
+            height: SCREEN_HEIGHT,
+            rgb: filter.apply(&ppu.frame_buffer, ppu.frame).to_vec(),
+        }
+    }
+
+    // binary PPM (P6)
+    pub fn encode_ppm(&self) -> Vec<u8> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend_from_slice(&self.rgb);
+        data
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer
+            .write_image_data(&self.rgb)
+            .map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    // format from the extension: .png or .ppm
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+
+        let data = match extension.as_deref() {
+            Some("png") => self.encode_png()?,
+            Some("ppm") => self.encode_ppm(),
+            _ => {
+                return Err(format!(
+                    "cannot save {}: screenshots must be .png or .ppm",
+                    path.display()
+                ))
+            }
+        };
+        fs::write(path, data).map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+}
+
+// -----------------------------
+// TEST Section
+// -----------------------------
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    fn test_screenshot() -> Screenshot {
+        let mut ppu = PPU::new(vec![], Mirroring::Horizontal);
+        ppu.frame_buffer[0] = 0x30;
+        ppu.frame_buffer[1] = 0x16;
+        Screenshot::capture(&ppu, &Palette::default())
+    }
+
+    #[test]
+    fn test_capture() {
+        let screenshot = test_screenshot();
+        let palette = Palette::default();
+        assert_eq!(screenshot.rgb.len(), 256 * 240 * 3);
+        assert_eq!(screenshot.rgb[0..3], palette.rgb(0x30));
+        assert_eq!(screenshot.rgb[3..6], palette.rgb(0x16));
+    }
+
+    #[test]
+    fn test_ppm() {
+        let screenshot = test_screenshot();
+        let ppm = screenshot.encode_ppm();
+        let header = b"P6\n256 240\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + 256 * 240 * 3);
+        assert_eq!(ppm[header.len()..header.len() + 3], [0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let screenshot = test_screenshot();
+        let data = screenshot.encode_png().unwrap();
+
+        let decoder = png::Decoder::new(data.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (256, 240));
+        assert_eq!(pixels[..info.buffer_size()], screenshot.rgb[..]);
+    }
+
+    #[test]
+    fn test_save_by_extension() {
+        let screenshot = test_screenshot();
+        let path = std::env::temp_dir().join("nest_emulator_screenshot_test.ppm");
+        screenshot.save(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), screenshot.encode_ppm());
+        fs::remove_file(&path).unwrap();
+
+        assert!(screenshot.save(Path::new("frame.bmp")).is_err());
+    }
+}